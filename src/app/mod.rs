@@ -1,4 +1,4 @@
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{Receiver, Sender};
 
 use macroquad::{miniquad::window::quit, prelude::*};
 
@@ -6,6 +6,8 @@ use crate::cpu::CpuEvent;
 
 pub struct App {
     cpu_events: Receiver<CpuEvent>,
+    /// The console input of the devices, fed with the characters typed into the window.
+    console: Sender<u8>,
     text_buffer: String,
     is_running: bool,
 }
@@ -15,11 +17,13 @@ impl App {
     /// It returns a tuple containing the `App` instance and a receiver for application events.
     /// # Arguments
     /// * `cpu_events` - A receiver channel for CPU events.
+    /// * `console` - A sender channel for the console input of the devices.
     /// # Returns
     /// A tuple containing the `App` instance and a receiver for application events.
-    pub fn new(cpu_events: Receiver<CpuEvent>) -> Self {
+    pub fn new(cpu_events: Receiver<CpuEvent>, console: Sender<u8>) -> Self {
         App {
             cpu_events,
+            console,
             text_buffer: String::new(),
             is_running: true,
         }
@@ -44,6 +48,13 @@ impl App {
                             self.text_buffer.push_str("\nPress 'Q' to quit.");
                             self.is_running = false;
                         }
+                        CpuEvent::Error { error } => {
+                            log::debug!("Stopping with error: {}", error);
+                            self.text_buffer
+                                .push_str(&format!("\nVirtual machine error: {}", error));
+                            self.text_buffer.push_str("\nPress 'Q' to quit.");
+                            self.is_running = false;
+                        }
                     }
                 }
             }
@@ -57,7 +68,15 @@ impl App {
                 macroquad::color::WHITE,
             );
 
-            if is_key_pressed(KeyCode::Q) {
+            // While the program runs, typed characters are sent to its console
+            if self.is_running {
+                while let Some(character) = get_char_pressed() {
+                    let mut bytes = [0; 4];
+                    for &byte in character.encode_utf8(&mut bytes).as_bytes() {
+                        let _ = self.console.send(byte);
+                    }
+                }
+            } else if is_key_pressed(KeyCode::Q) {
                 quit();
             }
 
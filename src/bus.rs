@@ -0,0 +1,420 @@
+//! # System Bus
+//!
+//! This module implements the physical address space of the machine. The DRAM is mapped at a
+//! configurable base address (`0x8000_0000` by default, like most RISC-V boards), while other
+//! address ranges are dispatched to memory-mapped devices. Accesses to unmapped addresses
+//! raise access-fault exceptions.
+
+use std::{io, ops::Range};
+
+use derive_more::{Display, Error};
+
+use crate::{
+    devices::{Device, MAX_IRQ},
+    monitored_memory::MonitoredMemory,
+    trap::AccessType,
+    trap::Exception,
+};
+
+/// The default physical address of the first byte of DRAM.
+pub const DEFAULT_DRAM_BASE: u64 = 0x8000_0000;
+
+/// An error that prevents a device from being attached to the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Error)]
+pub enum BusError {
+    /// The address range is empty, wraps around or is already mapped.
+    #[display("the address range {start:#x}..{end:#x} is invalid or already mapped")]
+    Overlap { start: u64, end: u64 },
+    /// The interrupt number is zero or exceeds [`MAX_IRQ`].
+    #[display("invalid interrupt number {irq}")]
+    InvalidIrq { irq: u32 },
+}
+
+/// A device together with the address range it is mapped at.
+struct MappedDevice {
+    range: Range<u64>,
+    /// The interrupt number the interrupt line of the device is connected to.
+    irq: Option<u32>,
+    device: Box<dyn Device>,
+}
+
+pub struct Bus {
+    /// The physical address of the first byte of DRAM.
+    dram_base: u64,
+
+    /// The main memory, indexed from the start of DRAM.
+    pub dram: MonitoredMemory,
+
+    /// The devices, whose address ranges never overlap each other or the DRAM.
+    devices: Vec<MappedDevice>,
+}
+
+impl Bus {
+    /// Creates a new bus with `dram_size` bytes of DRAM mapped at `dram_base`.
+    pub fn new(dram_base: u64, dram_size: usize) -> io::Result<Self> {
+        if dram_base.checked_add(dram_size as u64).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DRAM exceeds the address space",
+            ));
+        }
+        Ok(Bus {
+            dram_base,
+            dram: MonitoredMemory::new(dram_size)?,
+            devices: Vec::new(),
+        })
+    }
+
+    /// Returns the physical address range of the DRAM.
+    pub fn dram_range(&self) -> Range<u64> {
+        self.dram_base..self.dram_base + self.dram.size() as u64
+    }
+
+    /// Maps `device` at the `size` bytes starting at `base` and connects its interrupt line
+    /// to the interrupt `irq` of the interrupt controllers.
+    pub fn attach(
+        &mut self,
+        base: u64,
+        size: u64,
+        irq: Option<u32>,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        if let Some(irq) = irq.filter(|irq| !(1..=MAX_IRQ).contains(irq)) {
+            return Err(BusError::InvalidIrq { irq });
+        }
+        let end = base.checked_add(size).filter(|_| size > 0);
+        let Some(end) = end.filter(|&end| !self.is_overlapping(&(base..end))) else {
+            return Err(BusError::Overlap {
+                start: base,
+                end: base.wrapping_add(size),
+            });
+        };
+        self.devices.push(MappedDevice {
+            range: base..end,
+            irq,
+            device,
+        });
+        Ok(())
+    }
+
+    /// Returns the attached devices together with their address ranges and interrupt numbers,
+    /// in attachment order.
+    pub fn devices(&self) -> impl Iterator<Item = (Range<u64>, Option<u32>, &dyn Device)> {
+        self.devices
+            .iter()
+            .map(|mapped| (mapped.range.clone(), mapped.irq, mapped.device.as_ref()))
+    }
+
+    /// Advances all devices by one CPU cycle, lets them access the DRAM and routes their
+    /// interrupt lines to the interrupt controllers.
+    pub fn tick(&mut self) {
+        let mut memory = GuestMemory::new(self.dram_base, &mut self.dram);
+        let mut lines = 0;
+        for mapped in &mut self.devices {
+            mapped.device.tick();
+            mapped.device.transfer(&mut memory);
+            if let Some(irq) = mapped.irq
+                && mapped.device.interrupt_pending()
+            {
+                lines |= 1 << irq;
+            }
+        }
+        for mapped in &mut self.devices {
+            mapped.device.update_interrupt_lines(lines);
+        }
+    }
+
+    /// Returns the bits of `mip` raised by the devices.
+    pub fn hart_interrupts(&self) -> u64 {
+        self.devices
+            .iter()
+            .fold(0, |mip, mapped| mip | mapped.device.hart_interrupts())
+    }
+
+    /// Returns the value of the real-time counter, if a device like the CLINT provides one.
+    pub fn time(&self) -> Option<u64> {
+        self.devices.iter().find_map(|mapped| mapped.device.time())
+    }
+
+    /// Resets all devices to their power-on state. The DRAM is left untouched.
+    pub fn reset(&mut self) {
+        for mapped in &mut self.devices {
+            mapped.device.reset();
+        }
+    }
+
+    /// Returns `true` if any part of `range` is already mapped.
+    fn is_overlapping(&self, range: &Range<u64>) -> bool {
+        std::iter::once(self.dram_range())
+            .chain(self.devices.iter().map(|mapped| mapped.range.clone()))
+            .any(|mapped| mapped.start < range.end && range.start < mapped.end)
+    }
+
+    /// Returns the DRAM offset of an access of `size` bytes at `address`,
+    /// if the access lies entirely within DRAM.
+    pub fn dram_offset(&self, address: u64, size: u64) -> Option<usize> {
+        let offset = address.checked_sub(self.dram_base)?;
+        (offset.checked_add(size)? <= self.dram.size() as u64).then_some(offset as usize)
+    }
+
+    /// Returns `true` if an access of `size` bytes at `address` lies entirely within DRAM.
+    pub fn is_dram(&self, address: u64, size: u64) -> bool {
+        self.dram_offset(address, size).is_some()
+    }
+
+    /// Returns `true` if an access of `size` bytes at `address` is handled by the DRAM
+    /// or a device, even if the access itself may not be permitted.
+    pub fn is_mapped(&self, address: u64, size: u64) -> bool {
+        self.is_dram(address, size) || self.find_device(address, size).is_some()
+    }
+
+    /// Returns the index of the device that handles an access of `size` bytes at `address`.
+    fn find_device(&self, address: u64, size: u64) -> Option<usize> {
+        let end = address.checked_add(size)?;
+        self.devices
+            .iter()
+            .position(|mapped| mapped.range.start <= address && end <= mapped.range.end)
+    }
+
+    /// Returns the `len` bytes of DRAM starting at `address`.
+    pub fn dram_slice(&self, address: u64, len: u64) -> Option<&[u8]> {
+        let offset = self.dram_offset(address, len)?;
+        Some(&self.dram[offset..offset + len as usize])
+    }
+
+    /// Reads `size` bytes at `address`, zero-extended to 64 bits.
+    ///
+    /// Instructions can only be fetched from DRAM, whose permissions are checked for
+    /// every access.
+    pub fn read(
+        &mut self,
+        address: u64,
+        size: u64,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        let fault = access_type.access_fault(address, size);
+
+        if let Some(offset) = self.dram_offset(address, size) {
+            if !self
+                .dram
+                .is_allowed(offset..offset + size as usize, access_type)
+            {
+                return Err(fault);
+            }
+            return Ok(match size {
+                1 => self.dram.read_u8(offset) as u64,
+                2 => self.dram.read_u16(offset) as u64,
+                4 => self.dram.read_u32(offset) as u64,
+                _ => self.dram.read_u64(offset),
+            });
+        }
+
+        match self.find_device(address, size) {
+            Some(index) if access_type != AccessType::Fetch => {
+                let mapped = &mut self.devices[index];
+                let offset = address - mapped.range.start;
+                mapped.device.read(offset, size).ok_or(fault)
+            }
+            _ => Err(fault),
+        }
+    }
+
+    /// Writes the lowest `size` bytes of `value` at `address`.
+    pub fn write(&mut self, address: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let fault = AccessType::Store.access_fault(address, size);
+
+        if let Some(offset) = self.dram_offset(address, size) {
+            if !self
+                .dram
+                .is_allowed(offset..offset + size as usize, AccessType::Store)
+            {
+                return Err(fault);
+            }
+            match size {
+                1 => self.dram.write_u8(offset, value as u8),
+                2 => self.dram.write_u16(offset, value as u16),
+                4 => self.dram.write_u32(offset, value as u32),
+                _ => self.dram.write_u64(offset, value),
+            }
+            return Ok(());
+        }
+
+        let index = self.find_device(address, size).ok_or(fault)?;
+        let mapped = &mut self.devices[index];
+        let offset = address - mapped.range.start;
+        mapped.device.write(offset, size, value).ok_or(fault)
+    }
+
+    /// Registers a reservation on the `size` bytes of DRAM at `address`.
+    pub fn reserve(&mut self, address: u64, size: u64) {
+        if let Some(offset) = self.dram_offset(address, size) {
+            self.dram.reserve(offset..offset + size as usize);
+        }
+    }
+
+    /// Consumes the current reservation and returns whether it still covers the
+    /// `size` bytes of DRAM at `address`.
+    pub fn take_reservation(&mut self, address: u64, size: u64) -> bool {
+        match self.dram_offset(address, size) {
+            Some(offset) => self.dram.take_reservation(offset..offset + size as usize),
+            None => {
+                // Any store-conditional clears the reservation, even if it faults later
+                self.dram.take_reservation(0..0);
+                false
+            }
+        }
+    }
+}
+
+/// The DRAM as seen by devices that access it directly (DMA), addressed by physical addresses.
+///
+/// Unlike the accesses of the CPU, these accesses ignore the permissions of the memory.
+pub struct GuestMemory<'a> {
+    dram_base: u64,
+    dram: &'a mut MonitoredMemory,
+}
+
+impl<'a> GuestMemory<'a> {
+    /// Creates a view of `dram`, whose first byte is mapped at `dram_base`.
+    pub fn new(dram_base: u64, dram: &'a mut MonitoredMemory) -> Self {
+        GuestMemory { dram_base, dram }
+    }
+
+    /// Returns the DRAM offsets of the `len` bytes at `address`, if they lie within DRAM.
+    fn range(&self, address: u64, len: usize) -> Option<Range<usize>> {
+        let offset = usize::try_from(address.checked_sub(self.dram_base)?).ok()?;
+        let end = offset.checked_add(len)?;
+        (end <= self.dram.size()).then_some(offset..end)
+    }
+
+    /// Fills `buffer` with the bytes at `address`. Returns [`None`] if they are not in DRAM.
+    pub fn read(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+        let range = self.range(address, buffer.len())?;
+        buffer.copy_from_slice(&self.dram[range]);
+        Some(())
+    }
+
+    /// Writes `data` at `address`. Returns [`None`] if it does not fit into DRAM.
+    pub fn write(&mut self, address: u64, data: &[u8]) -> Option<()> {
+        let range = self.range(address, data.len())?;
+        self.dram[range].copy_from_slice(data);
+        Some(())
+    }
+
+    /// Reads the little-endian [`u16`] at `address`.
+    pub fn read_u16(&self, address: u64) -> Option<u16> {
+        let mut bytes = [0; 2];
+        self.read(address, &mut bytes)?;
+        Some(u16::from_le_bytes(bytes))
+    }
+
+    /// Reads the little-endian [`u32`] at `address`.
+    pub fn read_u32(&self, address: u64) -> Option<u32> {
+        let mut bytes = [0; 4];
+        self.read(address, &mut bytes)?;
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Reads the little-endian [`u64`] at `address`.
+    pub fn read_u64(&self, address: u64) -> Option<u64> {
+        let mut bytes = [0; 8];
+        self.read(address, &mut bytes)?;
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    /// Writes `value` as a little-endian [`u16`] at `address`.
+    pub fn write_u16(&mut self, address: u64, value: u16) -> Option<()> {
+        self.write(address, &value.to_le_bytes())
+    }
+
+    /// Writes `value` as a little-endian [`u32`] at `address`.
+    pub fn write_u32(&mut self, address: u64, value: u32) -> Option<()> {
+        self.write(address, &value.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device with a single 32-bit register at offset 0.
+    struct Register(u32);
+
+    impl Device for Register {
+        fn name(&self) -> &str {
+            "register"
+        }
+
+        fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+            (offset == 0 && size == 4).then_some(self.0 as u64)
+        }
+
+        fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+            (offset == 0 && size == 4).then(|| self.0 = value as u32)
+        }
+    }
+
+    #[test]
+    /// Accesses are dispatched to the DRAM or the device mapped at their address.
+    fn test_address_decoding() {
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        bus.attach(0x1000_0000, 0x100, None, Box::new(Register(0)))
+            .unwrap();
+
+        bus.write(0x8000_0ff8, 8, 0x1234).unwrap();
+        assert_eq!(bus.dram.read_u64(0xff8), 0x1234);
+        assert_eq!(bus.read(0x8000_0ff8, 8, AccessType::Load), Ok(0x1234));
+
+        bus.write(0x1000_0000, 4, 42).unwrap();
+        assert_eq!(bus.read(0x1000_0000, 4, AccessType::Load), Ok(42));
+        assert_eq!(
+            bus.read(0x1000_0000, 4, AccessType::Fetch),
+            Err(Exception::InstructionAccessFault(0x1000_0000))
+        );
+        assert_eq!(
+            bus.read(0x1000_0004, 4, AccessType::Load),
+            Err(Exception::LoadAccessFault {
+                address: 0x1000_0004,
+                size: 4
+            })
+        );
+        assert_eq!(
+            bus.write(0x8000_0ffc, 8, 0),
+            Err(Exception::StoreAccessFault {
+                address: 0x8000_0ffc,
+                size: 8
+            })
+        );
+        assert_eq!(
+            bus.read(0, 4, AccessType::Load),
+            Err(Exception::LoadAccessFault {
+                address: 0,
+                size: 4
+            })
+        );
+    }
+
+    #[test]
+    /// Devices cannot be mapped over the DRAM or other devices.
+    fn test_attach_overlap() {
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        bus.attach(0x1000_0000, 0x100, None, Box::new(Register(0)))
+            .unwrap();
+        assert!(
+            bus.attach(0x1000_00f0, 0x100, None, Box::new(Register(0)))
+                .is_err()
+        );
+        assert!(
+            bus.attach(0x7fff_f000, 0x2000, None, Box::new(Register(0)))
+                .is_err()
+        );
+        assert!(
+            bus.attach(u64::MAX, 2, None, Box::new(Register(0)))
+                .is_err()
+        );
+        assert_eq!(
+            bus.attach(0x2000_0000, 4, Some(64), Box::new(Register(0))),
+            Err(BusError::InvalidIrq { irq: 64 })
+        );
+    }
+}
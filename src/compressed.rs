@@ -0,0 +1,424 @@
+//! # Compressed Instructions
+//!
+//! This module expands the 16-bit instructions of the RISC-V compressed extension (RVC)
+//! into their 32-bit equivalents, so that they can be executed like any other instruction.
+//! See also: [https://five-embeddev.com/riscv-user-isa-manual/Priv-v1.12/c.html]
+//!
+//! ## Registers
+//!
+//! Most compressed instructions can only address the 8 most used registers `x8` to `x15`
+//! with a 3-bit field, written as `rd'`, `rs1'` and `rs2'`.
+//!
+//! ## RV32
+//!
+//! Some encodings depend on the XLEN: the RV64 `c.ld`, `c.sd`, `c.ldsp`, `c.sdsp` and `c.addiw`
+//! are `c.flw`, `c.fsw`, `c.flwsp`, `c.fswsp` and `c.jal` on RV32, where `c.addw`, `c.subw`
+//! and shift amounts above 31 are reserved.
+
+use crate::csr::Xlen;
+
+/// Returns `true` if the (lower half of the) instruction is a compressed instruction.
+/// All 32-bit instructions have the two least significant bits set.
+#[inline]
+pub fn is_compressed(instruction: u16) -> bool {
+    instruction & 0b11 != 0b11
+}
+
+/// Expands a 16-bit compressed instruction into the equivalent 32-bit instruction for a hart
+/// with the given `xlen`. Returns [`None`] for illegal and reserved encodings.
+pub fn expand(instruction: u16, xlen: Xlen) -> Option<u32> {
+    let instruction = instruction as u32;
+
+    // The quadrant (op) is bits 0-1 and the funct3 field is bits 13-15
+    let op = instruction & 0b11;
+    let funct3 = instruction >> 13 & 0x7;
+
+    // Full register fields: rd/rs1 is bits 7-11, rs2 is bits 2-6
+    let rd = (instruction >> 7) & 0x1f;
+    let rs2 = (instruction >> 2) & 0x1f;
+
+    // Compressed register fields: rd'/rs1' is bits 7-9, rd'/rs2' is bits 2-4
+    let rs1_prime = 8 + ((instruction >> 7) & 0x7);
+    let rs2_prime = 8 + ((instruction >> 2) & 0x7);
+
+    // The 6-bit immediate used by many instructions: imm[5] is bit 12, imm[4:0] is bits 2-6
+    let imm6 = bit(instruction, 12) << 5 | (instruction >> 2) & 0x1f;
+    let sext_imm6 = sign_extend(imm6, 6);
+
+    // Shift amounts are limited to 5 bits on RV32
+    let is_shift_reserved = xlen == Xlen::Rv32 && imm6 >= 32;
+
+    let expanded = match (op, funct3) {
+        // --- Quadrant 0 ---
+        (0b00, 0b000) => {
+            // c.addi4spn -> addi rd', x2, nzuimm
+            let imm = bits(instruction, 11, 12) << 4
+                | bits(instruction, 7, 10) << 6
+                | bit(instruction, 6) << 2
+                | bit(instruction, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm, 2, 0b000, rs2_prime, OP_IMM)
+        }
+        (0b00, 0b001) => {
+            // c.fld -> fld rd', offset(rs1')
+            let imm = load_store_d_offset(instruction);
+            i_type(imm, rs1_prime, 0b011, rs2_prime, LOAD_FP)
+        }
+        (0b00, 0b010) => {
+            // c.lw -> lw rd', offset(rs1')
+            let imm = load_store_w_offset(instruction);
+            i_type(imm, rs1_prime, 0b010, rs2_prime, LOAD)
+        }
+        (0b00, 0b011) if xlen == Xlen::Rv32 => {
+            // c.flw -> flw rd', offset(rs1')
+            let imm = load_store_w_offset(instruction);
+            i_type(imm, rs1_prime, 0b010, rs2_prime, LOAD_FP)
+        }
+        (0b00, 0b011) => {
+            // c.ld -> ld rd', offset(rs1')
+            let imm = load_store_d_offset(instruction);
+            i_type(imm, rs1_prime, 0b011, rs2_prime, LOAD)
+        }
+        (0b00, 0b101) => {
+            // c.fsd -> fsd rs2', offset(rs1')
+            let imm = load_store_d_offset(instruction);
+            s_type(imm, rs2_prime, rs1_prime, 0b011, STORE_FP)
+        }
+        (0b00, 0b110) => {
+            // c.sw -> sw rs2', offset(rs1')
+            let imm = load_store_w_offset(instruction);
+            s_type(imm, rs2_prime, rs1_prime, 0b010, STORE)
+        }
+        (0b00, 0b111) if xlen == Xlen::Rv32 => {
+            // c.fsw -> fsw rs2', offset(rs1')
+            let imm = load_store_w_offset(instruction);
+            s_type(imm, rs2_prime, rs1_prime, 0b010, STORE_FP)
+        }
+        (0b00, 0b111) => {
+            // c.sd -> sd rs2', offset(rs1')
+            let imm = load_store_d_offset(instruction);
+            s_type(imm, rs2_prime, rs1_prime, 0b011, STORE)
+        }
+
+        // --- Quadrant 1 ---
+        (0b01, 0b000) => i_type(sext_imm6, rd, 0b000, rd, OP_IMM), // c.addi, c.nop
+        (0b01, 0b001) if xlen == Xlen::Rv32 => {
+            // c.jal -> jal x1, offset
+            j_type(sign_extend(jump_offset(instruction), 12), 1)
+        }
+        (0b01, 0b001) => {
+            // c.addiw -> addiw rd, rd, imm
+            if rd == 0 {
+                return None;
+            }
+            i_type(sext_imm6, rd, 0b000, rd, OP_IMM_32)
+        }
+        (0b01, 0b010) => i_type(sext_imm6, 0, 0b000, rd, OP_IMM), // c.li
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp -> addi x2, x2, nzimm
+            let imm = bit(instruction, 12) << 9
+                | bit(instruction, 6) << 4
+                | bit(instruction, 5) << 6
+                | bits(instruction, 3, 4) << 7
+                | bit(instruction, 2) << 5;
+            if imm == 0 {
+                return None;
+            }
+            i_type(sign_extend(imm, 10), 2, 0b000, 2, OP_IMM)
+        }
+        (0b01, 0b011) => {
+            // c.lui -> lui rd, nzimm
+            if imm6 == 0 {
+                return None;
+            }
+            sign_extend(imm6, 6) << 12 | rd << 7 | LUI
+        }
+        (0b01, 0b100) => match bits(instruction, 10, 11) {
+            // c.srli / c.srai -> srli/srai rd', rd', shamt
+            0b00 | 0b01 if is_shift_reserved => return None,
+            0b00 => i_type(imm6, rs1_prime, 0b101, rs1_prime, OP_IMM),
+            0b01 => i_type(0b010000 << 6 | imm6, rs1_prime, 0b101, rs1_prime, OP_IMM),
+            // c.andi -> andi rd', rd', imm
+            0b10 => i_type(sext_imm6, rs1_prime, 0b111, rs1_prime, OP_IMM),
+            _ => {
+                let funct2 = bits(instruction, 5, 6);
+                let (funct7, funct3, opcode) = match (bit(instruction, 12), funct2) {
+                    (0, 0b00) => (0b0100000, 0b000, OP), // c.sub
+                    (0, 0b01) => (0b0000000, 0b100, OP), // c.xor
+                    (0, 0b10) => (0b0000000, 0b110, OP), // c.or
+                    (0, 0b11) => (0b0000000, 0b111, OP), // c.and
+                    (1, _) if xlen == Xlen::Rv32 => return None,
+                    (1, 0b00) => (0b0100000, 0b000, OP_32), // c.subw
+                    (1, 0b01) => (0b0000000, 0b000, OP_32), // c.addw
+                    _ => return None,
+                };
+                r_type(funct7, rs2_prime, rs1_prime, funct3, rs1_prime, opcode)
+            }
+        },
+        (0b01, 0b101) => {
+            // c.j -> jal x0, offset
+            j_type(sign_extend(jump_offset(instruction), 12), 0)
+        }
+        (0b01, 0b110 | 0b111) => {
+            // c.beqz / c.bnez -> beq/bne rs1', x0, offset
+            let imm = bit(instruction, 12) << 8
+                | bits(instruction, 10, 11) << 3
+                | bits(instruction, 5, 6) << 6
+                | bits(instruction, 3, 4) << 1
+                | bit(instruction, 2) << 5;
+            b_type(sign_extend(imm, 9), 0, rs1_prime, funct3 & 0b001)
+        }
+
+        // --- Quadrant 2 ---
+        (0b10, 0b000) if is_shift_reserved => return None,
+        (0b10, 0b000) => i_type(imm6, rd, 0b001, rd, OP_IMM), // c.slli
+        (0b10, 0b001) => {
+            // c.fldsp -> fld rd, offset(x2)
+            i_type(load_sp_d_offset(instruction), 2, 0b011, rd, LOAD_FP)
+        }
+        (0b10, 0b010) => {
+            // c.lwsp -> lw rd, offset(x2)
+            if rd == 0 {
+                return None;
+            }
+            i_type(load_sp_w_offset(instruction), 2, 0b010, rd, LOAD)
+        }
+        (0b10, 0b011) if xlen == Xlen::Rv32 => {
+            // c.flwsp -> flw rd, offset(x2)
+            i_type(load_sp_w_offset(instruction), 2, 0b010, rd, LOAD_FP)
+        }
+        (0b10, 0b011) => {
+            // c.ldsp -> ld rd, offset(x2)
+            if rd == 0 {
+                return None;
+            }
+            i_type(load_sp_d_offset(instruction), 2, 0b011, rd, LOAD)
+        }
+        (0b10, 0b100) => match (bit(instruction, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i_type(0, rd, 0b000, 0, JALR), // c.jr
+            (0, _, _) => r_type(0, rs2, 0, 0b000, rd, OP), // c.mv
+            (1, 0, 0) => 0x0010_0073,                   // c.ebreak
+            (1, _, 0) => i_type(0, rd, 0b000, 1, JALR), // c.jalr
+            _ => r_type(0, rs2, rd, 0b000, rd, OP),     // c.add
+        },
+        (0b10, 0b101) => {
+            // c.fsdsp -> fsd rs2, offset(x2)
+            s_type(store_sp_d_offset(instruction), rs2, 2, 0b011, STORE_FP)
+        }
+        (0b10, 0b110) => {
+            // c.swsp -> sw rs2, offset(x2)
+            s_type(store_sp_w_offset(instruction), rs2, 2, 0b010, STORE)
+        }
+        (0b10, 0b111) if xlen == Xlen::Rv32 => {
+            // c.fswsp -> fsw rs2, offset(x2)
+            s_type(store_sp_w_offset(instruction), rs2, 2, 0b010, STORE_FP)
+        }
+        (0b10, 0b111) => {
+            // c.sdsp -> sd rs2, offset(x2)
+            s_type(store_sp_d_offset(instruction), rs2, 2, 0b011, STORE)
+        }
+
+        _ => return None,
+    };
+
+    Some(expanded)
+}
+
+const LOAD: u32 = 0b0000011;
+const LOAD_FP: u32 = 0b0000111;
+const STORE: u32 = 0b0100011;
+const STORE_FP: u32 = 0b0100111;
+const OP_IMM: u32 = 0b0010011;
+const OP_IMM_32: u32 = 0b0011011;
+const OP: u32 = 0b0110011;
+const OP_32: u32 = 0b0111011;
+const LUI: u32 = 0b0110111;
+const JALR: u32 = 0b1100111;
+
+/// Returns the bit at `index`.
+#[inline]
+fn bit(value: u32, index: u32) -> u32 {
+    (value >> index) & 0x1
+}
+
+/// Returns the bits `low` to `high` (inclusive), shifted down to bit 0.
+#[inline]
+fn bits(value: u32, low: u32, high: u32) -> u32 {
+    (value >> low) & ((1 << (high - low + 1)) - 1)
+}
+
+/// Sign-extends the lowest `size` bits of `value` to 32 bits.
+#[inline]
+fn sign_extend(value: u32, size: u32) -> u32 {
+    (((value << (32 - size)) as i32) >> (32 - size)) as u32
+}
+
+/// The offset of c.lw and c.sw: uimm[5:3] is bits 10-12, uimm[2] is bit 6, uimm[6] is bit 5
+fn load_store_w_offset(instruction: u32) -> u32 {
+    bits(instruction, 10, 12) << 3 | bit(instruction, 6) << 2 | bit(instruction, 5) << 6
+}
+
+/// The offset of c.lwsp and c.flwsp: uimm[5] is bit 12, uimm[4:2] is bits 4-6, uimm[7:6] is bits 2-3
+fn load_sp_w_offset(instruction: u32) -> u32 {
+    bit(instruction, 12) << 5 | bits(instruction, 4, 6) << 2 | bits(instruction, 2, 3) << 6
+}
+
+/// The offset of c.swsp and c.fswsp: uimm[5:2] is bits 9-12, uimm[7:6] is bits 7-8
+fn store_sp_w_offset(instruction: u32) -> u32 {
+    bits(instruction, 9, 12) << 2 | bits(instruction, 7, 8) << 6
+}
+
+/// The offset of c.j and c.jal, which is scattered over bits 2-12
+fn jump_offset(instruction: u32) -> u32 {
+    bit(instruction, 12) << 11
+        | bit(instruction, 11) << 4
+        | bits(instruction, 9, 10) << 8
+        | bit(instruction, 8) << 10
+        | bit(instruction, 7) << 6
+        | bit(instruction, 6) << 7
+        | bits(instruction, 3, 5) << 1
+        | bit(instruction, 2) << 5
+}
+
+/// The offset of c.ld, c.sd, c.fld and c.fsd: uimm[5:3] is bits 10-12, uimm[7:6] is bits 5-6
+fn load_store_d_offset(instruction: u32) -> u32 {
+    bits(instruction, 10, 12) << 3 | bits(instruction, 5, 6) << 6
+}
+
+/// The offset of c.ldsp and c.fldsp: uimm[5] is bit 12, uimm[4:3] is bits 5-6, uimm[8:6] is bits 2-4
+fn load_sp_d_offset(instruction: u32) -> u32 {
+    bit(instruction, 12) << 5 | bits(instruction, 5, 6) << 3 | bits(instruction, 2, 4) << 6
+}
+
+/// The offset of c.sdsp and c.fsdsp: uimm[5:3] is bits 10-12, uimm[8:6] is bits 7-9
+fn store_sp_d_offset(instruction: u32) -> u32 {
+    bits(instruction, 10, 12) << 3 | bits(instruction, 7, 9) << 6
+}
+
+fn r_type(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+fn b_type(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    bit(imm, 12) << 31
+        | bits(imm, 5, 10) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | bits(imm, 1, 4) << 8
+        | bit(imm, 11) << 7
+        | 0b1100011
+}
+
+fn j_type(imm: u32, rd: u32) -> u32 {
+    bit(imm, 20) << 31
+        | bits(imm, 1, 10) << 21
+        | bit(imm, 11) << 20
+        | bits(imm, 12, 19) << 12
+        | rd << 7
+        | 0b1101111
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test cases comparing the expansions with the encodings produced by the assembler.
+    fn test_expand() {
+        let cases = [
+            (0x1fe0, 0x3fc10413), // addi s0, sp, 1020
+            (0x3ffc, 0x0f87b787), // fld fa5, 248(a5)
+            (0x5de8, 0x07c5a503), // lw a0, 124(a1)
+            (0x7de8, 0x0f85b503), // ld a0, 248(a1)
+            (0xa480, 0x0084b427), // fsd fs0, 8(s1)
+            (0xc33c, 0x04f72023), // sw a5, 64(a4)
+            (0xfd64, 0x0e953c23), // sd s1, 248(a0)
+            (0x1501, 0xfe050513), // addi a0, a0, -32
+            (0x257d, 0x01f5051b), // addiw a0, a0, 31
+            (0x557d, 0xfff00513), // addi a0, zero, -1
+            (0x7101, 0xe0010113), // addi sp, sp, -512
+            (0x7785, 0xfffe17b7), // lui a5, 0xfffe1
+            (0x90fd, 0x03f4d493), // srli s1, s1, 63
+            (0x8485, 0x4014d493), // srai s1, s1, 1
+            (0x9941, 0xff057513), // andi a0, a0, -16
+            (0x8c1d, 0x40f40433), // sub s0, s0, a5
+            (0x8c3d, 0x00f44433), // xor s0, s0, a5
+            (0x8c5d, 0x00f46433), // or s0, s0, a5
+            (0x8c7d, 0x00f47433), // and s0, s0, a5
+            (0x9c1d, 0x40f4043b), // subw s0, s0, a5
+            (0x9c3d, 0x00f4043b), // addw s0, s0, a5
+            (0xb001, 0x801ff06f), // jal zero, -2048
+            (0xd101, 0xf00500e3), // beq a0, zero, -256
+            (0xeffd, 0x0e079f63), // bne a5, zero, 254
+            (0x12fe, 0x03f29293), // slli t0, t0, 63
+            (0x307e, 0x1f813007), // fld ft0, 504(sp)
+            (0x50fe, 0x0fc12083), // lw ra, 252(sp)
+            (0x70fe, 0x1f813083), // ld ra, 504(sp)
+            (0x8082, 0x00008067), // jalr zero, 0(ra)
+            (0x852e, 0x00b00533), // add a0, zero, a1
+            (0x9002, 0x00100073), // ebreak
+            (0x9282, 0x000280e7), // jalr ra, 0(t0)
+            (0x952e, 0x00b50533), // add a0, a0, a1
+            (0xbf82, 0x1e013c27), // fsd ft0, 504(sp)
+            (0xdf86, 0x0e112e23), // sw ra, 252(sp)
+            (0xff86, 0x1e113c23), // sd ra, 504(sp)
+        ];
+        for (compressed, expected) in cases {
+            assert!(is_compressed(compressed));
+            assert_eq!(
+                expand(compressed, Xlen::Rv64),
+                Some(expected),
+                "{:#06x} should expand to {:#010x}",
+                compressed,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    /// Test cases for illegal and reserved encodings.
+    fn test_expand_illegal() {
+        assert_eq!(
+            expand(0x0000, Xlen::Rv64),
+            None,
+            "the all-zero instruction is illegal"
+        );
+        assert_eq!(
+            expand(0x6101, Xlen::Rv64),
+            None,
+            "c.addi16sp with a zero immediate"
+        );
+        assert_eq!(expand(0x4002, Xlen::Rv64), None, "c.lwsp with rd = x0");
+        assert_eq!(expand(0x8002, Xlen::Rv64), None, "c.jr with rs1 = x0");
+    }
+
+    #[test]
+    /// Test cases for the encodings that differ between RV32 and RV64.
+    fn test_expand_rv32() {
+        let cases = [
+            (0x7de8, 0x07c5a507), // flw fa0, 124(a1)
+            (0xfd64, 0x06952e27), // fsw fs1, 124(a0)
+            (0x3ff5, 0xffdff0ef), // jal ra, -4
+            (0x70fe, 0x0fc12087), // flw ft1, 252(sp)
+            (0xff86, 0x0e112e27), // fsw ft1, 252(sp)
+        ];
+        for (compressed, expected) in cases {
+            assert_eq!(expand(compressed, Xlen::Rv32), Some(expected));
+        }
+        assert_eq!(expand(0x90fd, Xlen::Rv32), None, "c.srli with shamt 63");
+        assert_eq!(expand(0x12fe, Xlen::Rv32), None, "c.slli with shamt 63");
+        assert_eq!(expand(0x9c3d, Xlen::Rv32), None, "c.addw");
+    }
+}
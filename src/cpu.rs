@@ -1,14 +1,26 @@
-use std::io;
+use std::ops::Range;
 
 use derive_more::Display;
-use goblin::elf::Elf;
 use log::{debug, info, trace};
 
 use crate::{
-    constants::{ECALL_EXIT, ECALL_WRITE, a0, a1, a2, a7},
-    format_u32_le_bits,
-    monitored_memory::MonitoredMemory,
-    utils::sign_extend_u64_to_i64,
+    bus::Bus,
+    compressed,
+    constants::{ECALL_EXIT, ECALL_WRITE, a0, a1, a2, a3, a4, a5, a6, a7},
+    csr::{self, CsrFile, PrivilegeMode, Xlen},
+    decoder::{
+        self, AluOp, AmoOp, AmoWidth, BranchOp, CompareOp, CsrOp, FmaOp, FpArithOp, FpFormat,
+        Instruction, IntFormat, LoadOp, MinMaxOp, MulDivOp, Register, SignInjectOp,
+    },
+    disasm,
+    error::VmError,
+    fdt,
+    float::{self, Float, RoundingMode},
+    loader::{LoaderError, Program},
+    mmu::Mmu,
+    pmp,
+    sbi::{Sbi, SbiResult},
+    trap::{AccessType, Exception, Interrupt, Trap},
 };
 
 pub struct Cpu {
@@ -21,15 +33,56 @@ pub struct Cpu {
     ///
     /// - `x0` is always zero and cannot be modified. Thus it is often called the zero register
     /// - `x1` to `x31` can be used for various purposes, such as holding function arguments,
-    ///    By convention, `x1` is used for the return address.
+    ///   By convention, `x1` is used for the return address.
+    ///
+    /// On RV32 harts, the registers hold their 32-bit values sign-extended to 64 bits.
     pub gprs: [u64; 32],
 
+    /// # Floating Point Registers
+    ///
+    /// The floating point registers (FPRs) f0 to f31 hold single- and double-precision values.
+    /// Single-precision values are NaN-boxed, i.e. the upper 32 bits of the register are all ones.
+    pub fprs: [u64; 32],
+
+    /// # Control and Status Registers
+    ///
+    /// The CSRs accessed through the Zicsr instructions, including the floating point `fcsr`.
+    pub csrs: CsrFile,
+
+    /// # Privilege Mode
+    ///
+    /// The privilege mode the hart is currently executing in. Execution starts in machine mode.
+    pub privilege: PrivilegeMode,
+
     /// # Program Counter
     ///
     /// The program counter (PC) register holds the address of the next instruction to be executed.
     pub pc: u64,
 
-    pub memory: MonitoredMemory,
+    /// The address of the instruction executed after the current one.
+    /// Control transfer instructions overwrite it during [`Cpu::tick`].
+    next_pc: u64,
+
+    /// # System Bus
+    ///
+    /// The physical address space with the DRAM and the memory-mapped devices.
+    pub bus: Bus,
+
+    /// # Memory Management Unit
+    ///
+    /// Translates the virtual addresses of supervisor and user mode accesses,
+    /// caching the translations in its TLB.
+    mmu: Mmu,
+
+    /// Whether the hart is stalled by `wfi` until an interrupt becomes pending.
+    is_waiting: bool,
+
+    /// The built-in SBI firmware, which services the `ecall`s of supervisor mode if enabled.
+    sbi: Option<Sbi>,
+
+    /// The end of the memory occupied by the loaded program, above which the device tree
+    /// is placed.
+    image_end: u64,
 
     pub is_running: bool,
     pub exit_code: i32,
@@ -38,403 +91,1422 @@ pub struct Cpu {
 
 #[derive(Debug, Clone, PartialEq, Eq, Display)]
 pub enum CpuEvent {
-    Write { text: String },
-    Exit { exit_code: i32 },
+    Write {
+        text: String,
+    },
+    Exit {
+        exit_code: i32,
+    },
+    /// The CPU stopped because of an error.
+    #[display("Error({error})")]
+    Error {
+        error: VmError,
+    },
+}
+
+/// The result of successfully executing a single instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum StepOutcome {
+    /// The instruction completed and the CPU continues with the next instruction.
+    Retired,
+    /// The instruction raised an exception, which is handled by the trap handler.
+    #[display("Trapped({_0})")]
+    Trapped(Exception),
+    /// An interrupt was taken by the trap handler before executing the next instruction.
+    #[display("Interrupted({_0})")]
+    Interrupted(Interrupt),
+    /// The hart is stalled by `wfi` and no interrupt is pending.
+    Waiting,
+    /// The program exited via the `ECALL_EXIT` system call.
+    #[display("Exited({exit_code})")]
+    Exited { exit_code: i32 },
 }
 
 impl Cpu {
     /// The size of a word in bytes. For RISC-V, this is typically 4 bytes (32 bits).
     const WORD_SIZE: u64 = 4;
 
-    /// Creates a new CPU instance with all registers initialized to zero.
-    pub fn new(memory_size: usize) -> io::Result<(Self, crossbeam::channel::Receiver<CpuEvent>)> {
-        let memory = MonitoredMemory::new(memory_size)?;
+    /// The size of a halfword in bytes, which is the size of a compressed instruction.
+    const HALFWORD_SIZE: u64 = 2;
+
+    /// Creates a new CPU instance attached to `bus`, with all registers initialized to zero.
+    /// Execution starts at the beginning of DRAM.
+    pub fn new(bus: Bus) -> (Self, crossbeam::channel::Receiver<CpuEvent>) {
         let (send, recv) = crossbeam::channel::unbounded();
+        let reset_vector = bus.dram_range().start;
 
-        Ok((
+        (
             Cpu {
                 gprs: [0; 32],
-                pc: 0x0,
-                memory,
+                fprs: [0; 32],
+                csrs: CsrFile::new(),
+                privilege: PrivilegeMode::Machine,
+                pc: reset_vector,
+                next_pc: reset_vector,
+                bus,
+                mmu: Mmu::new(),
+                is_waiting: false,
+                sbi: None,
+                image_end: reset_vector,
                 is_running: true,
                 exit_code: 0,
                 cpu_events: send,
             },
             recv,
-        ))
+        )
     }
 
-    pub fn load_program(&mut self, program: &[u8]) -> anyhow::Result<()> {
-        // Load the program into memory starting at address 0x8000_0000
-        let elf = Elf::parse(program)?;
+    /// Services the `ecall`s of supervisor mode with the built-in SBI firmware `sbi` and
+    /// switches to supervisor mode, like firmware does before it starts the kernel: the
+    /// exceptions and interrupts of supervisor mode are delegated, the counters are enabled and
+    /// all of memory is made accessible.
+    pub fn enable_sbi(&mut self, sbi: Sbi) {
+        let machine = PrivilegeMode::Machine;
+        self.csrs.medeleg = [
+            Exception::InstructionAddressMisaligned(0),
+            Exception::IllegalInstruction(0),
+            Exception::Breakpoint(0),
+            Exception::LoadAddressMisaligned(0),
+            Exception::StoreAddressMisaligned(0),
+            Exception::EnvironmentCallFromUMode,
+            Exception::InstructionPageFault(0),
+            Exception::LoadPageFault(0),
+            Exception::StorePageFault(0),
+        ]
+        .iter()
+        .fold(0, |medeleg, exception| medeleg | 1 << exception.code());
+        self.csrs.mideleg = csr::SUPERVISOR_INTERRUPTS;
+        self.csrs.mcounteren = 0b111;
+        self.csrs.write(csr::PMPADDR0, u64::MAX, machine);
+        let cfg = pmp::PMP_A_NAPOT | pmp::PMP_R | pmp::PMP_W | pmp::PMP_X;
+        self.csrs.write(csr::PMPCFG0, cfg as u64, machine);
+        self.privilege = PrivilegeMode::Supervisor;
+        self.sbi = Some(sbi);
+    }
 
-        assert!(elf.is_64, "Only 64-bit ELF files are supported");
+    /// Returns a sender for events to the application, e.g. for the output of devices.
+    pub fn event_sender(&self) -> crossbeam::channel::Sender<CpuEvent> {
+        self.cpu_events.clone()
+    }
 
-        for phdr in elf
-            .program_headers
+    /// Loads an ELF executable into memory and jumps to its entry point.
+    /// The XLEN of the CPU is selected by the class of the ELF file.
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), LoaderError> {
+        let program = Program::parse(program)?;
+        program.load(&mut self.bus)?;
+        self.set_xlen(program.xlen);
+        self.pc = program.entry;
+        self.image_end = program
+            .segments
             .iter()
-            .filter(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
-        {
-            dbg!(&phdr);
-            let start_address = phdr.p_vaddr as usize;
-            let end_address = start_address + phdr.p_memsz as usize;
-            if end_address > self.memory.size() {
-                return Err(anyhow::anyhow!(
-                    "Program exceeds memory bounds: {} > {}",
-                    end_address,
-                    self.memory.size()
-                ));
-            }
-            let data = &program[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize];
-            self.memory[start_address..end_address].copy_from_slice(data);
-            debug!(
-                "Loaded program segment from offset {} to {} (size: {})",
-                phdr.p_offset,
-                phdr.p_offset + phdr.p_filesz,
-                phdr.p_filesz
-            );
-        }
-
-        self.pc = elf.entry;
+            .map(|segment| segment.memory_range().end)
+            .fold(self.bus.dram_range().start, u64::max);
 
         Ok(())
     }
 
+    /// Places the device tree of the machine right above the loaded program and passes it to
+    /// the program like firmware does: `a0` holds the id of the hart and `a1` the address of
+    /// the blob. The end of DRAM is left to the stack of the program.
+    /// Returns the address range of the blob, or [`None`] if it does not fit into DRAM.
+    pub fn load_device_tree(&mut self) -> Option<Range<u64>> {
+        // The blob must be 8-byte aligned
+        let address = self.image_end.checked_next_multiple_of(8)?;
+        let dtb = fdt::generate(&self.bus, &self.csrs, address);
+        let start = self.bus.dram_offset(address, dtb.len() as u64)?;
+        self.bus.dram[start..start + dtb.len()].copy_from_slice(&dtb);
+        self.gprs[a0] = self.csrs.mhartid;
+        self.gprs[a1] = self.xlen().sign_extend(address);
+        Some(address..address + dtb.len() as u64)
+    }
+
+    /// Returns the XLEN the CPU is executing with.
+    #[inline]
+    pub fn xlen(&self) -> Xlen {
+        self.csrs.xlen
+    }
+
+    /// Switches the CPU to execute with the given XLEN.
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.csrs.set_xlen(xlen);
+        for register in self.gprs.iter_mut() {
+            *register = xlen.sign_extend(*register);
+        }
+        self.pc = xlen.truncate(self.pc);
+    }
+
     /// Returns the size of the CPU's memory in bytes.
     pub fn memory_size(&self) -> usize {
-        self.memory.size()
+        self.bus.dram.size()
     }
 
-    pub fn tick(&mut self) {
-        // Fetch the instruction at the current program counter
-        if self.pc as usize + Self::WORD_SIZE as usize > self.memory.size() {
-            panic!("Instruction address out of bounds: {:#x}", self.pc);
+    /// Executes a single instruction, or takes a pending interrupt instead.
+    ///
+    /// Exceptions raised by the program are handled by its trap handler. Only if the program
+    /// cannot handle them itself, the CPU halts and the error is returned and reported
+    /// via the CPU events channel.
+    pub fn tick(&mut self) -> Result<StepOutcome, VmError> {
+        if !self.is_running {
+            return Err(VmError::Halted);
         }
 
-        let raw_instruction =
-            &self.memory[self.pc as usize..(self.pc as usize) + (Self::WORD_SIZE as usize)];
-        assert_eq!(
-            raw_instruction.len(),
-            Self::WORD_SIZE as usize,
-            "Instruction size mismatch"
-        );
-
-        let instruction = u32::from_le_bytes(
-            raw_instruction
-                .try_into()
-                .expect("Failed to convert bytes to u32"),
-        );
-        trace!(
-            "PARSING_INSTRUCTION: {} at PC: {:#x}",
-            format_u32_le_bits!(instruction),
-            self.pc
-        );
+        self.csrs.device_interrupts = self.bus.hart_interrupts();
+        // Without a CLINT, the time advances with every cycle
+        self.csrs.time = self.bus.time().unwrap_or(self.csrs.mcycle);
+        if let Some(sbi) = &self.sbi
+            && sbi.is_timer_due(self.csrs.time)
+        {
+            self.csrs.mip |= csr::MIP_STIP;
+        }
 
-        // https://five-embeddev.com/riscv-user-isa-manual/Priv-v1.12/opcode-map.html
-        match instruction & 0x7f {
-            0b0110111 => self.handle_load_upper_immediate(instruction),
-            0b0010011 => self.handle_i_type_instruction(instruction),
-            0b1110011 => self.handle_system_instruction(instruction),
-            0b0011011 => self.handle_op32_type_instruction(instruction),
-            0b0000011 => self.handle_other_i_type_instruction(instruction),
-            ins => panic!("Unimplemented opcode: {:#x} | {:#b}", ins, ins),
+        let outcome = if let Some(interrupt) = self.pending_interrupt() {
+            self.is_waiting = false;
+            self.take_trap(Trap::Interrupt(interrupt));
+            Ok(StepOutcome::Interrupted(interrupt))
+        } else if self.is_waiting && self.csrs.pending_interrupts() & self.csrs.mie == 0 {
+            Ok(StepOutcome::Waiting)
+        } else {
+            // `wfi` also resumes on enabled interrupts that are globally disabled
+            self.is_waiting = false;
+            match self.step() {
+                Ok(()) => {
+                    self.retire();
+                    Ok(StepOutcome::Retired)
+                }
+                Err(exception) => self.handle_exception(exception),
+            }
         };
-
-        self.pc += Self::WORD_SIZE;
+        self.csrs.mcycle = self.csrs.mcycle.wrapping_add(1);
+        self.bus.tick();
         trace!("CPU_PC: {:#x}", self.pc);
         trace!("CPU_GPRS: {:?}", self.gprs);
+
+        if let Err(error) = outcome {
+            self.is_running = false;
+            self.send_event(CpuEvent::Error { error });
+        }
+        outcome
     }
 
-    /// Load upper immediate value into register
-    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#lui
-    fn handle_load_upper_immediate(&mut self, instruction: u32) {
-        assert!(
-            instruction & 0x7f == 0b0110111,
-            "Instruction is not a LUI (Load Upper Immediate) instruction"
-        );
+    /// Returns the enabled interrupt with the highest priority that is pending.
+    ///
+    /// Interrupts are handled in machine mode, unless they are delegated to supervisor mode in
+    /// `mideleg`. They are globally enabled by `mstatus.MIE` or `mstatus.SIE` while executing
+    /// in the mode that handles them, always enabled in less privileged modes and never
+    /// enabled in more privileged modes.
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csrs.pending_interrupts() & self.csrs.mie;
+        if pending == 0 {
+            return None;
+        }
+
+        let mstatus = self.csrs.mstatus;
+        let machine_enabled =
+            self.privilege != PrivilegeMode::Machine || mstatus & csr::MSTATUS_MIE != 0;
+        let supervisor_enabled = match self.privilege {
+            PrivilegeMode::User => true,
+            PrivilegeMode::Supervisor => mstatus & csr::MSTATUS_SIE != 0,
+            PrivilegeMode::Machine => false,
+        };
+
+        let mut enabled = 0;
+        if machine_enabled {
+            enabled |= pending & !self.csrs.mideleg;
+        }
+        if supervisor_enabled {
+            enabled |= pending & self.csrs.mideleg;
+        }
+        // Interrupts handled in machine mode take precedence over delegated interrupts
+        let machine = enabled & !self.csrs.mideleg;
+        let candidates = if machine != 0 { machine } else { enabled };
+        Interrupt::PRIORITY
+            .into_iter()
+            .find(|interrupt| candidates & interrupt.mask() != 0)
+    }
+
+    /// Completes the current instruction by advancing the program counter.
+    fn retire(&mut self) {
+        self.pc = self.next_pc;
+        self.csrs.minstret = self.csrs.minstret.wrapping_add(1);
+    }
 
-        // Destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
+    /// Sends an event to the application. Events are dropped if nobody is listening,
+    /// e.g. when the VM is embedded without a user interface.
+    fn send_event(&self, event: CpuEvent) {
+        if self.cpu_events.send(event).is_err() {
+            debug!("Dropped CPU event, the receiver is disconnected");
+        }
+    }
 
-        // NOTE: The zero register (x0) is always 0x0.
-        // Setting it as rd discards the resulting value.
-        if rd == 0 {
-            return;
+    /// Fetches, decodes and executes the instruction at the current program counter.
+    /// Architectural state is only modified if the instruction completes without an exception,
+    /// the program counter is advanced by the caller.
+    fn step(&mut self) -> Result<(), Exception> {
+        // Fetch the instruction at the current program counter.
+        // Instructions are fetched in 16-bit parcels, as compressed instructions are only
+        // 16 bits wide and 32-bit instructions may start at any 16-bit aligned address.
+        // The parcels are fetched separately, so an instruction may straddle a boundary.
+        let xlen = self.xlen();
+        let low_parcel = self.fetch_parcel(self.pc)?;
+        let (instruction, length) = if compressed::is_compressed(low_parcel) {
+            let instruction = compressed::expand(low_parcel, xlen)
+                .ok_or(Exception::IllegalInstruction(low_parcel as u32))?;
+            trace!(
+                "EXPANDED_COMPRESSED_INSTRUCTION: {:#06x} -> {:#010x}",
+                low_parcel, instruction
+            );
+            (instruction, Self::HALFWORD_SIZE)
+        } else {
+            let high_parcel =
+                self.fetch_parcel(xlen.truncate(self.pc.wrapping_add(Self::HALFWORD_SIZE)))?;
+            let instruction = (high_parcel as u32) << 16 | low_parcel as u32;
+            (instruction, Self::WORD_SIZE)
         };
 
-        // Immediate value (imm) is bits 12-31
-        let imm = instruction & 0xFFFFF;
+        // Unless the instruction transfers control, execution continues with
+        // the instruction directly following the current one.
+        self.next_pc = xlen.truncate(self.pc.wrapping_add(length));
+
+        let decoded =
+            decoder::decode(instruction).map_err(|_| Exception::IllegalInstruction(instruction))?;
+        trace!(
+            "EXECUTING_INSTRUCTION: {:#x}: {}",
+            self.pc,
+            disasm::disassemble(&decoded, self.pc)
+        );
+
+        // Floating point instructions are illegal while the floating point unit is off
+        if decoded.is_floating_point() && self.csrs.is_fp_disabled() {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+
+        if xlen == Xlen::Rv32 && decoded.requires_rv64() {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+
+        self.execute(decoded, instruction)
+    }
+
+    /// Fetches the 16-bit instruction parcel at the virtual `address`.
+    fn fetch_parcel(&mut self, address: u64) -> Result<u16, Exception> {
+        Self::check_alignment(address, Self::HALFWORD_SIZE, AccessType::Fetch)?;
+        let address = self.translate(
+            address,
+            Self::HALFWORD_SIZE,
+            AccessType::Fetch,
+            self.privilege,
+        )?;
+        Ok(self
+            .bus
+            .read(address, Self::HALFWORD_SIZE, AccessType::Fetch)? as u16)
+    }
 
-        // Load the immediate value into the destination register
-        let reg_value = (imm as u64) << 12;
-        self.gprs[rd as usize] = reg_value;
+    /// Returns the privilege mode loads and stores are made with, which is the mode in
+    /// `mstatus.MPP` if machine mode sets `mstatus.MPRV`.
+    fn data_privilege(&self) -> PrivilegeMode {
+        let mstatus = self.csrs.mstatus;
+        if self.privilege == PrivilegeMode::Machine && mstatus & csr::MSTATUS_MPRV != 0 {
+            PrivilegeMode::from_bits((mstatus & csr::MSTATUS_MPP) >> 11)
+                .unwrap_or(PrivilegeMode::User)
+        } else {
+            self.privilege
+        }
+    }
+
+    /// Translates the virtual `address` of an access of `size` bytes made in `privilege`
+    /// into a physical address, which must be accessible according to the PMP entries.
+    fn translate(
+        &mut self,
+        address: u64,
+        size: u64,
+        access_type: AccessType,
+        privilege: PrivilegeMode,
+    ) -> Result<u64, Exception> {
+        let physical = self.mmu.translate(
+            &mut self.bus,
+            &self.csrs,
+            privilege,
+            address,
+            size,
+            access_type,
+        )?;
+        if !self.csrs.pmp.allows(physical, size, access_type, privilege) {
+            return Err(access_type.access_fault(address, size));
+        }
+        Ok(physical)
+    }
+
+    /// Checks that an access of `size` bytes at `address` is naturally aligned.
+    fn check_alignment(address: u64, size: u64, access_type: AccessType) -> Result<(), Exception> {
+        if !address.is_multiple_of(size) {
+            return Err(access_type.misaligned(address));
+        }
+        Ok(())
+    }
+
+    /// Reads `size` bytes at the virtual `address`, zero-extended to 64 bits.
+    fn read_memory(&mut self, address: u64, size: u64) -> Result<u64, Exception> {
+        Self::check_alignment(address, size, AccessType::Load)?;
+        let address = self.translate(address, size, AccessType::Load, self.data_privilege())?;
+        self.bus.read(address, size, AccessType::Load)
+    }
+
+    /// Computes the address `rs1 + offset` of a memory access, which wraps around at XLEN bits.
+    #[inline]
+    fn effective_address(&self, rs1: Register, offset: i64) -> u64 {
+        self.xlen()
+            .truncate(self.gprs[rs1 as usize].wrapping_add(offset as u64))
+    }
+
+    /// Writes the lowest `size` bytes of `value` at the virtual `address`.
+    fn write_memory(&mut self, address: u64, size: u64, value: u64) -> Result<(), Exception> {
+        Self::check_alignment(address, size, AccessType::Store)?;
+        let address = self.translate(address, size, AccessType::Store, self.data_privilege())?;
+        self.bus.write(address, size, value)
+    }
+
+    /// Executes a decoded instruction. The raw bits are needed for illegal instruction
+    /// exceptions, which some instructions can only detect during execution.
+    fn execute(&mut self, instruction: Instruction, raw: u32) -> Result<(), Exception> {
+        match instruction {
+            Instruction::Lui { rd, imm } => {
+                self.write_gpr(rd, imm as u64);
+                Ok(())
+            }
+            Instruction::Auipc { rd, imm } => {
+                self.write_gpr(rd, self.pc.wrapping_add(imm as u64));
+                Ok(())
+            }
+            Instruction::Jal { rd, offset } => self.handle_jump_and_link(rd, offset),
+            Instruction::Jalr { rd, rs1, offset } => {
+                self.handle_jump_and_link_register(rd, rs1, offset)
+            }
+            Instruction::Branch {
+                op,
+                rs1,
+                rs2,
+                offset,
+            } => self.handle_branch_instruction(op, rs1, rs2, offset),
+            Instruction::Load {
+                op,
+                rd,
+                rs1,
+                offset,
+            } => self.handle_load_instruction(op, rd, rs1, offset),
+            Instruction::Store {
+                op,
+                rs1,
+                rs2,
+                offset,
+            } => {
+                let address = self.effective_address(rs1, offset);
+                self.write_memory(address, op.size(), self.gprs[rs2 as usize])
+            }
+            Instruction::OpImm { op, rd, rs1, imm } => {
+                // Shift amounts above 31 are reserved on RV32
+                if self.xlen() == Xlen::Rv32
+                    && matches!(op, AluOp::Sll | AluOp::Srl | AluOp::Sra)
+                    && imm >= 32
+                {
+                    return Err(Exception::IllegalInstruction(raw));
+                }
+                let result = self.alu_xlen(op, self.gprs[rs1 as usize], imm as u64);
+                self.write_gpr(rd, result);
+                Ok(())
+            }
+            Instruction::Op { op, rd, rs1, rs2 } => {
+                let result = self.alu_xlen(op, self.gprs[rs1 as usize], self.gprs[rs2 as usize]);
+                self.write_gpr(rd, result);
+                Ok(())
+            }
+            Instruction::OpImm32 { op, rd, rs1, imm } => {
+                let result = Self::alu_32(op, self.gprs[rs1 as usize] as u32, imm as u32);
+                self.write_gpr(rd, result as i32 as i64 as u64);
+                Ok(())
+            }
+            Instruction::Op32 { op, rd, rs1, rs2 } => {
+                let lhs = self.gprs[rs1 as usize] as u32;
+                let result = Self::alu_32(op, lhs, self.gprs[rs2 as usize] as u32);
+                self.write_gpr(rd, result as i32 as i64 as u64);
+                Ok(())
+            }
+            Instruction::MulDiv { op, rd, rs1, rs2 } => {
+                let (lhs, rhs) = (self.gprs[rs1 as usize], self.gprs[rs2 as usize]);
+                let result = match self.xlen() {
+                    Xlen::Rv32 => Self::mul_div_32(op, lhs as u32, rhs as u32) as u64,
+                    Xlen::Rv64 => Self::mul_div(op, lhs, rhs),
+                };
+                self.write_gpr(rd, result);
+                Ok(())
+            }
+            Instruction::MulDiv32 { op, rd, rs1, rs2 } => {
+                let lhs = self.gprs[rs1 as usize] as u32;
+                let result = Self::mul_div_32(op, lhs, self.gprs[rs2 as usize] as u32);
+                self.write_gpr(rd, result as i32 as i64 as u64);
+                Ok(())
+            }
+            // The VM executes a single hart in program order without caches,
+            // so both fences are no-ops.
+            Instruction::Fence { .. } | Instruction::FenceI => Ok(()),
+            Instruction::LoadReserved { width, rd, rs1, .. } => {
+                self.handle_load_reserved(width, rd, rs1)
+            }
+            Instruction::StoreConditional {
+                width,
+                rd,
+                rs1,
+                rs2,
+                ..
+            } => self.handle_store_conditional(width, rd, rs1, rs2),
+            Instruction::Amo {
+                op,
+                width,
+                rd,
+                rs1,
+                rs2,
+                ..
+            } => self.handle_amo_instruction(op, width, rd, rs1, rs2),
+            Instruction::LoadFp {
+                fmt,
+                rd,
+                rs1,
+                offset,
+            } => {
+                let address = self.effective_address(rs1, offset);
+                let value = match fmt {
+                    FpFormat::Single => {
+                        f32::from_bits(self.read_memory(address, 4)? as u32).to_register()
+                    }
+                    FpFormat::Double => self.read_memory(address, 8)?,
+                };
+                self.write_fpr(rd, value);
+                Ok(())
+            }
+            Instruction::StoreFp {
+                fmt,
+                rs1,
+                rs2,
+                offset,
+            } => {
+                // Stores write the raw register bits, without checking the NaN-boxing
+                let address = self.effective_address(rs1, offset);
+                let size = match fmt {
+                    FpFormat::Single => 4,
+                    FpFormat::Double => 8,
+                };
+                self.write_memory(address, size, self.fprs[rs2 as usize])
+            }
+            Instruction::FpConvert { fmt, rd, rs1, rm } => {
+                let (result, flags) = match fmt {
+                    FpFormat::Single => {
+                        let rm = self.rounding_mode(rm, raw)?;
+                        let (result, flags) =
+                            float::narrow(f64::from_register(self.fprs[rs1 as usize]), rm);
+                        (result.to_register(), flags)
+                    }
+                    FpFormat::Double => {
+                        let (result, flags) =
+                            float::widen(f32::from_register(self.fprs[rs1 as usize]));
+                        (result.to_register(), flags)
+                    }
+                };
+                self.write_fpr(rd, result);
+                self.accrue_fp_flags(flags);
+                Ok(())
+            }
+            Instruction::FpMoveToInt { fmt, rd, rs1 } => {
+                let value = match fmt {
+                    FpFormat::Single => self.fprs[rs1 as usize] as u32 as i32 as i64 as u64,
+                    FpFormat::Double => self.fprs[rs1 as usize],
+                };
+                self.write_gpr(rd, value);
+                Ok(())
+            }
+            Instruction::FpMoveFromInt { fmt, rd, rs1 } => {
+                let value = match fmt {
+                    FpFormat::Single => {
+                        f32::from_bits(self.gprs[rs1 as usize] as u32).to_register()
+                    }
+                    FpFormat::Double => self.gprs[rs1 as usize],
+                };
+                self.write_fpr(rd, value);
+                Ok(())
+            }
+            Instruction::Csr { op, rd, rs1, csr } => {
+                self.handle_csr_instruction(op, rd, self.gprs[rs1 as usize], rs1 != 0, csr, raw)
+            }
+            Instruction::CsrImm { op, rd, uimm, csr } => {
+                self.handle_csr_instruction(op, rd, uimm as u64, uimm != 0, csr, raw)
+            }
+            Instruction::Ecall => self.handle_ecall(),
+            Instruction::Ebreak => self.handle_ebreak(),
+            Instruction::Mret => self.handle_mret(raw),
+            Instruction::Sret => self.handle_sret(raw),
+            Instruction::Wfi => self.handle_wfi(raw),
+            Instruction::SfenceVma { rs1, .. } => self.handle_sfence_vma(rs1, raw),
+            // All other floating point instructions are shared by both formats
+            instruction => match instruction.fp_format() {
+                Some(FpFormat::Single) => self.execute_fp::<f32>(instruction, raw),
+                Some(FpFormat::Double) => self.execute_fp::<f64>(instruction, raw),
+                None => unreachable!("{:?} is not a floating point instruction", instruction),
+            },
+        }
     }
 
-    /// Handle I-Type instructions.
-    fn handle_i_type_instruction(&mut self, instruction: u32) {
-        // This is an immediate instruction
-        // The funct3 field is bits 12-14
-        let funct3 = instruction >> 12 & 0x7;
-        match funct3 {
-            0b000 => self.handle_addi(instruction),
-            _ => panic!("Unimplemented I-Type instruction: {:#x}", instruction),
+    /// Writes `value` into the general purpose register `rd`.
+    ///
+    /// NOTE: The zero register (x0) is always 0x0.
+    /// Setting it as rd discards the resulting value.
+    /// On RV32, the lower 32 bits of `value` are written sign-extended.
+    #[inline]
+    fn write_gpr(&mut self, rd: Register, value: u64) {
+        if rd != 0 {
+            self.gprs[rd as usize] = self.xlen().sign_extend(value);
         }
     }
 
-    /// Add immediate value to register
-    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#addi
-    fn handle_addi(&mut self, instruction: u32) {
-        // Destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
+    /// Jump to a pc-relative target and store the return address
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#jal
+    fn handle_jump_and_link(&mut self, rd: Register, offset: i64) -> Result<(), Exception> {
+        self.write_gpr(rd, self.next_pc);
+        self.next_pc = self.xlen().truncate(self.pc.wrapping_add(offset as u64));
+        Ok(())
+    }
 
-        // NOTE: The zero register (x0) is always 0x0.
-        // Setting it as rd discards the resulting value.
-        if rd == 0 {
-            return;
+    /// Jump to a register-relative target and store the return address
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#jalr
+    fn handle_jump_and_link_register(
+        &mut self,
+        rd: Register,
+        rs1: Register,
+        offset: i64,
+    ) -> Result<(), Exception> {
+        // The target is computed before writing rd, as rd and rs1 may be the same register.
+        // The least significant bit of the target is always cleared.
+        let target = self.effective_address(rs1, offset) & !1;
+
+        self.write_gpr(rd, self.next_pc);
+        self.next_pc = target;
+        Ok(())
+    }
+
+    /// Handle conditional branch instructions (BEQ, BNE, BLT, BGE, BLTU, BGEU).
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#beq
+    fn handle_branch_instruction(
+        &mut self,
+        op: BranchOp,
+        rs1: Register,
+        rs2: Register,
+        offset: i64,
+    ) -> Result<(), Exception> {
+        let lhs = self.gprs[rs1 as usize];
+        let rhs = self.gprs[rs2 as usize];
+
+        let taken = match op {
+            BranchOp::Beq => lhs == rhs,
+            BranchOp::Bne => lhs != rhs,
+            BranchOp::Blt => (lhs as i64) < (rhs as i64),
+            BranchOp::Bge => (lhs as i64) >= (rhs as i64),
+            BranchOp::Bltu => lhs < rhs,
+            BranchOp::Bgeu => lhs >= rhs,
         };
 
-        // Source register (rs1) is bits 15-19
-        let rs1 = (instruction >> 15) & 0x1f;
+        if taken {
+            self.next_pc = self.xlen().truncate(self.pc.wrapping_add(offset as u64));
+        }
 
-        // Immediate value (imm) is bits 20-31
-        let imm = instruction >> 20 & 0xFFF;
+        trace!("BRANCH_TAKEN: {}", taken);
 
-        // Sign-extend the immediate value to 64 bits
-        let sext_imm = sign_extend_u64_to_i64(imm as u64, 20);
+        Ok(())
+    }
 
-        // Add the immediate value to the value in the source register
-        let reg_value = self.gprs[rs1 as usize] as i64 + (sext_imm as i64);
+    /// Handle LOAD instructions (LB, LH, LW, LD, LBU, LHU, LWU).
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#lb
+    fn handle_load_instruction(
+        &mut self,
+        op: LoadOp,
+        rd: Register,
+        rs1: Register,
+        offset: i64,
+    ) -> Result<(), Exception> {
+        let address = self.effective_address(rs1, offset);
+
+        let value = match op {
+            LoadOp::Lb => self.read_memory(address, 1)? as i8 as i64 as u64,
+            LoadOp::Lh => self.read_memory(address, 2)? as i16 as i64 as u64,
+            LoadOp::Lw => self.read_memory(address, 4)? as i32 as i64 as u64,
+            LoadOp::Ld => self.read_memory(address, 8)?,
+            LoadOp::Lbu => self.read_memory(address, 1)?,
+            LoadOp::Lhu => self.read_memory(address, 2)?,
+            LoadOp::Lwu => self.read_memory(address, 4)?,
+        };
 
-        // Store the result in the destination register
-        self.gprs[rd as usize] = reg_value as u64;
+        self.write_gpr(rd, value);
 
-        trace!(
-            "EXECUTING_INSTRUCTION: addi x{}, x{}, {} -> x{}",
-            rd, rs1, sext_imm, rd
-        );
+        Ok(())
     }
 
-    fn handle_other_i_type_instruction(&mut self, instruction: u32) {
-        // This is an immediate instruction
-        // The funct3 field is bits 12-14
-        let funct3 = instruction >> 12 & 0x7;
-        match funct3 {
-            0b000 => self.handle_load_byte(instruction),
-            0b010 => self.handle_load_word(instruction),
-            _ => panic!("Unimplemented other I-Type instruction: {:#b}", funct3),
+    /// Computes the integer ALU operations (ADD, SUB, SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND),
+    /// shared by the OP and OP-IMM instructions.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#add
+    fn alu(op: AluOp, lhs: u64, rhs: u64) -> u64 {
+        // Only the lower 6 bits of rs2 are used as the shift amount
+        let shamt = rhs & 0x3f;
+
+        match op {
+            AluOp::Add => lhs.wrapping_add(rhs),
+            AluOp::Sub => lhs.wrapping_sub(rhs),
+            AluOp::Sll => lhs << shamt,
+            AluOp::Slt => ((lhs as i64) < (rhs as i64)) as u64,
+            AluOp::Sltu => (lhs < rhs) as u64,
+            AluOp::Xor => lhs ^ rhs,
+            AluOp::Srl => lhs >> shamt,
+            AluOp::Sra => ((lhs as i64) >> shamt) as u64,
+            AluOp::Or => lhs | rhs,
+            AluOp::And => lhs & rhs,
         }
     }
 
-    fn handle_load_byte(&mut self, instruction: u32) {
-        assert!(
-            instruction & 0x7f == 0b0000011,
-            "Instruction is not a LOAD instruction"
-        );
+    /// Computes an integer ALU operation at the XLEN of the CPU. On RV32, the shifts are the
+    /// word shifts of RV64, while all other operations give the same result on the
+    /// sign-extended register values, once the result is sign-extended by [`Cpu::write_gpr`].
+    fn alu_xlen(&self, op: AluOp, lhs: u64, rhs: u64) -> u64 {
+        match (self.xlen(), op) {
+            (Xlen::Rv32, AluOp::Sll | AluOp::Srl | AluOp::Sra) => {
+                Self::alu_32(op, lhs as u32, rhs as u32) as u64
+            }
+            _ => Self::alu(op, lhs, rhs),
+        }
+    }
 
-        // Destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
+    /// Computes the word ALU operations (ADDW, SUBW, SLLW, SRLW, SRAW), shared by the
+    /// OP-32 and OP-IMM-32 instructions. The caller sign-extends the result to 64 bits.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rv64i.html#addw
+    fn alu_32(op: AluOp, lhs: u32, rhs: u32) -> u32 {
+        // Only the lower 5 bits of rs2 are used as the shift amount
+        let shamt = rhs & 0x1f;
+
+        match op {
+            AluOp::Add => lhs.wrapping_add(rhs),
+            AluOp::Sub => lhs.wrapping_sub(rhs),
+            AluOp::Sll => lhs << shamt,
+            AluOp::Srl => lhs >> shamt,
+            AluOp::Sra => ((lhs as i32) >> shamt) as u32,
+            _ => unreachable!("{} has no word variant", op),
+        }
+    }
 
-        // NOTE: The zero register (x0) is always 0x0.
-        // Setting it as rd discards the resulting value.
-        if rd == 0 {
-            return;
-        };
+    /// Computes the M extension operations (MUL, MULH, MULHSU, MULHU, DIV, DIVU, REM, REMU).
+    ///
+    /// Division never traps: dividing by zero yields all ones for the quotient and the
+    /// dividend for the remainder, while the signed overflow `i64::MIN / -1` yields
+    /// `i64::MIN` for the quotient and zero for the remainder.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvm.html#mul
+    fn mul_div(op: MulDivOp, lhs: u64, rhs: u64) -> u64 {
+        match op {
+            MulDivOp::Mul => lhs.wrapping_mul(rhs),
+            MulDivOp::Mulh => ((lhs as i64 as i128 * rhs as i64 as i128) >> 64) as u64,
+            MulDivOp::Mulhsu => ((lhs as i64 as i128).wrapping_mul(rhs as i128) >> 64) as u64,
+            MulDivOp::Mulhu => ((lhs as u128 * rhs as u128) >> 64) as u64,
+            MulDivOp::Div => match rhs {
+                0 => u64::MAX,
+                _ => (lhs as i64).wrapping_div(rhs as i64) as u64,
+            },
+            MulDivOp::Divu => lhs.checked_div(rhs).unwrap_or(u64::MAX),
+            MulDivOp::Rem => match rhs {
+                0 => lhs,
+                _ => (lhs as i64).wrapping_rem(rhs as i64) as u64,
+            },
+            MulDivOp::Remu => lhs.checked_rem(rhs).unwrap_or(lhs),
+        }
+    }
 
-        // Source register (rs1) is bits 15-19
-        let rs1 = (instruction >> 15) & 0x1f;
+    /// Computes the RV64M word operations (MULW, DIVW, DIVUW, REMW, REMUW) and the RV32M
+    /// operations, which additionally include the high multiplications (MULH, MULHSU, MULHU).
+    /// The caller sign-extends the result to 64 bits, the division-by-zero and overflow
+    /// rules are the same as for [`Cpu::mul_div`].
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rv64m.html#mulw
+    fn mul_div_32(op: MulDivOp, lhs: u32, rhs: u32) -> u32 {
+        match op {
+            MulDivOp::Mul => lhs.wrapping_mul(rhs),
+            MulDivOp::Mulh => ((lhs as i32 as i64 * rhs as i32 as i64) >> 32) as u32,
+            MulDivOp::Mulhsu => ((lhs as i32 as i64 * rhs as i64) >> 32) as u32,
+            MulDivOp::Mulhu => ((lhs as u64 * rhs as u64) >> 32) as u32,
+            MulDivOp::Div => match rhs {
+                0 => u32::MAX,
+                _ => (lhs as i32).wrapping_div(rhs as i32) as u32,
+            },
+            MulDivOp::Divu => lhs.checked_div(rhs).unwrap_or(u32::MAX),
+            MulDivOp::Rem => match rhs {
+                0 => lhs,
+                _ => (lhs as i32).wrapping_rem(rhs as i32) as u32,
+            },
+            MulDivOp::Remu => lhs.checked_rem(rhs).unwrap_or(lhs),
+        }
+    }
 
-        // Immediate value (imm) is bits 20-31
-        let imm = instruction >> 20 & 0xFFF;
+    /// Checks that an atomic access is naturally aligned and targets DRAM, as devices do not
+    /// support atomic memory operations. Returns the physical address of the access.
+    fn check_atomic_access(
+        &mut self,
+        address: u64,
+        width: AmoWidth,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        Self::check_alignment(address, width.size(), access_type)?;
+        let address = self.translate(address, width.size(), access_type, self.data_privilege())?;
+        if !self.bus.is_dram(address, width.size()) {
+            return Err(access_type.access_fault(address, width.size()));
+        }
+        Ok(address)
+    }
 
-        let sext_imm = sign_extend_u64_to_i64(imm as u64, 12);
+    /// Reads the value of an atomic memory operation, sign-extended to 64 bits, so the
+    /// comparisons of the word-sized operations work on the full register.
+    fn read_atomic(
+        &mut self,
+        width: AmoWidth,
+        address: u64,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        let value = self.bus.read(address, width.size(), access_type)?;
+        Ok(match width {
+            AmoWidth::Word => value as i32 as i64 as u64,
+            AmoWidth::Double => value,
+        })
+    }
 
-        // Calculate the effective address
-        let effective_address = self.gprs[rs1 as usize] as i64 + sext_imm;
+    /// Handle the load reserved instructions (LR.W, LR.D), which register a reservation
+    /// on the loaded bytes. Reservations are tracked by the DRAM of the [`Bus`].
+    ///
+    /// The VM executes a single hart in program order, so the `aq` and `rl` ordering bits
+    /// of the A extension need no special treatment.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rva.html#lr-w
+    fn handle_load_reserved(
+        &mut self,
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+    ) -> Result<(), Exception> {
+        let address = self.effective_address(rs1, 0);
+        let address = self.check_atomic_access(address, width, AccessType::Load)?;
+        let value = self.read_atomic(width, address, AccessType::Load)?;
+        self.bus.reserve(address, width.size());
+        self.write_gpr(rd, value);
+        Ok(())
+    }
 
-        // Load the byte from memory at the effective address
-        let byte_value = self.memory[effective_address as usize];
+    /// Handle the store conditional instructions (SC.W, SC.D), which only store if the
+    /// reservation is still valid. Any SC clears the reservation.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rva.html#sc-w
+    fn handle_store_conditional(
+        &mut self,
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    ) -> Result<(), Exception> {
+        let address = self.effective_address(rs1, 0);
+        let address = self.check_atomic_access(address, width, AccessType::Store)?;
+        let success = self.bus.take_reservation(address, width.size());
+        if success {
+            self.bus
+                .write(address, width.size(), self.gprs[rs2 as usize])?;
+        }
+        self.write_gpr(rd, !success as u64);
 
-        // Set the value in the destination register
-        self.gprs[rd as usize] = byte_value as u64;
+        trace!("STORE_CONDITIONAL_SUCCESS: {}", success);
 
-        trace!(
-            "EXECUTING_INSTRUCTION: lb x{}, {}(x{}) -> x{}",
-            rd, sext_imm, rs1, rd
-        );
+        Ok(())
     }
 
-    fn handle_load_word(&mut self, instruction: u32) {
-        assert!(
-            instruction & 0x7f == 0b0000011,
-            "Instruction is not a LOAD instruction"
-        );
+    /// Handle the AMO read-modify-write instructions. AMOs raise store/AMO exceptions.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rva.html#amoswap-w
+    fn handle_amo_instruction(
+        &mut self,
+        op: AmoOp,
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    ) -> Result<(), Exception> {
+        let address = self.effective_address(rs1, 0);
+        let address = self.check_atomic_access(address, width, AccessType::Store)?;
+
+        let loaded = self.read_atomic(width, address, AccessType::Store)?;
+        let operand = match width {
+            AmoWidth::Word => self.gprs[rs2 as usize] as i32 as i64 as u64,
+            AmoWidth::Double => self.gprs[rs2 as usize],
+        };
 
-        // Destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
+        let result = match (op, width) {
+            (AmoOp::Swap, _) => operand,
+            (AmoOp::Add, _) => loaded.wrapping_add(operand),
+            (AmoOp::Xor, _) => loaded ^ operand,
+            (AmoOp::And, _) => loaded & operand,
+            (AmoOp::Or, _) => loaded | operand,
+            (AmoOp::Min, _) => (loaded as i64).min(operand as i64) as u64,
+            (AmoOp::Max, _) => (loaded as i64).max(operand as i64) as u64,
+            // The unsigned comparisons of words must ignore the sign-extended upper half
+            (AmoOp::Minu, AmoWidth::Word) => (loaded as u32).min(operand as u32) as u64,
+            (AmoOp::Maxu, AmoWidth::Word) => (loaded as u32).max(operand as u32) as u64,
+            (AmoOp::Minu, AmoWidth::Double) => loaded.min(operand),
+            (AmoOp::Maxu, AmoWidth::Double) => loaded.max(operand),
+        };
+
+        self.bus.write(address, width.size(), result)?;
+        self.write_gpr(rd, loaded);
+
+        Ok(())
+    }
+
+    /// Writes `value` into the floating point register `rd`, which marks the floating point state dirty.
+    #[inline]
+    fn write_fpr(&mut self, rd: Register, value: u64) {
+        self.fprs[rd as usize] = value;
+        self.csrs.mark_fp_dirty();
+    }
+
+    /// Accumulates floating point exception flags into `fcsr.fflags`.
+    #[inline]
+    fn accrue_fp_flags(&mut self, flags: u32) {
+        if flags != 0 {
+            self.csrs.fcsr |= flags & 0x1f;
+            self.csrs.mark_fp_dirty();
+        }
+    }
 
-        // NOTE: The zero register (x0) is always 0x0.
-        // Setting it as rd discards the resulting value.
-        if rd == 0 {
-            return;
+    /// Resolves the `rm` field of a floating point instruction,
+    /// where `0b111` selects the dynamic rounding mode from `fcsr.frm`.
+    /// Reserved rounding modes make the instruction illegal.
+    fn rounding_mode(&self, rm: u8, raw: u32) -> Result<RoundingMode, Exception> {
+        let rm = if rm == 0b111 {
+            self.csrs.fcsr >> 5 & 0x7
+        } else {
+            rm as u32
         };
+        RoundingMode::from_bits(rm).ok_or(Exception::IllegalInstruction(raw))
+    }
+
+    /// Executes the floating point instructions that are shared by the single- and
+    /// double-precision formats, with `F` being the format of the instruction.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvfd.html#fadd-s
+    fn execute_fp<F: Float>(
+        &mut self,
+        instruction: Instruction,
+        raw: u32,
+    ) -> Result<(), Exception> {
+        let fpr = |register: Register| F::from_register(self.fprs[register as usize]);
+
+        // The destination is either a floating point or an integer register
+        enum Output<F> {
+            Float(Register, F, u32),
+            Integer(Register, u64, u32),
+        }
 
-        // Source register (rs1) is bits 15-19
-        let rs1 = (instruction >> 15) & 0x1f;
+        let output = match instruction {
+            // Negating the first factor negates the product exactly
+            Instruction::FusedMultiplyAdd {
+                op,
+                rd,
+                rs1,
+                rs2,
+                rs3,
+                rm,
+                ..
+            } => {
+                let rm = self.rounding_mode(rm, raw)?;
+                let (a, b, c) = (fpr(rs1), fpr(rs2), fpr(rs3));
+                let (result, flags) = match op {
+                    FmaOp::Fmadd => float::mul_add(a, b, c, rm),
+                    FmaOp::Fmsub => float::mul_add(a, b, -c, rm),
+                    FmaOp::Fnmsub => float::mul_add(-a, b, c, rm),
+                    FmaOp::Fnmadd => float::mul_add(-a, b, -c, rm),
+                };
+                Output::Float(rd, result, flags)
+            }
+            Instruction::FpArith {
+                op,
+                rd,
+                rs1,
+                rs2,
+                rm,
+                ..
+            } => {
+                let rm = self.rounding_mode(rm, raw)?;
+                let (a, b) = (fpr(rs1), fpr(rs2));
+                let (result, flags) = match op {
+                    FpArithOp::Add => float::add(a, b, rm),
+                    FpArithOp::Sub => float::sub(a, b, rm),
+                    FpArithOp::Mul => float::mul(a, b, rm),
+                    FpArithOp::Div => float::div(a, b, rm),
+                };
+                Output::Float(rd, result, flags)
+            }
+            Instruction::FpSqrt { rd, rs1, rm, .. } => {
+                let (result, flags) = float::sqrt(fpr(rs1), self.rounding_mode(rm, raw)?);
+                Output::Float(rd, result, flags)
+            }
+            Instruction::FpSignInject {
+                op, rd, rs1, rs2, ..
+            } => {
+                let (a, b) = (fpr(rs1), fpr(rs2));
+                let result = match op {
+                    SignInjectOp::Sgnj => a.copysign(b),
+                    SignInjectOp::Sgnjn => a.copysign(-b),
+                    SignInjectOp::Sgnjx if b.is_sign_negative() => -a,
+                    SignInjectOp::Sgnjx => a,
+                };
+                Output::Float(rd, result, 0)
+            }
+            Instruction::FpMinMax {
+                op, rd, rs1, rs2, ..
+            } => {
+                let (result, flags) = match op {
+                    MinMaxOp::Min => float::min(fpr(rs1), fpr(rs2)),
+                    MinMaxOp::Max => float::max(fpr(rs1), fpr(rs2)),
+                };
+                Output::Float(rd, result, flags)
+            }
+            Instruction::FpCompare {
+                op, rd, rs1, rs2, ..
+            } => {
+                let (result, flags) = match op {
+                    CompareOp::Eq => float::eq(fpr(rs1), fpr(rs2)),
+                    CompareOp::Lt => float::lt(fpr(rs1), fpr(rs2)),
+                    CompareOp::Le => float::le(fpr(rs1), fpr(rs2)),
+                };
+                Output::Integer(rd, result as u64, flags)
+            }
+            Instruction::FpClassify { rd, rs1, .. } => {
+                Output::Integer(rd, float::classify(fpr(rs1)), 0)
+            }
+            Instruction::FpToInt {
+                int, rd, rs1, rm, ..
+            } => {
+                let rm = self.rounding_mode(rm, raw)?;
+                let a = fpr(rs1);
+                // Word results are sign-extended to 64 bits, even for the unsigned conversion
+                let (value, flags) = match int {
+                    IntFormat::Word => float::to_int(a, i32::MIN as i128, i32::MAX as i128, rm),
+                    IntFormat::WordUnsigned => float::to_int(a, 0, u32::MAX as i128, rm),
+                    IntFormat::Long => float::to_int(a, i64::MIN as i128, i64::MAX as i128, rm),
+                    IntFormat::LongUnsigned => float::to_int(a, 0, u64::MAX as i128, rm),
+                };
+                let value = match int {
+                    IntFormat::Word | IntFormat::WordUnsigned => value as i32 as i64 as u64,
+                    IntFormat::Long | IntFormat::LongUnsigned => value as u64,
+                };
+                Output::Integer(rd, value, flags)
+            }
+            Instruction::IntToFp {
+                int, rd, rs1, rm, ..
+            } => {
+                let rm = self.rounding_mode(rm, raw)?;
+                let source = self.gprs[rs1 as usize];
+                let value = match int {
+                    IntFormat::Word => source as i32 as i128,
+                    IntFormat::WordUnsigned => source as u32 as i128,
+                    IntFormat::Long => source as i64 as i128,
+                    IntFormat::LongUnsigned => source as i128,
+                };
+                let (result, flags) = float::from_int::<F>(value, rm);
+                Output::Float(rd, result, flags)
+            }
+            _ => unreachable!(
+                "{:?} is not a shared floating point instruction",
+                instruction
+            ),
+        };
 
-        // Immediate value (imm) is bits 20-31
-        let imm = instruction >> 20 & 0xFFF;
+        match output {
+            Output::Float(rd, result, flags) => {
+                self.write_fpr(rd, result.to_register());
+                self.accrue_fp_flags(flags);
+            }
+            Output::Integer(rd, value, flags) => {
+                self.write_gpr(rd, value);
+                self.accrue_fp_flags(flags);
+            }
+        }
 
-        let sext_imm = sign_extend_u64_to_i64(imm as u64, 12);
+        Ok(())
+    }
 
-        // Calculate the effective address
-        let effective_address = self.gprs[rs1 as usize] as i64 + sext_imm;
+    /// Handle Zicsr instructions (CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI).
+    ///
+    /// `csrrw` does not read the CSR if rd is x0, while `csrrs` and `csrrc` do not write
+    /// the CSR if rs1 (or the immediate) is x0 (or zero). This allows reading read-only CSRs.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#csrrw
+    fn handle_csr_instruction(
+        &mut self,
+        op: CsrOp,
+        rd: Register,
+        source: u64,
+        has_source: bool,
+        csr: u16,
+        raw: u32,
+    ) -> Result<(), Exception> {
+        let old_value = if op == CsrOp::Write && rd == 0 {
+            0
+        } else {
+            self.csrs
+                .read(csr, self.privilege)
+                .ok_or(Exception::IllegalInstruction(raw))?
+        };
 
-        // Load the word from memory at the effective address
-        let word_value = u32::from_le_bytes(
-            self.memory[effective_address as usize..effective_address as usize + 4]
-                .try_into()
-                .expect("Failed to convert bytes to u32"),
-        );
+        if op == CsrOp::Write || has_source {
+            // The interrupts raised by the devices are only visible to the read, writing them
+            // back to `mip` or `sip` would latch them after the devices lower them
+            let software_value = match csr {
+                csr::MIP | csr::SIP => old_value & !(self.csrs.device_interrupts & !self.csrs.mip),
+                _ => old_value,
+            };
+            let new_value = match op {
+                CsrOp::Write => source,
+                CsrOp::Set => software_value | source,
+                CsrOp::Clear => software_value & !source,
+            };
+            self.csrs
+                .write(csr, new_value, self.privilege)
+                .ok_or(Exception::IllegalInstruction(raw))?;
+            // The cached translations belong to the previous address space
+            if csr == csr::SATP {
+                self.mmu.tlb.flush();
+            }
+        }
 
-        // Set the value in the destination register
-        self.gprs[rd as usize] = word_value as u64;
+        self.write_gpr(rd, old_value);
 
-        trace!(
-            "EXECUTING_INSTRUCTION: lw x{}, {}(x{}) -> x{}",
-            rd, sext_imm, rs1, rd
-        );
+        Ok(())
     }
 
-    fn handle_op32_type_instruction(&mut self, instruction: u32) {
-        let funct3 = instruction >> 12 & 0x7;
-        match funct3 {
-            0b000 => self.handle_addiw(instruction),
-            _ => panic!("Unimplemented OP32 instruction: {:#x}", instruction),
+    /// Handles an exception raised by the current instruction.
+    ///
+    /// Programs running without a trap handler have the `ECALL_WRITE` and `ECALL_EXIT`
+    /// system calls serviced by the VM itself. Once a trap handler is installed (`mtvec` is
+    /// not zero), every exception, including environment calls, is taken by it.
+    fn handle_exception(&mut self, exception: Exception) -> Result<StepOutcome, VmError> {
+        let has_trap_handler = match self.trap_mode(Trap::Exception(exception)) {
+            PrivilegeMode::Supervisor => self.csrs.stvec & !0b11 != 0,
+            _ => self.csrs.mtvec & !0b11 != 0,
+        };
+
+        if exception == Exception::EnvironmentCallFromSMode
+            && self.sbi.is_some()
+            && self.trap_mode(Trap::Exception(exception)) == PrivilegeMode::Machine
+        {
+            return self.handle_sbi_call();
+        }
+
+        if let Exception::EnvironmentCallFromUMode
+        | Exception::EnvironmentCallFromSMode
+        | Exception::EnvironmentCallFromMMode = exception
+            && !has_trap_handler
+        {
+            return match self.gprs[a7] {
+                ECALL_WRITE => self.handle_ecall_write(),
+                ECALL_EXIT => self.handle_ecall_exit(),
+                number => Err(VmError::UnknownSyscall { number }),
+            };
         }
+
+        if has_trap_handler {
+            self.take_trap(Trap::Exception(exception));
+            return Ok(StepOutcome::Trapped(exception));
+        }
+
+        Err(match exception {
+            Exception::IllegalInstruction(raw) => VmError::UnimplementedOpcode { pc: self.pc, raw },
+            // Access faults within memory are caused by its permissions
+            Exception::InstructionAccessFault(addr)
+                if !self.bus.is_mapped(addr, Self::HALFWORD_SIZE) =>
+            {
+                VmError::MemoryOutOfBounds {
+                    addr,
+                    len: Self::HALFWORD_SIZE,
+                }
+            }
+            Exception::LoadAccessFault { address, size }
+            | Exception::StoreAccessFault { address, size }
+                if !self.bus.is_mapped(address, size) =>
+            {
+                VmError::MemoryOutOfBounds {
+                    addr: address,
+                    len: size,
+                }
+            }
+            exception => VmError::UnhandledTrap {
+                exception,
+                pc: self.pc,
+            },
+        })
     }
 
-    fn handle_addiw(&mut self, instruction: u32) {
-        // Destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
+    /// Returns the privilege mode that handles `trap`: supervisor mode for traps delegated
+    /// in `medeleg` or `mideleg` that occur in supervisor or user mode, machine mode otherwise.
+    fn trap_mode(&self, trap: Trap) -> PrivilegeMode {
+        let delegated = match trap {
+            Trap::Exception(exception) => self.csrs.medeleg >> exception.code() & 1 != 0,
+            Trap::Interrupt(interrupt) => self.csrs.mideleg & interrupt.mask() != 0,
+        };
+        if delegated && self.privilege != PrivilegeMode::Machine {
+            PrivilegeMode::Supervisor
+        } else {
+            PrivilegeMode::Machine
+        }
+    }
+
+    /// Takes a trap into the mode that handles it: the current program counter, the cause and
+    /// the exception specific value are saved and execution continues at the trap vector.
+    /// Interrupts set the most significant bit of the cause and, in vectored mode, continue
+    /// at the entry of their cause in the vector table.
+    fn take_trap(&mut self, trap: Trap) {
+        let mode = self.trap_mode(trap);
+        debug!("Taking trap at PC {:#x} into {}: {}", self.pc, mode, trap);
+
+        let tvec = match mode {
+            PrivilegeMode::Supervisor => self.csrs.stvec,
+            _ => self.csrs.mtvec,
+        };
+        let base = tvec & !0b11;
+        let (cause, value, target) = match trap {
+            Trap::Exception(exception) => (exception.code(), exception.value(), base),
+            Trap::Interrupt(interrupt) => {
+                let interrupt_bit = 1 << (self.xlen().bits() - 1);
+                let target = match tvec & 0b11 {
+                    1 => base + 4 * interrupt.code(),
+                    _ => base,
+                };
+                (interrupt_bit | interrupt.code(), 0, target)
+            }
+        };
 
-        // NOTE: The zero register (x0) is always 0x0.
-        // Setting it as rd discards the resulting value.
-        if rd == 0 {
-            return;
+        // Save and disable the interrupt enable bit and save the previous privilege mode
+        let mstatus = self.csrs.mstatus;
+        self.csrs.mstatus = match mode {
+            PrivilegeMode::Supervisor => {
+                self.csrs.sepc = self.pc;
+                self.csrs.scause = cause;
+                self.csrs.stval = value;
+                let mut mstatus =
+                    mstatus & !(csr::MSTATUS_SPIE | csr::MSTATUS_SIE | csr::MSTATUS_SPP);
+                if self.csrs.mstatus & csr::MSTATUS_SIE != 0 {
+                    mstatus |= csr::MSTATUS_SPIE;
+                }
+                if self.privilege == PrivilegeMode::Supervisor {
+                    mstatus |= csr::MSTATUS_SPP;
+                }
+                mstatus
+            }
+            _ => {
+                self.csrs.mepc = self.pc;
+                self.csrs.mcause = cause;
+                self.csrs.mtval = value;
+                let mut mstatus =
+                    mstatus & !(csr::MSTATUS_MPIE | csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+                if self.csrs.mstatus & csr::MSTATUS_MIE != 0 {
+                    mstatus |= csr::MSTATUS_MPIE;
+                }
+                mstatus | (self.privilege as u64) << 11
+            }
         };
+        self.privilege = mode;
+
+        self.pc = self.xlen().truncate(target);
+    }
 
-        // Source register (rs1) is bits 15-19
-        let rs1 = (instruction >> 15) & 0x1f;
+    /// Handle the `mret` instruction (return from a machine mode trap).
+    /// The privilege mode and interrupt enable bit saved by the trap are restored,
+    /// and execution continues at `mepc`.
+    /// https://msyksphinz-self.github.io/riscv-isadoc/html/rvi.html#mret
+    fn handle_mret(&mut self, instruction: u32) -> Result<(), Exception> {
+        if self.privilege != PrivilegeMode::Machine {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
 
-        // Immediate value (imm) is bits 20-31
-        let imm = instruction >> 20 & 0xFFF;
+        let mstatus = self.csrs.mstatus;
+        let privilege = PrivilegeMode::from_bits((mstatus & csr::MSTATUS_MPP) >> 11)
+            .unwrap_or(PrivilegeMode::User);
 
-        // Sign-extend the immediate value to 64 bits
-        let sext_imm = sign_extend_u64_to_i64(imm as u64, 20);
+        // MIE is restored from MPIE, MPIE is set and MPP is set to the least privileged mode
+        let mut mstatus = mstatus & !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+        if mstatus & csr::MSTATUS_MPIE != 0 {
+            mstatus |= csr::MSTATUS_MIE;
+        }
+        mstatus |= csr::MSTATUS_MPIE;
+        if privilege != PrivilegeMode::Machine {
+            mstatus &= !csr::MSTATUS_MPRV;
+        }
+        self.csrs.mstatus = mstatus;
+        self.privilege = privilege;
+        self.next_pc = self.csrs.mepc;
 
-        // Add the immediate value to the value in the source register
-        self.gprs[rd as usize] = (self.gprs[rs1 as usize] as i32 + (sext_imm as i32)) as u64;
+        trace!("MRET_PRIVILEGE: {}", privilege);
 
-        trace!(
-            "EXECUTING_INSTRUCTION: addiw x{}, x{}, {} -> x{}",
-            rd, rs1, sext_imm, rd
-        );
+        Ok(())
     }
 
-    fn handle_system_instruction(&mut self, instruction: u32) {
-        assert!(
-            instruction & 0x7f == 0b1110011,
-            "Instruction is not a SYSTEM instruction"
-        );
+    /// Handle the `sret` instruction (return from a supervisor mode trap).
+    /// The privilege mode and interrupt enable bit saved by the trap are restored,
+    /// and execution continues at `sepc`. Supervisor mode may not return if `mstatus.TSR`
+    /// is set.
+    fn handle_sret(&mut self, instruction: u32) -> Result<(), Exception> {
+        let mstatus = self.csrs.mstatus;
+        if self.privilege == PrivilegeMode::User
+            || self.privilege == PrivilegeMode::Supervisor && mstatus & csr::MSTATUS_TSR != 0
+        {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
 
-        // The destination register (rd) is bits 7-11
-        let rd = (instruction >> 7) & 0x1f;
-        assert!(
-            rd == 0,
-            "System instructions should not write to a destination register"
-        );
+        let privilege = match mstatus & csr::MSTATUS_SPP {
+            0 => PrivilegeMode::User,
+            _ => PrivilegeMode::Supervisor,
+        };
 
-        // The funct3 field is bits 12-14
-        let funct3 = instruction >> 12 & 0x7;
-        assert!(
-            funct3 == 0b000,
-            "System instructions should have funct3 = 0b000, got: {:#b}",
-            funct3
-        );
+        // SIE is restored from SPIE, SPIE is set and SPP is set to user mode
+        let mut mstatus = mstatus & !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV);
+        if mstatus & csr::MSTATUS_SPIE != 0 {
+            mstatus |= csr::MSTATUS_SIE;
+        }
+        mstatus |= csr::MSTATUS_SPIE;
+        self.csrs.mstatus = mstatus;
+        self.privilege = privilege;
+        self.next_pc = self.csrs.sepc;
+
+        trace!("SRET_PRIVILEGE: {}", privilege);
+
+        Ok(())
+    }
 
-        // The immediate value (imm) is bits 20-31
-        let imm = instruction >> 20 & 0xFFF;
-        match imm {
-            0x000 => self.handle_ecall(),
-            0x001 => self.handle_ebreak(),
-            _ => panic!("Unimplemented SYSTEM instruction with imm: {:#x}", imm),
+    /// Handle the `wfi` instruction (wait for interrupt).
+    /// The hart stalls after this instruction until an enabled interrupt becomes pending,
+    /// which is taken if interrupts are globally enabled. User mode may not wait, and neither
+    /// may supervisor mode if `mstatus.TW` is set.
+    fn handle_wfi(&mut self, instruction: u32) -> Result<(), Exception> {
+        let trapped = match self.privilege {
+            PrivilegeMode::User => true,
+            PrivilegeMode::Supervisor => self.csrs.mstatus & csr::MSTATUS_TW != 0,
+            PrivilegeMode::Machine => false,
+        };
+        if trapped {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        self.is_waiting = true;
+        Ok(())
+    }
+
+    /// Handle the `sfence.vma` instruction, which invalidates the cached translations of the
+    /// virtual address in `rs1`, or all translations if `rs1` is zero. User mode may not
+    /// fence, and neither may supervisor mode if `mstatus.TVM` is set.
+    fn handle_sfence_vma(&mut self, rs1: Register, instruction: u32) -> Result<(), Exception> {
+        if self.privilege == PrivilegeMode::User || self.csrs.is_vm_trapped(self.privilege) {
+            return Err(Exception::IllegalInstruction(instruction));
+        }
+        match rs1 {
+            0 => self.mmu.tlb.flush(),
+            _ => self.mmu.tlb.flush_address(self.gprs[rs1 as usize]),
         }
+        Ok(())
     }
 
     /// Handle the `ecall` instruction (environment call).
     /// This is a system call that allows the program to request services from the operating system.
+    /// The environment call is an exception, system calls serviced by the VM are handled
+    /// in [`Cpu::handle_exception`].
     /// TODO: Read for implementation details:
     /// https://jborza.com/post/2021-04-21-ecalls-and-syscalls/
     /// https://jborza.com/post/2021-05-11-riscv-linux-syscalls/
-    fn handle_ecall(&mut self) {
-        trace!("EXECUTING_INSTRUCTION: ecall");
-        match self.gprs[a7] {
-            64 => self.handle_ecall_write(),
-            93 => self.handle_ecall_exit(),
-            _ => {
-                panic!("Unimplemented ecall with a7 = {:#x}", self.gprs[a7]);
-            }
+    fn handle_ecall(&mut self) -> Result<(), Exception> {
+        match self.privilege {
+            PrivilegeMode::User => Err(Exception::EnvironmentCallFromUMode),
+            PrivilegeMode::Supervisor => Err(Exception::EnvironmentCallFromSMode),
+            PrivilegeMode::Machine => Err(Exception::EnvironmentCallFromMMode),
         }
     }
 
-    fn handle_ecall_write(&mut self) {
-        assert!(
-            self.gprs[a7] == ECALL_WRITE,
-            "ecall write should have a7 = {}, got: {:#x}",
-            ECALL_WRITE,
-            self.gprs[a7],
-        );
-
+    fn handle_ecall_write(&mut self) -> Result<StepOutcome, VmError> {
         let fd = (self.gprs[a0] & 0x1f) as usize; // File descriptor
-        let text_ptr = self.gprs[a1] as usize; // Pointer to the text to write
-        let text_len = self.gprs[a2] as usize; // Length of the text to write
+        let text_ptr = self.xlen().truncate(self.gprs[a1]); // Pointer to the text to write
+        let text_len = self.xlen().truncate(self.gprs[a2]); // Length of the text to write
 
         _ = fd; // Currently, we only support custom file descriptors
 
         // Read the text from memory
-        let text_bytes = &self.memory[text_ptr..text_ptr + text_len];
+        let text_bytes =
+            self.bus
+                .dram_slice(text_ptr, text_len)
+                .ok_or(VmError::MemoryOutOfBounds {
+                    addr: text_ptr,
+                    len: text_len,
+                })?;
         let text =
-            String::from_utf8(text_bytes.to_vec()).expect("Failed to convert bytes to String");
+            String::from_utf8(text_bytes.to_vec()).map_err(|_| VmError::InvalidUtf8Output)?;
         trace!("Writing text: '{}'", text.escape_debug());
         // Send the text to the application via the CPU events channel
-        self.cpu_events
-            .send(CpuEvent::Write { text })
-            .expect("Failed to send write event");
+        self.send_event(CpuEvent::Write { text });
+
+        self.retire();
+        Ok(StepOutcome::Retired)
+    }
+
+    fn handle_ecall_exit(&mut self) -> Result<StepOutcome, VmError> {
+        let exit_code = self.gprs[a0] as i32;
+        info!("Encountered ecall exit with code: {}", exit_code);
+        self.exit(exit_code)
     }
 
-    fn handle_ecall_exit(&mut self) {
-        assert!(
-            self.gprs[a7] == ECALL_EXIT,
-            "ecall exit should have a7 = {}, got: {:#x}",
-            ECALL_EXIT,
+    /// Services a supervisor mode `ecall` with the built-in SBI firmware.
+    fn handle_sbi_call(&mut self) -> Result<StepOutcome, VmError> {
+        let args = [a0, a1, a2, a3, a4, a5].map(|register| self.gprs[register]);
+        let Some(sbi) = &mut self.sbi else {
+            unreachable!("SBI calls are only handled if the SBI is enabled");
+        };
+        let result = sbi.call(
             self.gprs[a7],
+            self.gprs[a6],
+            args,
+            &mut self.csrs,
+            &mut self.mmu.tlb,
+        );
+        trace!(
+            "SBI_CALL: {:#x} {} -> {}",
+            self.gprs[a7], self.gprs[a6], result
         );
 
-        // Exit the program
-        let exit_code = self.gprs[a0] as i32;
-        info!("Encountered ecall exit with code: {}", exit_code);
+        match result {
+            SbiResult::Return { error, value } => {
+                self.write_gpr(a0 as Register, error as u64);
+                self.write_gpr(a1 as Register, value);
+            }
+            SbiResult::Legacy(value) => self.write_gpr(a0 as Register, value),
+            SbiResult::Suspend => {
+                self.write_gpr(a0 as Register, 0);
+                self.is_waiting = true;
+            }
+            SbiResult::Shutdown { exit_code } => {
+                info!("Shut down by the SBI with code: {}", exit_code);
+                return self.exit(exit_code);
+            }
+        }
+        self.retire();
+        Ok(StepOutcome::Retired)
+    }
+
+    /// Stops the program with `exit_code` after the current instruction.
+    fn exit(&mut self, exit_code: i32) -> Result<StepOutcome, VmError> {
         self.is_running = false;
         self.exit_code = exit_code;
-        self.cpu_events
-            .send(CpuEvent::Exit { exit_code })
-            .expect("Failed to send exit event");
+        self.send_event(CpuEvent::Exit { exit_code });
+
+        self.retire();
+        Ok(StepOutcome::Exited { exit_code })
     }
 
     /// Handle the `ebreak` instruction (environment break).
-    /// This instruction is used to trigger a breakpoint in the program,
-    fn handle_ebreak(&mut self) {
-        trace!("EXECUTING_INSTRUCTION: ebreak");
-        // TODO: Handle `ebreak` `properly`, e.g., by pausing execution and entering a debug mode
+    /// This instruction raises a breakpoint exception, which is handled by the trap handler.
+    fn handle_ebreak(&mut self) -> Result<(), Exception> {
+        Err(Exception::Breakpoint(self.pc))
     }
 
     #[inline]
@@ -442,3 +1514,719 @@ impl Cpu {
         !self.is_running
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        constants::*,
+        devices::{Device, DeviceContext, DeviceRegistry},
+        mmu,
+        monitored_memory::Permissions,
+        pmp,
+    };
+
+    /// Creates a CPU with the given instructions placed at address `0x0`.
+    fn cpu_with_program(instructions: &[u32]) -> (Cpu, crossbeam::channel::Receiver<CpuEvent>) {
+        let bus = Bus::new(0, 4096).expect("Failed to create the bus");
+        let (mut cpu, cpu_events) = Cpu::new(bus);
+        for (i, instruction) in instructions.iter().enumerate() {
+            cpu.bus.dram.write_u32(i * 4, *instruction);
+        }
+        (cpu, cpu_events)
+    }
+
+    /// Grants supervisor and user mode access to all of memory, like firmware does before
+    /// leaving machine mode.
+    fn grant_all_memory(cpu: &mut Cpu) {
+        let machine = PrivilegeMode::Machine;
+        cpu.csrs.write(csr::PMPADDR0, u64::MAX, machine).unwrap();
+        let cfg = pmp::PMP_A_NAPOT | pmp::PMP_R | pmp::PMP_W | pmp::PMP_X;
+        cpu.csrs.write(csr::PMPCFG0, cfg as u64, machine).unwrap();
+    }
+
+    #[test]
+    /// Sums the numbers 10 down to 1 using a backwards branch.
+    fn test_branch_loop() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x00000513, // addi a0, zero, 0
+            0x00a00593, // addi a1, zero, 10
+            0x00b50533, // add a0, a0, a1
+            0xfff58593, // addi a1, a1, -1
+            0xfe059ce3, // bne a1, zero, -8
+        ]);
+        while cpu.pc < 5 * 4 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], 55);
+        assert_eq!(cpu.gprs[a1], 0);
+    }
+
+    #[test]
+    /// Loads sign- or zero-extend depending on the instruction.
+    fn test_load_store_extension() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x800002b7, // lui t0, 0x80000
+            0xffe00313, // addi t1, zero, -2
+            0x04603023, // sd t1, 64(zero)
+            0x04000383, // lb t2, 64(zero)
+            0x04004e03, // lbu t3, 64(zero)
+            0x04006e83, // lwu t4, 64(zero)
+            0x4042df13, // srai t5, t0, 4
+            0x40135f9b, // sraiw t6, t1, 1
+        ]);
+        for _ in 0..8 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[t0], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.bus.dram.read_u64(64), -2i64 as u64);
+        assert_eq!(cpu.gprs[t2], -2i64 as u64);
+        assert_eq!(cpu.gprs[t3], 0xfe);
+        assert_eq!(cpu.gprs[t4], 0xffff_fffe);
+        assert_eq!(cpu.gprs[t5], 0xffff_ffff_f800_0000);
+        assert_eq!(cpu.gprs[t6], -1i64 as u64);
+    }
+
+    #[test]
+    /// `jal` and `jalr` store the address of the following instruction.
+    fn test_jump_and_link() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x008000ef, // jal ra, 8
+            0x00000013, // addi zero, zero, 0
+            0x00008467, // jalr s0, 0(ra)
+        ]);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 8);
+        assert_eq!(cpu.gprs[ra], 4);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.gprs[s0], 12);
+    }
+
+    #[test]
+    /// Multiplication high parts and the non-trapping division edge cases.
+    fn test_mul_div_edge_cases() {
+        let program = [
+            0xfff00293, // addi t0, zero, -1
+            0x00300313, // addi t1, zero, 3
+            0x02629533, // mulh a0, t0, t1
+            0x0262b5b3, // mulhu a1, t0, t1
+            0x0262a633, // mulhsu a2, t0, t1
+            0x020346b3, // div a3, t1, zero
+            0x02036733, // rem a4, t1, zero
+            0x0262c7bb, // divw a5, t0, t1
+            0x03f29393, // slli t2, t0, 63
+            0x0253c833, // div a6, t2, t0
+            0x0253e8b3, // rem a7, t2, t0
+            0x020354bb, // divuw s1, t1, zero
+        ];
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..program.len() {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], u64::MAX);
+        assert_eq!(cpu.gprs[a1], 2);
+        assert_eq!(cpu.gprs[a2], u64::MAX);
+        assert_eq!(cpu.gprs[a3], u64::MAX);
+        assert_eq!(cpu.gprs[a4], 3);
+        assert_eq!(cpu.gprs[a5], 0);
+        assert_eq!(cpu.gprs[a6], i64::MIN as u64);
+        assert_eq!(cpu.gprs[a7], 0);
+        assert_eq!(cpu.gprs[s1], u64::MAX);
+    }
+
+    #[test]
+    /// Store-conditional only succeeds on an intact reservation.
+    fn test_load_reserved_store_conditional() {
+        let program = [
+            0x08000293, // addi t0, zero, 128
+            0xffb00313, // addi t1, zero, -5
+            0x0062a023, // sw t1, 0(t0)
+            0x1002a52f, // lr.w a0, (t0)
+            0x00700393, // addi t2, zero, 7
+            0x1872a5af, // sc.w a1, t2, (t0)
+            0x1872a62f, // sc.w a2, t2, (t0)
+            0x1002b6af, // lr.d a3, (t0)
+            0x0002a023, // sw zero, 0(t0)
+            0x1872b72f, // sc.d a4, t2, (t0)
+            0x0062a7af, // amoadd.w a5, t1, (t0)
+            0xe072a82f, // amomaxu.w a6, t2, (t0)
+            0x0002a883, // lw a7, 0(t0)
+        ];
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..program.len() {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], -5i64 as u64);
+        assert_eq!(cpu.gprs[a1], 0, "sc.w after lr.w succeeds");
+        assert_eq!(cpu.gprs[a2], 1, "sc.w without reservation fails");
+        assert_eq!(cpu.gprs[a3], 7);
+        assert_eq!(cpu.gprs[a4], 1, "sc.d after an intervening store fails");
+        assert_eq!(cpu.gprs[a5], 0);
+        assert_eq!(cpu.gprs[a6], -5i64 as u64);
+        assert_eq!(cpu.gprs[a7], -5i64 as u64);
+    }
+
+    #[test]
+    /// Floating point arithmetic, conversions with static rounding modes and NaN-boxing.
+    fn test_floating_point() {
+        let program = [
+            0x00700293, // addi t0, zero, 7
+            0xd002f053, // fcvt.s.w ft0, t0
+            0x00200313, // addi t1, zero, 2
+            0xd00370d3, // fcvt.s.w ft1, t1
+            0x18107153, // fdiv.s ft2, ft0, ft1
+            0xc0011553, // fcvt.w.s a0, ft2, rtz
+            0xc00105d3, // fcvt.w.s a1, ft2, rne
+            0xe2010653, // fmv.x.d a2, ft2
+            0x420101d3, // fcvt.d.s ft3, ft2
+            0x22319253, // fsgnjn.d ft4, ft3, ft3
+            0xc22226d3, // fcvt.l.d a3, ft4, rdn
+            0xa231a753, // feq.d a4, ft3, ft3
+            0x2231f2c3, // fmadd.d ft5, ft3, ft3, ft4
+            0xc222f7d3, // fcvt.l.d a5, ft5
+        ];
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..program.len() {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], 3);
+        assert_eq!(cpu.gprs[a1], 4);
+        assert_eq!(cpu.gprs[a2], 0xffff_ffff_4060_0000);
+        assert_eq!(cpu.gprs[a3], -4i64 as u64);
+        assert_eq!(cpu.gprs[a4], 1);
+        assert_eq!(f64::from_bits(cpu.fprs[5]), 8.75);
+        assert_eq!(cpu.gprs[a5], 9);
+        assert_eq!(cpu.csrs.fcsr, float::FLAG_NX);
+    }
+
+    #[test]
+    /// Compressed and 32-bit instructions can be mixed, the latter only being 16-bit aligned.
+    fn test_compressed_instructions() {
+        let parcels: [u16; 7] = [
+            0x4515, // c.li a0, 5
+            0x0593, 0x00c0, // addi a1, zero, 12
+            0x9582, // c.jalr a1
+            0x0505, // c.addi a0, 1
+            0x0001, // c.nop
+            0x0509, // c.addi a0, 2
+        ];
+        let (mut cpu, _events) = cpu_with_program(&[]);
+        for (i, parcel) in parcels.iter().enumerate() {
+            cpu.bus.dram.write_u16(i * 2, *parcel);
+        }
+        for _ in 0..4 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.pc, 14);
+        assert_eq!(
+            cpu.gprs[ra], 8,
+            "c.jalr links the address of the next parcel"
+        );
+        assert_eq!(cpu.gprs[a0], 7);
+    }
+
+    #[test]
+    /// CSR accesses, where writing a read-only CSR traps to `mtvec`.
+    fn test_csr_instructions() {
+        let program = [
+            0x10000293, // addi t0, zero, 0x100
+            0x30529073, // csrrw zero, mtvec, t0
+            0x02a00313, // addi t1, zero, 42
+            0x34031073, // csrrw zero, mscratch, t1
+            0x34002573, // csrrs a0, mscratch, zero
+            0xf140d5f3, // csrrwi a1, mhartid, 1
+        ];
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..program.len() {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], 42);
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csrs.mepc, 20);
+        assert_eq!(cpu.csrs.mcause, 2);
+        assert_eq!(cpu.csrs.mtval, 0xf140d5f3);
+    }
+
+    #[test]
+    /// Tests that a misaligned load traps to `mtvec` and `mret` resumes after it
+    fn test_trap_and_mret() {
+        let mut program = [0; 21];
+        program[..5].copy_from_slice(&[
+            0x04000293, // addi t0, zero, 0x40
+            0x30529073, // csrrw zero, mtvec, t0
+            0x00100313, // addi t1, zero, 1
+            0x00032503, // lw a0, 0(t1)
+            0x00500593, // addi a1, zero, 5
+        ]);
+        // The trap handler at 0x40 skips the faulting instruction
+        program[16..].copy_from_slice(&[
+            0x34202673, // csrrs a2, mcause, zero
+            0x341026f3, // csrrs a3, mepc, zero
+            0x00468693, // addi a3, a3, 4
+            0x34169073, // csrrw zero, mepc, a3
+            0x30200073, // mret
+        ]);
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..4 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.csrs.mtval, 1);
+        for _ in 0..6 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], 0);
+        assert_eq!(cpu.gprs[a1], 5);
+        assert_eq!(cpu.gprs[a2], 4);
+        assert_eq!(cpu.pc, 20);
+        assert_eq!(cpu.csrs.minstret, 9);
+        assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+    }
+
+    #[test]
+    /// Tests that an `ecall` goes to the trap handler, not the built-in system calls,
+    /// once `mtvec` is set
+    fn test_ecall_with_trap_handler() {
+        let (mut cpu, events) = cpu_with_program(&[
+            0x05d00893, // addi a7, zero, 93
+            0x00000073, // ecall
+        ]);
+        cpu.csrs.mtvec = 0x40;
+        cpu.tick().unwrap();
+        assert_eq!(
+            cpu.tick(),
+            Ok(StepOutcome::Trapped(Exception::EnvironmentCallFromMMode))
+        );
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(cpu.csrs.mepc, 4);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    /// Tests that an exception without a trap handler halts the CPU
+    fn test_unhandled_trap() {
+        let (mut cpu, events) = cpu_with_program(&[0x00000000]);
+        let error = VmError::UnimplementedOpcode { pc: 0, raw: 0 };
+        assert_eq!(cpu.tick(), Err(error));
+        assert!(cpu.is_halted());
+        assert_eq!(events.try_recv(), Ok(CpuEvent::Error { error }));
+        assert_eq!(cpu.tick(), Err(VmError::Halted));
+    }
+
+    #[test]
+    /// Tests the 32-bit wraparound and sign-extension of RV32, where the RV64-only
+    /// instructions are illegal
+    fn test_rv32_execution() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x80000537, // lui a0, 0x80000
+            0x00155593, // srli a1, a0, 1
+            0xfff50613, // addi a2, a0, -1
+            0x02a536b3, // mulhu a3, a0, a0
+            0x02c55733, // divu a4, a0, a2
+            0x00a637b3, // sltu a5, a2, a0
+            0x02a51833, // mulh a6, a0, a0
+            0x02051513, // slli a0, a0, 32
+        ]);
+        cpu.set_xlen(Xlen::Rv32);
+        for _ in 0..7 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.gprs[a0], 0xffff_ffff_8000_0000);
+        assert_eq!(cpu.gprs[a1], 0x4000_0000);
+        assert_eq!(cpu.gprs[a2], 0x7fff_ffff);
+        assert_eq!(cpu.gprs[a3], 0x4000_0000);
+        assert_eq!(cpu.gprs[a4], 1);
+        assert_eq!(cpu.gprs[a5], 1);
+        assert_eq!(cpu.gprs[a6], 0x4000_0000);
+
+        let error = VmError::UnimplementedOpcode {
+            pc: 28,
+            raw: 0x02051513,
+        };
+        assert_eq!(cpu.tick(), Err(error));
+
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x0015051b, // addiw a0, a0, 1
+        ]);
+        cpu.set_xlen(Xlen::Rv32);
+        let error = VmError::UnimplementedOpcode {
+            pc: 0,
+            raw: 0x0015051b,
+        };
+        assert_eq!(cpu.tick(), Err(error));
+    }
+
+    #[test]
+    /// Tests that stores to read-only memory and fetches from non-executable memory fault
+    fn test_memory_protection() {
+        let text = Permissions {
+            read: true,
+            write: false,
+            execute: true,
+        };
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x00002023, // sw zero, 0(zero)
+        ]);
+        cpu.bus
+            .dram
+            .set_default_permissions(Permissions::READ_WRITE);
+        cpu.bus.dram.protect(0..4, text);
+        let exception = Exception::StoreAccessFault {
+            address: 0,
+            size: 4,
+        };
+        assert_eq!(cpu.tick(), Err(VmError::UnhandledTrap { exception, pc: 0 }));
+
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x0080006f, // jal zero, 8
+        ]);
+        cpu.bus
+            .dram
+            .set_default_permissions(Permissions::READ_WRITE);
+        cpu.bus.dram.protect(0..4, text);
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        let exception = Exception::InstructionAccessFault(8);
+        assert_eq!(cpu.tick(), Err(VmError::UnhandledTrap { exception, pc: 8 }));
+    }
+
+    #[test]
+    /// Tests that `wfi` stalls until the CLINT raises the timer interrupt, which is taken
+    /// through the vector table
+    fn test_timer_interrupt() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x10500073, // wfi
+        ]);
+        let registry = DeviceRegistry::new();
+        let spec = "clint".parse().unwrap();
+        registry
+            .attach(&mut cpu.bus, &spec, &DeviceContext::default())
+            .unwrap();
+        cpu.bus.write(0x0200_4000, 8, 3).unwrap();
+        cpu.csrs.mtvec = 0x100 | 1;
+        cpu.csrs.mie = csr::MIP_MTIP;
+        cpu.csrs.mstatus |= csr::MSTATUS_MIE;
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Waiting));
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Waiting));
+        assert_eq!(
+            cpu.tick(),
+            Ok(StepOutcome::Interrupted(Interrupt::MachineTimer))
+        );
+        assert_eq!(cpu.pc, 0x100 + 4 * 7);
+        assert_eq!(cpu.csrs.mepc, 4);
+        assert_eq!(cpu.csrs.mcause, 1 << 63 | 7);
+        assert_eq!(cpu.csrs.mstatus & csr::MSTATUS_MIE, 0);
+        assert_eq!(cpu.csrs.pending_interrupts() & csr::MIP_MTIP, csr::MIP_MTIP);
+    }
+
+    #[test]
+    /// Tests that `time` reads `mtime` of the CLINT, including the values written to it
+    fn test_time_follows_clint() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0xc0102573, // csrrs a0, time, zero
+            0xc01025f3, // csrrs a1, time, zero
+        ]);
+        let registry = DeviceRegistry::new();
+        let spec = "clint".parse().unwrap();
+        registry
+            .attach(&mut cpu.bus, &spec, &DeviceContext::default())
+            .unwrap();
+        cpu.bus.write(0x0200_bff8, 8, 1000).unwrap();
+
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.gprs[a0], 1000);
+        assert_eq!(cpu.gprs[a1], 1001);
+        assert_eq!(cpu.csrs.mcycle, 2);
+    }
+
+    #[test]
+    /// Tests that reading and writing back `mip` while the PLIC raises `SEIP` does not latch
+    /// it, so it clears once the device lowers its interrupt line
+    fn test_device_interrupt_not_latched() {
+        /// A device whose interrupt line is raised while `raised` is set.
+        struct Line {
+            raised: Arc<AtomicBool>,
+        }
+
+        impl Device for Line {
+            fn name(&self) -> &str {
+                "line"
+            }
+
+            fn read(&mut self, _offset: u64, _size: u64) -> Option<u64> {
+                None
+            }
+
+            fn write(&mut self, _offset: u64, _size: u64, _value: u64) -> Option<()> {
+                None
+            }
+
+            fn interrupt_pending(&self) -> bool {
+                self.raised.load(Ordering::Relaxed)
+            }
+        }
+
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x00200293, // addi t0, zero, 2
+            0x34402073, // csrrs zero, mip, zero
+            0x3442b073, // csrrc zero, mip, t0
+            0x1442b073, // csrrc zero, sip, t0
+        ]);
+        let registry = DeviceRegistry::new();
+        let spec = "plic".parse().unwrap();
+        registry
+            .attach(&mut cpu.bus, &spec, &DeviceContext::default())
+            .unwrap();
+        let raised = Arc::new(AtomicBool::new(true));
+        let line = Line {
+            raised: raised.clone(),
+        };
+        cpu.bus
+            .attach(0x1000_0000, 0x1000, Some(1), Box::new(line))
+            .unwrap();
+        // Enable source 1 for the supervisor context
+        cpu.bus.write(0x0c00_0004, 4, 1).unwrap();
+        cpu.bus.write(0x0c00_2080, 4, 1 << 1).unwrap();
+        cpu.csrs.mideleg = csr::SUPERVISOR_INTERRUPTS;
+
+        cpu.tick().unwrap();
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+            assert_eq!(cpu.csrs.pending_interrupts(), csr::MIP_SEIP);
+        }
+        raised.store(false, Ordering::Relaxed);
+        cpu.bus.tick();
+        assert_eq!(cpu.csrs.mip, 0);
+        assert_eq!(cpu.bus.hart_interrupts(), 0);
+    }
+
+    #[test]
+    /// Tests that delegated exceptions and interrupts are taken in supervisor mode,
+    /// which returns to user mode with `sret`
+    fn test_supervisor_delegation() {
+        let mut program = [0; 17];
+        program[0] = 0x00000073; // ecall
+        program[16] = 0x10200073; // sret
+        let (mut cpu, _events) = cpu_with_program(&program);
+        grant_all_memory(&mut cpu);
+        cpu.privilege = PrivilegeMode::User;
+        cpu.csrs.medeleg = 1 << 8;
+        cpu.csrs.stvec = 0x40;
+        cpu.csrs.mtvec = 0x80;
+
+        assert_eq!(
+            cpu.tick(),
+            Ok(StepOutcome::Trapped(Exception::EnvironmentCallFromUMode))
+        );
+        assert_eq!(cpu.privilege, PrivilegeMode::Supervisor);
+        assert_eq!((cpu.pc, cpu.csrs.sepc, cpu.csrs.scause), (0x40, 0, 8));
+        assert_eq!(cpu.csrs.mcause, 0);
+        assert_eq!(cpu.csrs.mstatus & csr::MSTATUS_SPP, 0);
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        assert_eq!((cpu.privilege, cpu.pc), (PrivilegeMode::User, 0));
+
+        // The supervisor timer interrupt is raised by machine mode and delegated
+        cpu.csrs.mideleg = csr::MIP_STIP;
+        cpu.csrs.mie = csr::MIP_STIP;
+        cpu.csrs.mip = csr::MIP_STIP;
+        assert_eq!(
+            cpu.tick(),
+            Ok(StepOutcome::Interrupted(Interrupt::SupervisorTimer))
+        );
+        assert_eq!(cpu.privilege, PrivilegeMode::Supervisor);
+        assert_eq!(cpu.csrs.scause, 1 << 63 | 5);
+
+        // Supervisor interrupts are masked by `sstatus.SIE` in supervisor mode
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        assert_eq!(cpu.privilege, PrivilegeMode::User);
+    }
+
+    #[test]
+    /// Supervisor mode loads and stores are translated by the Sv39 page tables,
+    /// and `sfence.vma` invalidates the cached translations.
+    fn test_virtual_memory() {
+        let program = [
+            0x00001537, // lui a0,1
+            0x00a53023, // sd a0,0(a0)
+            0x12050073, // sfence.vma a0
+            0x00053603, // ld a2,0(a0)
+            0x000025b7, // lui a1,2
+            0x0005b683, // ld a3,0(a1)
+        ];
+        let (mut cpu, _events) = Cpu::new(Bus::new(0, 0x6000).unwrap());
+        for (i, instruction) in program.iter().enumerate() {
+            cpu.bus.dram.write_u32(i * 4, *instruction);
+        }
+        let pte = |address: u64, flags: u64| address >> 2 | flags | mmu::PTE_V;
+        // The root table at 0x1000 maps the code at 0x0 and the data at 0x1000 to 0x4000
+        cpu.bus.write(0x1000, 8, pte(0x2000, 0)).unwrap();
+        cpu.bus.write(0x2000, 8, pte(0x3000, 0)).unwrap();
+        cpu.bus
+            .write(0x3000, 8, pte(0x0, mmu::PTE_R | mmu::PTE_X))
+            .unwrap();
+        cpu.bus
+            .write(0x3008, 8, pte(0x4000, mmu::PTE_R | mmu::PTE_W))
+            .unwrap();
+        cpu.csrs.satp = 8 << 60 | 0x1;
+        grant_all_memory(&mut cpu);
+        cpu.privilege = PrivilegeMode::Supervisor;
+        cpu.csrs.mtvec = 0x40;
+
+        for _ in 0..2 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        }
+        assert_eq!(cpu.bus.read(0x4000, 8, AccessType::Load), Ok(0x1000));
+        let flags = cpu.bus.read(0x3008, 8, AccessType::Load).unwrap();
+        assert_eq!(flags & (mmu::PTE_A | mmu::PTE_D), mmu::PTE_A | mmu::PTE_D);
+
+        // The data page is remapped to 0x5000, which is only visible after the fence
+        cpu.bus
+            .write(0x3008, 8, pte(0x5000, mmu::PTE_R | mmu::PTE_A))
+            .unwrap();
+        cpu.bus.write(0x5000, 8, 7).unwrap();
+        for _ in 0..3 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        }
+        assert_eq!(cpu.gprs[12], 7);
+
+        assert_eq!(
+            cpu.tick(),
+            Ok(StepOutcome::Trapped(Exception::LoadPageFault(0x2000)))
+        );
+        assert_eq!((cpu.csrs.mcause, cpu.csrs.mtval), (13, 0x2000));
+        assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+    }
+
+    #[test]
+    /// User mode may only access the physical memory granted by the PMP entries.
+    fn test_physical_memory_protection() {
+        let (mut cpu, _events) = cpu_with_program(&[
+            0x10002503, // lw a0,256(zero)
+        ]);
+        let machine = PrivilegeMode::Machine;
+        // The first 256 bytes are executable and the next 4 bytes are readable
+        cpu.csrs.write(csr::PMPADDR0, 0x100 >> 2, machine).unwrap();
+        cpu.csrs
+            .write(csr::PMPADDR0 + 1, 0x100 >> 2, machine)
+            .unwrap();
+        let cfg = [pmp::PMP_A_TOR | pmp::PMP_X, pmp::PMP_A_NA4 | pmp::PMP_R];
+        cpu.csrs
+            .write(csr::PMPCFG0, u16::from_le_bytes(cfg) as u64, machine)
+            .unwrap();
+        assert_eq!(cpu.csrs.read(csr::PMPCFG0 + 2, machine), Some(0));
+        assert_eq!(cpu.csrs.read(csr::PMPCFG0 + 1, machine), None);
+        cpu.privilege = PrivilegeMode::User;
+        cpu.csrs.mtvec = 0x40;
+
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+
+        // Without the NA4 entry, no entry matches the load
+        cpu.pc = 0;
+        cpu.csrs
+            .write(csr::PMPCFG0, cfg[0] as u64, machine)
+            .unwrap();
+        let exception = Exception::LoadAccessFault {
+            address: 0x100,
+            size: 4,
+        };
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Trapped(exception)));
+        assert_eq!(cpu.privilege, PrivilegeMode::Machine);
+    }
+
+    #[test]
+    /// Supervisor mode `ecall`s are serviced by the built-in SBI firmware.
+    fn test_sbi_calls() {
+        let (mut cpu, events) = cpu_with_program(&[
+            0x00100893, // addi a7,zero,1
+            0x04f00513, // addi a0,zero,0x4f
+            0x00000073, // ecall
+            0x535258b7, // lui a7,0x53525
+            0x3548889b, // addiw a7,a7,0x354
+            0x00000813, // addi a6,zero,0
+            0x00000513, // addi a0,zero,0
+            0x00100593, // addi a1,zero,1
+            0x00000073, // ecall
+        ]);
+        let context = DeviceContext {
+            events: cpu.event_sender(),
+            ..DeviceContext::default()
+        };
+        cpu.enable_sbi(Sbi::new(&context));
+        assert_eq!(cpu.privilege, PrivilegeMode::Supervisor);
+
+        for _ in 0..8 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        }
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Exited { exit_code: 1 }));
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                CpuEvent::Write {
+                    text: "O".to_string()
+                },
+                CpuEvent::Exit { exit_code: 1 }
+            ]
+        );
+    }
+
+    #[test]
+    /// The device tree is placed right above the program and passed in `a1`.
+    fn test_load_device_tree() {
+        let (mut cpu, _events) = cpu_with_program(&[0x00000013; 3]);
+        cpu.image_end = 12;
+        let range = cpu.load_device_tree().unwrap();
+        let dtb = fdt::generate(&cpu.bus, &cpu.csrs, 16);
+
+        assert_eq!(range, 16..16 + dtb.len() as u64);
+        assert_eq!(cpu.gprs[a0], 0);
+        assert_eq!(cpu.gprs[a1], 16);
+        assert_eq!(cpu.bus.dram_slice(16, dtb.len() as u64), Some(&dtb[..]));
+        // The program below the blob is left untouched
+        assert_eq!(cpu.bus.dram_slice(8, 4), Some(&[0x13, 0, 0, 0][..]));
+
+        cpu.image_end = 4096 - 8;
+        assert_eq!(cpu.load_device_tree(), None);
+    }
+
+    #[test]
+    /// Tests that errors in the system calls serviced by the VM are returned to the host
+    fn test_syscall_errors() {
+        let mut program = [
+            0x04000893, // addi a7, zero, ECALL_WRITE
+            0x00c00593, // addi a1, zero, 12
+            0x00200613, // addi a2, zero, 2
+            0x00000073, // ecall
+            0x00100893, // addi a7, zero, 1
+            0x00000073, // ecall
+        ];
+        let (mut cpu, events) = cpu_with_program(&program);
+        for _ in 0..5 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        }
+        assert_eq!(
+            events.try_recv(),
+            Ok(CpuEvent::Write {
+                text: "s\0".to_string()
+            })
+        );
+        assert_eq!(cpu.tick(), Err(VmError::UnknownSyscall { number: 1 }));
+
+        // The text now starts at the second `addi a7`, whose first byte is 0x93
+        program[1] = 0x01000593; // addi a1, zero, 16
+        let (mut cpu, _events) = cpu_with_program(&program);
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.tick(), Err(VmError::InvalidUtf8Output));
+    }
+}
@@ -0,0 +1,793 @@
+//! # Control and Status Registers
+//!
+//! This module implements the CSR file accessed through the Zicsr instructions
+//! (`csrrw`, `csrrs`, `csrrc` and their immediate forms).
+//! See also: [https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/machine.html]
+//!
+//! The supervisor registers `sstatus`, `sie` and `sip` are restricted views of their machine
+//! mode counterparts, all other supervisor registers are stored separately.
+//! See also: [https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/supervisor.html]
+//!
+//! ## Address Space
+//!
+//! The 12-bit CSR address encodes its access rights:
+//! - Bits 10-11 are `0b11` for read-only registers.
+//! - Bits 8-9 hold the lowest privilege mode that may access the register.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+
+use crate::{
+    mmu::TranslationMode,
+    pmp::{PMP_ENTRIES, Pmp},
+};
+
+/// The privilege mode the hart is currently executing in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Display)]
+pub enum PrivilegeMode {
+    #[display("U")]
+    User = 0,
+    #[display("S")]
+    Supervisor = 1,
+    #[display("M")]
+    Machine = 3,
+}
+
+impl PrivilegeMode {
+    /// Returns the privilege mode for its 2-bit encoding, as used by `mstatus.MPP`.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0b00 => Some(PrivilegeMode::User),
+            0b01 => Some(PrivilegeMode::Supervisor),
+            0b11 => Some(PrivilegeMode::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// The width of the integer registers and the address space, as reported by `misa.MXL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Xlen {
+    #[display("RV32")]
+    Rv32,
+    #[display("RV64")]
+    Rv64,
+}
+
+impl Xlen {
+    /// Returns the number of bits of a register.
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    /// Sign-extends the lowest XLEN bits of `value` to 64 bits.
+    ///
+    /// The registers of an RV32 hart hold their values sign-extended to 64 bits, so the
+    /// signed and unsigned comparisons work on the full register for both widths.
+    #[inline]
+    pub fn sign_extend(self, value: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => value as i32 as i64 as u64,
+            Xlen::Rv64 => value,
+        }
+    }
+
+    /// Zero-extends the lowest XLEN bits of `value` to 64 bits,
+    /// which wraps addresses around the end of the address space.
+    #[inline]
+    pub fn truncate(self, value: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => value & 0xffff_ffff,
+            Xlen::Rv64 => value,
+        }
+    }
+}
+
+impl FromStr for Xlen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "32" | "rv32" => Ok(Xlen::Rv32),
+            "64" | "rv64" => Ok(Xlen::Rv64),
+            _ => Err(format!("Unknown XLEN '{}', expected 32 or 64", s)),
+        }
+    }
+}
+
+macro_rules! csr_address {
+    ($(($name:ident, $address:expr)),* $(,)?) => {
+        ::paste::paste! {
+            $(
+                #[doc = "CSR [`" $name "`] has the address: `" $address "`."]
+                pub const $name: u16 = $address;
+            )*
+        }
+    };
+}
+
+// --- Floating point CSRs ---
+csr_address! {
+    (FFLAGS, 0x001), (FRM, 0x002), (FCSR, 0x003),
+}
+
+// --- Unprivileged counters ---
+csr_address! {
+    (CYCLE, 0xc00), (TIME, 0xc01), (INSTRET, 0xc02),
+}
+
+// --- Upper halves of the unprivileged counters (RV32 only) ---
+csr_address! {
+    (CYCLEH, 0xc80), (TIMEH, 0xc81), (INSTRETH, 0xc82),
+}
+
+// --- Supervisor trap setup and handling ---
+csr_address! {
+    (SSTATUS, 0x100), (SIE, 0x104), (STVEC, 0x105), (SCOUNTEREN, 0x106),
+    (SSCRATCH, 0x140), (SEPC, 0x141), (SCAUSE, 0x142), (STVAL, 0x143), (SIP, 0x144),
+}
+
+// --- Supervisor address translation and protection ---
+csr_address! {
+    (SATP, 0x180),
+}
+
+// --- Machine information registers ---
+csr_address! {
+    (MVENDORID, 0xf11), (MARCHID, 0xf12), (MIMPID, 0xf13), (MHARTID, 0xf14), (MCONFIGPTR, 0xf15),
+}
+
+// --- Machine trap setup and handling ---
+csr_address! {
+    (MSTATUS, 0x300), (MISA, 0x301), (MEDELEG, 0x302), (MIDELEG, 0x303), (MIE, 0x304),
+    (MTVEC, 0x305), (MCOUNTEREN, 0x306),
+    (MSCRATCH, 0x340), (MEPC, 0x341), (MCAUSE, 0x342), (MTVAL, 0x343), (MIP, 0x344),
+}
+
+// --- Machine memory protection ---
+csr_address! {
+    (PMPCFG0, 0x3a0), (PMPCFG15, 0x3af), (PMPADDR0, 0x3b0), (PMPADDR63, 0x3ef),
+}
+
+/// The names of the implemented `pmpcfg` registers.
+const PMPCFG_NAMES: [&str; PMP_ENTRIES / 4] = ["pmpcfg0", "pmpcfg1", "pmpcfg2", "pmpcfg3"];
+/// The names of the implemented `pmpaddr` registers.
+const PMPADDR_NAMES: [&str; PMP_ENTRIES] = [
+    "pmpaddr0",
+    "pmpaddr1",
+    "pmpaddr2",
+    "pmpaddr3",
+    "pmpaddr4",
+    "pmpaddr5",
+    "pmpaddr6",
+    "pmpaddr7",
+    "pmpaddr8",
+    "pmpaddr9",
+    "pmpaddr10",
+    "pmpaddr11",
+    "pmpaddr12",
+    "pmpaddr13",
+    "pmpaddr14",
+    "pmpaddr15",
+];
+
+// --- Machine counters ---
+csr_address! {
+    (MCYCLE, 0xb00), (MINSTRET, 0xb02),
+}
+
+// --- Upper halves of the machine counters (RV32 only) ---
+csr_address! {
+    (MCYCLEH, 0xb80), (MINSTRETH, 0xb82),
+}
+
+/// Returns the name of the CSR at `address`, as used in assembly.
+pub fn name(address: u16) -> Option<&'static str> {
+    Some(match address {
+        FFLAGS => "fflags",
+        FRM => "frm",
+        FCSR => "fcsr",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        CYCLEH => "cycleh",
+        TIMEH => "timeh",
+        INSTRETH => "instreth",
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        MCONFIGPTR => "mconfigptr",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        PMPCFG0..=PMPCFG15 => PMPCFG_NAMES.get((address - PMPCFG0) as usize)?,
+        PMPADDR0..=PMPADDR63 => PMPADDR_NAMES.get((address - PMPADDR0) as usize)?,
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MCYCLEH => "mcycleh",
+        MINSTRETH => "minstreth",
+        _ => return None,
+    })
+}
+
+/// `mstatus.SIE`: Supervisor interrupt enable.
+pub const MSTATUS_SIE: u64 = 1 << 1;
+/// `mstatus.MIE`: Machine interrupt enable.
+pub const MSTATUS_MIE: u64 = 1 << 3;
+/// `mstatus.SPIE`: Supervisor interrupt enable before the last trap.
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+/// `mstatus.MPIE`: Machine interrupt enable before the last trap.
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+/// `mstatus.SPP`: Privilege mode before the last trap into supervisor mode.
+pub const MSTATUS_SPP: u64 = 1 << 8;
+/// `mstatus.MPP`: Privilege mode before the last trap into machine mode.
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+/// `mstatus.FS`: State of the floating point unit (Off, Initial, Clean, Dirty).
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+/// `mstatus.MPRV`: Modify the privilege of loads and stores to `mstatus.MPP`.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+/// `mstatus.SUM`: Permit supervisor mode accesses to user memory.
+pub const MSTATUS_SUM: u64 = 1 << 18;
+/// `mstatus.MXR`: Make executable memory readable.
+pub const MSTATUS_MXR: u64 = 1 << 19;
+/// `mstatus.TVM`: Trap virtual memory management (`satp`, `sfence.vma`) in supervisor mode.
+pub const MSTATUS_TVM: u64 = 1 << 20;
+/// `mstatus.TW`: Trap `wfi` in supervisor mode.
+pub const MSTATUS_TW: u64 = 1 << 21;
+/// `mstatus.TSR`: Trap `sret` in supervisor mode.
+pub const MSTATUS_TSR: u64 = 1 << 22;
+/// `mstatus.UXL`: The XLEN of user mode.
+pub const MSTATUS_UXL: u64 = 0b11 << 32;
+/// `mstatus.SXL`: The XLEN of supervisor mode.
+pub const MSTATUS_SXL: u64 = 0b11 << 34;
+/// `mstatus.SD`: Summarizes whether the FS field signals dirty state.
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+const MSTATUS_FS_OFF: u64 = 0b00 << 13;
+const MSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+const MSTATUS_FS_DIRTY: u64 = 0b11 << 13;
+
+/// `mip.SSIP` / `mie.SSIE`: Supervisor software interrupt.
+pub const MIP_SSIP: u64 = 1 << 1;
+/// `mip.MSIP` / `mie.MSIE`: Machine software interrupt.
+pub const MIP_MSIP: u64 = 1 << 3;
+/// `mip.STIP` / `mie.STIE`: Supervisor timer interrupt.
+pub const MIP_STIP: u64 = 1 << 5;
+/// `mip.MTIP` / `mie.MTIE`: Machine timer interrupt.
+pub const MIP_MTIP: u64 = 1 << 7;
+/// `mip.SEIP` / `mie.SEIE`: Supervisor external interrupt.
+pub const MIP_SEIP: u64 = 1 << 9;
+/// `mip.MEIP` / `mie.MEIE`: Machine external interrupt.
+pub const MIP_MEIP: u64 = 1 << 11;
+
+/// The supervisor interrupts, which can be delegated to supervisor mode and set by
+/// machine mode software.
+pub const SUPERVISOR_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+/// The interrupts that can be enabled in `mie`.
+const INTERRUPTS: u64 = SUPERVISOR_INTERRUPTS | MIP_MSIP | MIP_MTIP | MIP_MEIP;
+
+/// The exceptions that can be delegated to supervisor mode: all except the reserved codes
+/// and environment calls from machine mode.
+const MEDELEG_WRITABLE: u64 = 0xb3ff & !(1 << 11);
+
+/// The bits of `mstatus` that can be written by software.
+const MSTATUS_WRITABLE: u64 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_FS
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+
+/// The bits of `mstatus` that are visible in `sstatus`.
+const SSTATUS_READABLE: u64 = MSTATUS_SIE
+    | MSTATUS_SPIE
+    | MSTATUS_SPP
+    | MSTATUS_FS
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_UXL
+    | MSTATUS_SD;
+/// The bits of `mstatus` that can be written through `sstatus`.
+const SSTATUS_WRITABLE: u64 =
+    MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_FS | MSTATUS_SUM | MSTATUS_MXR;
+
+/// The extensions reported in `misa`, one bit per letter.
+const MISA_EXTENSIONS: u64 = misa_extensions(b"ACDFIMSU");
+/// The XLEN encoding in `misa.MXL` for 32-bit harts.
+const MISA_MXL_32: u64 = 1 << 30;
+/// The XLEN encoding in `misa.MXL` for 64-bit harts.
+const MISA_MXL_64: u64 = 2 << 62;
+
+/// Returns the `misa` extension bits for the given extension letters.
+const fn misa_extensions(letters: &[u8]) -> u64 {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < letters.len() {
+        bits |= 1 << (letters[i] - b'A');
+        i += 1;
+    }
+    bits
+}
+
+pub struct CsrFile {
+    /// # Floating Point Control and Status Register
+    ///
+    /// - Bits 0-4 (`fflags`) accumulate the exception flags raised by floating point instructions.
+    /// - Bits 5-7 (`frm`) hold the dynamic rounding mode.
+    pub fcsr: u32,
+
+    /// The XLEN of the hart. All registers are XLEN bits wide and `mstatus.SD` is the
+    /// most significant bit of `mstatus`, the register values are stored in their RV64 layout.
+    pub xlen: Xlen,
+
+    pub mstatus: u64,
+    pub misa: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub mie: u64,
+    /// The interrupt pending bits written by software, see [`CsrFile::device_interrupts`].
+    pub mip: u64,
+    /// The interrupt pending bits raised by the devices, which are combined with the bits
+    /// written by software when reading `mip`.
+    pub device_interrupts: u64,
+    /// The value of the real-time counter, read through `time`. The CPU keeps it in sync with
+    /// `mtime` of the CLINT.
+    pub time: u64,
+    pub mtvec: u64,
+    pub mcounteren: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub mcycle: u64,
+    pub minstret: u64,
+    pub mhartid: u64,
+
+    pub stvec: u64,
+    pub scounteren: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub satp: u64,
+
+    /// The physical memory protection entries, configured by `pmpcfg` and `pmpaddr`.
+    pub pmp: Pmp,
+}
+
+impl CsrFile {
+    /// Creates a new CSR file with the reset values of the registers.
+    pub fn new() -> Self {
+        CsrFile {
+            fcsr: 0,
+            xlen: Xlen::Rv64,
+            // The floating point unit starts enabled, so programs do not need to turn it on first.
+            // Supervisor and user mode always run with XLEN = 64.
+            mstatus: MSTATUS_FS_INITIAL | MSTATUS_UXL & 2 << 32 | MSTATUS_SXL & 2 << 34,
+            misa: MISA_MXL_64 | MISA_EXTENSIONS,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            device_interrupts: 0,
+            time: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mcycle: 0,
+            minstret: 0,
+            mhartid: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmp: Pmp::new(),
+        }
+    }
+
+    /// Changes the XLEN of the hart, which is reported in `misa.MXL`.
+    pub fn set_xlen(&mut self, xlen: Xlen) {
+        self.xlen = xlen;
+        self.misa = MISA_EXTENSIONS
+            | match xlen {
+                Xlen::Rv32 => MISA_MXL_32,
+                Xlen::Rv64 => MISA_MXL_64,
+            };
+    }
+
+    /// Returns `true` if `mstatus.FS` is Off, which makes all floating point instructions illegal.
+    pub fn is_fp_disabled(&self) -> bool {
+        self.mstatus & MSTATUS_FS == MSTATUS_FS_OFF
+    }
+
+    /// Marks the floating point state as modified by setting `mstatus.FS` to Dirty.
+    pub fn mark_fp_dirty(&mut self) {
+        self.mstatus |= MSTATUS_FS_DIRTY | MSTATUS_SD;
+    }
+
+    /// Returns `true` if the register at `address` may be accessed from the `privilege` mode.
+    fn is_accessible(&self, address: u16, privilege: PrivilegeMode, write: bool) -> bool {
+        let read_only = (address >> 10) & 0b11 == 0b11;
+        let minimum_privilege = (address >> 8) & 0b11;
+        !(write && read_only) && privilege as u16 >= minimum_privilege
+    }
+
+    /// Returns `true` if the counter at `address` is readable from the `privilege` mode,
+    /// which is controlled by `mcounteren` for supervisor mode, and additionally by
+    /// `scounteren` for user mode.
+    fn is_counter_enabled(&self, address: u16, privilege: PrivilegeMode) -> bool {
+        let bit = 1 << (address & 0x1f);
+        match privilege {
+            PrivilegeMode::Machine => true,
+            PrivilegeMode::Supervisor => self.mcounteren & bit != 0,
+            PrivilegeMode::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    /// Returns the pending interrupts, raised by software or the devices.
+    pub fn pending_interrupts(&self) -> u64 {
+        self.mip | self.device_interrupts
+    }
+
+    /// Returns `true` if supervisor mode accesses to `satp` and `sfence.vma` are trapped
+    /// by `mstatus.TVM`.
+    pub fn is_vm_trapped(&self, privilege: PrivilegeMode) -> bool {
+        privilege == PrivilegeMode::Supervisor && self.mstatus & MSTATUS_TVM != 0
+    }
+
+    /// Returns `true` if `satp` can hold the translation mode of `value`.
+    fn is_supported_satp_mode(&self, value: u64) -> bool {
+        TranslationMode::from_satp(value, self.xlen).is_some()
+    }
+
+    /// Returns the index of the first PMP entry configured by the `pmpcfg` register at
+    /// `address`, or [`None`] if the register does not exist for the XLEN. On RV64, the
+    /// odd-numbered registers do not exist, as the even ones hold 8 entries each.
+    fn pmpcfg_entries(&self, address: u16) -> Option<usize> {
+        let register = (address - PMPCFG0) as usize;
+        match self.xlen {
+            Xlen::Rv64 if register % 2 == 1 => None,
+            _ => Some(register * 4),
+        }
+    }
+
+    /// Returns the `pmpcfg` register whose first entry is `first`, with the bytes of the
+    /// unimplemented entries reading as zero.
+    fn read_pmpcfg(&self, first: usize) -> u64 {
+        (0..self.xlen.bits() as usize / 8)
+            .filter(|offset| first + offset < PMP_ENTRIES)
+            .fold(0, |value, offset| {
+                value | (self.pmp.cfg(first + offset) as u64) << (8 * offset)
+            })
+    }
+
+    /// Reads the register at `address`.
+    /// Returns [`None`] for unknown registers and insufficient privileges.
+    pub fn read(&self, address: u16, privilege: PrivilegeMode) -> Option<u64> {
+        if !self.is_accessible(address, privilege, false) {
+            return None;
+        }
+
+        let value = match address {
+            FFLAGS | FRM | FCSR if self.is_fp_disabled() => return None,
+            FFLAGS => self.fcsr as u64 & 0x1f,
+            FRM => self.fcsr as u64 >> 5 & 0x7,
+            FCSR => self.fcsr as u64 & 0xff,
+            CYCLEH | TIMEH | INSTRETH | MCYCLEH | MINSTRETH if self.xlen != Xlen::Rv32 => {
+                return None;
+            }
+            CYCLE | TIME | INSTRET | CYCLEH | TIMEH | INSTRETH
+                if !self.is_counter_enabled(address, privilege) =>
+            {
+                return None;
+            }
+            CYCLE | MCYCLE => self.mcycle,
+            TIME => self.time,
+            INSTRET | MINSTRET => self.minstret,
+            CYCLEH | MCYCLEH => self.mcycle >> 32,
+            TIMEH => self.time >> 32,
+            INSTRETH | MINSTRETH => self.minstret >> 32,
+            MVENDORID | MARCHID | MIMPID | MCONFIGPTR => 0,
+            MHARTID => self.mhartid,
+            MSTATUS => self.mstatus,
+            MISA => self.misa,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MIP => self.pending_interrupts(),
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            SSTATUS => self.mstatus & SSTATUS_READABLE,
+            SIE => self.mie & self.mideleg,
+            SIP => self.pending_interrupts() & self.mideleg,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SATP if self.is_vm_trapped(privilege) => return None,
+            SATP => self.satp,
+            PMPCFG0..=PMPCFG15 => self.read_pmpcfg(self.pmpcfg_entries(address)?),
+            PMPADDR0..=PMPADDR63 => match (address - PMPADDR0) as usize {
+                index if index < PMP_ENTRIES => self.pmp.addr(index),
+                _ => 0,
+            },
+            _ => return None,
+        };
+
+        // The RV32 `mstatus.SD` bit is bit 31 instead of bit 63
+        Some(match (self.xlen, address) {
+            (Xlen::Rv32, MSTATUS | SSTATUS) => value & 0x7fff_ffff | (value >> 63) << 31,
+            (xlen, _) => xlen.truncate(value),
+        })
+    }
+
+    /// Writes `value` into the register at `address`, ignoring writes to read-only fields.
+    /// Returns [`None`] for unknown and read-only registers and insufficient privileges.
+    pub fn write(&mut self, address: u16, value: u64, privilege: PrivilegeMode) -> Option<()> {
+        if !self.is_accessible(address, privilege, true) {
+            return None;
+        }
+
+        let value = self.xlen.truncate(value);
+        match address {
+            FFLAGS | FRM | FCSR if self.is_fp_disabled() => return None,
+            FFLAGS => self.fcsr = self.fcsr & !0x1f | (value as u32 & 0x1f),
+            FRM => self.fcsr = self.fcsr & !0xe0 | (value as u32 & 0x7) << 5,
+            FCSR => self.fcsr = value as u32 & 0xff,
+            MCYCLEH | MINSTRETH if self.xlen != Xlen::Rv32 => return None,
+            // On RV32, the counters are written in two halves
+            MCYCLE if self.xlen == Xlen::Rv32 => self.mcycle = self.mcycle & !0xffff_ffff | value,
+            MINSTRET if self.xlen == Xlen::Rv32 => {
+                self.minstret = self.minstret & !0xffff_ffff | value
+            }
+            MCYCLE => self.mcycle = value,
+            MINSTRET => self.minstret = value,
+            MCYCLEH => self.mcycle = self.mcycle & 0xffff_ffff | value << 32,
+            MINSTRETH => self.minstret = self.minstret & 0xffff_ffff | value << 32,
+            MSTATUS => {
+                let mut mstatus = self.mstatus & !MSTATUS_WRITABLE | value & MSTATUS_WRITABLE;
+                // MPP is WARL and only holds supported privilege modes.
+                if PrivilegeMode::from_bits((mstatus & MSTATUS_MPP) >> 11).is_none() {
+                    mstatus = mstatus & !MSTATUS_MPP | self.mstatus & MSTATUS_MPP;
+                }
+                self.mstatus = Self::with_state_dirty(mstatus);
+            }
+            // The ISA is fixed, so writes are ignored.
+            MISA => {}
+            MEDELEG => self.medeleg = value & MEDELEG_WRITABLE,
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & INTERRUPTS,
+            // The machine-level pending bits are set by the interrupt sources,
+            // machine mode may raise the supervisor interrupts.
+            MIP => {
+                self.mip = self.mip & !SUPERVISOR_INTERRUPTS | value & SUPERVISOR_INTERRUPTS;
+            }
+            MTVEC => self.mtvec = Self::legal_tvec(self.mtvec, value),
+            MCOUNTEREN => self.mcounteren = value & 0xffff_ffff,
+            MSCRATCH => self.mscratch = value,
+            // Instructions are 16-bit aligned, so the least significant bit is always zero.
+            MEPC => self.mepc = value & !1,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            SSTATUS => {
+                let mstatus = self.mstatus & !SSTATUS_WRITABLE | value & SSTATUS_WRITABLE;
+                self.mstatus = Self::with_state_dirty(mstatus);
+            }
+            SIE => self.mie = self.mie & !self.mideleg | value & self.mideleg,
+            // Supervisor mode may only clear its own software interrupt
+            SIP => {
+                let writable = MIP_SSIP & self.mideleg;
+                self.mip = self.mip & !writable | value & writable;
+            }
+            STVEC => self.stvec = Self::legal_tvec(self.stvec, value),
+            SCOUNTEREN => self.scounteren = value & 0xffff_ffff,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !1,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SATP if self.is_vm_trapped(privilege) => return None,
+            // Writes of unsupported translation modes have no effect
+            SATP if !self.is_supported_satp_mode(value) => {}
+            SATP => self.satp = value,
+            PMPCFG0..=PMPCFG15 => {
+                let first = self.pmpcfg_entries(address)?;
+                for offset in 0..self.xlen.bits() as usize / 8 {
+                    if first + offset < PMP_ENTRIES {
+                        self.pmp
+                            .write_cfg(first + offset, (value >> (8 * offset)) as u8);
+                    }
+                }
+            }
+            PMPADDR0..=PMPADDR63 => {
+                let index = (address - PMPADDR0) as usize;
+                if index < PMP_ENTRIES {
+                    self.pmp.write_addr(index, value);
+                }
+            }
+            _ => return None,
+        }
+
+        if matches!(address, FFLAGS | FRM | FCSR) {
+            self.mark_fp_dirty();
+        }
+        Some(())
+    }
+
+    /// Returns the trap vector for a write of `value` to `mtvec` or `stvec` holding `current`.
+    /// Only the direct (0) and vectored (1) modes are supported.
+    fn legal_tvec(current: u64, value: u64) -> u64 {
+        let mode = if value & 0b11 < 2 {
+            value & 0b11
+        } else {
+            current & 0b11
+        };
+        value & !0b11 | mode
+    }
+
+    /// Updates the read-only `mstatus.SD` summary bit from the `mstatus.FS` field.
+    fn with_state_dirty(mstatus: u64) -> u64 {
+        match mstatus & MSTATUS_FS == MSTATUS_FS_DIRTY {
+            true => mstatus | MSTATUS_SD,
+            false => mstatus & !MSTATUS_SD,
+        }
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test cases for the access rights encoded in the CSR address.
+    fn test_access_rights() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(csrs.read(MHARTID, PrivilegeMode::Machine), Some(0));
+        assert_eq!(csrs.write(MHARTID, 1, PrivilegeMode::Machine), None);
+        assert_eq!(csrs.read(MSCRATCH, PrivilegeMode::User), None);
+        assert_eq!(
+            csrs.read(0x7c0, PrivilegeMode::Machine),
+            None,
+            "unknown CSR"
+        );
+        assert_eq!(csrs.read(CYCLE, PrivilegeMode::User), None);
+        csrs.mcounteren = 1;
+        assert_eq!(csrs.read(CYCLE, PrivilegeMode::Supervisor), Some(0));
+        assert_eq!(csrs.read(CYCLE, PrivilegeMode::User), None);
+        csrs.scounteren = 1;
+        assert_eq!(csrs.read(CYCLE, PrivilegeMode::User), Some(0));
+        assert_eq!(csrs.read(MSTATUS, PrivilegeMode::Supervisor), None);
+        assert_eq!(csrs.read(SSTATUS, PrivilegeMode::User), None);
+    }
+
+    #[test]
+    /// Test cases for the WARL fields, which only hold legal values.
+    fn test_warl_fields() {
+        let mut csrs = CsrFile::new();
+        csrs.write(MTVEC, 0x8000_0003, PrivilegeMode::Machine);
+        assert_eq!(csrs.mtvec, 0x8000_0000);
+        csrs.write(MEPC, 0x8000_0003, PrivilegeMode::Machine);
+        assert_eq!(csrs.mepc, 0x8000_0002);
+        csrs.write(MSTATUS, u64::MAX, PrivilegeMode::Machine);
+        assert_eq!(csrs.mstatus & MSTATUS_MPP, MSTATUS_MPP);
+        assert_eq!(csrs.mstatus & MSTATUS_SD, MSTATUS_SD);
+        csrs.write(FRM, 0b101, PrivilegeMode::Machine);
+        assert_eq!(csrs.fcsr, 0b1010_0000);
+    }
+
+    #[test]
+    /// Test cases for the supervisor registers that are views of the machine registers.
+    fn test_supervisor_views() {
+        let mut csrs = CsrFile::new();
+        let supervisor = PrivilegeMode::Supervisor;
+        csrs.write(SSTATUS, u64::MAX, supervisor).unwrap();
+        assert_eq!(csrs.mstatus & MSTATUS_MIE, 0);
+        assert_eq!(csrs.mstatus & MSTATUS_SIE, MSTATUS_SIE);
+        assert_eq!(csrs.mstatus & MSTATUS_SPP, MSTATUS_SPP);
+
+        csrs.write(MIDELEG, u64::MAX, PrivilegeMode::Machine);
+        assert_eq!(csrs.mideleg, MIP_SSIP | MIP_STIP | MIP_SEIP);
+        csrs.write(SIE, u64::MAX, supervisor).unwrap();
+        assert_eq!(csrs.mie, MIP_SSIP | MIP_STIP | MIP_SEIP);
+
+        csrs.device_interrupts = MIP_MEIP | MIP_SEIP;
+        csrs.write(MIP, MIP_STIP | MIP_SSIP, PrivilegeMode::Machine);
+        assert_eq!(
+            csrs.read(SIP, supervisor),
+            Some(MIP_SSIP | MIP_STIP | MIP_SEIP)
+        );
+        csrs.write(SIP, 0, supervisor).unwrap();
+        assert_eq!(
+            csrs.read(MIP, PrivilegeMode::Machine),
+            Some(MIP_STIP | MIP_SEIP | MIP_MEIP)
+        );
+
+        csrs.write(MEDELEG, u64::MAX, PrivilegeMode::Machine);
+        assert_eq!(csrs.medeleg & 1 << 11, 0);
+
+        csrs.write(SATP, 0x1234, supervisor).unwrap();
+        assert_eq!(csrs.satp, 0x1234);
+        csrs.write(SATP, 8 << 60 | 0x1234, supervisor).unwrap();
+        assert_eq!(csrs.satp, 8 << 60 | 0x1234);
+        // Unsupported translation modes leave `satp` unchanged
+        csrs.write(SATP, 10 << 60, supervisor).unwrap();
+        assert_eq!(csrs.satp, 8 << 60 | 0x1234);
+        csrs.mstatus |= MSTATUS_TVM;
+        assert_eq!(csrs.read(SATP, supervisor), None);
+        assert_eq!(
+            csrs.read(SATP, PrivilegeMode::Machine),
+            Some(8 << 60 | 0x1234)
+        );
+    }
+
+    #[test]
+    /// Test cases for the 32-bit layout of the registers on RV32 harts.
+    fn test_rv32_registers() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(csrs.read(MCYCLEH, PrivilegeMode::Machine), None);
+
+        csrs.set_xlen(Xlen::Rv32);
+        assert_eq!(
+            csrs.read(MISA, PrivilegeMode::Machine),
+            Some(MISA_MXL_32 | MISA_EXTENSIONS)
+        );
+        csrs.mark_fp_dirty();
+        let mstatus = csrs.read(MSTATUS, PrivilegeMode::Machine).unwrap();
+        assert_eq!(mstatus >> 31, 1, "SD is bit 31");
+        assert_eq!(mstatus & MSTATUS_UXL, 0);
+
+        csrs.write(MCYCLE, 0xffff_ffff_8000_0000, PrivilegeMode::Machine);
+        csrs.write(MCYCLEH, 0x1234, PrivilegeMode::Machine);
+        assert_eq!(csrs.mcycle, 0x1234_8000_0000);
+        assert_eq!(csrs.read(MCYCLEH, PrivilegeMode::Machine), Some(0x1234));
+        assert_eq!(csrs.read(MCYCLE, PrivilegeMode::Machine), Some(0x8000_0000));
+    }
+}
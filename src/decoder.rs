@@ -0,0 +1,1270 @@
+//! # Instruction Decoder
+//!
+//! This module decodes raw 32-bit instructions into the typed [`Instruction`] enum, which is
+//! shared by the executor, the tracer and the tests. Compressed instructions are expanded by
+//! [`crate::compressed`] before they are decoded.
+//! See also: [https://five-embeddev.com/riscv-user-isa-manual/Priv-v1.12/instr-table.html]
+//!
+//! ## Formats
+//!
+//! All register fields and immediates are extracted here, following the base formats:
+//! - R-type: `funct7 | rs2 | rs1 | funct3 | rd | opcode`
+//! - I-type: `imm[11:0] | rs1 | funct3 | rd | opcode`
+//! - S-type: `imm[11:5] | rs2 | rs1 | funct3 | imm[4:0] | opcode`
+//! - B-type: `imm[12|10:5] | rs2 | rs1 | funct3 | imm[4:1|11] | opcode`
+//! - U-type: `imm[31:12] | rd | opcode`
+//! - J-type: `imm[20|10:1|11|19:12] | rd | opcode`
+//!
+//! The R4-type of the fused multiply-add instructions additionally holds `rs3` in bits 27-31.
+//! Immediates are sign-extended to 64 bits and already shifted into their final position.
+
+use derive_more::{Display, Error};
+
+use crate::utils::sign_extend_u64_to_i64;
+
+/// The index of an integer or floating point register.
+pub type Register = u8;
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// `lui`, the immediate holds the upper 20 bits of the sign-extended 32-bit value.
+    Lui {
+        rd: Register,
+        imm: i64,
+    },
+    /// `auipc`, the immediate holds the upper 20 bits of the sign-extended 32-bit value.
+    Auipc {
+        rd: Register,
+        imm: i64,
+    },
+    Jal {
+        rd: Register,
+        offset: i64,
+    },
+    Jalr {
+        rd: Register,
+        rs1: Register,
+        offset: i64,
+    },
+    Branch {
+        op: BranchOp,
+        rs1: Register,
+        rs2: Register,
+        offset: i64,
+    },
+    Load {
+        op: LoadOp,
+        rd: Register,
+        rs1: Register,
+        offset: i64,
+    },
+    Store {
+        op: StoreOp,
+        rs1: Register,
+        rs2: Register,
+        offset: i64,
+    },
+    /// OP-IMM instructions, the immediate of shifts is the shift amount.
+    OpImm {
+        op: AluOp,
+        rd: Register,
+        rs1: Register,
+        imm: i64,
+    },
+    Op {
+        op: AluOp,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    /// OP-IMM-32 instructions (`addiw`, `slliw`, `srliw`, `sraiw`).
+    OpImm32 {
+        op: AluOp,
+        rd: Register,
+        rs1: Register,
+        imm: i64,
+    },
+    /// OP-32 instructions (`addw`, `subw`, `sllw`, `srlw`, `sraw`).
+    Op32 {
+        op: AluOp,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    MulDiv {
+        op: MulDivOp,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    /// RV64M word instructions (`mulw`, `divw`, `divuw`, `remw`, `remuw`).
+    MulDiv32 {
+        op: MulDivOp,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    /// `fence`, with the predecessor and successor sets as `iorw` bits.
+    Fence {
+        /// The fence mode, `0b1000` for `fence.tso`.
+        fm: u8,
+        pred: u8,
+        succ: u8,
+    },
+    FenceI,
+    LoadReserved {
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+        aq: bool,
+        rl: bool,
+    },
+    StoreConditional {
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+        aq: bool,
+        rl: bool,
+    },
+    Amo {
+        op: AmoOp,
+        width: AmoWidth,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+        aq: bool,
+        rl: bool,
+    },
+    LoadFp {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        offset: i64,
+    },
+    StoreFp {
+        fmt: FpFormat,
+        rs1: Register,
+        rs2: Register,
+        offset: i64,
+    },
+    FusedMultiplyAdd {
+        op: FmaOp,
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+        rs3: Register,
+        rm: u8,
+    },
+    FpArith {
+        op: FpArithOp,
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+        rm: u8,
+    },
+    FpSqrt {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rm: u8,
+    },
+    FpSignInject {
+        op: SignInjectOp,
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    FpMinMax {
+        op: MinMaxOp,
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    /// Floating point comparisons, writing the result to the integer register `rd`.
+    FpCompare {
+        op: CompareOp,
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rs2: Register,
+    },
+    FpClassify {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+    },
+    /// `fcvt.{int}.{fmt}`, converting a floating point value to the integer register `rd`.
+    FpToInt {
+        fmt: FpFormat,
+        int: IntFormat,
+        rd: Register,
+        rs1: Register,
+        rm: u8,
+    },
+    /// `fcvt.{fmt}.{int}`, converting the integer register `rs1` to a floating point value.
+    IntToFp {
+        fmt: FpFormat,
+        int: IntFormat,
+        rd: Register,
+        rs1: Register,
+        rm: u8,
+    },
+    /// `fcvt.s.d` and `fcvt.d.s`, where `fmt` is the format of the destination.
+    FpConvert {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+        rm: u8,
+    },
+    /// `fmv.x.w` and `fmv.x.d`, moving the raw bits to an integer register.
+    FpMoveToInt {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+    },
+    /// `fmv.w.x` and `fmv.d.x`, moving the raw bits from an integer register.
+    FpMoveFromInt {
+        fmt: FpFormat,
+        rd: Register,
+        rs1: Register,
+    },
+    Csr {
+        op: CsrOp,
+        rd: Register,
+        rs1: Register,
+        csr: u16,
+    },
+    /// The immediate forms of the Zicsr instructions, with a zero-extended 5-bit immediate.
+    CsrImm {
+        op: CsrOp,
+        rd: Register,
+        uimm: u8,
+        csr: u16,
+    },
+    Ecall,
+    Ebreak,
+    Mret,
+    Sret,
+    /// Wait for interrupt, which stalls the hart until an interrupt is pending.
+    Wfi,
+    /// Orders the page table updates before the following address translations and
+    /// invalidates the cached translations of the address in `rs1` (all if `rs1` is zero).
+    /// `rs2` selects the address space, which the VM does not distinguish.
+    SfenceVma {
+        rs1: Register,
+        rs2: Register,
+    },
+}
+
+impl Instruction {
+    /// Returns the floating point format of the F and D extension instructions.
+    pub fn fp_format(&self) -> Option<FpFormat> {
+        match *self {
+            Instruction::LoadFp { fmt, .. }
+            | Instruction::StoreFp { fmt, .. }
+            | Instruction::FusedMultiplyAdd { fmt, .. }
+            | Instruction::FpArith { fmt, .. }
+            | Instruction::FpSqrt { fmt, .. }
+            | Instruction::FpSignInject { fmt, .. }
+            | Instruction::FpMinMax { fmt, .. }
+            | Instruction::FpCompare { fmt, .. }
+            | Instruction::FpClassify { fmt, .. }
+            | Instruction::FpToInt { fmt, .. }
+            | Instruction::IntToFp { fmt, .. }
+            | Instruction::FpConvert { fmt, .. }
+            | Instruction::FpMoveToInt { fmt, .. }
+            | Instruction::FpMoveFromInt { fmt, .. } => Some(fmt),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the instruction belongs to the F or D extension,
+    /// and is therefore illegal while the floating point unit is off.
+    pub fn is_floating_point(&self) -> bool {
+        self.fp_format().is_some()
+    }
+
+    /// Returns `true` if the instruction only exists on RV64 and is illegal on RV32 harts:
+    /// the word operations, the doubleword loads, stores and atomics and the conversions
+    /// and moves between 64-bit integer registers and floating point registers.
+    pub fn requires_rv64(&self) -> bool {
+        match *self {
+            Instruction::OpImm32 { .. }
+            | Instruction::Op32 { .. }
+            | Instruction::MulDiv32 { .. } => true,
+            Instruction::Load { op, .. } => matches!(op, LoadOp::Ld | LoadOp::Lwu),
+            Instruction::Store { op, .. } => op == StoreOp::Sd,
+            Instruction::LoadReserved { width, .. }
+            | Instruction::StoreConditional { width, .. }
+            | Instruction::Amo { width, .. } => width == AmoWidth::Double,
+            Instruction::FpToInt { int, .. } | Instruction::IntToFp { int, .. } => {
+                matches!(int, IntFormat::Long | IntFormat::LongUnsigned)
+            }
+            Instruction::FpMoveToInt { fmt, .. } | Instruction::FpMoveFromInt { fmt, .. } => {
+                fmt == FpFormat::Double
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum BranchOp {
+    #[display("beq")]
+    Beq,
+    #[display("bne")]
+    Bne,
+    #[display("blt")]
+    Blt,
+    #[display("bge")]
+    Bge,
+    #[display("bltu")]
+    Bltu,
+    #[display("bgeu")]
+    Bgeu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum LoadOp {
+    #[display("lb")]
+    Lb,
+    #[display("lh")]
+    Lh,
+    #[display("lw")]
+    Lw,
+    #[display("ld")]
+    Ld,
+    #[display("lbu")]
+    Lbu,
+    #[display("lhu")]
+    Lhu,
+    #[display("lwu")]
+    Lwu,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum StoreOp {
+    #[display("sb")]
+    Sb,
+    #[display("sh")]
+    Sh,
+    #[display("sw")]
+    Sw,
+    #[display("sd")]
+    Sd,
+}
+
+impl StoreOp {
+    /// Returns the number of bytes written by the store.
+    pub fn size(self) -> u64 {
+        match self {
+            StoreOp::Sb => 1,
+            StoreOp::Sh => 2,
+            StoreOp::Sw => 4,
+            StoreOp::Sd => 8,
+        }
+    }
+}
+
+/// The integer ALU operations, shared by the register and immediate forms.
+/// The immediate forms have no `sub`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AluOp {
+    #[display("add")]
+    Add,
+    #[display("sub")]
+    Sub,
+    #[display("sll")]
+    Sll,
+    #[display("slt")]
+    Slt,
+    #[display("sltu")]
+    Sltu,
+    #[display("xor")]
+    Xor,
+    #[display("srl")]
+    Srl,
+    #[display("sra")]
+    Sra,
+    #[display("or")]
+    Or,
+    #[display("and")]
+    And,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum MulDivOp {
+    #[display("mul")]
+    Mul,
+    #[display("mulh")]
+    Mulh,
+    #[display("mulhsu")]
+    Mulhsu,
+    #[display("mulhu")]
+    Mulhu,
+    #[display("div")]
+    Div,
+    #[display("divu")]
+    Divu,
+    #[display("rem")]
+    Rem,
+    #[display("remu")]
+    Remu,
+}
+
+/// The width of an atomic memory operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AmoWidth {
+    #[display("w")]
+    Word,
+    #[display("d")]
+    Double,
+}
+
+impl AmoWidth {
+    /// Returns the number of bytes accessed by the operation.
+    pub fn size(self) -> u64 {
+        match self {
+            AmoWidth::Word => 4,
+            AmoWidth::Double => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum AmoOp {
+    #[display("amoswap")]
+    Swap,
+    #[display("amoadd")]
+    Add,
+    #[display("amoxor")]
+    Xor,
+    #[display("amoand")]
+    And,
+    #[display("amoor")]
+    Or,
+    #[display("amomin")]
+    Min,
+    #[display("amomax")]
+    Max,
+    #[display("amominu")]
+    Minu,
+    #[display("amomaxu")]
+    Maxu,
+}
+
+/// The floating point format, displayed as the instruction suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum FpFormat {
+    #[display("s")]
+    Single,
+    #[display("d")]
+    Double,
+}
+
+/// The integer format of a floating point conversion, displayed as the instruction suffix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum IntFormat {
+    #[display("w")]
+    Word,
+    #[display("wu")]
+    WordUnsigned,
+    #[display("l")]
+    Long,
+    #[display("lu")]
+    LongUnsigned,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum FmaOp {
+    #[display("fmadd")]
+    Fmadd,
+    #[display("fmsub")]
+    Fmsub,
+    #[display("fnmsub")]
+    Fnmsub,
+    #[display("fnmadd")]
+    Fnmadd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum FpArithOp {
+    #[display("fadd")]
+    Add,
+    #[display("fsub")]
+    Sub,
+    #[display("fmul")]
+    Mul,
+    #[display("fdiv")]
+    Div,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum SignInjectOp {
+    #[display("fsgnj")]
+    Sgnj,
+    #[display("fsgnjn")]
+    Sgnjn,
+    #[display("fsgnjx")]
+    Sgnjx,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum MinMaxOp {
+    #[display("fmin")]
+    Min,
+    #[display("fmax")]
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum CompareOp {
+    #[display("feq")]
+    Eq,
+    #[display("flt")]
+    Lt,
+    #[display("fle")]
+    Le,
+}
+
+/// The Zicsr operations, which atomically read and modify a CSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum CsrOp {
+    #[display("csrrw")]
+    Write,
+    #[display("csrrs")]
+    Set,
+    #[display("csrrc")]
+    Clear,
+}
+
+/// An instruction that cannot be decoded, holding its raw bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Error)]
+pub enum DecodeError {
+    /// The major opcode (bits 0-6) is not implemented.
+    #[display("unknown opcode {:#09b} in {raw:#010x}", raw & 0x7f)]
+    UnknownOpcode { raw: u32 },
+    /// The opcode is known, but the remaining fields are reserved or not implemented.
+    #[display("illegal encoding: {raw:#010x}")]
+    IllegalEncoding { raw: u32 },
+}
+
+impl DecodeError {
+    /// Returns the raw bits of the instruction that could not be decoded.
+    pub fn raw(&self) -> u32 {
+        match self {
+            DecodeError::UnknownOpcode { raw } | DecodeError::IllegalEncoding { raw } => *raw,
+        }
+    }
+}
+
+/// Destination register (rd) is bits 7-11
+#[inline]
+fn rd(raw: u32) -> Register {
+    (raw >> 7 & 0x1f) as Register
+}
+
+/// Source register (rs1) is bits 15-19
+#[inline]
+fn rs1(raw: u32) -> Register {
+    (raw >> 15 & 0x1f) as Register
+}
+
+/// Source register (rs2) is bits 20-24
+#[inline]
+fn rs2(raw: u32) -> Register {
+    (raw >> 20 & 0x1f) as Register
+}
+
+/// Source register (rs3) is bits 27-31
+#[inline]
+fn rs3(raw: u32) -> Register {
+    (raw >> 27) as Register
+}
+
+/// The I-type immediate is bits 20-31
+#[inline]
+fn i_imm(raw: u32) -> i64 {
+    sign_extend_u64_to_i64((raw >> 20) as u64, 12)
+}
+
+/// The S-type immediate is split into bits 7-11 and 25-31: imm[11:5] and imm[4:0]
+#[inline]
+fn s_imm(raw: u32) -> i64 {
+    let imm = (raw >> 25) << 5 | (raw >> 7) & 0x1f;
+    sign_extend_u64_to_i64(imm as u64, 12)
+}
+
+/// The B-type immediate is scattered across bits 7-11 and 25-31: imm[12|10:5] and imm[4:1|11]
+#[inline]
+fn b_imm(raw: u32) -> i64 {
+    let imm = ((raw >> 31) & 0x1) << 12
+        | ((raw >> 25) & 0x3f) << 5
+        | ((raw >> 8) & 0xf) << 1
+        | ((raw >> 7) & 0x1) << 11;
+    sign_extend_u64_to_i64(imm as u64, 13)
+}
+
+/// The U-type immediate is bits 12-31, already in its final position
+#[inline]
+fn u_imm(raw: u32) -> i64 {
+    (raw & 0xffff_f000) as i32 as i64
+}
+
+/// The J-type immediate is scattered across bits 12-31: imm[20|10:1|11|19:12]
+#[inline]
+fn j_imm(raw: u32) -> i64 {
+    let imm = ((raw >> 31) & 0x1) << 20
+        | ((raw >> 21) & 0x3ff) << 1
+        | ((raw >> 20) & 0x1) << 11
+        | ((raw >> 12) & 0xff) << 12;
+    sign_extend_u64_to_i64(imm as u64, 21)
+}
+
+/// Decodes a 32-bit instruction.
+/// https://five-embeddev.com/riscv-user-isa-manual/Priv-v1.12/opcode-map.html
+pub fn decode(raw: u32) -> Result<Instruction, DecodeError> {
+    let illegal = DecodeError::IllegalEncoding { raw };
+
+    // The funct3 field is bits 12-14 and the funct7 field is bits 25-31
+    let funct3 = raw >> 12 & 0x7;
+    let funct7 = raw >> 25;
+
+    let instruction = match raw & 0x7f {
+        0b0110111 => Instruction::Lui {
+            rd: rd(raw),
+            imm: u_imm(raw),
+        },
+        0b0010111 => Instruction::Auipc {
+            rd: rd(raw),
+            imm: u_imm(raw),
+        },
+        0b1101111 => Instruction::Jal {
+            rd: rd(raw),
+            offset: j_imm(raw),
+        },
+        0b1100111 if funct3 == 0b000 => Instruction::Jalr {
+            rd: rd(raw),
+            rs1: rs1(raw),
+            offset: i_imm(raw),
+        },
+        0b1100011 => Instruction::Branch {
+            op: match funct3 {
+                0b000 => BranchOp::Beq,
+                0b001 => BranchOp::Bne,
+                0b100 => BranchOp::Blt,
+                0b101 => BranchOp::Bge,
+                0b110 => BranchOp::Bltu,
+                0b111 => BranchOp::Bgeu,
+                _ => return Err(illegal),
+            },
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+            offset: b_imm(raw),
+        },
+        0b0000011 => Instruction::Load {
+            op: match funct3 {
+                0b000 => LoadOp::Lb,
+                0b001 => LoadOp::Lh,
+                0b010 => LoadOp::Lw,
+                0b011 => LoadOp::Ld,
+                0b100 => LoadOp::Lbu,
+                0b101 => LoadOp::Lhu,
+                0b110 => LoadOp::Lwu,
+                _ => return Err(illegal),
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            offset: i_imm(raw),
+        },
+        0b0100011 => Instruction::Store {
+            op: match funct3 {
+                0b000 => StoreOp::Sb,
+                0b001 => StoreOp::Sh,
+                0b010 => StoreOp::Sw,
+                0b011 => StoreOp::Sd,
+                _ => return Err(illegal),
+            },
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+            offset: s_imm(raw),
+        },
+        0b0010011 => {
+            // For shifts, the shift amount (shamt) is bits 20-25 and the funct6 field is bits 26-31
+            let shamt = (raw >> 20 & 0x3f) as i64;
+            let (op, imm) = match (funct3, raw >> 26) {
+                (0b000, _) => (AluOp::Add, i_imm(raw)),
+                (0b010, _) => (AluOp::Slt, i_imm(raw)),
+                (0b011, _) => (AluOp::Sltu, i_imm(raw)),
+                (0b100, _) => (AluOp::Xor, i_imm(raw)),
+                (0b110, _) => (AluOp::Or, i_imm(raw)),
+                (0b111, _) => (AluOp::And, i_imm(raw)),
+                (0b001, 0b000000) => (AluOp::Sll, shamt),
+                (0b101, 0b000000) => (AluOp::Srl, shamt),
+                (0b101, 0b010000) => (AluOp::Sra, shamt),
+                _ => return Err(illegal),
+            };
+            Instruction::OpImm {
+                op,
+                rd: rd(raw),
+                rs1: rs1(raw),
+                imm,
+            }
+        }
+        // The M extension shares the OP opcode and is selected by funct7 = 0b0000001
+        0b0110011 if funct7 == 0b0000001 => Instruction::MulDiv {
+            op: match funct3 {
+                0b000 => MulDivOp::Mul,
+                0b001 => MulDivOp::Mulh,
+                0b010 => MulDivOp::Mulhsu,
+                0b011 => MulDivOp::Mulhu,
+                0b100 => MulDivOp::Div,
+                0b101 => MulDivOp::Divu,
+                0b110 => MulDivOp::Rem,
+                _ => MulDivOp::Remu,
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+        },
+        0b0110011 => Instruction::Op {
+            op: match (funct7, funct3) {
+                (0b0000000, 0b000) => AluOp::Add,
+                (0b0100000, 0b000) => AluOp::Sub,
+                (0b0000000, 0b001) => AluOp::Sll,
+                (0b0000000, 0b010) => AluOp::Slt,
+                (0b0000000, 0b011) => AluOp::Sltu,
+                (0b0000000, 0b100) => AluOp::Xor,
+                (0b0000000, 0b101) => AluOp::Srl,
+                (0b0100000, 0b101) => AluOp::Sra,
+                (0b0000000, 0b110) => AluOp::Or,
+                (0b0000000, 0b111) => AluOp::And,
+                _ => return Err(illegal),
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+        },
+        0b0011011 => {
+            // For shifts, the shift amount (shamt) is bits 20-24
+            let shamt = (raw >> 20 & 0x1f) as i64;
+            let (op, imm) = match (funct3, funct7) {
+                (0b000, _) => (AluOp::Add, i_imm(raw)),
+                (0b001, 0b0000000) => (AluOp::Sll, shamt),
+                (0b101, 0b0000000) => (AluOp::Srl, shamt),
+                (0b101, 0b0100000) => (AluOp::Sra, shamt),
+                _ => return Err(illegal),
+            };
+            Instruction::OpImm32 {
+                op,
+                rd: rd(raw),
+                rs1: rs1(raw),
+                imm,
+            }
+        }
+        0b0111011 if funct7 == 0b0000001 => Instruction::MulDiv32 {
+            op: match funct3 {
+                0b000 => MulDivOp::Mul,
+                0b100 => MulDivOp::Div,
+                0b101 => MulDivOp::Divu,
+                0b110 => MulDivOp::Rem,
+                0b111 => MulDivOp::Remu,
+                _ => return Err(illegal),
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+        },
+        0b0111011 => Instruction::Op32 {
+            op: match (funct7, funct3) {
+                (0b0000000, 0b000) => AluOp::Add,
+                (0b0100000, 0b000) => AluOp::Sub,
+                (0b0000000, 0b001) => AluOp::Sll,
+                (0b0000000, 0b101) => AluOp::Srl,
+                (0b0100000, 0b101) => AluOp::Sra,
+                _ => return Err(illegal),
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+        },
+        0b0001111 => match funct3 {
+            // The fence mode is bits 28-31, the predecessor and successor sets are bits 24-27
+            // and 20-23
+            0b000 => Instruction::Fence {
+                fm: (raw >> 28) as u8,
+                pred: (raw >> 24 & 0xf) as u8,
+                succ: (raw >> 20 & 0xf) as u8,
+            },
+            0b001 => Instruction::FenceI,
+            _ => return Err(illegal),
+        },
+        0b0101111 => decode_amo(raw)?,
+        0b0000111 => Instruction::LoadFp {
+            fmt: match funct3 {
+                0b010 => FpFormat::Single,
+                0b011 => FpFormat::Double,
+                _ => return Err(illegal),
+            },
+            rd: rd(raw),
+            rs1: rs1(raw),
+            offset: i_imm(raw),
+        },
+        0b0100111 => Instruction::StoreFp {
+            fmt: match funct3 {
+                0b010 => FpFormat::Single,
+                0b011 => FpFormat::Double,
+                _ => return Err(illegal),
+            },
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+            offset: s_imm(raw),
+        },
+        opcode @ (0b1000011 | 0b1000111 | 0b1001011 | 0b1001111) => Instruction::FusedMultiplyAdd {
+            op: match opcode {
+                0b1000011 => FmaOp::Fmadd,
+                0b1000111 => FmaOp::Fmsub,
+                0b1001011 => FmaOp::Fnmsub,
+                _ => FmaOp::Fnmadd,
+            },
+            fmt: fp_format(raw)?,
+            rd: rd(raw),
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+            rs3: rs3(raw),
+            rm: funct3 as u8,
+        },
+        0b1010011 => decode_op_fp(raw)?,
+        0b1110011 => decode_system(raw)?,
+        _ => return Err(DecodeError::UnknownOpcode { raw }),
+    };
+
+    Ok(instruction)
+}
+
+/// Decodes the format (fmt) in bits 25-26, only single and double precision are supported.
+fn fp_format(raw: u32) -> Result<FpFormat, DecodeError> {
+    match raw >> 25 & 0x3 {
+        0b00 => Ok(FpFormat::Single),
+        0b01 => Ok(FpFormat::Double),
+        _ => Err(DecodeError::IllegalEncoding { raw }),
+    }
+}
+
+/// Decodes the A extension instructions.
+/// The funct5 field is bits 27-31, the aq and rl bits are bits 26 and 25.
+fn decode_amo(raw: u32) -> Result<Instruction, DecodeError> {
+    let illegal = DecodeError::IllegalEncoding { raw };
+
+    // The funct3 field selects the width (0b010 = W, 0b011 = D)
+    let width = match raw >> 12 & 0x7 {
+        0b010 => AmoWidth::Word,
+        0b011 => AmoWidth::Double,
+        _ => return Err(illegal),
+    };
+    let aq = raw >> 26 & 0x1 != 0;
+    let rl = raw >> 25 & 0x1 != 0;
+
+    let op = match raw >> 27 {
+        0b00010 if rs2(raw) == 0 => {
+            return Ok(Instruction::LoadReserved {
+                width,
+                rd: rd(raw),
+                rs1: rs1(raw),
+                aq,
+                rl,
+            });
+        }
+        0b00011 => {
+            return Ok(Instruction::StoreConditional {
+                width,
+                rd: rd(raw),
+                rs1: rs1(raw),
+                rs2: rs2(raw),
+                aq,
+                rl,
+            });
+        }
+        0b00001 => AmoOp::Swap,
+        0b00000 => AmoOp::Add,
+        0b00100 => AmoOp::Xor,
+        0b01100 => AmoOp::And,
+        0b01000 => AmoOp::Or,
+        0b10000 => AmoOp::Min,
+        0b10100 => AmoOp::Max,
+        0b11000 => AmoOp::Minu,
+        0b11100 => AmoOp::Maxu,
+        _ => return Err(illegal),
+    };
+
+    Ok(Instruction::Amo {
+        op,
+        width,
+        rd: rd(raw),
+        rs1: rs1(raw),
+        rs2: rs2(raw),
+        aq,
+        rl,
+    })
+}
+
+/// Decodes the OP-FP instructions.
+/// The funct7 field is made up of funct5 (bits 27-31) and the format (bits 25-26),
+/// the funct3 field holds the rounding mode (rm) for rounding instructions.
+fn decode_op_fp(raw: u32) -> Result<Instruction, DecodeError> {
+    let illegal = DecodeError::IllegalEncoding { raw };
+
+    let fmt = fp_format(raw)?;
+    let funct3 = raw >> 12 & 0x7;
+    let rm = funct3 as u8;
+    let (rd, rs1, rs2) = (rd(raw), rs1(raw), rs2(raw));
+
+    // Several instructions use the rs2 field to select the operation
+    let int_format = || match rs2 {
+        0b00000 => Ok(IntFormat::Word),
+        0b00001 => Ok(IntFormat::WordUnsigned),
+        0b00010 => Ok(IntFormat::Long),
+        0b00011 => Ok(IntFormat::LongUnsigned),
+        _ => Err(illegal),
+    };
+
+    let instruction = match (raw >> 27, funct3) {
+        (0b00000, _) => Instruction::FpArith {
+            op: FpArithOp::Add,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rm,
+        },
+        (0b00001, _) => Instruction::FpArith {
+            op: FpArithOp::Sub,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rm,
+        },
+        (0b00010, _) => Instruction::FpArith {
+            op: FpArithOp::Mul,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rm,
+        },
+        (0b00011, _) => Instruction::FpArith {
+            op: FpArithOp::Div,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rm,
+        },
+        (0b01011, _) if rs2 == 0 => Instruction::FpSqrt { fmt, rd, rs1, rm },
+        (0b00100, 0b000..=0b010) => Instruction::FpSignInject {
+            op: match funct3 {
+                0b000 => SignInjectOp::Sgnj,
+                0b001 => SignInjectOp::Sgnjn,
+                _ => SignInjectOp::Sgnjx,
+            },
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        },
+        (0b00101, 0b000 | 0b001) => Instruction::FpMinMax {
+            op: match funct3 {
+                0b000 => MinMaxOp::Min,
+                _ => MinMaxOp::Max,
+            },
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        },
+        (0b10100, 0b000..=0b010) => Instruction::FpCompare {
+            op: match funct3 {
+                0b010 => CompareOp::Eq,
+                0b001 => CompareOp::Lt,
+                _ => CompareOp::Le,
+            },
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        },
+        // The source format of the conversion between formats is in the rs2 field
+        (0b01000, _) => match (fmt, rs2) {
+            (FpFormat::Single, 0b00001) | (FpFormat::Double, 0b00000) => {
+                Instruction::FpConvert { fmt, rd, rs1, rm }
+            }
+            _ => return Err(illegal),
+        },
+        (0b11000, _) => Instruction::FpToInt {
+            fmt,
+            int: int_format()?,
+            rd,
+            rs1,
+            rm,
+        },
+        (0b11010, _) => Instruction::IntToFp {
+            fmt,
+            int: int_format()?,
+            rd,
+            rs1,
+            rm,
+        },
+        (0b11100, 0b000) if rs2 == 0 => Instruction::FpMoveToInt { fmt, rd, rs1 },
+        (0b11100, 0b001) if rs2 == 0 => Instruction::FpClassify { fmt, rd, rs1 },
+        (0b11110, 0b000) if rs2 == 0 => Instruction::FpMoveFromInt { fmt, rd, rs1 },
+        _ => return Err(illegal),
+    };
+
+    Ok(instruction)
+}
+
+/// Decodes the SYSTEM instructions, the privileged instructions and Zicsr.
+fn decode_system(raw: u32) -> Result<Instruction, DecodeError> {
+    let illegal = DecodeError::IllegalEncoding { raw };
+
+    // The CSR address (or the function of privileged instructions) is bits 20-31
+    let csr = (raw >> 20) as u16;
+    let funct3 = raw >> 12 & 0x7;
+
+    // `sfence.vma` is the only privileged instruction with source registers
+    if funct3 == 0b000 && raw >> 25 == 0b0001001 && rd(raw) == 0 {
+        return Ok(Instruction::SfenceVma {
+            rs1: rs1(raw),
+            rs2: rs2(raw),
+        });
+    }
+
+    let op = match funct3 & 0b011 {
+        0b001 => CsrOp::Write,
+        0b010 => CsrOp::Set,
+        0b011 => CsrOp::Clear,
+        // The destination and source registers must be zero for the privileged instructions
+        _ if funct3 != 0b000 || rd(raw) != 0 || rs1(raw) != 0 => return Err(illegal),
+        _ => {
+            return match csr {
+                0x000 => Ok(Instruction::Ecall),
+                0x001 => Ok(Instruction::Ebreak),
+                0x302 => Ok(Instruction::Mret),
+                0x102 => Ok(Instruction::Sret),
+                0x105 => Ok(Instruction::Wfi),
+                _ => Err(illegal),
+            };
+        }
+    };
+
+    // Bit 14 selects the immediate forms, which hold a 5-bit immediate in the rs1 field
+    Ok(if funct3 & 0b100 == 0 {
+        Instruction::Csr {
+            op,
+            rd: rd(raw),
+            rs1: rs1(raw),
+            csr,
+        }
+    } else {
+        Instruction::CsrImm {
+            op,
+            rd: rd(raw),
+            uimm: rs1(raw),
+            csr,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Tests decoding one instruction of every format, with encodings generated by `llvm-mc`
+    fn test_decode() {
+        let cases = [
+            (
+                0x123452b7,
+                Instruction::Lui {
+                    rd: 5,
+                    imm: 0x12345000,
+                },
+            ),
+            (
+                0xfffff517,
+                Instruction::Auipc {
+                    rd: 10,
+                    imm: -0x1000,
+                },
+            ),
+            (0xff9ff0ef, Instruction::Jal { rd: 1, offset: -8 }),
+            (
+                0x00c50067,
+                Instruction::Jalr {
+                    rd: 0,
+                    rs1: 10,
+                    offset: 12,
+                },
+            ),
+            (
+                0xfeb51ee3,
+                Instruction::Branch {
+                    op: BranchOp::Bne,
+                    rs1: 10,
+                    rs2: 11,
+                    offset: -4,
+                },
+            ),
+            (
+                0xff843283,
+                Instruction::Load {
+                    op: LoadOp::Ld,
+                    rd: 5,
+                    rs1: 8,
+                    offset: -8,
+                },
+            ),
+            (
+                0x80b12023,
+                Instruction::Store {
+                    op: StoreOp::Sw,
+                    rs1: 2,
+                    rs2: 11,
+                    offset: -2048,
+                },
+            ),
+            (
+                0x43f55513,
+                Instruction::OpImm {
+                    op: AluOp::Sra,
+                    rd: 10,
+                    rs1: 10,
+                    imm: 63,
+                },
+            ),
+            (
+                0x40b50533,
+                Instruction::Op {
+                    op: AluOp::Sub,
+                    rd: 10,
+                    rs1: 10,
+                    rs2: 11,
+                },
+            ),
+            (
+                0x02b5453b,
+                Instruction::MulDiv32 {
+                    op: MulDivOp::Div,
+                    rd: 10,
+                    rs1: 10,
+                    rs2: 11,
+                },
+            ),
+            (
+                0x0ff0000f,
+                Instruction::Fence {
+                    fm: 0,
+                    pred: 0xf,
+                    succ: 0xf,
+                },
+            ),
+            (
+                0x0eb5362f,
+                Instruction::Amo {
+                    op: AmoOp::Swap,
+                    width: AmoWidth::Double,
+                    rd: 12,
+                    rs1: 10,
+                    rs2: 11,
+                    aq: true,
+                    rl: true,
+                },
+            ),
+            (
+                0x18b5362f,
+                Instruction::StoreConditional {
+                    width: AmoWidth::Double,
+                    rd: 12,
+                    rs1: 10,
+                    rs2: 11,
+                    aq: false,
+                    rl: false,
+                },
+            ),
+            (
+                0x62b57543,
+                Instruction::FusedMultiplyAdd {
+                    op: FmaOp::Fmadd,
+                    fmt: FpFormat::Double,
+                    rd: 10,
+                    rs1: 10,
+                    rs2: 11,
+                    rs3: 12,
+                    rm: 0b111,
+                },
+            ),
+            (
+                0xc0251553,
+                Instruction::FpToInt {
+                    fmt: FpFormat::Single,
+                    int: IntFormat::Long,
+                    rd: 10,
+                    rs1: 10,
+                    rm: 0b001,
+                },
+            ),
+            (
+                0x40150553,
+                Instruction::FpConvert {
+                    fmt: FpFormat::Single,
+                    rd: 10,
+                    rs1: 10,
+                    rm: 0b000,
+                },
+            ),
+            (
+                0x34015573,
+                Instruction::CsrImm {
+                    op: CsrOp::Write,
+                    rd: 10,
+                    uimm: 2,
+                    csr: 0x340,
+                },
+            ),
+            (0x30200073, Instruction::Mret),
+            (0x10200073, Instruction::Sret),
+            (0x10500073, Instruction::Wfi),
+            (0x12b50073, Instruction::SfenceVma { rs1: 10, rs2: 11 }),
+        ];
+        for (raw, instruction) in cases {
+            assert_eq!(decode(raw), Ok(instruction), "decoding {:#010x}", raw);
+        }
+    }
+
+    #[test]
+    /// Tests that reserved and unknown encodings are rejected
+    fn test_decode_illegal() {
+        assert_eq!(
+            decode(0x00000000),
+            Err(DecodeError::UnknownOpcode { raw: 0x00000000 })
+        );
+        // addw with funct7 = 0b0000010
+        assert_eq!(
+            decode(0x04b5053b),
+            Err(DecodeError::IllegalEncoding { raw: 0x04b5053b })
+        );
+        // lr.w with a non-zero rs2 field
+        assert!(decode(0x1015262f).is_err());
+        // fadd.q is not supported
+        assert!(decode(0x06b50553).is_err());
+        // ecall with a non-zero rd field
+        assert!(decode(0x000000f3).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! # Core-Local Interruptor
+//!
+//! This module implements the CLINT of the QEMU `virt` machine for a single hart. It provides
+//! the machine timer `mtime`, which advances by one for every CPU cycle, the timer compare
+//! register `mtimecmp` and the software interrupt register `msip`. The timer interrupt
+//! (`mip.MTIP`) is pending while `mtime >= mtimecmp`, the software interrupt (`mip.MSIP`)
+//! while bit 0 of `msip` is set.
+//!
+//! The 64-bit registers can be accessed as a whole or, on RV32, in two 32-bit halves.
+//! See also: [https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc]
+
+use crate::{
+    csr::{MIP_MSIP, MIP_MTIP},
+    devices::{Device, DeviceKind},
+};
+
+/// The CLINT of the QEMU `virt` machine.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "clint",
+    description: "Core-local interruptor with the machine timer",
+    default_base: 0x0200_0000,
+    size: 0x1_0000,
+    irq: None,
+    options: &[],
+    create: |_, _| Ok(Box::new(Clint::new())),
+};
+
+/// The offset of the `msip` register of hart 0.
+const MSIP: u64 = 0x0;
+/// The offset of the `mtimecmp` register of hart 0.
+const MTIMECMP: u64 = 0x4000;
+/// The offset of the `mtime` register.
+const MTIME: u64 = 0xbff8;
+
+pub struct Clint {
+    msip: bool,
+    mtimecmp: u64,
+    mtime: u64,
+}
+
+impl Clint {
+    /// Creates a CLINT with the timer at zero and no interrupts pending.
+    pub fn new() -> Self {
+        Clint {
+            msip: false,
+            // The timer interrupt stays clear until the program programs the compare register
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Clint::new()
+    }
+}
+
+/// Reads the `size` bytes at `offset` of a 64-bit register starting at `base`.
+fn read_register(register: u64, base: u64, offset: u64, size: u64) -> Option<u64> {
+    match (offset - base, size) {
+        (0, 8) => Some(register),
+        (0, 4) => Some(register & 0xffff_ffff),
+        (4, 4) => Some(register >> 32),
+        _ => None,
+    }
+}
+
+/// Writes the lowest `size` bytes of `value` at `offset` of a 64-bit register starting at `base`.
+fn write_register(register: &mut u64, base: u64, offset: u64, size: u64, value: u64) -> Option<()> {
+    *register = match (offset - base, size) {
+        (0, 8) => value,
+        (0, 4) => *register & !0xffff_ffff | value & 0xffff_ffff,
+        (4, 4) => *register & 0xffff_ffff | value << 32,
+        _ => return None,
+    };
+    Some(())
+}
+
+impl Device for Clint {
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        match offset {
+            MSIP if size == 4 => Some(self.msip as u64),
+            MTIMECMP..=0x4007 => read_register(self.mtimecmp, MTIMECMP, offset, size),
+            MTIME..=0xbfff => read_register(self.mtime, MTIME, offset, size),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        match offset {
+            MSIP if size == 4 => {
+                self.msip = value & 1 != 0;
+                Some(())
+            }
+            MTIMECMP..=0x4007 => write_register(&mut self.mtimecmp, MTIMECMP, offset, size, value),
+            MTIME..=0xbfff => write_register(&mut self.mtime, MTIME, offset, size, value),
+            _ => None,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Clint::new();
+    }
+
+    fn tick(&mut self) {
+        self.mtime = self.mtime.wrapping_add(1);
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        let software = if self.msip { MIP_MSIP } else { 0 };
+        let timer = if self.mtime >= self.mtimecmp {
+            MIP_MTIP
+        } else {
+            0
+        };
+        software | timer
+    }
+
+    fn time(&self) -> Option<u64> {
+        Some(self.mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// The timer interrupt is pending once `mtime` reaches `mtimecmp`.
+    fn test_timer_and_software_interrupts() {
+        let mut clint = Clint::new();
+        assert_eq!(clint.hart_interrupts(), 0);
+
+        // Program the compare register in two halves, like RV32 programs do
+        clint.write(MTIMECMP + 4, 4, 0).unwrap();
+        clint.write(MTIMECMP, 4, 3).unwrap();
+        assert_eq!(clint.read(MTIMECMP, 8), Some(3));
+
+        clint.tick();
+        clint.tick();
+        assert_eq!(clint.read(MTIME, 8), Some(2));
+        assert_eq!(clint.hart_interrupts(), 0);
+        clint.tick();
+        assert_eq!(clint.hart_interrupts(), MIP_MTIP);
+
+        clint.write(MSIP, 4, 1).unwrap();
+        assert_eq!(clint.hart_interrupts(), MIP_MSIP | MIP_MTIP);
+        clint.write(MTIMECMP, 8, u64::MAX).unwrap();
+        clint.write(MSIP, 4, 0).unwrap();
+        assert_eq!(clint.hart_interrupts(), 0);
+
+        clint.write(MTIME + 4, 4, 1).unwrap();
+        assert_eq!(clint.read(MTIME, 8), Some(0x1_0000_0003));
+        assert_eq!(clint.read(MTIME + 2, 2), None);
+    }
+}
@@ -0,0 +1,533 @@
+//! # Devices
+//!
+//! This module defines the [`Device`] trait implemented by memory-mapped peripherals and the
+//! [`DeviceRegistry`], which creates devices by name. Devices are attached to the bus from the
+//! command line (`--device name@address`) or from a machine configuration file that lists one
+//! device per line:
+//!
+//! ```text
+//! # Devices of the machine, attached at their default address and interrupt unless given
+//! uart
+//! uart@0x1000_1000,irq=11
+//! virtio-blk,file=disk.img,mode=cow
+//! ```
+//!
+//! The interrupt lines of the devices are routed to the interrupt controllers, e.g. the PLIC,
+//! by their interrupt number. Devices that access the DRAM themselves (DMA), like the virtio
+//! devices, do so through [`GuestMemory`].
+
+use std::str::FromStr;
+
+use crossbeam::channel::{Receiver, Sender};
+use derive_more::{Display, Error};
+
+use crate::{
+    bus::{Bus, BusError, GuestMemory},
+    cpu::CpuEvent,
+    utils::parse_address,
+};
+
+pub mod clint;
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+
+/// The devices of the machine if no machine configuration is given,
+/// mapped like on the QEMU `virt` machine.
+pub const DEFAULT_MACHINE: &str = "clint\nplic\nuart";
+
+/// The highest interrupt number a device can be connected to. Interrupt 0 means "no interrupt".
+pub const MAX_IRQ: u32 = 63;
+
+/// A memory-mapped device on the bus.
+///
+/// Offsets are relative to the base address the device is attached at, and accesses are
+/// 1, 2, 4 or 8 bytes wide. Returning [`None`] raises an access-fault exception.
+pub trait Device: Send {
+    /// Returns the name of the device, used when listing the devices of the machine.
+    fn name(&self) -> &str;
+
+    /// Reads `size` bytes at `offset`, zero-extended to 64 bits.
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64>;
+
+    /// Writes the lowest `size` bytes of `value` at `offset`.
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()>;
+
+    /// Restores the state of the device after power-on.
+    fn reset(&mut self) {}
+
+    /// Advances the device by one CPU cycle, e.g. to count down timers or poll for input.
+    fn tick(&mut self) {}
+
+    /// Accesses the DRAM after each CPU cycle, e.g. to process the requests a driver placed
+    /// in memory. Only devices capable of DMA use it.
+    fn transfer(&mut self, _memory: &mut GuestMemory) {}
+
+    /// Returns `true` while the device raises its interrupt line.
+    fn interrupt_pending(&self) -> bool {
+        false
+    }
+
+    /// Receives the levels of the interrupt lines of all devices, as a bit mask indexed by
+    /// their interrupt number. Only interrupt controllers, like the PLIC, use them.
+    fn update_interrupt_lines(&mut self, _lines: u64) {}
+
+    /// Returns the bits of `mip` the device raises. Only interrupt controllers that are wired
+    /// directly to the hart, like the CLINT, drive these bits.
+    fn hart_interrupts(&self) -> u64 {
+        0
+    }
+
+    /// Returns the value of the real-time counter the device provides. Only the CLINT
+    /// provides one, its `mtime`, which is read through the `time` CSR.
+    fn time(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// An error that prevents a device from being attached to the machine.
+#[derive(Debug, Clone, PartialEq, Eq, Display, Error)]
+pub enum DeviceError {
+    /// No device with this name is registered.
+    #[display("unknown device `{name}`")]
+    UnknownDevice {
+        #[error(not(source))]
+        name: String,
+    },
+    /// A device specification is not of the form `name[@address]`.
+    #[display("invalid device `{spec}`: {reason}")]
+    InvalidSpec {
+        #[error(not(source))]
+        spec: String,
+        reason: String,
+    },
+    /// The device rejected its options, or its host resources are unavailable.
+    #[display("cannot create device `{name}`: {reason}")]
+    Create { name: String, reason: String },
+    /// The device cannot be mapped at the requested address.
+    #[display("cannot attach device `{name}`: {error}")]
+    Bus { name: String, error: BusError },
+}
+
+/// The connections of the devices to the host, shared by all devices of the machine.
+#[derive(Debug, Clone)]
+pub struct DeviceContext {
+    /// The channel for events sent to the application, e.g. console output.
+    pub events: Sender<CpuEvent>,
+    /// The console input of the host, e.g. the keys pressed in the window.
+    pub console_input: Receiver<u8>,
+}
+
+impl Default for DeviceContext {
+    /// Creates a context that discards all output and never receives any input.
+    fn default() -> Self {
+        DeviceContext {
+            events: crossbeam::channel::unbounded().0,
+            console_input: crossbeam::channel::never(),
+        }
+    }
+}
+
+impl DeviceContext {
+    /// Returns a copy of the context whose console input never receives any bytes, for the
+    /// devices that must leave the console input to another consumer.
+    pub fn without_console_input(&self) -> Self {
+        DeviceContext {
+            console_input: crossbeam::channel::never(),
+            ..self.clone()
+        }
+    }
+}
+
+/// A kind of device known to the [`DeviceRegistry`].
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceKind {
+    /// The name used to attach the device, e.g. `uart`.
+    pub name: &'static str,
+    /// A short description shown when listing the available devices.
+    pub description: &'static str,
+    /// The address the device is attached at, unless another one is given.
+    pub default_base: u64,
+    /// The size of the address range the device occupies.
+    pub size: u64,
+    /// The interrupt number the device is connected to, unless another one is given.
+    pub irq: Option<u32>,
+    /// The options the device accepts besides `irq`, e.g. `file`.
+    pub options: &'static [&'static str],
+    /// Creates a new instance of the device in its power-on state.
+    pub create: CreateDevice,
+}
+
+/// Creates a device connected to the host by the context and configured by the options of
+/// the specification, or returns the reason why it cannot be created.
+pub type CreateDevice = fn(&DeviceContext, &DeviceSpec) -> Result<Box<dyn Device>, String>;
+
+/// A request to attach a device, written as `name[@address][,irq=number][,option=value]...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    /// The name of the kind of device.
+    pub name: String,
+    /// The address to attach the device at, the default address of its kind if [`None`].
+    pub base: Option<u64>,
+    /// The interrupt number to connect the device to, the default of its kind if [`None`].
+    pub irq: Option<u32>,
+    /// The options of the device as `(option, value)` pairs, e.g. `("file", "disk.img")`.
+    pub options: Vec<(String, String)>,
+}
+
+impl DeviceSpec {
+    /// Returns the value of `option`, if it is given.
+    pub fn option(&self, option: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(name, _)| name == option)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = DeviceError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| DeviceError::InvalidSpec {
+            spec: spec.to_string(),
+            reason,
+        };
+        let mut parts = spec.split(',');
+        let device = parts.next().unwrap_or_default().trim();
+        let mut irq = None;
+        let mut options = Vec::new();
+        for option in parts.map(str::trim) {
+            let (name, value) = option
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("the option `{option}` has no value")))?;
+            if name.trim() == "irq" {
+                let number = value
+                    .trim()
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|irq| (1..=MAX_IRQ).contains(irq))
+                    .ok_or_else(|| invalid(format!("the interrupt must be 1 to {MAX_IRQ}")))?;
+                irq = Some(number);
+            } else {
+                options.push((name.trim().to_string(), value.trim().to_string()));
+            }
+        }
+        let (name, base) = match device.split_once('@') {
+            Some((name, base)) => (name, Some(parse_address(base.trim()).map_err(invalid)?)),
+            None => (device, None),
+        };
+        if name.trim().is_empty() {
+            return Err(invalid("missing device name".to_string()));
+        }
+        Ok(DeviceSpec {
+            name: name.trim().to_string(),
+            base,
+            irq,
+            options,
+        })
+    }
+}
+
+/// Parses a machine configuration with one [`DeviceSpec`] per line.
+/// Empty lines and everything after a `#` are ignored.
+pub fn parse_machine_config(config: &str) -> Result<Vec<DeviceSpec>, DeviceError> {
+    config
+        .lines()
+        .map(|line| line.split_once('#').map_or(line, |(spec, _)| spec).trim())
+        .filter(|line| !line.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// The kinds of devices that can be attached to the machine by name.
+#[derive(Debug, Clone)]
+pub struct DeviceRegistry {
+    kinds: Vec<DeviceKind>,
+}
+
+impl Default for DeviceRegistry {
+    fn default() -> Self {
+        DeviceRegistry::new()
+    }
+}
+
+impl DeviceRegistry {
+    /// Creates a registry with the devices built into the virtual machine.
+    pub fn new() -> Self {
+        DeviceRegistry {
+            kinds: vec![
+                clint::KIND,
+                plic::KIND,
+                uart::KIND,
+                virtio::block::KIND,
+                virtio::console::KIND,
+                virtio::rng::KIND,
+            ],
+        }
+    }
+
+    /// Adds a kind of device, replacing any kind registered with the same name.
+    pub fn register(&mut self, kind: DeviceKind) {
+        self.kinds.retain(|registered| registered.name != kind.name);
+        self.kinds.push(kind);
+    }
+
+    /// Returns the kind of device registered as `name`.
+    pub fn get(&self, name: &str) -> Option<&DeviceKind> {
+        self.kinds.iter().find(|kind| kind.name == name)
+    }
+
+    /// Returns all registered kinds of devices.
+    pub fn kinds(&self) -> &[DeviceKind] {
+        &self.kinds
+    }
+
+    /// Creates the devices requested by `specs` and attaches them to `bus` in order.
+    ///
+    /// Each byte of the console input is received only once, so only the first device named
+    /// `console` is connected to the console input of `context`.
+    pub fn attach_all(
+        &self,
+        bus: &mut Bus,
+        specs: &[DeviceSpec],
+        context: &DeviceContext,
+        console: &str,
+    ) -> Result<(), DeviceError> {
+        let without_console = context.without_console_input();
+        let mut has_console = false;
+        for spec in specs {
+            let is_console = !has_console && spec.name == console;
+            has_console |= is_console;
+            let context = if is_console {
+                context
+            } else {
+                &without_console
+            };
+            self.attach(bus, spec, context)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the device requested by `spec`, connected to the host by `context`,
+    /// and attaches it to `bus`.
+    pub fn attach(
+        &self,
+        bus: &mut Bus,
+        spec: &DeviceSpec,
+        context: &DeviceContext,
+    ) -> Result<(), DeviceError> {
+        let kind = self.get(&spec.name).ok_or(DeviceError::UnknownDevice {
+            name: spec.name.clone(),
+        })?;
+        let create_error = |reason: String| DeviceError::Create {
+            name: spec.name.clone(),
+            reason,
+        };
+        if let Some((option, _)) = spec
+            .options
+            .iter()
+            .find(|(option, _)| !kind.options.contains(&option.as_str()))
+        {
+            return Err(create_error(format!("unknown option `{option}`")));
+        }
+        let device = (kind.create)(context, spec).map_err(create_error)?;
+        let base = spec.base.unwrap_or(kind.default_base);
+        let irq = spec.irq.or(kind.irq);
+        bus.attach(base, kind.size, irq, device)
+            .map_err(|error| DeviceError::Bus {
+                name: spec.name.clone(),
+                error,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bus::DEFAULT_DRAM_BASE, trap::AccessType};
+
+    /// A device that counts the cycles since it was reset.
+    struct Counter(u64);
+
+    impl Device for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+            (offset == 0 && size == 8).then_some(self.0)
+        }
+
+        fn write(&mut self, _offset: u64, _size: u64, _value: u64) -> Option<()> {
+            None
+        }
+
+        fn reset(&mut self) {
+            self.0 = 0;
+        }
+
+        fn tick(&mut self) {
+            self.0 += 1;
+        }
+
+        fn interrupt_pending(&self) -> bool {
+            self.0 >= 2
+        }
+    }
+
+    const COUNTER: DeviceKind = DeviceKind {
+        name: "counter",
+        description: "Counts the cycles since reset",
+        default_base: 0x1000_0000,
+        size: 8,
+        irq: Some(1),
+        options: &["start"],
+        create: |_, spec| {
+            let start = spec.option("start").unwrap_or("0");
+            let start = start
+                .parse()
+                .map_err(|_| format!("invalid start `{start}`"))?;
+            Ok(Box::new(Counter(start)))
+        },
+    };
+
+    #[test]
+    /// Device specifications and machine configurations are parsed with optional addresses
+    /// and interrupts.
+    fn test_parse_device_specs() {
+        assert_eq!(
+            "counter".parse(),
+            Ok(DeviceSpec {
+                name: "counter".to_string(),
+                base: None,
+                irq: None,
+                options: vec![]
+            })
+        );
+        assert_eq!(
+            "counter@0x2000_0000".parse(),
+            Ok(DeviceSpec {
+                name: "counter".to_string(),
+                base: Some(0x2000_0000),
+                irq: None,
+                options: vec![]
+            })
+        );
+        assert_eq!(
+            "counter@0x2000_0000, irq=5".parse(),
+            Ok(DeviceSpec {
+                name: "counter".to_string(),
+                base: Some(0x2000_0000),
+                irq: Some(5),
+                options: vec![]
+            })
+        );
+        assert_eq!(
+            "counter,start=4, irq=5"
+                .parse::<DeviceSpec>()
+                .map(|spec| spec.options),
+            Ok(vec![("start".to_string(), "4".to_string())])
+        );
+        assert!("counter,irq=64".parse::<DeviceSpec>().is_err());
+        assert!("counter,size".parse::<DeviceSpec>().is_err());
+        assert!("counter@zero".parse::<DeviceSpec>().is_err());
+        assert!("@0x1000".parse::<DeviceSpec>().is_err());
+
+        let config = "# Timers\ncounter\n\n  counter@4096 # second timer\n";
+        let specs = parse_machine_config(config).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[1].base, Some(4096));
+    }
+
+    #[test]
+    /// Registered devices are attached by name, ticked and reset through the bus.
+    fn test_registry_attach() {
+        let mut registry = DeviceRegistry::new();
+        registry.register(COUNTER);
+        let context = DeviceContext::default();
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+
+        registry
+            .attach(&mut bus, &"counter".parse().unwrap(), &context)
+            .unwrap();
+        registry
+            .attach(&mut bus, &"counter@0x2000_0000".parse().unwrap(), &context)
+            .unwrap();
+        assert!(matches!(
+            registry.attach(&mut bus, &"counter".parse().unwrap(), &context),
+            Err(DeviceError::Bus { .. })
+        ));
+        assert!(matches!(
+            registry.attach(&mut bus, &"timer".parse().unwrap(), &context),
+            Err(DeviceError::UnknownDevice { .. })
+        ));
+        // Options are checked by the kind of device
+        for spec in ["counter@0x3000_0000,size=4", "counter@0x3000_0000,start=x"] {
+            assert!(matches!(
+                registry.attach(&mut bus, &spec.parse().unwrap(), &context),
+                Err(DeviceError::Create { .. })
+            ));
+        }
+
+        let names: Vec<_> = bus
+            .devices()
+            .map(|(range, irq, device)| (range.start, irq, device.name()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (0x1000_0000, Some(1), "counter"),
+                (0x2000_0000, Some(1), "counter")
+            ]
+        );
+
+        for _ in 0..3 {
+            bus.tick();
+        }
+        assert_eq!(bus.read(0x1000_0000, 8, AccessType::Load), Ok(3));
+        assert!(
+            bus.devices()
+                .all(|(_, _, device)| device.interrupt_pending())
+        );
+
+        bus.reset();
+        assert_eq!(bus.read(0x2000_0000, 8, AccessType::Load), Ok(0));
+        assert!(
+            !bus.devices()
+                .any(|(_, _, device)| device.interrupt_pending())
+        );
+    }
+
+    #[test]
+    /// Only the first device named as the console receives the console input.
+    fn test_attach_console() {
+        let registry = DeviceRegistry::new();
+        let specs = parse_machine_config("uart\nuart@0x1000_0100,irq=11\nvirtio-console").unwrap();
+        let (console, console_input) = crossbeam::channel::unbounded();
+        let context = DeviceContext {
+            console_input,
+            ..DeviceContext::default()
+        };
+        // Reading the line status register of a UART polls the input
+        let data_ready =
+            |bus: &mut Bus, base: u64| bus.read(base + 5, 1, AccessType::Load).unwrap() & 1 != 0;
+
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        registry
+            .attach_all(&mut bus, &specs, &context, "uart")
+            .unwrap();
+        console.send(b'a').unwrap();
+        assert!(!data_ready(&mut bus, 0x1000_0100));
+        assert!(data_ready(&mut bus, 0x1000_0000));
+
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        registry
+            .attach_all(&mut bus, &specs, &context, "virtio-console")
+            .unwrap();
+        console.send(b'b').unwrap();
+        assert!(!data_ready(&mut bus, 0x1000_0000));
+        assert!(!data_ready(&mut bus, 0x1000_0100));
+        assert_eq!(context.console_input.try_recv(), Ok(b'b'));
+    }
+}
@@ -0,0 +1,267 @@
+//! # Platform-Level Interrupt Controller
+//!
+//! This module implements the PLIC of the QEMU `virt` machine for a single hart. It collects
+//! the interrupt lines of the devices (sources 1 to [`MAX_IRQ`]) and signals them to two
+//! interrupt targets, called contexts: machine mode (`mip.MEIP`) and supervisor mode
+//! (`mip.SEIP`) of hart 0.
+//!
+//! An interrupt is pending while the line of its source is raised and it is not being served.
+//! A context is interrupted by the pending sources it enables whose priority exceeds its
+//! threshold. The handler claims the interrupt with the highest priority by reading the claim
+//! register, and completes it by writing the claimed source back.
+//! See also: [https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc]
+
+use crate::{
+    csr::{MIP_MEIP, MIP_SEIP},
+    devices::{Device, DeviceKind, MAX_IRQ},
+};
+
+/// The PLIC of the QEMU `virt` machine.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "plic",
+    description: "Platform-level interrupt controller for the device interrupts",
+    default_base: 0x0c00_0000,
+    size: 0x60_0000,
+    irq: None,
+    options: &[],
+    create: |_, _| Ok(Box::new(Plic::new())),
+};
+
+/// The number of interrupt sources, including the reserved source 0.
+const SOURCES: usize = MAX_IRQ as usize + 1;
+/// The interrupt contexts: machine mode and supervisor mode of hart 0.
+const CONTEXTS: usize = 2;
+/// The bits of `mip` the contexts raise.
+const CONTEXT_INTERRUPTS: [u64; CONTEXTS] = [MIP_MEIP, MIP_SEIP];
+
+/// The offset of the priority registers, one word per source.
+const PRIORITY: u64 = 0x0;
+/// The offset of the pending bits, one bit per source.
+const PENDING: u64 = 0x1000;
+/// The offset of the enable bits of the contexts, one bit per source.
+const ENABLE: u64 = 0x2000;
+/// The distance between the enable bits of two contexts.
+const ENABLE_STRIDE: u64 = 0x80;
+/// The offset of the threshold and claim registers of the contexts.
+const CONTEXT: u64 = 0x20_0000;
+/// The distance between the registers of two contexts.
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// The highest priority of a source, priority 0 never interrupts.
+const MAX_PRIORITY: u32 = 7;
+
+pub struct Plic {
+    priorities: [u32; SOURCES],
+    /// The levels of the interrupt lines of the sources.
+    lines: u64,
+    /// The sources that are claimed, but not yet completed.
+    claimed: u64,
+    enables: [u64; CONTEXTS],
+    thresholds: [u32; CONTEXTS],
+}
+
+impl Plic {
+    /// Creates a PLIC with all sources disabled.
+    pub fn new() -> Self {
+        Plic {
+            priorities: [0; SOURCES],
+            lines: 0,
+            claimed: 0,
+            enables: [0; CONTEXTS],
+            thresholds: [0; CONTEXTS],
+        }
+    }
+
+    /// Returns the sources that are waiting to be claimed.
+    fn pending(&self) -> u64 {
+        self.lines & !self.claimed & !1
+    }
+
+    /// Returns the pending source with the highest priority that interrupts `context`,
+    /// preferring the lowest source number among equal priorities.
+    fn best_source(&self, context: usize) -> Option<usize> {
+        let candidates = self.pending() & self.enables[context];
+        (1..SOURCES)
+            .filter(|&source| candidates & 1 << source != 0)
+            .filter(|&source| self.priorities[source] > self.thresholds[context])
+            .min_by_key(|&source| u32::MAX - self.priorities[source])
+    }
+
+    /// Claims the interrupt with the highest priority for `context`, 0 if there is none.
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.claimed |= 1 << source;
+                source as u32
+            }
+            None => 0,
+        }
+    }
+
+    /// Returns the enable bits of the context whose enable words contain `offset`.
+    fn enables_at(&mut self, offset: u64) -> Option<&mut u64> {
+        let context = (offset - ENABLE) / ENABLE_STRIDE;
+        let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+        match word {
+            0 | 1 => self.enables.get_mut(context as usize),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Plic::new()
+    }
+}
+
+/// Returns the 32 bits of `bits` selected by the word index `word`.
+fn bit_word(bits: u64, word: u64) -> u64 {
+    bits >> (32 * word) & 0xffff_ffff
+}
+
+impl Device for Plic {
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = match offset {
+            // The priorities of the sources beyond `MAX_IRQ` are hardwired to zero
+            PRIORITY..PENDING => self
+                .priorities
+                .get(offset as usize / 4)
+                .map_or(0, |&priority| priority as u64),
+            PENDING..ENABLE => match (offset - PENDING) / 4 {
+                word @ (0 | 1) => bit_word(self.pending(), word),
+                _ => 0,
+            },
+            ENABLE..CONTEXT => {
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+                match self.enables_at(offset) {
+                    Some(enables) => bit_word(*enables, word),
+                    None => 0,
+                }
+            }
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= CONTEXTS {
+                    return None;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context] as u64,
+                    4 => self.claim(context) as u64,
+                    _ => 0,
+                }
+            }
+        };
+        Some(value)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = value & 0xffff_ffff;
+        match offset {
+            PRIORITY..PENDING => {
+                // Source 0 and the sources beyond `MAX_IRQ` do not exist
+                if offset != 0
+                    && let Some(priority) = self.priorities.get_mut(offset as usize / 4)
+                {
+                    *priority = value as u32 & MAX_PRIORITY;
+                }
+            }
+            // The pending bits are read-only
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+                if let Some(enables) = self.enables_at(offset) {
+                    let mask = 0xffff_ffff << (32 * word);
+                    // Source 0 cannot be enabled
+                    *enables = (*enables & !mask | value << (32 * word)) & !1;
+                }
+            }
+            CONTEXT.. => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                if context >= CONTEXTS {
+                    return None;
+                }
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context] = value as u32 & MAX_PRIORITY,
+                    // Completions for sources the context does not enable are ignored
+                    4 if value < SOURCES as u64 && self.enables[context] & 1 << value != 0 => {
+                        self.claimed &= !(1 << value);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        *self = Plic::new();
+    }
+
+    fn update_interrupt_lines(&mut self, lines: u64) {
+        self.lines = lines;
+    }
+
+    fn hart_interrupts(&self) -> u64 {
+        (0..CONTEXTS)
+            .filter(|&context| self.best_source(context).is_some())
+            .fold(0, |mip, context| mip | CONTEXT_INTERRUPTS[context])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The offset of the claim register of `context`.
+    fn claim(context: u64) -> u64 {
+        CONTEXT + CONTEXT_STRIDE * context + 4
+    }
+
+    #[test]
+    /// Pending interrupts are claimed by priority and raised again only after completion.
+    fn test_claim_and_complete() {
+        let mut plic = Plic::new();
+        plic.write(PRIORITY + 4 * 10, 4, 1).unwrap();
+        plic.write(PRIORITY + 4 * 33, 4, 5).unwrap();
+        plic.write(ENABLE, 4, 1 << 10).unwrap();
+        plic.write(ENABLE + 4, 4, 1 << 1).unwrap();
+        // Sources beyond `MAX_IRQ` have no priority
+        plic.write(PRIORITY + 4 * 64, 4, 7).unwrap();
+        assert_eq!(plic.read(PRIORITY + 4 * 64, 4), Some(0));
+        assert_eq!(plic.read(PENDING - 4, 4), Some(0));
+
+        plic.update_interrupt_lines(1 << 10 | 1 << 33);
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 10));
+        assert_eq!(plic.read(PENDING + 4, 4), Some(1 << 1));
+        assert_eq!(plic.hart_interrupts(), MIP_MEIP);
+
+        // The supervisor context enables nothing
+        assert_eq!(plic.read(claim(1), 4), Some(0));
+
+        assert_eq!(plic.read(claim(0), 4), Some(33));
+        assert_eq!(plic.read(claim(0), 4), Some(10));
+        assert_eq!(plic.read(claim(0), 4), Some(0));
+        assert_eq!(plic.hart_interrupts(), 0);
+
+        // The line of source 33 is still raised, so it is pending again after completion
+        plic.write(claim(0), 4, 33).unwrap();
+        plic.write(claim(0), 4, 10).unwrap();
+        plic.update_interrupt_lines(1 << 33);
+        assert_eq!(plic.hart_interrupts(), MIP_MEIP);
+
+        // The threshold masks interrupts of lower or equal priority
+        plic.write(CONTEXT, 4, 5).unwrap();
+        assert_eq!(plic.hart_interrupts(), 0);
+        assert_eq!(plic.read(claim(0), 4), Some(0));
+    }
+}
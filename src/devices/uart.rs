@@ -0,0 +1,344 @@
+//! # NS16550A UART
+//!
+//! This module implements the serial port of the QEMU `virt` machine, a 16550A-compatible UART
+//! with byte-wide registers. Transmitted bytes are sent to the application as
+//! [`CpuEvent::Write`] events and received bytes are read from the console input of the host,
+//! if the UART is the console of the machine, buffered in a 16-byte receive FIFO.
+//!
+//! Modem control, baud rate and line settings are stored, but have no effect, as transmission
+//! completes instantly.
+//! See also: [https://www.ti.com/lit/ds/symlink/pc16550d.pdf]
+
+use std::collections::VecDeque;
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::{
+    cpu::CpuEvent,
+    devices::{Device, DeviceContext, DeviceKind},
+    utils::push_utf8,
+};
+
+/// The UART of the QEMU `virt` machine.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "uart",
+    description: "NS16550A-compatible serial port",
+    default_base: 0x1000_0000,
+    size: 0x100,
+    irq: Some(10),
+    options: &[],
+    create: |context, _| Ok(Box::new(Uart::new(context))),
+};
+
+/// Receiver Buffer Register (read) and Transmitter Holding Register (write),
+/// or the low byte of the divisor latch if `LCR.DLAB` is set.
+const RBR_THR: u64 = 0;
+/// Interrupt Enable Register, or the high byte of the divisor latch if `LCR.DLAB` is set.
+const IER: u64 = 1;
+/// Interrupt Identification Register (read) and FIFO Control Register (write).
+const IIR_FCR: u64 = 2;
+/// Line Control Register.
+const LCR: u64 = 3;
+/// Modem Control Register.
+const MCR: u64 = 4;
+/// Line Status Register.
+const LSR: u64 = 5;
+/// Modem Status Register.
+const MSR: u64 = 6;
+/// Scratch Register.
+const SCR: u64 = 7;
+
+/// `IER`: Enable the received data available interrupt.
+const IER_RDA: u8 = 1 << 0;
+/// `IER`: Enable the transmitter holding register empty interrupt.
+const IER_THRE: u8 = 1 << 1;
+/// The bits of `IER` that can be written.
+const IER_MASK: u8 = 0x0f;
+
+/// `IIR`: No interrupt is pending.
+const IIR_NONE: u8 = 0x01;
+/// `IIR`: The transmitter holding register is empty.
+const IIR_THRE: u8 = 0x02;
+/// `IIR`: Received data is available.
+const IIR_RDA: u8 = 0x04;
+/// `IIR`: The FIFOs are enabled.
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+/// `FCR`: Enable the FIFOs.
+const FCR_FIFO_ENABLE: u8 = 1 << 0;
+/// `FCR`: Clear the receive FIFO.
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+/// `LCR`: Divisor Latch Access Bit.
+const LCR_DLAB: u8 = 1 << 7;
+
+/// `LSR`: Data Ready.
+const LSR_DR: u8 = 1 << 0;
+/// `LSR`: Transmitter Holding Register Empty.
+const LSR_THRE: u8 = 1 << 5;
+/// `LSR`: Transmitter Empty.
+const LSR_TEMT: u8 = 1 << 6;
+
+/// `MSR`: Clear To Send, Data Set Ready and Data Carrier Detect, i.e. the host is connected.
+const MSR_CONNECTED: u8 = 0xb0;
+
+/// The number of bytes the receive FIFO can hold.
+const RX_FIFO_SIZE: usize = 16;
+
+/// The number of cycles between two polls of the console input.
+const POLL_INTERVAL: u32 = 1024;
+
+pub struct Uart {
+    /// The bytes received but not yet read by the program.
+    rx_fifo: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    /// The divisor latch, which sets the baud rate.
+    divisor: u16,
+    fifo_enabled: bool,
+    /// Whether the transmitter holding register empty interrupt is pending. It is raised when
+    /// the transmitter becomes empty and cleared by reading `IIR` or writing `THR`.
+    thre_pending: bool,
+
+    /// The bytes of a UTF-8 sequence that is not yet completely transmitted.
+    tx_pending: Vec<u8>,
+    /// The cycles until the console input is polled again.
+    poll_countdown: u32,
+    events: Sender<CpuEvent>,
+    input: Receiver<u8>,
+}
+
+impl Uart {
+    /// Creates a UART that transmits to and receives from the console of `context`.
+    pub fn new(context: &DeviceContext) -> Self {
+        Uart {
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thre_pending: false,
+            tx_pending: Vec::new(),
+            poll_countdown: 0,
+            events: context.events.clone(),
+            input: context.console_input.clone(),
+        }
+    }
+
+    /// Moves the available console input into the receive FIFO, as long as it has space.
+    fn poll_input(&mut self) {
+        while self.rx_fifo.len() < RX_FIFO_SIZE {
+            match self.input.try_recv() {
+                Ok(byte) => self.rx_fifo.push_back(byte),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Sends a byte to the console. Bytes are collected until they form a complete UTF-8
+    /// sequence, invalid sequences are replaced by `U+FFFD`.
+    fn transmit(&mut self, byte: u8) {
+        if let Some(text) = push_utf8(&mut self.tx_pending, byte) {
+            // The output is dropped if the application is not listening
+            let _ = self.events.send(CpuEvent::Write { text });
+        }
+    }
+
+    /// Returns the value of `IIR` for the pending interrupt with the highest priority.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RDA != 0 && !self.rx_fifo.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    /// Returns the value of `LSR`, where transmission always completes instantly.
+    fn line_status(&self) -> u8 {
+        let data_ready = if self.rx_fifo.is_empty() { 0 } else { LSR_DR };
+        LSR_THRE | LSR_TEMT | data_ready
+    }
+
+    /// Returns `true` if `LCR.DLAB` maps the divisor latch over `RBR`/`THR` and `IER`.
+    fn is_divisor_latch(&self) -> bool {
+        self.lcr & LCR_DLAB != 0
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+        let value = match offset {
+            RBR_THR if self.is_divisor_latch() => self.divisor as u8,
+            RBR_THR => {
+                if self.rx_fifo.is_empty() {
+                    self.poll_input();
+                }
+                self.rx_fifo.pop_front().unwrap_or(0)
+            }
+            IER if self.is_divisor_latch() => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // Reading the interrupt identification acknowledges the THRE interrupt
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fifo_enabled {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                if self.rx_fifo.is_empty() {
+                    self.poll_input();
+                }
+                self.line_status()
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => return None,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+        let value = value as u8;
+        match offset {
+            RBR_THR if self.is_divisor_latch() => {
+                self.divisor = (self.divisor & 0xff00) | value as u16;
+            }
+            RBR_THR => {
+                self.transmit(value);
+                // Transmission completes instantly, so the holding register is empty again
+                self.thre_pending = true;
+            }
+            IER if self.is_divisor_latch() => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8;
+            }
+            IER => {
+                // Enabling the THRE interrupt raises it if the transmitter is empty
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & IER_MASK;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx_fifo.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            // The status registers are read-only
+            LSR | MSR => {}
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        self.rx_fifo.clear();
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.divisor = 0;
+        self.fifo_enabled = false;
+        self.thre_pending = false;
+        self.tx_pending.clear();
+    }
+
+    fn tick(&mut self) {
+        // Polling the input is comparatively expensive, so it is only done periodically
+        if self.poll_countdown > 0 {
+            self.poll_countdown -= 1;
+            return;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+        self.poll_input();
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Bytes are transmitted as text and received through the FIFO, raising interrupts.
+    fn test_transmit_and_receive() {
+        let (events, cpu_events) = crossbeam::channel::unbounded();
+        let (console, console_input) = crossbeam::channel::unbounded();
+        let mut uart = Uart::new(&DeviceContext {
+            events,
+            console_input,
+        });
+
+        for byte in "hé".bytes() {
+            uart.write(RBR_THR, 1, byte as u64).unwrap();
+        }
+        let text: Vec<_> = cpu_events.try_iter().collect();
+        assert_eq!(
+            text,
+            [
+                CpuEvent::Write {
+                    text: "h".to_string()
+                },
+                CpuEvent::Write {
+                    text: "é".to_string()
+                }
+            ]
+        );
+
+        assert_eq!(uart.read(LSR, 1), Some((LSR_THRE | LSR_TEMT) as u64));
+        assert!(!uart.interrupt_pending());
+        uart.write(IER, 1, IER_RDA as u64).unwrap();
+        console.send(b'a').unwrap();
+        console.send(b'b').unwrap();
+        uart.tick();
+        assert!(uart.interrupt_pending());
+        assert_eq!(uart.read(IIR_FCR, 1), Some(IIR_RDA as u64));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(RBR_THR, 1), Some(b'a' as u64));
+        assert_eq!(uart.read(RBR_THR, 1), Some(b'b' as u64));
+        assert!(!uart.interrupt_pending());
+
+        // The THRE interrupt is acknowledged by reading IIR
+        uart.write(IER, 1, IER_THRE as u64).unwrap();
+        assert_eq!(uart.read(IIR_FCR, 1), Some(IIR_THRE as u64));
+        assert_eq!(uart.read(IIR_FCR, 1), Some(IIR_NONE as u64));
+
+        // With DLAB set, the first two registers access the divisor latch
+        uart.write(LCR, 1, LCR_DLAB as u64).unwrap();
+        uart.write(RBR_THR, 1, 0x03).unwrap();
+        uart.write(IER, 1, 0x01).unwrap();
+        assert_eq!(uart.divisor, 0x0103);
+        assert_eq!(uart.read(IER, 1), Some(0x01));
+        assert_eq!(uart.read(RBR_THR, 4), None);
+    }
+}
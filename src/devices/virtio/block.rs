@@ -0,0 +1,423 @@
+//! # Virtio Block Device
+//!
+//! This module implements a virtio-blk device backed by a disk image on the host, which is
+//! mapped into the memory of the VM with one of three modes:
+//!
+//! - `rw`: writes of the driver are written back to the image
+//! - `ro`: the device is read-only and rejects writes
+//! - `cow`: writes of the driver are kept in memory and discarded when the VM exits,
+//!   leaving the image untouched
+//!
+//! The driver reads and writes the image in sectors of 512 bytes. Each request consists of a
+//! header with the type of the request and the first sector, the data and a status byte.
+//! See also: [https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-2740002]
+
+use std::{fs::OpenOptions, io, ops::Range, path::Path, str::FromStr};
+
+use derive_more::Display;
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::{
+    bus::GuestMemory,
+    devices::{
+        DeviceKind,
+        virtio::{
+            VirtioDevice, VirtioMmio,
+            queue::{DescriptorChain, QueueError, Virtqueue},
+        },
+    },
+};
+
+/// A virtio block device, mapped like the first virtio-mmio device of the QEMU `virt` machine.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "virtio-blk",
+    description: "Virtio block device, options: file=<disk image>, mode=rw|ro|cow",
+    default_base: 0x1000_1000,
+    size: 0x1000,
+    irq: Some(1),
+    options: &["file", "mode"],
+    create: |_, spec| {
+        let path = spec.option("file").ok_or("missing option `file`")?;
+        let mode = spec.option("mode").unwrap_or("rw").parse()?;
+        let block = Block::open(path, mode)
+            .map_err(|error| format!("cannot open disk image `{path}`: {error}"))?;
+        Ok(Box::new(VirtioMmio::new(block)))
+    },
+};
+
+/// The device type of block devices.
+const DEVICE_ID: u32 = 2;
+/// The size of a sector in bytes.
+pub const SECTOR_SIZE: u64 = 512;
+/// The length of the serial number returned by `VIRTIO_BLK_T_GET_ID`.
+const ID_LEN: usize = 20;
+
+/// `VIRTIO_BLK_F_RO`: The device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// `VIRTIO_BLK_F_FLUSH`: The device supports flush requests.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// `VIRTIO_BLK_T_IN`: Read sectors.
+const VIRTIO_BLK_T_IN: u32 = 0;
+/// `VIRTIO_BLK_T_OUT`: Write sectors.
+const VIRTIO_BLK_T_OUT: u32 = 1;
+/// `VIRTIO_BLK_T_FLUSH`: Write back all previous writes.
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+/// `VIRTIO_BLK_T_GET_ID`: Read the serial number of the device.
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+/// `VIRTIO_BLK_S_OK`: The request succeeded.
+const VIRTIO_BLK_S_OK: u8 = 0;
+/// `VIRTIO_BLK_S_IOERR`: The request failed.
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+/// `VIRTIO_BLK_S_UNSUPP`: The request is not supported.
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the request header: the type, a reserved word and the sector.
+const HEADER_SIZE: usize = 16;
+
+/// How the writes of the driver affect the disk image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum DiskMode {
+    /// Writes are written back to the image.
+    #[display("rw")]
+    ReadWrite,
+    /// Writes are rejected.
+    #[display("ro")]
+    ReadOnly,
+    /// Writes are kept in memory only.
+    #[display("cow")]
+    CopyOnWrite,
+}
+
+impl FromStr for DiskMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "rw" => Ok(DiskMode::ReadWrite),
+            "ro" => Ok(DiskMode::ReadOnly),
+            "cow" => Ok(DiskMode::CopyOnWrite),
+            _ => Err(format!(
+                "unknown disk mode `{mode}`, expected rw, ro or cow"
+            )),
+        }
+    }
+}
+
+/// A virtio block device with a single request queue.
+pub struct Block {
+    /// The disk image, shared with the file in [`DiskMode::ReadWrite`]
+    /// and private to the VM otherwise.
+    image: MmapMut,
+    mode: DiskMode,
+    /// The serial number, derived from the name of the image.
+    id: [u8; ID_LEN],
+}
+
+impl Block {
+    /// Maps the disk image at `path` with `mode`. The image must hold at least one sector.
+    pub fn open(path: impl AsRef<Path>, mode: DiskMode) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == DiskMode::ReadWrite)
+            .open(path)?;
+        if file.metadata()?.len() < SECTOR_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the disk image is smaller than a sector",
+            ));
+        }
+        // SAFETY: The image must not be modified by other processes while the VM runs,
+        // like for any disk image in use
+        let image = unsafe {
+            match mode {
+                DiskMode::ReadWrite => MmapOptions::new().map_mut(&file)?,
+                DiskMode::ReadOnly | DiskMode::CopyOnWrite => MmapOptions::new().map_copy(&file)?,
+            }
+        };
+        let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+        let mut id = [0; ID_LEN];
+        let len = name.len().min(ID_LEN);
+        id[..len].copy_from_slice(&name[..len]);
+        log::debug!(
+            "Mapped disk image {} ({} bytes, mode {})",
+            path.display(),
+            image.len(),
+            mode
+        );
+        Ok(Block { image, mode, id })
+    }
+
+    /// Returns the number of sectors of the disk. A partial sector at the end is ignored.
+    fn capacity(&self) -> u64 {
+        self.image.len() as u64 / SECTOR_SIZE
+    }
+
+    /// Returns the bytes of the image covered by `len` bytes starting at `sector`,
+    /// if they are within the disk.
+    fn sectors(&self, sector: u64, len: usize) -> Option<Range<usize>> {
+        let start = sector.checked_mul(SECTOR_SIZE)?;
+        let end = start.checked_add(len as u64)?;
+        (end <= self.capacity() * SECTOR_SIZE).then_some(start as usize..end as usize)
+    }
+
+    /// Executes the request of `chain` and returns its status.
+    /// The data of read requests is written to `chain`, in front of the status byte.
+    fn execute(
+        &mut self,
+        chain: &mut DescriptorChain,
+        memory: &mut GuestMemory,
+    ) -> Result<u8, QueueError> {
+        let mut header = [0; HEADER_SIZE];
+        if chain.read(memory, &mut header)? < HEADER_SIZE {
+            return Ok(VIRTIO_BLK_S_IOERR);
+        }
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        // The last writable byte holds the status
+        let data_len = chain.writable_len().saturating_sub(1);
+
+        let status = match request_type {
+            VIRTIO_BLK_T_IN => match self.sectors(sector, data_len) {
+                Some(range) => {
+                    chain.write(memory, &self.image[range])?;
+                    VIRTIO_BLK_S_OK
+                }
+                None => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_OUT => {
+                let range = self.sectors(sector, chain.remaining_readable());
+                match range {
+                    Some(range) if self.mode != DiskMode::ReadOnly => {
+                        chain.read(memory, &mut self.image[range])?;
+                        VIRTIO_BLK_S_OK
+                    }
+                    _ => VIRTIO_BLK_S_IOERR,
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => match self.mode {
+                DiskMode::ReadWrite if self.image.flush().is_err() => VIRTIO_BLK_S_IOERR,
+                _ => VIRTIO_BLK_S_OK,
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                chain.write(memory, &self.id[..data_len.min(ID_LEN)])?;
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        Ok(status)
+    }
+}
+
+impl VirtioDevice for Block {
+    fn name(&self) -> &str {
+        "virtio-blk"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        match self.mode {
+            DiskMode::ReadOnly => VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO,
+            _ => VIRTIO_BLK_F_FLUSH,
+        }
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // Only the capacity in sectors is reported
+        let capacity = self.capacity().to_le_bytes();
+        capacity.get(offset as usize).copied().unwrap_or(0)
+    }
+
+    fn process(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<(), QueueError> {
+        let queue = &mut queues[0];
+        while let Some(mut chain) = queue.pop(memory)? {
+            let status = self.execute(&mut chain, memory)?;
+            if let Some(position) = chain.writable_len().checked_sub(1) {
+                chain.write_at(memory, position, &[status])?;
+            }
+            queue.push_used(memory, &chain)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices::{Device, virtio::*},
+        monitored_memory::MonitoredMemory,
+    };
+
+    /// The address of the first byte of the memory of the tests.
+    const BASE: u64 = 0x8000_0000;
+    /// The addresses of the virtqueue, the request header, the data and the status byte.
+    const DESCRIPTORS: u64 = BASE;
+    const DRIVER_AREA: u64 = BASE + 0x100;
+    const DEVICE_AREA: u64 = BASE + 0x200;
+    const HEADER: u64 = BASE + 0x300;
+    const DATA: u64 = BASE + 0x400;
+    const STATUS: u64 = BASE + 0x800;
+
+    /// Creates a disk image of 4 sectors, each filled with its number.
+    fn disk_image(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("riscv-vm-{}-{name}", std::process::id()));
+        let image: Vec<u8> = (0..4).flat_map(|sector| [sector; 512]).collect();
+        std::fs::write(&path, image).unwrap();
+        path
+    }
+
+    /// Initializes the device like a driver and sets up its virtqueue with 4 descriptors.
+    fn start(device: &mut VirtioMmio<Block>) {
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        device.write(REG_STATUS, 4, status as u64);
+        device.write(REG_DRIVER_FEATURES_SEL, 4, 1);
+        device.write(REG_DRIVER_FEATURES, 4, VIRTIO_F_VERSION_1 >> 32);
+        status |= STATUS_FEATURES_OK;
+        device.write(REG_STATUS, 4, status as u64);
+        assert_eq!(device.read(REG_STATUS, 4), Some(status as u64));
+        device.write(REG_QUEUE_NUM, 4, 4);
+        device.write(REG_QUEUE_DESC_LOW, 4, DESCRIPTORS);
+        device.write(REG_QUEUE_DRIVER_LOW, 4, DRIVER_AREA);
+        device.write(REG_QUEUE_DEVICE_LOW, 4, DEVICE_AREA);
+        device.write(REG_QUEUE_READY, 4, 1);
+        device.write(REG_STATUS, 4, (status | STATUS_DRIVER_OK) as u64);
+    }
+
+    /// Submits a request for the `len` bytes at `sector` and returns its status.
+    fn request(
+        device: &mut VirtioMmio<Block>,
+        memory: &mut GuestMemory,
+        request_type: u32,
+        sector: u64,
+        len: u32,
+    ) -> u8 {
+        memory.write_u32(HEADER, request_type).unwrap();
+        memory.write(HEADER + 8, &sector.to_le_bytes()).unwrap();
+        let data_flags = if request_type == VIRTIO_BLK_T_OUT {
+            1
+        } else {
+            3
+        };
+        let descriptors = [
+            (HEADER, 16, 1, 1),
+            (DATA, len, data_flags, 2),
+            (STATUS, 1, 2, 0),
+        ];
+        for (index, (address, len, flags, next)) in descriptors.into_iter().enumerate() {
+            let descriptor = DESCRIPTORS + 16 * index as u64;
+            memory.write(descriptor, &address.to_le_bytes()).unwrap();
+            memory.write_u32(descriptor + 8, len).unwrap();
+            memory.write_u16(descriptor + 12, flags).unwrap();
+            memory.write_u16(descriptor + 14, next).unwrap();
+        }
+        let available = memory.read_u16(DRIVER_AREA + 2).unwrap();
+        memory
+            .write_u16(DRIVER_AREA + 4 + 2 * (available % 4) as u64, 0)
+            .unwrap();
+        memory.write_u16(DRIVER_AREA + 2, available + 1).unwrap();
+
+        device.transfer(memory);
+        assert_eq!(memory.read_u16(DEVICE_AREA + 2), Some(available + 1));
+        assert!(device.interrupt_pending());
+        device.write(REG_INTERRUPT_ACK, 4, 1);
+        let mut status = [0];
+        memory.read(STATUS, &mut status).unwrap();
+        status[0]
+    }
+
+    #[test]
+    /// Sectors are read and written back to the image, and requests beyond the disk fail.
+    fn test_block_requests() {
+        let path = disk_image("rw.img");
+        let mut device = VirtioMmio::new(Block::open(&path, DiskMode::ReadWrite).unwrap());
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(BASE, &mut dram);
+        assert_eq!(device.read(REG_DEVICE_ID, 4), Some(DEVICE_ID as u64));
+        assert_eq!(device.read(REG_CONFIG, 8), Some(4));
+        start(&mut device);
+
+        let ok = VIRTIO_BLK_S_OK;
+        assert_eq!(
+            request(&mut device, &mut memory, VIRTIO_BLK_T_IN, 2, 1024),
+            ok
+        );
+        let mut data = [0; 1024];
+        memory.read(DATA, &mut data).unwrap();
+        assert_eq!((data[0], data[511], data[512], data[1023]), (2, 2, 3, 3));
+
+        memory.write(DATA, &[0xaa; 512]).unwrap();
+        assert_eq!(
+            request(&mut device, &mut memory, VIRTIO_BLK_T_OUT, 1, 512),
+            ok
+        );
+        assert_eq!(
+            request(&mut device, &mut memory, VIRTIO_BLK_T_FLUSH, 0, 0),
+            ok
+        );
+        assert_eq!(std::fs::read(&path).unwrap()[512..1024], [0xaa; 512]);
+
+        assert_eq!(
+            request(&mut device, &mut memory, VIRTIO_BLK_T_GET_ID, 0, 20),
+            ok
+        );
+        memory.read(DATA, &mut data[..20]).unwrap();
+        let name = path.file_name().unwrap().as_encoded_bytes();
+        let len = name.len().min(ID_LEN);
+        assert_eq!(data[..len], name[..len]);
+
+        let ioerr = VIRTIO_BLK_S_IOERR;
+        assert_eq!(
+            request(&mut device, &mut memory, VIRTIO_BLK_T_IN, 3, 1024),
+            ioerr
+        );
+        let unsupp = VIRTIO_BLK_S_UNSUPP;
+        assert_eq!(request(&mut device, &mut memory, 11, 0, 0), unsupp);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    /// Read-only disks reject writes and copy-on-write disks keep them in memory.
+    fn test_disk_modes() {
+        let path = disk_image("modes.img");
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(BASE, &mut dram);
+        memory.write(DATA, &[0xaa; 512]).unwrap();
+
+        let mut device = VirtioMmio::new(Block::open(&path, DiskMode::ReadOnly).unwrap());
+        assert_eq!(
+            device.read(REG_DEVICE_FEATURES, 4),
+            Some(VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO)
+        );
+        start(&mut device);
+        let status = request(&mut device, &mut memory, VIRTIO_BLK_T_OUT, 0, 512);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let mut device = VirtioMmio::new(Block::open(&path, DiskMode::CopyOnWrite).unwrap());
+        start(&mut device);
+        let status = request(&mut device, &mut memory, VIRTIO_BLK_T_OUT, 0, 512);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        memory.write(DATA, &[0; 512]).unwrap();
+        request(&mut device, &mut memory, VIRTIO_BLK_T_IN, 0, 512);
+        let mut data = [0; 512];
+        memory.read(DATA, &mut data).unwrap();
+        assert_eq!(data, [0xaa; 512]);
+        assert_eq!(std::fs::read(&path).unwrap()[..512], [0; 512]);
+
+        assert!("rwx".parse::<DiskMode>().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,426 @@
+//! # Virtio Console
+//!
+//! This module implements a virtio-console device with one or more ports. Port 0 is the
+//! console of the VM: its output is sent to the application as [`CpuEvent::Write`] events and
+//! its input is read from the console input of the host, if the device is chosen as the
+//! console of the machine. The output of the other ports is written to the standard output of
+//! the host.
+//!
+//! Each port has a receive and a transmit virtqueue. If the driver accepts
+//! `VIRTIO_CONSOLE_F_MULTIPORT`, the device announces its ports through a pair of control
+//! virtqueues, and the driver reports which ports it is ready to use.
+//! See also: [https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-2900003]
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use crate::{
+    bus::GuestMemory,
+    cpu::CpuEvent,
+    devices::{
+        DeviceContext, DeviceKind,
+        virtio::{
+            VirtioDevice, VirtioMmio,
+            queue::{QueueError, Virtqueue},
+        },
+    },
+    utils::push_utf8,
+};
+
+/// A virtio console, mapped after the virtio block device.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "virtio-console",
+    description: "Virtio console on port 0 and the host output on the other ports, \
+        options: ports=<number>",
+    default_base: 0x1000_2000,
+    size: 0x1000,
+    irq: Some(2),
+    options: &["ports"],
+    create: |context, spec| {
+        let ports = spec.option("ports").unwrap_or("1");
+        let ports = ports
+            .parse()
+            .ok()
+            .filter(|ports| (1..=MAX_PORTS).contains(ports))
+            .ok_or_else(|| format!("the number of ports must be 1 to {MAX_PORTS}"))?;
+        Ok(Box::new(VirtioMmio::new(Console::new(context, ports))))
+    },
+};
+
+/// The device type of console devices.
+const DEVICE_ID: u32 = 3;
+/// The largest number of ports of a console.
+pub const MAX_PORTS: u32 = 16;
+
+/// `VIRTIO_CONSOLE_F_MULTIPORT`: The device has control virtqueues and may have several ports.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// `VIRTIO_CONSOLE_F_EMERG_WRITE`: The driver can write to port 0 through the configuration.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// The offset of `max_nr_ports` in the configuration space.
+const CONFIG_MAX_NR_PORTS: u64 = 4;
+/// The offset of `emerg_wr` in the configuration space.
+const CONFIG_EMERG_WR: u64 = 8;
+
+/// The control receive and transmit virtqueues, between the queues of port 0 and port 1.
+const CONTROL_RECEIVE: usize = 2;
+const CONTROL_TRANSMIT: usize = 3;
+
+/// `VIRTIO_CONSOLE_DEVICE_READY`: The driver is ready to receive the ports.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+/// `VIRTIO_CONSOLE_DEVICE_ADD`: The device adds a port.
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+/// `VIRTIO_CONSOLE_PORT_READY`: The driver set up a port.
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+/// `VIRTIO_CONSOLE_CONSOLE_PORT`: The port is the console.
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+/// `VIRTIO_CONSOLE_PORT_OPEN`: The port was opened or closed.
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+/// `VIRTIO_CONSOLE_PORT_NAME`: The name of the port follows the message.
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The size of a control message: the port, the event and its value.
+const CONTROL_SIZE: usize = 8;
+
+/// The number of bytes read from a transmit buffer at a time. The length of the buffer is
+/// chosen by the driver, so the host never allocates memory for all of it.
+const TRANSMIT_CHUNK_SIZE: usize = 256;
+
+/// The number of cycles between two polls of the console input.
+const POLL_INTERVAL: u32 = 1024;
+
+/// A virtio console with its ports.
+pub struct Console {
+    ports: u32,
+    /// Whether the driver accepted `VIRTIO_CONSOLE_F_MULTIPORT`.
+    multiport: bool,
+    /// The control messages not yet received by the driver.
+    control: VecDeque<Vec<u8>>,
+    /// The bytes of the console input not yet received by the driver.
+    input: VecDeque<u8>,
+    /// The bytes of incomplete UTF-8 sequences transmitted on each port.
+    tx_pending: Vec<Vec<u8>>,
+    /// The cycles until the console input is polled again.
+    poll_countdown: u32,
+    events: Sender<CpuEvent>,
+    console_input: Receiver<u8>,
+}
+
+impl Console {
+    /// Creates a console with `ports` ports, whose port 0 transmits to and receives from the
+    /// console of `context`.
+    pub fn new(context: &DeviceContext, ports: u32) -> Self {
+        Console {
+            ports,
+            multiport: false,
+            control: VecDeque::new(),
+            input: VecDeque::new(),
+            tx_pending: vec![Vec::new(); ports as usize],
+            poll_countdown: 0,
+            events: context.events.clone(),
+            console_input: context.console_input.clone(),
+        }
+    }
+
+    /// Returns the receive virtqueue of `port`, which is followed by its transmit virtqueue.
+    fn receive_queue(port: u32) -> usize {
+        match port {
+            0 => 0,
+            port => 2 + 2 * port as usize,
+        }
+    }
+
+    /// Returns the number of ports the driver can use.
+    fn active_ports(&self) -> u32 {
+        if self.multiport { self.ports } else { 1 }
+    }
+
+    /// Sends `bytes` transmitted on `port` to the host.
+    fn transmit(&mut self, port: u32, bytes: &[u8]) {
+        if port != 0 {
+            // The output is lost if the host closed its standard output
+            let _ = io::stdout().lock().write_all(bytes);
+            let _ = io::stdout().flush();
+            return;
+        }
+        for &byte in bytes {
+            if let Some(text) = push_utf8(&mut self.tx_pending[0], byte) {
+                // The output is dropped if the application is not listening
+                let _ = self.events.send(CpuEvent::Write { text });
+            }
+        }
+    }
+
+    /// Queues a control message about `port` for the driver.
+    fn send_control(&mut self, port: u32, event: u16, value: u16, data: &[u8]) {
+        let mut message = Vec::with_capacity(CONTROL_SIZE + data.len());
+        message.extend_from_slice(&port.to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    /// Handles a control message of the driver.
+    fn receive_control(&mut self, message: &[u8; CONTROL_SIZE]) {
+        let port = u32::from_le_bytes(message[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(message[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(message[6..8].try_into().unwrap());
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports {
+                    self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && port < self.ports => {
+                if port == 0 {
+                    self.send_control(port, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                } else {
+                    let name = format!("port{port}");
+                    self.send_control(port, VIRTIO_CONSOLE_PORT_NAME, 0, name.as_bytes());
+                }
+                // The host side of every port is always connected
+                self.send_control(port, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            // Whether the driver opened a port makes no difference to the host
+            _ => {}
+        }
+    }
+
+    /// Moves the available console input into the input buffer.
+    fn poll_input(&mut self) {
+        if self.poll_countdown > 0 {
+            self.poll_countdown -= 1;
+            return;
+        }
+        self.poll_countdown = POLL_INTERVAL;
+        self.input.extend(self.console_input.try_iter());
+    }
+
+    /// Fills the buffers of `queue` with the bytes of `pending`, until either runs out.
+    fn fill(
+        pending: &mut VecDeque<u8>,
+        queue: &mut Virtqueue,
+        memory: &mut GuestMemory,
+    ) -> Result<(), QueueError> {
+        while !pending.is_empty() {
+            let Some(mut chain) = queue.pop(memory)? else {
+                break;
+            };
+            let count = chain.write(memory, pending.make_contiguous())?;
+            pending.drain(..count);
+            queue.push_used(memory, &chain)?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Console {
+    fn name(&self) -> &str {
+        "virtio-console"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn queue_count(&self) -> usize {
+        // The control virtqueues exist even if there is only port 0
+        2 * (self.ports as usize + 1)
+    }
+
+    fn read_config(&self, offset: u64) -> u8 {
+        // The size of the console is not reported
+        match offset.checked_sub(CONFIG_MAX_NR_PORTS) {
+            Some(byte @ 0..4) => self.ports.to_le_bytes()[byte as usize],
+            _ => 0,
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, value: u8) {
+        if offset == CONFIG_EMERG_WR {
+            self.transmit(0, &[value]);
+        }
+    }
+
+    fn activate(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+        self.input.clear();
+        self.tx_pending.iter_mut().for_each(Vec::clear);
+    }
+
+    fn process(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<(), QueueError> {
+        for port in 0..self.active_ports() {
+            let queue = &mut queues[Console::receive_queue(port) + 1];
+            while let Some(mut chain) = queue.pop(memory)? {
+                let mut chunk = [0; TRANSMIT_CHUNK_SIZE];
+                loop {
+                    let count = chain.read(memory, &mut chunk)?;
+                    if count == 0 {
+                        break;
+                    }
+                    self.transmit(port, &chunk[..count]);
+                }
+                queue.push_used(memory, &chain)?;
+            }
+        }
+
+        if self.multiport {
+            let queue = &mut queues[CONTROL_TRANSMIT];
+            while let Some(mut chain) = queue.pop(memory)? {
+                // Messages of another size are ignored
+                let is_message = chain.remaining_readable() == CONTROL_SIZE;
+                let mut message = [0; CONTROL_SIZE];
+                chain.read(memory, &mut message)?;
+                queue.push_used(memory, &chain)?;
+                if is_message {
+                    self.receive_control(&message);
+                }
+            }
+            let queue = &mut queues[CONTROL_RECEIVE];
+            while !self.control.is_empty() {
+                let Some(mut chain) = queue.pop(memory)? else {
+                    break;
+                };
+                let message = self.control.pop_front().unwrap_or_default();
+                chain.write(memory, &message)?;
+                queue.push_used(memory, &chain)?;
+            }
+        }
+
+        self.poll_input();
+        Console::fill(&mut self.input, &mut queues[0], memory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitored_memory::MonitoredMemory;
+
+    /// Sets up virtqueue `index` with 4 descriptors, each queue in its own 0x100 bytes.
+    fn queue(index: u64) -> Virtqueue {
+        let mut queue = Virtqueue::new();
+        queue.size = 4;
+        queue.ready = true;
+        queue.descriptor_table = 0x100 * index;
+        queue.driver_area = queue.descriptor_table + 0x40;
+        queue.device_area = queue.descriptor_table + 0x80;
+        queue
+    }
+
+    /// Offers the `len` bytes at `address` to the device in a chain of one buffer.
+    fn offer(memory: &mut GuestMemory, queue: &Virtqueue, address: u64, len: u32, writable: bool) {
+        let index = memory.read_u16(queue.driver_area + 2).unwrap();
+        let descriptor = queue.descriptor_table + 16 * (index % 4) as u64;
+        memory.write(descriptor, &address.to_le_bytes()).unwrap();
+        memory.write_u32(descriptor + 8, len).unwrap();
+        memory
+            .write_u16(descriptor + 12, if writable { 2 } else { 0 })
+            .unwrap();
+        let slot = queue.driver_area + 4 + 2 * (index % 4) as u64;
+        memory.write_u16(slot, index % 4).unwrap();
+        memory.write_u16(queue.driver_area + 2, index + 1).unwrap();
+    }
+
+    /// Returns the control message at `address`.
+    fn message(memory: &GuestMemory, address: u64) -> (u32, u16, u16) {
+        let port = memory.read_u32(address).unwrap();
+        let event = memory.read_u16(address + 4).unwrap();
+        (port, event, memory.read_u16(address + 6).unwrap())
+    }
+
+    #[test]
+    /// The ports are announced through the control virtqueues, and port 0 transmits to and
+    /// receives from the console of the host.
+    fn test_console_ports() {
+        let (events, cpu_events) = crossbeam::channel::unbounded();
+        let (console_input, input) = crossbeam::channel::unbounded();
+        let context = DeviceContext {
+            events,
+            console_input: input,
+        };
+        let mut console = Console::new(&context, 2);
+        assert_eq!(console.queue_count(), 6);
+        assert_eq!(Console::new(&context, 1).queue_count(), 4);
+        assert_eq!(console.read_config(CONFIG_MAX_NR_PORTS), 2);
+        console.activate(VIRTIO_CONSOLE_F_MULTIPORT);
+
+        let mut dram = MonitoredMemory::new(0x2000).unwrap();
+        let mut memory = GuestMemory::new(0, &mut dram);
+        let mut queues: Vec<_> = (0..6).map(queue).collect();
+
+        // The driver is ready, so the device adds its ports
+        memory.write(0x1000, &[0, 0, 0, 0, 0, 0, 1, 0]).unwrap();
+        offer(&mut memory, &queues[CONTROL_TRANSMIT], 0x1000, 8, false);
+        for address in [0x1100, 0x1200, 0x1300] {
+            offer(&mut memory, &queues[CONTROL_RECEIVE], address, 64, true);
+        }
+        console.process(&mut queues, &mut memory).unwrap();
+        assert_eq!(message(&memory, 0x1100), (0, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+        assert_eq!(message(&memory, 0x1200), (1, VIRTIO_CONSOLE_DEVICE_ADD, 0));
+
+        // Port 0 is the console
+        memory.write(0x1000, &[0, 0, 0, 0, 3, 0, 1, 0]).unwrap();
+        offer(&mut memory, &queues[CONTROL_TRANSMIT], 0x1000, 8, false);
+        offer(&mut memory, &queues[CONTROL_RECEIVE], 0x1100, 64, true);
+        console.process(&mut queues, &mut memory).unwrap();
+        assert_eq!(
+            message(&memory, 0x1300),
+            (0, VIRTIO_CONSOLE_CONSOLE_PORT, 1)
+        );
+        assert_eq!(message(&memory, 0x1100), (0, VIRTIO_CONSOLE_PORT_OPEN, 1));
+
+        memory.write(0x1400, "hé".as_bytes()).unwrap();
+        offer(&mut memory, &queues[1], 0x1400, 3, false);
+        console_input.send(b'k').unwrap();
+        // The input was polled by the last call, so poll it again right away
+        console.poll_countdown = 0;
+        offer(&mut memory, &queues[0], 0x1500, 16, true);
+        console.process(&mut queues, &mut memory).unwrap();
+        let text: String = cpu_events
+            .try_iter()
+            .map(|event| match event {
+                CpuEvent::Write { text } => text,
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(text, "hé");
+        assert_eq!(memory.read_u32(queues[0].device_area + 8), Some(1));
+        let mut received = [0];
+        memory.read(0x1500, &mut received).unwrap();
+        assert_eq!(received, *b"k");
+    }
+
+    #[test]
+    /// A transmit buffer longer than the memory is reported as an error.
+    fn test_oversized_buffer() {
+        let mut console = Console::new(&DeviceContext::default(), 1);
+        let mut dram = MonitoredMemory::new(0x2000).unwrap();
+        let mut memory = GuestMemory::new(0, &mut dram);
+        let mut queues: Vec<_> = (0..4).map(queue).collect();
+
+        offer(&mut memory, &queues[1], 0x1400, u32::MAX, false);
+        assert_eq!(
+            console.process(&mut queues, &mut memory),
+            Err(QueueError::InvalidAddress { address: 0x2000 })
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! # Virtio
+//!
+//! This module implements the virtio-mmio transport (version 2), through which the driver
+//! negotiates the features of a virtio device, sets up its virtqueues and reads its
+//! configuration space. The transport is shared by all virtio devices, which implement
+//! [`VirtioDevice`] and process the buffers the driver makes available in their virtqueues.
+//!
+//! The virtqueues are checked after every cycle, so the device does not need to be notified.
+//! If the driver sets up a virtqueue incorrectly, the device stops and reports that it needs
+//! to be reset.
+//! See also: [https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-1650002]
+
+use log::warn;
+
+use crate::{bus::GuestMemory, devices::Device};
+
+pub mod block;
+pub mod console;
+pub mod queue;
+pub mod rng;
+
+use queue::{QUEUE_SIZE_MAX, QueueError, Virtqueue};
+
+/// The magic value at offset 0, "virt" in little-endian.
+const MAGIC_VALUE: u32 = 0x7472_6976;
+/// The version of the virtio-mmio transport.
+const VERSION: u32 = 2;
+/// The vendor id reported by the devices, the same as the id of the SBI implementation.
+const VENDOR_ID: u32 = 0x0052_564d;
+
+const REG_MAGIC_VALUE: u64 = 0x000;
+const REG_VERSION: u64 = 0x004;
+const REG_DEVICE_ID: u64 = 0x008;
+const REG_VENDOR_ID: u64 = 0x00c;
+const REG_DEVICE_FEATURES: u64 = 0x010;
+const REG_DEVICE_FEATURES_SEL: u64 = 0x014;
+const REG_DRIVER_FEATURES: u64 = 0x020;
+const REG_DRIVER_FEATURES_SEL: u64 = 0x024;
+const REG_QUEUE_SEL: u64 = 0x030;
+const REG_QUEUE_NUM_MAX: u64 = 0x034;
+const REG_QUEUE_NUM: u64 = 0x038;
+const REG_QUEUE_READY: u64 = 0x044;
+const REG_QUEUE_NOTIFY: u64 = 0x050;
+const REG_INTERRUPT_STATUS: u64 = 0x060;
+const REG_INTERRUPT_ACK: u64 = 0x064;
+const REG_STATUS: u64 = 0x070;
+const REG_QUEUE_DESC_LOW: u64 = 0x080;
+const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const REG_CONFIG_GENERATION: u64 = 0x0fc;
+/// The offset of the device-specific configuration space.
+const REG_CONFIG: u64 = 0x100;
+
+/// `status.ACKNOWLEDGE`: The driver found the device.
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+/// `status.DRIVER`: The driver knows how to drive the device.
+pub const STATUS_DRIVER: u32 = 2;
+/// `status.DRIVER_OK`: The driver is set up and the device may process its virtqueues.
+pub const STATUS_DRIVER_OK: u32 = 4;
+/// `status.FEATURES_OK`: The device accepted the features of the driver.
+pub const STATUS_FEATURES_OK: u32 = 8;
+/// `status.DEVICE_NEEDS_RESET`: The device stopped after an error.
+pub const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+/// `status.FAILED`: The driver gave up on the device.
+pub const STATUS_FAILED: u32 = 128;
+
+/// `InterruptStatus`: The device returned buffers in a virtqueue.
+const INTERRUPT_USED_BUFFER: u32 = 1;
+/// `InterruptStatus`: The configuration of the device changed.
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+/// `VIRTIO_F_VERSION_1`: The device complies with version 1 of the specification. Drivers
+/// must accept it, legacy drivers are not supported.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A virtio device, attached to the machine through [`VirtioMmio`].
+pub trait VirtioDevice: Send {
+    /// Returns the name of the device, e.g. `virtio-blk`.
+    fn name(&self) -> &str;
+
+    /// Returns the type of the device, e.g. 2 for block devices.
+    fn device_id(&self) -> u32;
+
+    /// Returns the device-specific feature bits offered to the driver.
+    fn features(&self) -> u64;
+
+    /// Returns the number of virtqueues of the device.
+    fn queue_count(&self) -> usize;
+
+    /// Reads the byte at `offset` of the configuration space.
+    fn read_config(&self, offset: u64) -> u8;
+
+    /// Writes the byte at `offset` of the configuration space.
+    fn write_config(&mut self, _offset: u64, _value: u8) {}
+
+    /// Starts the device with the `features` the driver accepted.
+    fn activate(&mut self, _features: u64) {}
+
+    /// Restores the state of the device after power-on.
+    fn reset(&mut self) {}
+
+    /// Processes the buffers the driver made available in `queues`, and returns them to the
+    /// driver once they are used.
+    fn process(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<(), QueueError>;
+}
+
+/// The virtio-mmio transport of a [`VirtioDevice`].
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    status: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    interrupt_status: u32,
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    /// Creates the transport of `device`, which waits for a driver.
+    pub fn new(device: D) -> Self {
+        VirtioMmio {
+            status: 0,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues: vec![Virtqueue::new(); device.queue_count()],
+            interrupt_status: 0,
+            device,
+        }
+    }
+
+    /// Returns the features offered to the driver.
+    fn device_features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    /// Returns the virtqueue selected by `QueueSel`, if it exists.
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Resets the transport and the device, as requested by writing 0 to `Status`.
+    fn reset_transport(&mut self) {
+        self.device.reset();
+        self.status = 0;
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        self.queues = vec![Virtqueue::new(); self.device.queue_count()];
+        self.interrupt_status = 0;
+    }
+
+    /// Writes `Status`, which advances the initialization of the device by the driver.
+    fn write_status(&mut self, value: u32) {
+        if value == 0 {
+            self.reset_transport();
+            return;
+        }
+        let mut status = value;
+        let added = value & !self.status;
+        if added & STATUS_FEATURES_OK != 0 {
+            let unknown = self.driver_features & !self.device_features();
+            if unknown != 0 || self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                warn!(
+                    "{}: rejected the driver features {:#x}",
+                    self.device.name(),
+                    self.driver_features
+                );
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        if added & STATUS_DRIVER_OK != 0 {
+            self.device.activate(self.driver_features);
+        }
+        // The device keeps reporting that it needs a reset
+        self.status = status | self.status & STATUS_DEVICE_NEEDS_RESET;
+    }
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn read(&mut self, offset: u64, size: u64) -> Option<u64> {
+        if offset >= REG_CONFIG {
+            let value = (0..size).rev().fold(0, |value, byte| {
+                value << 8 | self.device.read_config(offset - REG_CONFIG + byte) as u64
+            });
+            return Some(value);
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = match offset {
+            REG_MAGIC_VALUE => MAGIC_VALUE,
+            REG_VERSION => VERSION,
+            REG_DEVICE_ID => self.device.device_id(),
+            REG_VENDOR_ID => VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => match self.selected_queue() {
+                Some(_) => QUEUE_SIZE_MAX as u32,
+                None => 0,
+            },
+            REG_QUEUE_READY => self.selected_queue().is_some_and(|queue| queue.ready) as u32,
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: u64, size: u64, value: u64) -> Option<()> {
+        if offset >= REG_CONFIG {
+            for byte in 0..size {
+                let value = (value >> (8 * byte)) as u8;
+                self.device.write_config(offset - REG_CONFIG + byte, value);
+            }
+            return Some(());
+        }
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = value as u32;
+        /// Replaces the lower or upper half of `address` with `value`.
+        fn set_half(address: &mut u64, value: u32, upper: bool) {
+            *address = match upper {
+                false => *address & !0xffff_ffff | value as u64,
+                true => *address & 0xffff_ffff | (value as u64) << 32,
+            }
+        }
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES if self.driver_features_sel < 2 => {
+                let upper = self.driver_features_sel == 1;
+                set_half(&mut self.driver_features, value, upper);
+            }
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue()
+                    && value <= QUEUE_SIZE_MAX as u32
+                    && value.is_power_of_two()
+                {
+                    queue.size = value as u16;
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value & 1 != 0;
+                }
+            }
+            // The virtqueues are checked after every cycle anyway
+            REG_QUEUE_NOTIFY => {}
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS => self.write_status(value),
+            REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    let upper = offset == REG_QUEUE_DESC_HIGH;
+                    set_half(&mut queue.descriptor_table, value, upper);
+                }
+            }
+            REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    let upper = offset == REG_QUEUE_DRIVER_HIGH;
+                    set_half(&mut queue.driver_area, value, upper);
+                }
+            }
+            REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH => {
+                if let Some(queue) = self.selected_queue() {
+                    let upper = offset == REG_QUEUE_DEVICE_HIGH;
+                    set_half(&mut queue.device_area, value, upper);
+                }
+            }
+            // The remaining registers are read-only
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn reset(&mut self) {
+        self.reset_transport();
+    }
+
+    fn transfer(&mut self, memory: &mut GuestMemory) {
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+        if let Err(error) = self.device.process(&mut self.queues, memory) {
+            warn!(
+                "{}: {}, the device needs a reset",
+                self.device.name(),
+                error
+            );
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+        }
+        for queue in &mut self.queues {
+            if queue.take_interrupt() {
+                self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            }
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        self.interrupt_status != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitored_memory::MonitoredMemory;
+
+    /// A device with one virtqueue, whose buffers it returns unused.
+    struct Sink;
+
+    impl VirtioDevice for Sink {
+        fn name(&self) -> &str {
+            "sink"
+        }
+
+        fn device_id(&self) -> u32 {
+            0x1f
+        }
+
+        fn features(&self) -> u64 {
+            1 << 3
+        }
+
+        fn queue_count(&self) -> usize {
+            1
+        }
+
+        fn read_config(&self, offset: u64) -> u8 {
+            offset as u8
+        }
+
+        fn process(
+            &mut self,
+            queues: &mut [Virtqueue],
+            memory: &mut GuestMemory,
+        ) -> Result<(), QueueError> {
+            while let Some(chain) = queues[0].pop(memory)? {
+                queues[0].push_used(memory, &chain)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// The driver negotiates features and sets up virtqueues, and invalid virtqueues stop the
+    /// device until it is reset.
+    fn test_transport() {
+        let mut device = VirtioMmio::new(Sink);
+        assert_eq!(device.read(REG_MAGIC_VALUE, 4), Some(0x7472_6976));
+        assert_eq!(device.read(REG_DEVICE_ID, 4), Some(0x1f));
+        assert_eq!(device.read(REG_CONFIG + 2, 2), Some(0x0302));
+        assert_eq!(device.read(REG_MAGIC_VALUE, 2), None);
+        assert_eq!(device.read(REG_DEVICE_FEATURES, 4), Some(1 << 3));
+        device.write(REG_DEVICE_FEATURES_SEL, 4, 1);
+        assert_eq!(device.read(REG_DEVICE_FEATURES, 4), Some(1));
+
+        // Drivers without VIRTIO_F_VERSION_1 are rejected
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        device.write(REG_DRIVER_FEATURES, 4, 1 << 3);
+        device.write(REG_STATUS, 4, status as u64);
+        assert_eq!(device.read(REG_STATUS, 4), Some(0b11));
+        device.write(REG_DRIVER_FEATURES_SEL, 4, 1);
+        device.write(REG_DRIVER_FEATURES, 4, 1);
+        device.write(REG_STATUS, 4, status as u64);
+        assert_eq!(device.read(REG_STATUS, 4), Some(status as u64));
+
+        device.write(REG_QUEUE_SEL, 4, 1);
+        assert_eq!(device.read(REG_QUEUE_NUM_MAX, 4), Some(0));
+        device.write(REG_QUEUE_SEL, 4, 0);
+        assert_eq!(device.read(REG_QUEUE_NUM_MAX, 4), Some(256));
+        // The virtqueue lies outside of DRAM
+        device.write(REG_QUEUE_DRIVER_HIGH, 4, 1);
+        device.write(REG_QUEUE_READY, 4, 1);
+        device.write(REG_STATUS, 4, (status | STATUS_DRIVER_OK) as u64);
+
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(0, &mut dram);
+        device.transfer(&mut memory);
+        let status = device.read(REG_STATUS, 4).unwrap() as u32;
+        assert_ne!(status & STATUS_DEVICE_NEEDS_RESET, 0);
+        assert_eq!(device.read(REG_INTERRUPT_STATUS, 4), Some(2));
+        assert!(device.interrupt_pending());
+
+        device.write(REG_STATUS, 4, 0);
+        assert_eq!(device.read(REG_STATUS, 4), Some(0));
+        assert_eq!(device.read(REG_QUEUE_READY, 4), Some(0));
+        assert!(!device.interrupt_pending());
+    }
+}
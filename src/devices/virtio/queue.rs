@@ -0,0 +1,350 @@
+//! # Split Virtqueues
+//!
+//! A virtqueue transfers buffers between the driver and the device through three areas in
+//! guest memory, whose addresses the driver writes to the transport:
+//!
+//! - the descriptor table, with the address, length and flags of each buffer
+//! - the driver area (available ring), where the driver offers chains of descriptors
+//! - the device area (used ring), where the device returns them with the number of bytes
+//!   it wrote
+//!
+//! A chain consists of the buffers the device reads, followed by the buffers it writes.
+//! See also: [https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-270006]
+
+use std::ops::Range;
+
+use derive_more::{Display, Error};
+
+use crate::bus::GuestMemory;
+
+/// The largest number of descriptors a virtqueue can hold.
+pub const QUEUE_SIZE_MAX: u16 = 256;
+
+/// `VIRTQ_DESC_F_NEXT`: The chain continues with the descriptor in `next`.
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// `VIRTQ_DESC_F_WRITE`: The buffer is written by the device.
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// `VIRTQ_DESC_F_INDIRECT`: The buffer holds a table of descriptors. Not offered.
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+/// `VIRTQ_AVAIL_F_NO_INTERRUPT`: The driver does not want to be interrupted for used buffers.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// The size of a descriptor in the descriptor table.
+const DESCRIPTOR_SIZE: u64 = 16;
+/// The size of an element of the used ring.
+const USED_ELEMENT_SIZE: u64 = 8;
+
+/// An error in the virtqueue set up by the driver, after which the device needs a reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Error)]
+pub enum QueueError {
+    /// The virtqueue refers to memory outside of DRAM.
+    #[display("the virtqueue accesses memory outside of DRAM at {address:#x}")]
+    InvalidAddress { address: u64 },
+    /// A descriptor is out of range, loops, uses an unsupported feature, or is readable
+    /// after a writable descriptor.
+    #[display("the descriptor chain of the virtqueue is invalid at descriptor {index}")]
+    InvalidDescriptor { index: u16 },
+}
+
+/// A virtqueue in the split layout, configured by the driver through the transport.
+#[derive(Debug, Clone, Default)]
+pub struct Virtqueue {
+    /// The number of descriptors, a power of two.
+    pub size: u16,
+    /// Whether the driver finished the set up and the device may use the virtqueue.
+    pub ready: bool,
+    /// The address of the descriptor table.
+    pub descriptor_table: u64,
+    /// The address of the available ring.
+    pub driver_area: u64,
+    /// The address of the used ring.
+    pub device_area: u64,
+    /// The index in the available ring of the next chain to take.
+    next_available: u16,
+    /// The index in the used ring of the next chain to return.
+    next_used: u16,
+    /// Whether chains were returned since the last interrupt and the driver wants to know.
+    interrupt: bool,
+}
+
+/// The address and length of a buffer of a descriptor chain.
+type Buffer = (u64, u32);
+
+impl Virtqueue {
+    /// Creates a virtqueue that is not set up yet.
+    pub fn new() -> Self {
+        Virtqueue {
+            size: QUEUE_SIZE_MAX,
+            ..Virtqueue::default()
+        }
+    }
+
+    /// Takes the next chain the driver made available, if any.
+    pub fn pop(&mut self, memory: &GuestMemory) -> Result<Option<DescriptorChain>, QueueError> {
+        if !self.ready || self.size == 0 {
+            return Ok(None);
+        }
+        let available = read_u16(memory, self.driver_area + 2)?;
+        if available == self.next_available {
+            return Ok(None);
+        }
+        let slot = (self.next_available % self.size) as u64;
+        let head = read_u16(memory, self.driver_area + 4 + 2 * slot)?;
+        self.next_available = self.next_available.wrapping_add(1);
+
+        let mut chain = DescriptorChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+            read_position: 0,
+            written: 0,
+        };
+        let mut index = head;
+        // A chain has at most one descriptor per entry of the table, otherwise it loops
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(QueueError::InvalidDescriptor { index });
+            }
+            let descriptor = self.descriptor_table + DESCRIPTOR_SIZE * index as u64;
+            let address = read_u64(memory, descriptor)?;
+            let len = read_u32(memory, descriptor + 8)?;
+            let flags = read_u16(memory, descriptor + 12)?;
+            let next = read_u16(memory, descriptor + 14)?;
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                return Err(QueueError::InvalidDescriptor { index });
+            }
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((address, len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((address, len));
+            } else {
+                return Err(QueueError::InvalidDescriptor { index });
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(Some(chain));
+            }
+            index = next;
+        }
+        Err(QueueError::InvalidDescriptor { index })
+    }
+
+    /// Returns `chain` to the driver, with the number of bytes the device wrote into it.
+    pub fn push_used(
+        &mut self,
+        memory: &mut GuestMemory,
+        chain: &DescriptorChain,
+    ) -> Result<(), QueueError> {
+        let slot = (self.next_used % self.size) as u64;
+        let element = self.device_area + 4 + USED_ELEMENT_SIZE * slot;
+        write_u32(memory, element, chain.head as u32)?;
+        write_u32(memory, element + 4, chain.written as u32)?;
+        self.next_used = self.next_used.wrapping_add(1);
+        write_u16(memory, self.device_area + 2, self.next_used)?;
+
+        let flags = read_u16(memory, self.driver_area)?;
+        self.interrupt |= flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0;
+        Ok(())
+    }
+
+    /// Returns `true` once if chains were returned that the driver wants to be interrupted for.
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+}
+
+/// A chain of buffers taken from a [`Virtqueue`], which is read and written front to back.
+#[derive(Debug, Clone)]
+pub struct DescriptorChain {
+    /// The index of the first descriptor, which identifies the chain.
+    head: u16,
+    readable: Vec<Buffer>,
+    writable: Vec<Buffer>,
+    /// The number of bytes read so far.
+    read_position: usize,
+    /// The number of bytes written, up to the last byte written.
+    written: usize,
+}
+
+impl DescriptorChain {
+    /// Returns the number of bytes the device can read.
+    pub fn readable_len(&self) -> usize {
+        self.readable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Returns the number of bytes the device can write.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Returns the number of bytes that were not read yet.
+    pub fn remaining_readable(&self) -> usize {
+        self.readable_len() - self.read_position
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Reads the next bytes of the readable buffers into `buffer`.
+    /// Returns the number of bytes read, which is less than requested at the end of the chain.
+    pub fn read(&mut self, memory: &GuestMemory, buffer: &mut [u8]) -> Result<usize, QueueError> {
+        let count = for_each_part(
+            &self.readable,
+            self.read_position,
+            buffer.len(),
+            |address, part| memory.read(address, &mut buffer[part]),
+        )?;
+        self.read_position += count;
+        Ok(count)
+    }
+
+    /// Writes `data` after the bytes written so far.
+    /// Returns the number of bytes written, which is less than given at the end of the chain.
+    pub fn write(&mut self, memory: &mut GuestMemory, data: &[u8]) -> Result<usize, QueueError> {
+        self.write_at(memory, self.written, data)
+    }
+
+    /// Writes `data` at `position` of the writable buffers.
+    /// Returns the number of bytes written, which is less than given at the end of the chain.
+    pub fn write_at(
+        &mut self,
+        memory: &mut GuestMemory,
+        position: usize,
+        data: &[u8],
+    ) -> Result<usize, QueueError> {
+        let count = for_each_part(&self.writable, position, data.len(), |address, part| {
+            memory.write(address, &data[part])
+        })?;
+        if count > 0 {
+            self.written = self.written.max(position + count);
+        }
+        Ok(count)
+    }
+}
+
+/// Calls `access` with the address of each part of the `len` bytes at `position` of the
+/// concatenated `buffers`, and the range of the part within these bytes.
+/// Returns the number of bytes within the buffers.
+fn for_each_part(
+    buffers: &[Buffer],
+    position: usize,
+    len: usize,
+    mut access: impl FnMut(u64, Range<usize>) -> Option<()>,
+) -> Result<usize, QueueError> {
+    let mut done = 0;
+    let mut start = 0;
+    for &(address, size) in buffers {
+        let end = start + size as usize;
+        if done < len && position + done < end {
+            let skip = position + done - start;
+            let count = (end - start - skip).min(len - done);
+            let address = address.wrapping_add(skip as u64);
+            access(address, done..done + count).ok_or(QueueError::InvalidAddress { address })?;
+            done += count;
+        }
+        start = end;
+    }
+    Ok(done)
+}
+
+fn read_u16(memory: &GuestMemory, address: u64) -> Result<u16, QueueError> {
+    memory
+        .read_u16(address)
+        .ok_or(QueueError::InvalidAddress { address })
+}
+
+fn read_u32(memory: &GuestMemory, address: u64) -> Result<u32, QueueError> {
+    memory
+        .read_u32(address)
+        .ok_or(QueueError::InvalidAddress { address })
+}
+
+fn read_u64(memory: &GuestMemory, address: u64) -> Result<u64, QueueError> {
+    memory
+        .read_u64(address)
+        .ok_or(QueueError::InvalidAddress { address })
+}
+
+fn write_u16(memory: &mut GuestMemory, address: u64, value: u16) -> Result<(), QueueError> {
+    memory
+        .write_u16(address, value)
+        .ok_or(QueueError::InvalidAddress { address })
+}
+
+fn write_u32(memory: &mut GuestMemory, address: u64, value: u32) -> Result<(), QueueError> {
+    memory
+        .write_u32(address, value)
+        .ok_or(QueueError::InvalidAddress { address })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitored_memory::MonitoredMemory;
+
+    #[test]
+    /// Chains are taken from the available ring, read and written across their buffers and
+    /// returned in the used ring.
+    fn test_descriptor_chain() {
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(0x1000, &mut dram);
+        let mut queue = Virtqueue {
+            size: 4,
+            ready: true,
+            descriptor_table: 0x1000,
+            driver_area: 0x1100,
+            device_area: 0x1200,
+            ..Virtqueue::new()
+        };
+        // Descriptor 2 reads 3 bytes, and descriptors 0 and 3 write 2 and 4 bytes
+        let descriptors = [
+            (2, 0x1400, 3, VIRTQ_DESC_F_NEXT, 0),
+            (0, 0x1500, 2, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 3),
+            (3, 0x1600, 4, VIRTQ_DESC_F_WRITE, 0),
+        ];
+        for (index, address, len, flags, next) in descriptors {
+            let descriptor = 0x1000 + 16 * index;
+            memory
+                .write(descriptor, &u64::to_le_bytes(address))
+                .unwrap();
+            memory.write_u32(descriptor + 8, len).unwrap();
+            memory.write_u16(descriptor + 12, flags).unwrap();
+            memory.write_u16(descriptor + 14, next).unwrap();
+        }
+        memory.write(0x1400, b"abc").unwrap();
+        assert!(queue.pop(&memory).unwrap().is_none());
+        memory.write_u16(0x1104, 2).unwrap();
+        memory.write_u16(0x1102, 1).unwrap();
+
+        let mut chain = queue.pop(&memory).unwrap().unwrap();
+        assert!(queue.pop(&memory).unwrap().is_none());
+        assert_eq!((chain.readable_len(), chain.writable_len()), (3, 6));
+        let mut buffer = [0; 8];
+        assert_eq!(chain.read(&memory, &mut buffer), Ok(3));
+        assert_eq!(&buffer[..3], b"abc");
+        assert_eq!(chain.write(&mut memory, b"uvwxyz!"), Ok(6));
+        let mut written = [0; 4];
+        memory.read(0x1600, &mut written).unwrap();
+        assert_eq!(&written, b"wxyz");
+
+        queue.push_used(&mut memory, &chain).unwrap();
+        assert_eq!(memory.read_u16(0x1202), Some(1));
+        assert_eq!(memory.read_u32(0x1204), Some(2));
+        assert_eq!(memory.read_u32(0x1208), Some(6));
+        assert!(queue.take_interrupt());
+        assert!(!queue.take_interrupt());
+
+        // A chain that loops back to its head is rejected
+        memory
+            .write_u16(0x1000 + 16 * 3 + 12, VIRTQ_DESC_F_NEXT)
+            .unwrap();
+        memory.write_u16(0x1000 + 16 * 3 + 14, 2).unwrap();
+        memory.write_u16(0x1106, 2).unwrap();
+        memory.write_u16(0x1102, 2).unwrap();
+        assert!(matches!(
+            queue.pop(&memory),
+            Err(QueueError::InvalidDescriptor { .. })
+        ));
+    }
+}
@@ -0,0 +1,183 @@
+//! # Virtio Entropy Device
+//!
+//! This module implements a virtio-rng device, which fills the buffers of the driver with
+//! random bytes. The bytes come from a pseudo-random generator with a fixed seed, so runs of
+//! the VM are reproducible unless another seed is given, or `seed=random` asks for a seed
+//! from the host.
+//! See also: [https://docs.oasis-open.org/virtio/virtio/v1.2/csd01/virtio-v1.2-csd01.html#x1-3050004]
+
+use std::hash::{BuildHasher, RandomState};
+
+use crate::{
+    bus::GuestMemory,
+    devices::{
+        DeviceKind,
+        virtio::{
+            VirtioDevice, VirtioMmio,
+            queue::{QueueError, Virtqueue},
+        },
+    },
+    utils::parse_address,
+};
+
+/// A virtio entropy device, mapped after the virtio block and console devices.
+pub const KIND: DeviceKind = DeviceKind {
+    name: "virtio-rng",
+    description: "Virtio entropy device, options: seed=<number>|random",
+    default_base: 0x1000_3000,
+    size: 0x1000,
+    irq: Some(3),
+    options: &["seed"],
+    create: |_, spec| {
+        let seed = match spec.option("seed") {
+            None => DEFAULT_SEED,
+            Some("random") => RandomState::new().hash_one(std::time::SystemTime::now()),
+            Some(seed) => parse_address(seed).map_err(|error| format!("invalid seed: {error}"))?,
+        };
+        Ok(Box::new(VirtioMmio::new(Rng::new(seed))))
+    },
+};
+
+/// The device type of entropy devices.
+const DEVICE_ID: u32 = 4;
+/// The seed used unless another one is given.
+pub const DEFAULT_SEED: u64 = 0x5eed;
+
+/// A virtio entropy device with a single request queue.
+pub struct Rng {
+    seed: u64,
+    /// The state of the SplitMix64 generator.
+    state: u64,
+}
+
+impl Rng {
+    /// Creates an entropy device whose bytes are determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Rng { seed, state: seed }
+    }
+
+    /// Returns the next 8 random bytes.
+    /// See also: [https://prng.di.unimi.it/splitmix64.c]
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn name(&self) -> &str {
+        "virtio-rng"
+    }
+
+    fn device_id(&self) -> u32 {
+        DEVICE_ID
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queue_count(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, _offset: u64) -> u8 {
+        0
+    }
+
+    fn reset(&mut self) {
+        // The bytes repeat after a reset, like after power-on
+        self.state = self.seed;
+    }
+
+    fn process(
+        &mut self,
+        queues: &mut [Virtqueue],
+        memory: &mut GuestMemory,
+    ) -> Result<(), QueueError> {
+        let queue = &mut queues[0];
+        while let Some(mut chain) = queue.pop(memory)? {
+            // The length of the buffers is chosen by the driver, so the bytes are written as
+            // they are generated
+            let len = chain.writable_len();
+            while chain.written() < len {
+                chain.write(memory, &self.next().to_le_bytes())?;
+            }
+            queue.push_used(memory, &chain)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitored_memory::MonitoredMemory;
+
+    /// Requests 12 random bytes from `rng` and returns them.
+    fn request(rng: &mut Rng, memory: &mut GuestMemory, queue: &mut Virtqueue) -> [u8; 12] {
+        let index = memory.read_u16(0x102).unwrap();
+        memory.write(0x0, &0x800_u64.to_le_bytes()).unwrap();
+        memory.write_u32(0x8, 12).unwrap();
+        memory.write_u16(0xc, 2).unwrap();
+        memory.write_u16(0x104 + 2 * (index % 4) as u64, 0).unwrap();
+        memory.write_u16(0x102, index + 1).unwrap();
+
+        rng.process(std::slice::from_mut(queue), memory).unwrap();
+        assert_eq!(memory.read_u16(0x202), Some(index + 1));
+        assert_eq!(
+            memory.read_u32(0x204 + 8 * (index % 4) as u64 + 4),
+            Some(12)
+        );
+        let mut bytes = [0; 12];
+        memory.read(0x800, &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    /// The random bytes are determined by the seed and repeat after a reset.
+    fn test_seeded_bytes() {
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(0, &mut dram);
+        let mut queue = Virtqueue::new();
+        queue.size = 4;
+        queue.ready = true;
+        queue.driver_area = 0x100;
+        queue.device_area = 0x200;
+
+        let mut rng = Rng::new(DEFAULT_SEED);
+        let first = request(&mut rng, &mut memory, &mut queue);
+        let second = request(&mut rng, &mut memory, &mut queue);
+        assert_ne!(first, second);
+        rng.reset();
+        assert_eq!(request(&mut rng, &mut memory, &mut queue), first);
+
+        let mut other = Rng::new(DEFAULT_SEED + 1);
+        assert_ne!(request(&mut other, &mut memory, &mut queue), first);
+    }
+
+    #[test]
+    /// A buffer longer than the memory is reported as an error.
+    fn test_oversized_buffer() {
+        let mut dram = MonitoredMemory::new(0x1000).unwrap();
+        let mut memory = GuestMemory::new(0, &mut dram);
+        let mut queue = Virtqueue::new();
+        queue.size = 4;
+        queue.ready = true;
+        queue.driver_area = 0x100;
+        queue.device_area = 0x200;
+
+        memory.write(0x0, &0x800_u64.to_le_bytes()).unwrap();
+        memory.write_u32(0x8, u32::MAX).unwrap();
+        memory.write_u16(0xc, 2).unwrap();
+        memory.write_u16(0x102, 1).unwrap();
+        let mut rng = Rng::new(DEFAULT_SEED);
+        assert_eq!(
+            rng.process(std::slice::from_mut(&mut queue), &mut memory),
+            Err(QueueError::InvalidAddress { address: 0x1000 })
+        );
+    }
+}
@@ -0,0 +1,645 @@
+//! # Disassembler
+//!
+//! This module renders decoded instructions in the assembly syntax printed by
+//! `riscv64-unknown-elf-objdump -d`: a mnemonic, a tab and the comma separated operands,
+//! using the ABI register names and the common pseudo-instructions (`li`, `mv`, `ret`, `j`, ...).
+//! Branch and jump targets are printed as absolute addresses.
+//! See also: [https://github.com/riscv-non-isa/riscv-asm-manual/blob/main/src/asm-manual.adoc]
+
+use std::io::{self, Write};
+
+use goblin::elf::{Elf, section_header::SHF_EXECINSTR, sym::STT_SECTION};
+
+use crate::{
+    compressed,
+    constants::{FPR_ABI_NAMES, GPR_ABI_NAMES},
+    csr::{self, Xlen},
+    decoder::{
+        self, AluOp, BranchOp, CompareOp, CsrOp, FpFormat, Instruction, MinMaxOp, MulDivOp,
+        Register, SignInjectOp,
+    },
+};
+
+/// Returns the ABI name of the general purpose register `register`.
+#[inline]
+fn x(register: Register) -> &'static str {
+    GPR_ABI_NAMES[register as usize]
+}
+
+/// Returns the ABI name of the floating point register `register`.
+#[inline]
+fn f(register: Register) -> &'static str {
+    FPR_ABI_NAMES[register as usize]
+}
+
+/// Returns the name of a CSR, or its address for CSRs unknown to the VM.
+fn csr_name(address: u16) -> String {
+    match csr::name(address) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", address),
+    }
+}
+
+/// Returns the rounding mode operand, which is omitted for the dynamic rounding mode.
+fn rounding_mode(rm: u8) -> String {
+    match rm {
+        0b000 => ",rne".to_string(),
+        0b001 => ",rtz".to_string(),
+        0b010 => ",rdn".to_string(),
+        0b011 => ",rup".to_string(),
+        0b100 => ",rmm".to_string(),
+        0b111 => String::new(),
+        rm => format!(",{}", rm),
+    }
+}
+
+/// Returns the `iorw` names of a fence predecessor or successor set.
+fn fence_set(set: u8) -> String {
+    ["i", "o", "r", "w"]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| set & (0b1000 >> i) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Disassembles a decoded instruction located at `pc`.
+pub fn disassemble(instruction: &Instruction, pc: u64) -> String {
+    let target = |offset: i64| format!("{:x}", pc.wrapping_add(offset as u64));
+
+    let (mnemonic, operands) = match *instruction {
+        Instruction::Lui { rd, imm } => (
+            "lui".to_string(),
+            format!("{},0x{:x}", x(rd), imm >> 12 & 0xfffff),
+        ),
+        Instruction::Auipc { rd, imm } => (
+            "auipc".to_string(),
+            format!("{},0x{:x}", x(rd), imm >> 12 & 0xfffff),
+        ),
+        Instruction::Jal { rd: 0, offset } => ("j".to_string(), target(offset)),
+        Instruction::Jal { rd: 1, offset } => ("jal".to_string(), target(offset)),
+        Instruction::Jal { rd, offset } => {
+            ("jal".to_string(), format!("{},{}", x(rd), target(offset)))
+        }
+        Instruction::Jalr {
+            rd: 0,
+            rs1: 1,
+            offset: 0,
+        } => ("ret".to_string(), String::new()),
+        Instruction::Jalr {
+            rd: 0,
+            rs1,
+            offset: 0,
+        } => ("jr".to_string(), x(rs1).to_string()),
+        Instruction::Jalr {
+            rd: 1,
+            rs1,
+            offset: 0,
+        } => ("jalr".to_string(), x(rs1).to_string()),
+        Instruction::Jalr { rd, rs1, offset } => (
+            "jalr".to_string(),
+            format!("{},{}({})", x(rd), offset, x(rs1)),
+        ),
+        Instruction::Branch {
+            op,
+            rs1,
+            rs2,
+            offset,
+        } => match (op, rs1, rs2) {
+            (BranchOp::Beq, _, 0) => ("beqz".to_string(), format!("{},{}", x(rs1), target(offset))),
+            (BranchOp::Bne, _, 0) => ("bnez".to_string(), format!("{},{}", x(rs1), target(offset))),
+            (BranchOp::Blt, _, 0) => ("bltz".to_string(), format!("{},{}", x(rs1), target(offset))),
+            (BranchOp::Bge, _, 0) => ("bgez".to_string(), format!("{},{}", x(rs1), target(offset))),
+            (BranchOp::Blt, 0, _) => ("bgtz".to_string(), format!("{},{}", x(rs2), target(offset))),
+            (BranchOp::Bge, 0, _) => ("blez".to_string(), format!("{},{}", x(rs2), target(offset))),
+            _ => (
+                op.to_string(),
+                format!("{},{},{}", x(rs1), x(rs2), target(offset)),
+            ),
+        },
+        Instruction::Load {
+            op,
+            rd,
+            rs1,
+            offset,
+        } => (op.to_string(), format!("{},{}({})", x(rd), offset, x(rs1))),
+        Instruction::Store {
+            op,
+            rs1,
+            rs2,
+            offset,
+        } => (op.to_string(), format!("{},{}({})", x(rs2), offset, x(rs1))),
+        Instruction::OpImm { op, rd, rs1, imm } => match (op, rd, rs1, imm) {
+            (AluOp::Add, 0, 0, 0) => ("nop".to_string(), String::new()),
+            (AluOp::Add, _, 0, _) => ("li".to_string(), format!("{},{}", x(rd), imm)),
+            (AluOp::Add, _, _, 0) => ("mv".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::Xor, _, _, -1) => ("not".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::Sltu, _, _, 1) => ("seqz".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::And, _, _, 255) => ("zext.b".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::Sll | AluOp::Srl | AluOp::Sra, _, _, _) => (
+                format!("{}i", op),
+                format!("{},{},0x{:x}", x(rd), x(rs1), imm),
+            ),
+            _ => (format!("{}i", op), format!("{},{},{}", x(rd), x(rs1), imm)),
+        },
+        Instruction::Op { op, rd, rs1, rs2 } => match (op, rs1, rs2) {
+            // `c.mv` expands to an `add` from the zero register
+            (AluOp::Add, 0, _) => ("mv".to_string(), format!("{},{}", x(rd), x(rs2))),
+            (AluOp::Sub, 0, _) => ("neg".to_string(), format!("{},{}", x(rd), x(rs2))),
+            (AluOp::Sltu, 0, _) => ("snez".to_string(), format!("{},{}", x(rd), x(rs2))),
+            (AluOp::Slt, _, 0) => ("sltz".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::Slt, 0, _) => ("sgtz".to_string(), format!("{},{}", x(rd), x(rs2))),
+            _ => (op.to_string(), format!("{},{},{}", x(rd), x(rs1), x(rs2))),
+        },
+        Instruction::OpImm32 { op, rd, rs1, imm } => match (op, imm) {
+            (AluOp::Add, 0) => ("sext.w".to_string(), format!("{},{}", x(rd), x(rs1))),
+            (AluOp::Add, _) => ("addiw".to_string(), format!("{},{},{}", x(rd), x(rs1), imm)),
+            _ => (
+                format!("{}iw", op),
+                format!("{},{},0x{:x}", x(rd), x(rs1), imm),
+            ),
+        },
+        Instruction::Op32 { op, rd, rs1, rs2 } => match (op, rs1) {
+            (AluOp::Sub, 0) => ("negw".to_string(), format!("{},{}", x(rd), x(rs2))),
+            _ => (
+                format!("{}w", op),
+                format!("{},{},{}", x(rd), x(rs1), x(rs2)),
+            ),
+        },
+        Instruction::MulDiv { op, rd, rs1, rs2 } => {
+            (op.to_string(), format!("{},{},{}", x(rd), x(rs1), x(rs2)))
+        }
+        Instruction::MulDiv32 { op, rd, rs1, rs2 } => {
+            let mnemonic = match op {
+                MulDivOp::Mul => "mulw",
+                MulDivOp::Div => "divw",
+                MulDivOp::Divu => "divuw",
+                MulDivOp::Rem => "remw",
+                _ => "remuw",
+            };
+            (
+                mnemonic.to_string(),
+                format!("{},{},{}", x(rd), x(rs1), x(rs2)),
+            )
+        }
+        Instruction::Fence {
+            pred: 0xf,
+            succ: 0xf,
+            ..
+        } => ("fence".to_string(), String::new()),
+        Instruction::Fence {
+            fm: 0b1000,
+            pred: 0b0011,
+            succ: 0b0011,
+        } => ("fence.tso".to_string(), String::new()),
+        Instruction::Fence { pred, succ, .. } => (
+            "fence".to_string(),
+            format!("{},{}", fence_set(pred), fence_set(succ)),
+        ),
+        Instruction::FenceI => ("fence.i".to_string(), String::new()),
+        Instruction::LoadReserved {
+            width,
+            rd,
+            rs1,
+            aq,
+            rl,
+        } => (
+            format!("lr.{}{}", width, ordering(aq, rl)),
+            format!("{},({})", x(rd), x(rs1)),
+        ),
+        Instruction::StoreConditional {
+            width,
+            rd,
+            rs1,
+            rs2,
+            aq,
+            rl,
+        } => (
+            format!("sc.{}{}", width, ordering(aq, rl)),
+            format!("{},{},({})", x(rd), x(rs2), x(rs1)),
+        ),
+        Instruction::Amo {
+            op,
+            width,
+            rd,
+            rs1,
+            rs2,
+            aq,
+            rl,
+        } => (
+            format!("{}.{}{}", op, width, ordering(aq, rl)),
+            format!("{},{},({})", x(rd), x(rs2), x(rs1)),
+        ),
+        Instruction::LoadFp {
+            fmt,
+            rd,
+            rs1,
+            offset,
+        } => (
+            match fmt {
+                FpFormat::Single => "flw",
+                FpFormat::Double => "fld",
+            }
+            .to_string(),
+            format!("{},{}({})", f(rd), offset, x(rs1)),
+        ),
+        Instruction::StoreFp {
+            fmt,
+            rs1,
+            rs2,
+            offset,
+        } => (
+            match fmt {
+                FpFormat::Single => "fsw",
+                FpFormat::Double => "fsd",
+            }
+            .to_string(),
+            format!("{},{}({})", f(rs2), offset, x(rs1)),
+        ),
+        Instruction::FusedMultiplyAdd {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rs3,
+            rm,
+        } => (
+            format!("{}.{}", op, fmt),
+            format!(
+                "{},{},{},{}{}",
+                f(rd),
+                f(rs1),
+                f(rs2),
+                f(rs3),
+                rounding_mode(rm)
+            ),
+        ),
+        Instruction::FpArith {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+            rm,
+        } => (
+            format!("{}.{}", op, fmt),
+            format!("{},{},{}{}", f(rd), f(rs1), f(rs2), rounding_mode(rm)),
+        ),
+        Instruction::FpSqrt { fmt, rd, rs1, rm } => (
+            format!("fsqrt.{}", fmt),
+            format!("{},{}{}", f(rd), f(rs1), rounding_mode(rm)),
+        ),
+        Instruction::FpSignInject {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        } if rs1 == rs2 => (
+            match op {
+                SignInjectOp::Sgnj => format!("fmv.{}", fmt),
+                SignInjectOp::Sgnjn => format!("fneg.{}", fmt),
+                SignInjectOp::Sgnjx => format!("fabs.{}", fmt),
+            },
+            format!("{},{}", f(rd), f(rs1)),
+        ),
+        Instruction::FpSignInject {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        } => (
+            format!("{}.{}", op, fmt),
+            format!("{},{},{}", f(rd), f(rs1), f(rs2)),
+        ),
+        Instruction::FpMinMax {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        } => (
+            match op {
+                MinMaxOp::Min => format!("fmin.{}", fmt),
+                MinMaxOp::Max => format!("fmax.{}", fmt),
+            },
+            format!("{},{},{}", f(rd), f(rs1), f(rs2)),
+        ),
+        Instruction::FpCompare {
+            op,
+            fmt,
+            rd,
+            rs1,
+            rs2,
+        } => (
+            match op {
+                CompareOp::Eq => format!("feq.{}", fmt),
+                CompareOp::Lt => format!("flt.{}", fmt),
+                CompareOp::Le => format!("fle.{}", fmt),
+            },
+            format!("{},{},{}", x(rd), f(rs1), f(rs2)),
+        ),
+        Instruction::FpClassify { fmt, rd, rs1 } => {
+            (format!("fclass.{}", fmt), format!("{},{}", x(rd), f(rs1)))
+        }
+        Instruction::FpToInt {
+            fmt,
+            int,
+            rd,
+            rs1,
+            rm,
+        } => (
+            format!("fcvt.{}.{}", int, fmt),
+            format!("{},{}{}", x(rd), f(rs1), rounding_mode(rm)),
+        ),
+        Instruction::IntToFp {
+            fmt,
+            int,
+            rd,
+            rs1,
+            rm,
+        } => (
+            format!("fcvt.{}.{}", fmt, int),
+            format!("{},{}{}", f(rd), x(rs1), rounding_mode(rm)),
+        ),
+        Instruction::FpConvert { fmt, rd, rs1, rm } => (
+            match fmt {
+                FpFormat::Single => "fcvt.s.d",
+                FpFormat::Double => "fcvt.d.s",
+            }
+            .to_string(),
+            format!("{},{}{}", f(rd), f(rs1), rounding_mode(rm)),
+        ),
+        Instruction::FpMoveToInt { fmt, rd, rs1 } => (
+            match fmt {
+                FpFormat::Single => "fmv.x.w",
+                FpFormat::Double => "fmv.x.d",
+            }
+            .to_string(),
+            format!("{},{}", x(rd), f(rs1)),
+        ),
+        Instruction::FpMoveFromInt { fmt, rd, rs1 } => (
+            match fmt {
+                FpFormat::Single => "fmv.w.x",
+                FpFormat::Double => "fmv.d.x",
+            }
+            .to_string(),
+            format!("{},{}", f(rd), x(rs1)),
+        ),
+        Instruction::Csr { op, rd, rs1, csr } => disassemble_csr(op, rd, x(rs1), rs1 == 0, csr),
+        Instruction::CsrImm { op, rd, uimm, csr } => {
+            let (mnemonic, operands) = disassemble_csr(op, rd, &uimm.to_string(), false, csr);
+            // The pseudo-instructions of the immediate forms end in `i`
+            match mnemonic.as_str() {
+                "csrw" | "csrs" | "csrc" | "fsrm" | "fsflags" => {
+                    (format!("{}i", mnemonic), operands)
+                }
+                // There is no immediate form of `fscsr`
+                "fscsr" if rd == 0 => ("csrwi".to_string(), format!("{},{}", csr_name(csr), uimm)),
+                _ => (
+                    format!("{}i", op),
+                    format!("{},{},{}", x(rd), csr_name(csr), uimm),
+                ),
+            }
+        }
+        Instruction::Ecall => ("ecall".to_string(), String::new()),
+        Instruction::Ebreak => ("ebreak".to_string(), String::new()),
+        Instruction::Mret => ("mret".to_string(), String::new()),
+        Instruction::Sret => ("sret".to_string(), String::new()),
+        Instruction::Wfi => ("wfi".to_string(), String::new()),
+        Instruction::SfenceVma { rs1: 0, rs2: 0 } => ("sfence.vma".to_string(), String::new()),
+        Instruction::SfenceVma { rs1, rs2: 0 } => ("sfence.vma".to_string(), x(rs1).to_string()),
+        Instruction::SfenceVma { rs1, rs2 } => {
+            ("sfence.vma".to_string(), format!("{},{}", x(rs1), x(rs2)))
+        }
+    };
+
+    if operands.is_empty() {
+        mnemonic
+    } else {
+        format!("{}\t{}", mnemonic, operands)
+    }
+}
+
+/// Returns the `.aq`, `.rl` or `.aqrl` suffix of atomic instructions.
+fn ordering(aq: bool, rl: bool) -> &'static str {
+    match (aq, rl) {
+        (false, false) => "",
+        (true, false) => ".aq",
+        (false, true) => ".rl",
+        (true, true) => ".aqrl",
+    }
+}
+
+/// Disassembles the Zicsr instructions with the pseudo-instructions for reading and writing
+/// CSRs, where `source` is either the source register or the immediate.
+fn disassemble_csr(
+    op: CsrOp,
+    rd: Register,
+    source: &str,
+    is_zero_register: bool,
+    csr: u16,
+) -> (String, String) {
+    let name = csr_name(csr);
+    match (op, rd, is_zero_register) {
+        (CsrOp::Write, 0, true) if csr == csr::CYCLE => ("unimp".to_string(), String::new()),
+        (CsrOp::Set, _, true) => match csr {
+            csr::CYCLE => ("rdcycle".to_string(), x(rd).to_string()),
+            csr::TIME => ("rdtime".to_string(), x(rd).to_string()),
+            csr::INSTRET => ("rdinstret".to_string(), x(rd).to_string()),
+            csr::CYCLEH => ("rdcycleh".to_string(), x(rd).to_string()),
+            csr::TIMEH => ("rdtimeh".to_string(), x(rd).to_string()),
+            csr::INSTRETH => ("rdinstreth".to_string(), x(rd).to_string()),
+            csr::FCSR => ("frcsr".to_string(), x(rd).to_string()),
+            csr::FRM => ("frrm".to_string(), x(rd).to_string()),
+            csr::FFLAGS => ("frflags".to_string(), x(rd).to_string()),
+            _ => ("csrr".to_string(), format!("{},{}", x(rd), name)),
+        },
+        (CsrOp::Write, _, _) if matches!(csr, csr::FCSR | csr::FRM | csr::FFLAGS) => {
+            let mnemonic = match csr {
+                csr::FCSR => "fscsr",
+                csr::FRM => "fsrm",
+                _ => "fsflags",
+            };
+            let operands = match rd {
+                0 => source.to_string(),
+                _ => format!("{},{}", x(rd), source),
+            };
+            (mnemonic.to_string(), operands)
+        }
+        (CsrOp::Write, 0, _) => ("csrw".to_string(), format!("{},{}", name, source)),
+        (CsrOp::Set, 0, _) => ("csrs".to_string(), format!("{},{}", name, source)),
+        (CsrOp::Clear, 0, _) => ("csrc".to_string(), format!("{},{}", name, source)),
+        _ => (op.to_string(), format!("{},{},{}", x(rd), name, source)),
+    }
+}
+
+/// Disassembles the executable sections of an ELF file, in the format of `objdump -d`.
+pub fn disassemble_elf(program: &[u8], out: &mut impl Write) -> anyhow::Result<()> {
+    let elf = Elf::parse(program)?;
+    let xlen = if elf.is_64 { Xlen::Rv64 } else { Xlen::Rv32 };
+
+    for section in elf
+        .section_headers
+        .iter()
+        .filter(|section| section.sh_flags & SHF_EXECINSTR as u64 != 0)
+    {
+        let name = elf.shdr_strtab.get_at(section.sh_name).unwrap_or("");
+        let bytes = section
+            .file_range()
+            .and_then(|range| program.get(range))
+            .ok_or_else(|| anyhow::anyhow!("Section {} exceeds the file", name))?;
+
+        writeln!(out, "\nDisassembly of section {}:", name)?;
+        disassemble_bytes(&elf, xlen, bytes, section.sh_addr, out)?;
+    }
+
+    Ok(())
+}
+
+/// Disassembles `bytes` located at `address`, printing labels for the symbols of `elf`.
+fn disassemble_bytes(
+    elf: &Elf,
+    xlen: Xlen,
+    bytes: &[u8],
+    address: u64,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut offset = 0;
+    while offset + 2 <= bytes.len() {
+        let pc = address + offset as u64;
+
+        for symbol in elf
+            .syms
+            .iter()
+            .filter(|symbol| symbol.st_value == pc && symbol.st_type() != STT_SECTION)
+        {
+            if let Some(name) = elf
+                .strtab
+                .get_at(symbol.st_name)
+                .filter(|name| !name.is_empty())
+            {
+                let width = xlen.bits() as usize / 4;
+                writeln!(out, "\n{:0width$x} <{}>:", pc, name)?;
+            }
+        }
+
+        let low_parcel = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        if compressed::is_compressed(low_parcel) {
+            let text = match compressed::expand(low_parcel, xlen).map(decoder::decode) {
+                Some(Ok(instruction)) => disassemble(&instruction, pc),
+                _ => format!(".2byte\t0x{:x}", low_parcel),
+            };
+            writeln!(
+                out,
+                "{:8x}:\t{:04x}                \t{}",
+                pc, low_parcel, text
+            )?;
+            offset += 2;
+        } else if let Some(high_bytes) = bytes.get(offset + 2..offset + 4) {
+            let raw = u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                high_bytes[0],
+                high_bytes[1],
+            ]);
+            let text = match decoder::decode(raw) {
+                Ok(instruction) => disassemble(&instruction, pc),
+                Err(_) => format!(".4byte\t0x{:x}", raw),
+            };
+            writeln!(out, "{:8x}:\t{:08x}          \t{}", pc, raw, text)?;
+            offset += 4;
+        } else {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble_raw(raw: u32, pc: u64) -> String {
+        disassemble(&decoder::decode(raw).unwrap(), pc)
+    }
+
+    #[test]
+    /// Tests the disassembly of regular instructions against the output of `objdump`
+    fn test_disassemble() {
+        let cases = [
+            (0x00000517, "auipc\ta0,0x0"),
+            (0x123452b7, "lui\tt0,0x12345"),
+            (0xff010113, "addi\tsp,sp,-16"),
+            (0x00113423, "sd\tra,8(sp)"),
+            (0xff843283, "ld\tt0,-8(s0)"),
+            (0x02079793, "slli\ta5,a5,0x20"),
+            (0x40b50533, "sub\ta0,a0,a1"),
+            (0x02b5453b, "divw\ta0,a0,a1"),
+            (0x0eb5362f, "amoswap.d.aqrl\ta2,a1,(a0)"),
+            (0x1005272f, "lr.w\ta4,(a0)"),
+            (0x62b57543, "fmadd.d\tfa0,fa0,fa1,fa2"),
+            (0xc0251553, "fcvt.l.s\ta0,fa0,rtz"),
+            (0xa2b52553, "feq.d\ta0,fa0,fa1"),
+            (0x30529073, "csrw\tmtvec,t0"),
+            (0x34015573, "csrrwi\ta0,mscratch,2"),
+            (0x30046073, "csrsi\tmstatus,8"),
+            (0x7c0022f3, "csrr\tt0,0x7c0"),
+            (0x0230000f, "fence\tr,rw"),
+            (0x8330000f, "fence.tso"),
+            (0xc0102573, "rdtime\ta0"),
+            (0xc8102573, "rdtimeh\ta0"),
+            (0x00351073, "fscsr\ta0"),
+            (0x003515f3, "fscsr\ta1,a0"),
+            (0x00251073, "fsrm\ta0"),
+            (0x00151073, "fsflags\ta0"),
+            (0x00215073, "fsrmi\t2"),
+            (0x002155f3, "fsrmi\ta1,2"),
+            (0x0010d073, "fsflagsi\t1"),
+            (0x00315073, "csrwi\tfcsr,2"),
+            (0x30200073, "mret"),
+            (0x12050073, "sfence.vma\ta0"),
+            (0x12b50073, "sfence.vma\ta0,a1"),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(disassemble_raw(raw, 0), text, "disassembling {:#010x}", raw);
+        }
+    }
+
+    #[test]
+    /// Tests the recognition of pseudo-instructions and absolute branch targets
+    fn test_disassemble_pseudo_instructions() {
+        let cases = [
+            (0x00000013, "nop"),
+            (0x02a00513, "li\ta0,42"),
+            (0x00058513, "mv\ta0,a1"),
+            (0xfff54513, "not\ta0,a0"),
+            (0x00153513, "seqz\ta0,a0"),
+            (0x40a00533, "neg\ta0,a0"),
+            (0x00a005b3, "mv\ta1,a0"),
+            (0x0005051b, "sext.w\ta0,a0"),
+            (0x00008067, "ret"),
+            (0x00050067, "jr\ta0"),
+            (0x000500e7, "jalr\ta0"),
+            (0x0100006f, "j\t80000010"),
+            (0xff9ff0ef, "jal\t7ffffff8"),
+            (0x00050863, "beqz\ta0,80000010"),
+            (0xfea04ee3, "bgtz\ta0,7ffffffc"),
+            (0x20b58553, "fmv.s\tfa0,fa1"),
+            (0xc00022f3, "rdcycle\tt0"),
+            (0xc0001073, "unimp"),
+            (0x0ff0000f, "fence"),
+        ];
+        for (raw, text) in cases {
+            assert_eq!(
+                disassemble_raw(raw, 0x8000_0000),
+                text,
+                "disassembling {:#010x}",
+                raw
+            );
+        }
+    }
+}
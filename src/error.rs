@@ -0,0 +1,35 @@
+//! # Errors
+//!
+//! This module defines the errors that stop the virtual machine. Unlike [`Exception`]s, which
+//! are handled by the guest program itself, these are reported to the embedding host.
+
+use derive_more::{Display, Error};
+
+use crate::trap::Exception;
+
+/// An error that stops the execution of the virtual machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Error)]
+pub enum VmError {
+    /// An illegal or unimplemented instruction was executed without a trap handler.
+    #[display("unimplemented opcode at {pc:#x}: {raw:#010x}")]
+    UnimplementedOpcode { pc: u64, raw: u32 },
+    /// The program accessed memory outside of the physical memory without a trap handler.
+    #[display("memory access out of bounds: {len} bytes at {addr:#x}")]
+    MemoryOutOfBounds { addr: u64, len: u64 },
+    /// The program wrote text that is not valid UTF-8 via the `ECALL_WRITE` system call.
+    #[display("output is not valid UTF-8")]
+    InvalidUtf8Output,
+    /// The program made a system call the virtual machine does not implement.
+    #[display("unknown system call: {number}")]
+    UnknownSyscall { number: u64 },
+    /// Any other exception was raised without a trap handler.
+    #[display("unhandled trap at {pc:#x}: {exception}")]
+    UnhandledTrap {
+        #[error(not(source))]
+        exception: Exception,
+        pc: u64,
+    },
+    /// The CPU was ticked after it halted.
+    #[display("the CPU is halted")]
+    Halted,
+}
@@ -0,0 +1,423 @@
+//! # Flattened Device Tree
+//!
+//! This module generates the device tree blob (DTB) that describes the machine to the program,
+//! from the live configuration of the hart and the devices attached to the bus. Following the
+//! RISC-V boot convention, the blob is placed in memory and its address is passed in `a1`,
+//! with the id of the hart in `a0`.
+//!
+//! The blob consists of a header, a memory reservation block that keeps the program from
+//! reusing the memory of the blob, the structure block with the nodes and their properties,
+//! and the strings block with the property names. All values are big-endian.
+//! See also: [https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html]
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    bus::Bus,
+    csr::{CsrFile, MISA, PrivilegeMode, Xlen},
+    devices::MAX_IRQ,
+};
+
+/// The magic number at the start of the header.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The version of the format.
+const FDT_VERSION: u32 = 17;
+/// The oldest version the format is compatible with.
+const FDT_LAST_COMPATIBLE_VERSION: u32 = 16;
+/// The size of the header in bytes.
+const FDT_HEADER_SIZE: usize = 40;
+
+/// Structure block token: the start of a node, followed by its name.
+const FDT_BEGIN_NODE: u32 = 0x1;
+/// Structure block token: the end of a node.
+const FDT_END_NODE: u32 = 0x2;
+/// Structure block token: a property, followed by its length, name offset and value.
+const FDT_PROP: u32 = 0x3;
+/// Structure block token: the end of the structure block.
+const FDT_END: u32 = 0x9;
+
+/// The frequency of the timer in Hz. The timer advances with every cycle, so the time seen by
+/// the program only matches the time of the host if the VM executes at this frequency.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The frequency of the clock of the UART in Hz, as on the QEMU `virt` machine.
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+/// The phandle of the interrupt controller of the hart.
+const CPU_INTC_PHANDLE: u32 = 1;
+/// The phandle of the PLIC.
+const PLIC_PHANDLE: u32 = 2;
+
+/// Writes the nodes and properties of a device tree into a blob.
+#[derive(Debug, Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// The offsets of the property names in the strings block.
+    string_offsets: HashMap<String, u32>,
+    /// The number of nodes that are not yet ended.
+    depth: usize,
+    /// The address the blob is placed at, if it reserves its own memory.
+    blob_address: Option<u64>,
+}
+
+impl FdtWriter {
+    /// Creates a writer for an empty device tree. The first node must be the root node.
+    pub fn new() -> Self {
+        FdtWriter::default()
+    }
+
+    /// Adds a memory reservation entry for the blob itself, once it is placed at `address`.
+    pub fn reserve_blob(&mut self, address: u64) {
+        self.blob_address = Some(address);
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Appends `bytes` to the structure block, padded with zeros to a multiple of 4 bytes.
+    fn push_padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        let padding = bytes.len().next_multiple_of(4) - bytes.len();
+        self.structure.extend(std::iter::repeat_n(0, padding));
+    }
+
+    /// Returns the offset of `name` in the strings block, adding it if necessary.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.string_offsets.get(name) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    /// Starts a node called `name`, which contains the following properties and nodes
+    /// until [`FdtWriter::end_node`].
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_padded(&bytes);
+        self.depth += 1;
+    }
+
+    /// Ends the node started last.
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Adds the property `name` with the raw `value` to the current node.
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.push_padded(value);
+    }
+
+    /// Adds a property without a value, e.g. `interrupt-controller`.
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    /// Adds a property with a single 32-bit cell.
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Adds a property with a list of 32-bit cells.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    /// Adds a property with a list of 64-bit values, each taking two cells.
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property(name, &bytes);
+    }
+
+    /// Adds a property with a null-terminated string.
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// Adds a property with a list of null-terminated strings.
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Returns the blob of the device tree. All nodes must be ended.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "all nodes must be ended");
+        self.push_u32(FDT_END);
+
+        // Each reservation entry holds an address and a size, the last entry is empty
+        let entries = usize::from(self.blob_address.is_some()) + 1;
+        let reservations_offset = FDT_HEADER_SIZE;
+        let structure_offset = reservations_offset + 16 * entries;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            reservations_offset as u32,
+            FDT_VERSION,
+            FDT_LAST_COMPATIBLE_VERSION,
+            // The id of the boot hart
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        if let Some(address) = self.blob_address {
+            blob.extend_from_slice(&address.to_be_bytes());
+            blob.extend_from_slice(&(total_size as u64).to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Returns the ISA string of the hart, e.g. `rv64imafdc`, from the extensions in `misa`.
+fn isa_string(csrs: &CsrFile) -> String {
+    let misa = csrs.read(MISA, PrivilegeMode::Machine).unwrap_or(0);
+    let base = match csrs.xlen {
+        Xlen::Rv32 => "rv32",
+        Xlen::Rv64 => "rv64",
+    };
+    // The extensions are listed in the canonical order of the ISA manual
+    let extensions = "IMAFDQC"
+        .chars()
+        .filter(|letter| misa & 1 << (*letter as u8 - b'A') != 0)
+        .map(|letter| letter.to_ascii_lowercase());
+    base.chars().chain(extensions).collect()
+}
+
+/// Returns the `reg` property of a device, with two cells for the address and the size.
+fn reg(range: &Range<u64>) -> [u64; 2] {
+    [range.start, range.end - range.start]
+}
+
+/// Generates the device tree of the hart described by `csrs` and the DRAM and devices of
+/// `bus`, to be placed at `address`. Devices the device tree has no binding for are left out.
+pub fn generate(bus: &Bus, csrs: &CsrFile, address: u64) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.reserve_blob(address);
+    let devices: Vec<_> = bus.devices().collect();
+    let has_plic = devices.iter().any(|(_, _, device)| device.name() == "plic");
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-vm");
+    fdt.property_string("model", "riscv-vm");
+
+    fdt.begin_node("chosen");
+    if let Some((range, _, _)) = devices
+        .iter()
+        .find(|(_, _, device)| device.name() == "uart")
+    {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", range.start));
+    }
+    fdt.end_node();
+
+    let dram = bus.dram_range();
+    fdt.begin_node(&format!("memory@{:x}", dram.start));
+    fdt.property_string("device_type", "memory");
+    fdt.property_u64s("reg", &reg(&dram));
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", csrs.mhartid as u32);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &isa_string(csrs));
+    // Sv32 is not supported, so RV32 harts have no MMU
+    if csrs.xlen == Xlen::Rv64 {
+        fdt.property_string("mmu-type", "riscv,sv48");
+    }
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+    for (range, irq, device) in &devices {
+        match device.name() {
+            "clint" => {
+                fdt.begin_node(&format!("clint@{:x}", range.start));
+                fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+                fdt.property_u64s("reg", &reg(range));
+                // The machine software and timer interrupts of the hart
+                fdt.property_cells(
+                    "interrupts-extended",
+                    &[CPU_INTC_PHANDLE, 3, CPU_INTC_PHANDLE, 7],
+                );
+            }
+            "plic" => {
+                fdt.begin_node(&format!("plic@{:x}", range.start));
+                fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+                fdt.property_u64s("reg", &reg(range));
+                fdt.property_u32("#address-cells", 0);
+                fdt.property_u32("#interrupt-cells", 1);
+                fdt.property_empty("interrupt-controller");
+                // The contexts raise the machine and supervisor external interrupts
+                fdt.property_cells(
+                    "interrupts-extended",
+                    &[CPU_INTC_PHANDLE, 11, CPU_INTC_PHANDLE, 9],
+                );
+                fdt.property_u32("riscv,ndev", MAX_IRQ);
+                fdt.property_u32("phandle", PLIC_PHANDLE);
+            }
+            "uart" => {
+                fdt.begin_node(&format!("serial@{:x}", range.start));
+                fdt.property_string("compatible", "ns16550a");
+                fdt.property_u64s("reg", &reg(range));
+                fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+            }
+            name if name.starts_with("virtio-") => {
+                fdt.begin_node(&format!("virtio_mmio@{:x}", range.start));
+                fdt.property_string("compatible", "virtio,mmio");
+                fdt.property_u64s("reg", &reg(range));
+            }
+            name => {
+                log::debug!("The device tree has no binding for device {}", name);
+                continue;
+            }
+        }
+        if let Some(irq) = irq
+            && has_plic
+        {
+            fdt.property_u32("interrupts", *irq);
+            fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        }
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::DEFAULT_DRAM_BASE,
+        devices::{DeviceContext, DeviceRegistry},
+    };
+
+    /// Reads the big-endian word at `offset` of `blob`.
+    fn word(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    /// Nodes and properties are written into the blocks of the blob, sharing property names.
+    fn test_writer() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpus");
+        fdt.property_u32("#size-cells", 0);
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        assert_eq!(word(&blob, 0), FDT_MAGIC);
+        assert_eq!(word(&blob, 4) as usize, blob.len());
+        let structure = word(&blob, 8) as usize;
+        let strings = word(&blob, 12) as usize;
+        assert_eq!(&blob[strings..], b"#size-cells\0");
+
+        let tokens: Vec<u32> = (structure..strings)
+            .step_by(4)
+            .map(|offset| word(&blob, offset))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                FDT_BEGIN_NODE,
+                0,
+                FDT_PROP,
+                4,
+                0,
+                2,
+                FDT_BEGIN_NODE,
+                u32::from_be_bytes(*b"cpus"),
+                0,
+                FDT_PROP,
+                4,
+                0,
+                0,
+                FDT_END_NODE,
+                FDT_END_NODE,
+                FDT_END
+            ]
+        );
+    }
+
+    #[test]
+    /// The device tree describes the hart, the DRAM and the attached devices.
+    fn test_generate() {
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 0x10_0000).unwrap();
+        let registry = DeviceRegistry::new();
+        for spec in ["clint", "plic", "uart"] {
+            registry
+                .attach(&mut bus, &spec.parse().unwrap(), &DeviceContext::default())
+                .unwrap();
+        }
+        let blob = generate(&bus, &CsrFile::new(), 0x8000_1000);
+        let contains = |text: &str| {
+            blob.windows(text.len())
+                .any(|window| window == text.as_bytes())
+        };
+
+        assert_eq!(word(&blob, 0), FDT_MAGIC);
+        assert_eq!(word(&blob, 4) as usize, blob.len());
+        // The blob reserves its own memory
+        let reservations = word(&blob, 16) as usize;
+        let entry: Vec<u32> = (0..6)
+            .map(|index| word(&blob, reservations + 4 * index))
+            .collect();
+        assert_eq!(entry, [0, 0x8000_1000, 0, blob.len() as u32, 0, 0]);
+        assert!(contains("memory@80000000\0"));
+        assert!(contains("rv64imafdc\0"));
+        assert!(contains("clint@2000000\0"));
+        assert!(contains("plic@c000000\0"));
+        assert!(contains("/soc/serial@10000000\0"));
+        assert!(contains("ns16550a\0"));
+    }
+}
@@ -0,0 +1,669 @@
+//! # Floating Point Helpers
+//!
+//! This module implements the IEEE 754 semantics required by the RISC-V F and D extensions
+//! on top of the host's `f32` and `f64` types. The host always rounds to nearest, ties to even,
+//! so every operation also computes the rounding error, which is used to correct the result
+//! for the other rounding modes and to derive the accrued exception flags.
+//! See also: [https://five-embeddev.com/riscv-user-isa-manual/Priv-v1.12/f.html]
+
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Invalid operation.
+pub const FLAG_NV: u32 = 0b10000;
+/// Divide by zero.
+pub const FLAG_DZ: u32 = 0b01000;
+/// Overflow.
+pub const FLAG_OF: u32 = 0b00100;
+/// Underflow.
+pub const FLAG_UF: u32 = 0b00010;
+/// Inexact.
+pub const FLAG_NX: u32 = 0b00001;
+
+/// The rounding modes encoded in the `rm` field of instructions and in `fcsr.frm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even (`rne`).
+    NearestEven,
+    /// Round towards zero (`rtz`).
+    TowardsZero,
+    /// Round down, towards negative infinity (`rdn`).
+    Down,
+    /// Round up, towards positive infinity (`rup`).
+    Up,
+    /// Round to nearest, ties to max magnitude (`rmm`).
+    NearestMaxMagnitude,
+}
+
+impl RoundingMode {
+    /// Returns the rounding mode for a 3-bit `rm` encoding.
+    /// The reserved encodings and the dynamic mode (`0b111`) return [`None`].
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            0b000 => Some(RoundingMode::NearestEven),
+            0b001 => Some(RoundingMode::TowardsZero),
+            0b010 => Some(RoundingMode::Down),
+            0b011 => Some(RoundingMode::Up),
+            0b100 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// A floating point format supported by the FPU.
+pub trait Float:
+    Copy
+    + PartialEq
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    /// The canonical quiet NaN produced by all operations that generate a NaN.
+    const CANONICAL_NAN: Self;
+    const ZERO: Self;
+    const MAX: Self;
+    const INFINITY: Self;
+    const MIN_POSITIVE: Self;
+    /// The smallest positive subnormal number.
+    const MIN_SUBNORMAL: Self;
+
+    /// Reads the value from a 64-bit floating point register, undoing the NaN-boxing.
+    fn from_register(value: u64) -> Self;
+    /// Converts the value into a 64-bit floating point register value, applying the NaN-boxing.
+    fn to_register(self) -> u64;
+
+    fn is_nan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_finite(self) -> bool;
+    fn is_subnormal(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    /// Returns `true` if the value is a signaling NaN, i.e. the most significant
+    /// bit of the mantissa is cleared.
+    fn is_signaling(self) -> bool;
+    fn abs(self) -> Self;
+    fn copysign(self, sign: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn sqrt(self) -> Self;
+    fn round_ties_even(self) -> Self;
+    fn trunc(self) -> Self;
+    fn floor(self) -> Self;
+    fn ceil(self) -> Self;
+    fn round(self) -> Self;
+    /// Converts to an integer, saturating at the bounds of [`i128`].
+    fn to_i128(self) -> i128;
+    /// Converts from an integer, rounding to nearest, ties to even.
+    fn from_i128(value: i128) -> Self;
+}
+
+macro_rules! impl_float {
+    ($ty:ty, $bits:ty, $canonical_nan:expr, $quiet_bit:expr, $nan_box:expr) => {
+        impl Float for $ty {
+            const CANONICAL_NAN: Self = <$ty>::from_bits($canonical_nan);
+            const ZERO: Self = 0.0;
+            const MAX: Self = <$ty>::MAX;
+            const INFINITY: Self = <$ty>::INFINITY;
+            const MIN_POSITIVE: Self = <$ty>::MIN_POSITIVE;
+            const MIN_SUBNORMAL: Self = <$ty>::from_bits(1);
+
+            fn from_register(value: u64) -> Self {
+                // Values narrower than the register are only valid if all upper bits are set,
+                // otherwise they are interpreted as the canonical NaN.
+                #[allow(clippy::bad_bit_mask)]
+                if value & $nan_box != $nan_box {
+                    return Self::CANONICAL_NAN;
+                }
+                <$ty>::from_bits(value as $bits)
+            }
+
+            fn to_register(self) -> u64 {
+                $nan_box | self.to_bits() as u64
+            }
+
+            fn is_nan(self) -> bool {
+                <$ty>::is_nan(self)
+            }
+
+            fn is_infinite(self) -> bool {
+                <$ty>::is_infinite(self)
+            }
+
+            fn is_finite(self) -> bool {
+                <$ty>::is_finite(self)
+            }
+
+            fn is_subnormal(self) -> bool {
+                <$ty>::is_subnormal(self)
+            }
+
+            fn is_sign_negative(self) -> bool {
+                <$ty>::is_sign_negative(self)
+            }
+
+            fn is_signaling(self) -> bool {
+                <$ty>::is_nan(self) && self.to_bits() & $quiet_bit == 0
+            }
+
+            fn abs(self) -> Self {
+                <$ty>::abs(self)
+            }
+
+            fn copysign(self, sign: Self) -> Self {
+                <$ty>::copysign(self, sign)
+            }
+
+            fn next_up(self) -> Self {
+                <$ty>::next_up(self)
+            }
+
+            fn next_down(self) -> Self {
+                <$ty>::next_down(self)
+            }
+
+            fn mul_add(self, a: Self, b: Self) -> Self {
+                <$ty>::mul_add(self, a, b)
+            }
+
+            fn sqrt(self) -> Self {
+                <$ty>::sqrt(self)
+            }
+
+            fn round_ties_even(self) -> Self {
+                <$ty>::round_ties_even(self)
+            }
+
+            fn trunc(self) -> Self {
+                <$ty>::trunc(self)
+            }
+
+            fn floor(self) -> Self {
+                <$ty>::floor(self)
+            }
+
+            fn ceil(self) -> Self {
+                <$ty>::ceil(self)
+            }
+
+            fn round(self) -> Self {
+                <$ty>::round(self)
+            }
+
+            fn to_i128(self) -> i128 {
+                self as i128
+            }
+
+            fn from_i128(value: i128) -> Self {
+                value as $ty
+            }
+        }
+    };
+}
+
+impl_float!(f32, u32, 0x7fc0_0000, 1 << 22, 0xffff_ffff_0000_0000);
+impl_float!(f64, u64, 0x7ff8_0000_0000_0000, 1 << 51, 0);
+
+/// Returns the canonical NaN for an operation with at least one NaN operand,
+/// raising the invalid operation flag if any operand is a signaling NaN.
+fn propagate_nan<F: Float>(operands: &[F]) -> (F, u32) {
+    let flags = if operands.iter().any(|operand| operand.is_signaling()) {
+        FLAG_NV
+    } else {
+        0
+    };
+    (F::CANONICAL_NAN, flags)
+}
+
+/// Returns the result of an overflowing operation with the given sign.
+fn overflow<F: Float>(negative: bool, rm: RoundingMode) -> (F, u32) {
+    let to_infinity = match rm {
+        RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+        RoundingMode::TowardsZero => false,
+        RoundingMode::Down => negative,
+        RoundingMode::Up => !negative,
+    };
+    let magnitude = if to_infinity { F::INFINITY } else { F::MAX };
+    let value = if negative { -magnitude } else { magnitude };
+    (value, FLAG_OF | FLAG_NX)
+}
+
+/// Corrects the result `rounded`, which was rounded to nearest, ties to even,
+/// to the rounding mode `rm`.
+///
+/// The `error` is the difference between the exact result and `rounded`.
+/// Only its sign is relevant, except for detecting ties in [`RoundingMode::NearestMaxMagnitude`].
+fn round<F: Float>(rounded: F, error: F, rm: RoundingMode) -> (F, u32) {
+    if error == F::ZERO {
+        return (rounded, 0);
+    }
+
+    let above = error > F::ZERO;
+    let result = match rm {
+        RoundingMode::NearestEven => rounded,
+        RoundingMode::TowardsZero if rounded == F::ZERO => rounded,
+        RoundingMode::TowardsZero if above == rounded.is_sign_negative() => {
+            if above {
+                rounded.next_up()
+            } else {
+                rounded.next_down()
+            }
+        }
+        RoundingMode::TowardsZero => rounded,
+        RoundingMode::Down if !above => rounded.next_down(),
+        RoundingMode::Down => rounded,
+        RoundingMode::Up if above => rounded.next_up(),
+        RoundingMode::Up => rounded,
+        RoundingMode::NearestMaxMagnitude => {
+            let neighbour = if above {
+                rounded.next_up()
+            } else {
+                rounded.next_down()
+            };
+            // On a tie, rounding to even picked the smaller magnitude
+            // iff the exact result lies further away from zero.
+            let is_tie = neighbour.is_finite() && neighbour - rounded == error + error;
+            let away_from_zero = rounded == F::ZERO || above != rounded.is_sign_negative();
+            if is_tie && away_from_zero {
+                neighbour
+            } else {
+                rounded
+            }
+        }
+    };
+
+    let mut flags = FLAG_NX;
+    if result.is_infinite() {
+        flags |= FLAG_OF;
+    }
+    // Tininess is detected after rounding.
+    if result.abs() < F::MIN_POSITIVE {
+        flags |= FLAG_UF;
+    }
+    (result, flags)
+}
+
+/// Returns the error of the sum `a + b`, which was rounded to `sum`.
+fn two_sum_error<F: Float>(a: F, b: F, sum: F) -> F {
+    let b_virtual = sum - a;
+    let a_virtual = sum - b_virtual;
+    (a - a_virtual) + (b - b_virtual)
+}
+
+/// Replaces an error that underflowed to zero by the smallest value with the given sign.
+fn nonzero_error<F: Float>(error: F, negative: bool) -> F {
+    match error == F::ZERO {
+        true if negative => -F::MIN_SUBNORMAL,
+        true => F::MIN_SUBNORMAL,
+        false => error,
+    }
+}
+
+/// Computes `a + b`.
+pub fn add<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u32) {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b]);
+    }
+
+    let sum = a + b;
+    if sum.is_nan() {
+        // Adding infinities of opposite signs.
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+    if sum.is_infinite() {
+        return match a.is_finite() && b.is_finite() {
+            true => overflow(sum.is_sign_negative(), rm),
+            false => (sum, 0),
+        };
+    }
+
+    let error = two_sum_error(a, b, sum);
+    if sum == F::ZERO && error == F::ZERO && a.is_sign_negative() != b.is_sign_negative() {
+        // An exact zero sum of operands with opposite signs is -0 when rounding down, else +0.
+        return match rm {
+            RoundingMode::Down => (-F::ZERO, 0),
+            _ => (F::ZERO, 0),
+        };
+    }
+    round(sum, error, rm)
+}
+
+/// Computes `a - b`.
+pub fn sub<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u32) {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b]);
+    }
+    add(a, -b, rm)
+}
+
+/// Computes `a * b`.
+pub fn mul<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u32) {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b]);
+    }
+
+    let product = a * b;
+    if product.is_nan() {
+        // Multiplying zero with infinity.
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+    if product.is_infinite() {
+        return match a.is_finite() && b.is_finite() {
+            true => overflow(product.is_sign_negative(), rm),
+            false => (product, 0),
+        };
+    }
+
+    let error = a.mul_add(b, -product);
+    let negative = a.is_sign_negative() != b.is_sign_negative();
+    if product == F::ZERO && a != F::ZERO && b != F::ZERO {
+        // The product underflowed entirely.
+        return round(product, nonzero_error(error, negative), rm);
+    }
+    round(product, error, rm)
+}
+
+/// Computes `a / b`.
+pub fn div<F: Float>(a: F, b: F, rm: RoundingMode) -> (F, u32) {
+    if a.is_nan() || b.is_nan() {
+        return propagate_nan(&[a, b]);
+    }
+
+    let quotient = a / b;
+    if quotient.is_nan() {
+        // Dividing zero by zero or infinity by infinity.
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+    if b == F::ZERO {
+        let flags = if a.is_finite() { FLAG_DZ } else { 0 };
+        return (quotient, flags);
+    }
+    if quotient.is_infinite() {
+        return match a.is_finite() {
+            true => overflow(quotient.is_sign_negative(), rm),
+            false => (quotient, 0),
+        };
+    }
+    if a == F::ZERO || b.is_infinite() {
+        return (quotient, 0);
+    }
+
+    // The remainder `a - quotient * b` is exactly representable.
+    let remainder = (-quotient).mul_add(b, a);
+    let negative = remainder.is_sign_negative() != b.is_sign_negative();
+    let error = match remainder == F::ZERO {
+        true => F::ZERO,
+        false => nonzero_error(remainder / b, negative),
+    };
+    round(quotient, error, rm)
+}
+
+/// Computes the square root of `a`.
+pub fn sqrt<F: Float>(a: F, rm: RoundingMode) -> (F, u32) {
+    if a.is_nan() {
+        return propagate_nan(&[a]);
+    }
+    if a == F::ZERO || a == F::INFINITY {
+        return (a, 0);
+    }
+    if a.is_sign_negative() {
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+
+    let root = a.sqrt();
+    // The remainder `a - root * root` is exactly representable.
+    let remainder = (-root).mul_add(root, a);
+    let error = match remainder == F::ZERO {
+        true => F::ZERO,
+        false => nonzero_error(remainder / (root + root), remainder.is_sign_negative()),
+    };
+    round(root, error, rm)
+}
+
+/// Computes `a * b + c` with a single rounding.
+pub fn mul_add<F: Float>(a: F, b: F, c: F, rm: RoundingMode) -> (F, u32) {
+    let infinity_times_zero =
+        (a.is_infinite() && b == F::ZERO) || (a == F::ZERO && b.is_infinite());
+    if infinity_times_zero {
+        // Invalid even if the addend is a quiet NaN.
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+    if a.is_nan() || b.is_nan() || c.is_nan() {
+        return propagate_nan(&[a, b, c]);
+    }
+
+    let result = a.mul_add(b, c);
+    if result.is_nan() {
+        // Adding infinities of opposite signs.
+        return (F::CANONICAL_NAN, FLAG_NV);
+    }
+    if result.is_infinite() {
+        return match a.is_finite() && b.is_finite() && c.is_finite() {
+            true => overflow(result.is_sign_negative(), rm),
+            false => (result, 0),
+        };
+    }
+
+    let product = a * b;
+    let error = if product.is_finite() {
+        // exact = product + product_error + c = result + error
+        let product_error = a.mul_add(b, -product);
+        let sum = product + c;
+        let sum_error = two_sum_error(product, c, sum);
+        ((sum - result) + sum_error) + product_error
+    } else {
+        F::ZERO
+    };
+
+    let product_negative = a.is_sign_negative() != b.is_sign_negative();
+    if result == F::ZERO && error == F::ZERO && product_negative != c.is_sign_negative() {
+        // An exact zero sum of terms with opposite signs is -0 when rounding down, else +0.
+        return match rm {
+            RoundingMode::Down => (-F::ZERO, 0),
+            _ => (F::ZERO, 0),
+        };
+    }
+    round(result, error, rm)
+}
+
+/// Returns the smaller of `a` and `b`, treating -0 as smaller than +0.
+/// If only one operand is a NaN, the other operand is returned.
+pub fn min<F: Float>(a: F, b: F) -> (F, u32) {
+    min_max(a, b, true)
+}
+
+/// Returns the larger of `a` and `b`, treating +0 as larger than -0.
+/// If only one operand is a NaN, the other operand is returned.
+pub fn max<F: Float>(a: F, b: F) -> (F, u32) {
+    min_max(a, b, false)
+}
+
+fn min_max<F: Float>(a: F, b: F, is_min: bool) -> (F, u32) {
+    let flags = if a.is_signaling() || b.is_signaling() {
+        FLAG_NV
+    } else {
+        0
+    };
+    let result = match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        // Equal values only differ in the sign of zero.
+        _ if a == b => match a.is_sign_negative() == is_min {
+            true => a,
+            false => b,
+        },
+        _ if (a < b) == is_min => a,
+        _ => b,
+    };
+    (result, flags)
+}
+
+/// Quiet comparison `a == b`, only signaling NaNs raise the invalid operation flag.
+pub fn eq<F: Float>(a: F, b: F) -> (bool, u32) {
+    let flags = if a.is_signaling() || b.is_signaling() {
+        FLAG_NV
+    } else {
+        0
+    };
+    (a == b, flags)
+}
+
+/// Signaling comparison `a < b`, any NaN raises the invalid operation flag.
+pub fn lt<F: Float>(a: F, b: F) -> (bool, u32) {
+    let flags = if a.is_nan() || b.is_nan() { FLAG_NV } else { 0 };
+    (a < b, flags)
+}
+
+/// Signaling comparison `a <= b`, any NaN raises the invalid operation flag.
+pub fn le<F: Float>(a: F, b: F) -> (bool, u32) {
+    let flags = if a.is_nan() || b.is_nan() { FLAG_NV } else { 0 };
+    (a <= b, flags)
+}
+
+/// Returns the 10-bit mask of the `fclass` instruction.
+pub fn classify<F: Float>(a: F) -> u64 {
+    let negative = a.is_sign_negative();
+    let bit = if a.is_nan() {
+        if a.is_signaling() { 8 } else { 9 }
+    } else if a.is_infinite() {
+        if negative { 0 } else { 7 }
+    } else if a == F::ZERO {
+        if negative { 3 } else { 4 }
+    } else if a.is_subnormal() {
+        if negative { 2 } else { 5 }
+    } else if negative {
+        1
+    } else {
+        6
+    };
+    1 << bit
+}
+
+/// Converts `a` to an integer in the range `min..=max`.
+/// NaNs and out of range values saturate and raise the invalid operation flag.
+pub fn to_int<F: Float>(a: F, min: i128, max: i128, rm: RoundingMode) -> (i128, u32) {
+    if a.is_nan() {
+        return (max, FLAG_NV);
+    }
+
+    let rounded = match rm {
+        RoundingMode::NearestEven => a.round_ties_even(),
+        RoundingMode::TowardsZero => a.trunc(),
+        RoundingMode::Down => a.floor(),
+        RoundingMode::Up => a.ceil(),
+        RoundingMode::NearestMaxMagnitude => a.round(),
+    };
+
+    let value = rounded.to_i128();
+    if value < min {
+        (min, FLAG_NV)
+    } else if value > max {
+        (max, FLAG_NV)
+    } else if rounded != a {
+        (value, FLAG_NX)
+    } else {
+        (value, 0)
+    }
+}
+
+/// Converts the integer `value` to a floating point value.
+pub fn from_int<F: Float>(value: i128, rm: RoundingMode) -> (F, u32) {
+    let rounded = F::from_i128(value);
+    // The difference is smaller than one unit in the last place and thus exact.
+    let error = value - rounded.to_i128();
+    round(rounded, F::from_i128(error), rm)
+}
+
+/// Converts a single-precision value to double-precision, which is always exact.
+pub fn widen(a: f32) -> (f64, u32) {
+    if a.is_nan() {
+        let flags = if a.is_signaling() { FLAG_NV } else { 0 };
+        return (f64::CANONICAL_NAN, flags);
+    }
+    (a as f64, 0)
+}
+
+/// Converts a double-precision value to single-precision.
+pub fn narrow(a: f64, rm: RoundingMode) -> (f32, u32) {
+    if a.is_nan() {
+        let flags = if a.is_signaling() { FLAG_NV } else { 0 };
+        return (f32::CANONICAL_NAN, flags);
+    }
+
+    let rounded = a as f32;
+    if rounded.is_infinite() && a.is_finite() {
+        return overflow(a.is_sign_negative(), rm);
+    }
+
+    // The difference is exactly representable as a double.
+    let error = a - rounded as f64;
+    let narrowed_error = match error == 0.0 {
+        true => 0.0,
+        false => nonzero_error(error as f32, error.is_sign_negative()),
+    };
+    round(rounded, narrowed_error, rm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test cases for the directed rounding modes of an inexact division.
+    fn test_rounding_modes() {
+        let third = |rm| div(1.0f32, 3.0f32, rm);
+        let (nearest, flags) = third(RoundingMode::NearestEven);
+        assert_eq!(flags, FLAG_NX);
+        // The nearest single-precision value 0x3eaa_aaab lies above 1/3
+        assert_eq!(nearest.to_bits(), 0x3eaa_aaab);
+        assert_eq!(third(RoundingMode::Up).0, nearest);
+        assert_eq!(third(RoundingMode::Down).0, nearest.next_down());
+        assert_eq!(third(RoundingMode::TowardsZero).0, nearest.next_down());
+        assert_eq!(div(-1.0f32, 3.0, RoundingMode::Up).0, -nearest.next_down());
+    }
+
+    #[test]
+    /// Test cases for ties, which only differ between the two nearest rounding modes.
+    fn test_ties() {
+        // 2^53 + 1 lies exactly between two doubles.
+        let value = (1i128 << 53) + 1;
+        let (even, _) = from_int::<f64>(value, RoundingMode::NearestEven);
+        let (away, _) = from_int::<f64>(value, RoundingMode::NearestMaxMagnitude);
+        assert_eq!(even, 9007199254740992.0);
+        assert_eq!(away, 9007199254740994.0);
+    }
+
+    #[test]
+    /// Test cases for invalid operations and the canonical NaN.
+    fn test_invalid_operations() {
+        let signaling = f32::from_bits(0x7f80_0001);
+        assert_eq!(sqrt(-1.0f64, RoundingMode::NearestEven).1, FLAG_NV);
+        assert_eq!(
+            add(signaling, 1.0, RoundingMode::NearestEven).0.to_bits(),
+            0x7fc0_0000
+        );
+        assert_eq!(min(signaling, 1.0f32), (1.0, FLAG_NV));
+        assert_eq!(
+            to_int(f64::NAN, -128, 127, RoundingMode::NearestEven),
+            (127, FLAG_NV)
+        );
+        assert_eq!(
+            to_int(-0.5f64, 0, 255, RoundingMode::TowardsZero),
+            (0, FLAG_NX)
+        );
+        assert_eq!(
+            div(1.0f64, 0.0, RoundingMode::NearestEven),
+            (f64::INFINITY, FLAG_DZ)
+        );
+    }
+
+    #[test]
+    /// Single-precision values are NaN-boxed in the 64-bit registers.
+    fn test_nan_boxing() {
+        assert_eq!(1.0f32.to_register(), 0xffff_ffff_3f80_0000);
+        assert_eq!(f32::from_register(0xffff_ffff_3f80_0000), 1.0);
+        assert!(f32::from_register(0x0000_0000_3f80_0000).is_nan());
+    }
+}
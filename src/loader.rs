@@ -0,0 +1,375 @@
+//! # ELF Loader
+//!
+//! This module loads the `PT_LOAD` segments of an ELF executable into the physical memory.
+//! Each segment occupies `p_memsz` bytes of memory starting at its physical address `p_paddr`,
+//! of which the first `p_filesz` bytes are copied from the file and the remaining bytes
+//! (usually `.bss`) are filled with zeros. The virtual address `p_vaddr` only matters once the
+//! program enables address translation, for which it sets up its own page tables.
+//!
+//! The `p_flags` of the segments become the access permissions of their memory. All memory
+//! outside of the segments (e.g. the stack) allows any access, as the program may place code
+//! there, like a kernel loading a user program.
+//! See also: [https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html]
+
+use std::ops::Range;
+
+use derive_more::{Display, Error};
+use goblin::elf::{Elf, header::EM_RISCV, program_header::PT_LOAD};
+
+use crate::{bus::Bus, csr::Xlen, monitored_memory::Permissions};
+
+/// An error that prevents a program from being loaded.
+#[derive(Debug, Display, Error)]
+pub enum LoaderError {
+    /// The file is not a valid ELF file.
+    #[display("invalid ELF file: {_0}")]
+    InvalidElf(goblin::error::Error),
+    /// The ELF file is not a RISC-V executable.
+    #[display("unsupported ELF file: expected a RISC-V executable, found machine {machine}")]
+    UnsupportedMachine { machine: u16 },
+    /// The file contents of a segment lie outside of the file.
+    #[display("segment {index} exceeds the file: {size} bytes at offset {offset:#x}")]
+    SegmentOutOfFile {
+        index: usize,
+        offset: u64,
+        size: u64,
+    },
+    /// A segment contains more bytes in the file than in memory.
+    #[display(
+        "segment {index} has a file size of {file_size} bytes, but a memory size of {memory_size} bytes"
+    )]
+    FileSizeExceedsMemorySize {
+        index: usize,
+        file_size: u64,
+        memory_size: u64,
+    },
+    /// A segment lies outside of the physical memory.
+    #[display("segment {index} exceeds the memory: {size} bytes at {address:#x}")]
+    SegmentOutOfMemory {
+        index: usize,
+        address: u64,
+        size: u64,
+    },
+    /// Two segments occupy the same memory.
+    #[display("segment {first} overlaps segment {second}")]
+    OverlappingSegments { first: usize, second: usize },
+}
+
+impl From<goblin::error::Error> for LoaderError {
+    fn from(error: goblin::error::Error) -> Self {
+        LoaderError::InvalidElf(error)
+    }
+}
+
+/// A loadable segment of an ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment<'a> {
+    /// The index of the segment in the program header table.
+    pub index: usize,
+    /// The physical address of the first byte of the segment in memory.
+    pub address: u64,
+    /// The virtual address of the first byte of the segment, which the program expects once
+    /// it enables address translation.
+    pub virtual_address: u64,
+    /// The number of bytes the segment occupies in memory.
+    pub memory_size: u64,
+    /// The bytes copied from the file, the rest of the segment is zero-filled.
+    pub data: &'a [u8],
+    /// The access permissions of the segment in memory.
+    pub permissions: Permissions,
+}
+
+impl Segment<'_> {
+    /// Returns the range of memory occupied by the segment.
+    pub fn memory_range(&self) -> Range<u64> {
+        self.address..self.address + self.memory_size
+    }
+}
+
+/// A validated ELF executable, ready to be loaded into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program<'a> {
+    /// The physical address of the first instruction to execute.
+    pub entry: u64,
+    /// The XLEN the program was compiled for, given by the ELF class.
+    pub xlen: Xlen,
+    /// The loadable segments, sorted by their address.
+    pub segments: Vec<Segment<'a>>,
+}
+
+impl<'a> Program<'a> {
+    /// Parses an ELF executable and validates its loadable segments.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, LoaderError> {
+        let elf = Elf::parse(bytes)?;
+
+        if elf.header.e_machine != EM_RISCV {
+            return Err(LoaderError::UnsupportedMachine {
+                machine: elf.header.e_machine,
+            });
+        }
+
+        let mut segments = Vec::new();
+        for (index, phdr) in elf.program_headers.iter().enumerate() {
+            if phdr.p_type != PT_LOAD {
+                continue;
+            }
+
+            if phdr.p_filesz > phdr.p_memsz {
+                return Err(LoaderError::FileSizeExceedsMemorySize {
+                    index,
+                    file_size: phdr.p_filesz,
+                    memory_size: phdr.p_memsz,
+                });
+            }
+
+            let data = phdr
+                .p_offset
+                .checked_add(phdr.p_filesz)
+                .and_then(|end| bytes.get(phdr.p_offset as usize..end as usize))
+                .ok_or(LoaderError::SegmentOutOfFile {
+                    index,
+                    offset: phdr.p_offset,
+                    size: phdr.p_filesz,
+                })?;
+
+            if phdr.p_paddr.checked_add(phdr.p_memsz).is_none() {
+                return Err(LoaderError::SegmentOutOfMemory {
+                    index,
+                    address: phdr.p_paddr,
+                    size: phdr.p_memsz,
+                });
+            }
+
+            // Empty segments occupy no memory and cannot overlap anything
+            if phdr.p_memsz == 0 {
+                continue;
+            }
+
+            segments.push(Segment {
+                index,
+                address: phdr.p_paddr,
+                virtual_address: phdr.p_vaddr,
+                memory_size: phdr.p_memsz,
+                data,
+                permissions: Permissions::from_elf_flags(phdr.p_flags),
+            });
+        }
+
+        // After sorting, a segment can only overlap its successor
+        segments.sort_by_key(|segment| segment.address);
+        for pair in segments.windows(2) {
+            if pair[0].memory_range().end > pair[1].address {
+                return Err(LoaderError::OverlappingSegments {
+                    first: pair[0].index.min(pair[1].index),
+                    second: pair[0].index.max(pair[1].index),
+                });
+            }
+        }
+
+        // The entry point is a virtual address, which is loaded at the physical address of its
+        // segment
+        let entry = segments
+            .iter()
+            .find(|segment| {
+                (segment.virtual_address..segment.virtual_address + segment.memory_size)
+                    .contains(&elf.entry)
+            })
+            .map_or(elf.entry, |segment| {
+                elf.entry - segment.virtual_address + segment.address
+            });
+
+        Ok(Program {
+            entry,
+            xlen: if elf.is_64 { Xlen::Rv64 } else { Xlen::Rv32 },
+            segments,
+        })
+    }
+
+    /// Copies the segments into the DRAM of `bus`, zero-fills the remainder of each segment
+    /// and protects the memory of each segment with its permissions.
+    pub fn load(&self, bus: &mut Bus) -> Result<(), LoaderError> {
+        // Check all segments first, so the memory is left untouched on errors
+        if let Some(segment) = self
+            .segments
+            .iter()
+            .find(|segment| !bus.is_dram(segment.address, segment.memory_size))
+        {
+            return Err(LoaderError::SegmentOutOfMemory {
+                index: segment.index,
+                address: segment.address,
+                size: segment.memory_size,
+            });
+        }
+
+        let dram_base = bus.dram_range().start;
+        let memory = &mut bus.dram;
+        memory.clear_protection();
+
+        for segment in &self.segments {
+            let start = (segment.address - dram_base) as usize;
+            let file_end = start + segment.data.len();
+            let end = start + segment.memory_size as usize;
+            memory[start..file_end].copy_from_slice(segment.data);
+            memory[file_end..end].fill(0);
+            memory.protect(start..end, segment.permissions);
+            log::debug!(
+                "Loaded segment {} at {:#x} (virtual address: {:#x}, file size: {}, memory size: {}, permissions: {})",
+                segment.index,
+                segment.address,
+                segment.virtual_address,
+                segment.data.len(),
+                segment.memory_size,
+                segment.permissions
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::DEFAULT_DRAM_BASE,
+        cpu::{Cpu, StepOutcome},
+        trap::AccessType,
+    };
+
+    /// Builds a 64-bit RISC-V ELF file with one `PT_LOAD` program header per
+    /// `(offset, address, file size, memory size)` and the given file contents appended.
+    fn build_elf(segments: &[(u64, u64, u64, u64)], contents: &[u8]) -> Vec<u8> {
+        let mut elf = Vec::new();
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        elf.extend_from_slice(&2u16.to_le_bytes()); // e_type: ET_EXEC
+        elf.extend_from_slice(&EM_RISCV.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes()); // e_version
+        elf.extend_from_slice(&0x40u64.to_le_bytes()); // e_entry
+        elf.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        elf.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        elf.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        elf.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        elf.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        elf.extend_from_slice(&(segments.len() as u16).to_le_bytes());
+        elf.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+        for &(offset, address, file_size, memory_size) in segments {
+            elf.extend_from_slice(&PT_LOAD.to_le_bytes());
+            elf.extend_from_slice(&6u32.to_le_bytes()); // p_flags: RW
+            for value in [offset, address, address, file_size, memory_size, 8] {
+                elf.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        elf.extend_from_slice(contents);
+        elf
+    }
+
+    #[test]
+    /// The part of a segment beyond its file size is zero-filled.
+    fn test_load_zero_fills_bss() {
+        let contents_offset = 64 + 56;
+        let elf = build_elf(&[(contents_offset, 0x8000_0040, 4, 16)], &[1, 2, 3, 4]);
+        let program = Program::parse(&elf).unwrap();
+        assert_eq!(program.entry, 0x40);
+        assert_eq!(program.xlen, Xlen::Rv64);
+
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        bus.dram[0x40..0x50].fill(0xff);
+        program.load(&mut bus).unwrap();
+        let memory = &bus.dram;
+        assert_eq!(&memory[0x40..0x44], &[1, 2, 3, 4]);
+        assert_eq!(&memory[0x44..0x50], &[0; 12]);
+        assert_eq!(memory[0x50], 0);
+
+        assert_eq!(program.segments[0].permissions, Permissions::READ_WRITE);
+        assert!(memory.is_allowed(0x40..0x50, AccessType::Store));
+        assert!(!memory.is_allowed(0x48..0x50, AccessType::Fetch));
+        assert!(memory.is_allowed(0x100..0x104, AccessType::Fetch));
+    }
+
+    #[test]
+    /// Code the program copies outside of its segments can be executed.
+    fn test_run_copied_code() {
+        let code: Vec<u8> = [
+            0x05d00893, // addi a7, zero, 93
+            0x00700513, // addi a0, zero, 7
+            0x00000297, // auipc t0, 0
+            0x0182a303, // lw t1, 24(t0)
+            0x40028393, // addi t2, t0, 1024
+            0x40038393, // addi t2, t2, 1024
+            0x0063a023, // sw t1, 0(t2)
+            0x00038067, // jalr zero, 0(t2)
+            0x00000073, // ecall, copied to 0x8000_0808
+        ]
+        .iter()
+        .flat_map(|instruction: &u32| instruction.to_le_bytes())
+        .collect();
+        let size = code.len() as u64;
+        let mut elf = build_elf(&[(64 + 56, DEFAULT_DRAM_BASE, size, size)], &code);
+        // Start at the segment, which is readable and executable
+        elf[24..32].copy_from_slice(&DEFAULT_DRAM_BASE.to_le_bytes());
+        elf[64 + 4..64 + 8].copy_from_slice(&5u32.to_le_bytes());
+
+        let bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        let (mut cpu, _events) = Cpu::new(bus);
+        cpu.load_program(&elf).unwrap();
+        for _ in 0..8 {
+            assert_eq!(cpu.tick(), Ok(StepOutcome::Retired));
+        }
+        assert_eq!(cpu.tick(), Ok(StepOutcome::Exited { exit_code: 7 }));
+    }
+
+    #[test]
+    /// Segments and the entry point are placed at their physical address, not their virtual
+    /// address.
+    fn test_load_physical_address() {
+        let contents_offset = 64 + 56;
+        let mut elf = build_elf(&[(contents_offset, 0x8000_0100, 4, 4)], &[1, 2, 3, 4]);
+        // Move p_vaddr to the upper half, as in a kernel linked to run with paging enabled
+        elf[64 + 16..64 + 24].copy_from_slice(&0xffff_ffff_8000_0000_u64.to_le_bytes());
+        // The entry point lies in the segment
+        elf[24..32].copy_from_slice(&0xffff_ffff_8000_0002_u64.to_le_bytes());
+        let program = Program::parse(&elf).unwrap();
+        assert_eq!(program.entry, 0x8000_0102);
+        assert_eq!(program.segments[0].address, 0x8000_0100);
+        assert_eq!(program.segments[0].virtual_address, 0xffff_ffff_8000_0000);
+
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        program.load(&mut bus).unwrap();
+        assert_eq!(&bus.dram[0x100..0x104], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    /// Malformed segments are reported instead of panicking.
+    fn test_invalid_segments() {
+        let elf = build_elf(&[(64 + 56, 0, 8, 8)], &[0; 4]);
+        assert!(matches!(
+            Program::parse(&elf),
+            Err(LoaderError::SegmentOutOfFile { index: 0, .. })
+        ));
+
+        let elf = build_elf(&[(0, 0, 8, 4)], &[]);
+        assert!(matches!(
+            Program::parse(&elf),
+            Err(LoaderError::FileSizeExceedsMemorySize { index: 0, .. })
+        ));
+
+        let elf = build_elf(&[(0, 0x100, 0, 0x20), (0, 0x80, 0, 0x81)], &[]);
+        assert!(matches!(
+            Program::parse(&elf),
+            Err(LoaderError::OverlappingSegments {
+                first: 0,
+                second: 1
+            })
+        ));
+
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        for address in [0x1000, 0x8000_1000] {
+            let elf = build_elf(&[(0, address, 0, 0x20)], &[]);
+            let program = Program::parse(&elf).unwrap();
+            assert!(matches!(
+                program.load(&mut bus),
+                Err(LoaderError::SegmentOutOfMemory { index: 0, .. })
+            ));
+        }
+    }
+}
@@ -1,35 +1,174 @@
 #![allow(dead_code)]
 
-use std::fs;
+use std::{fs, io::Read};
+
+use crossbeam::channel::Sender;
 
 use bytesize::ByteSize;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::{app::App, cpu::Cpu};
+use crate::{
+    app::App,
+    bus::{Bus, DEFAULT_DRAM_BASE},
+    cpu::Cpu,
+    csr::Xlen,
+    devices::{DeviceContext, DeviceRegistry, DeviceSpec},
+    sbi::Sbi,
+    utils::parse_address,
+};
 
-use log::info;
+use log::{error, info, warn};
 
 mod app;
+mod bus;
+mod compressed;
 mod constants;
 mod cpu;
+mod csr;
+mod decoder;
+mod devices;
+mod disasm;
+mod error;
+mod fdt;
+mod float;
+mod loader;
+mod mmu;
 mod monitored_memory;
+mod pmp;
+mod sbi;
+mod trap;
 mod utils;
 
+/// The name of the SBI firmware as the console, see [`Args::console`].
+const SBI_CONSOLE: &str = "sbi";
+
 #[derive(Clone, Parser)]
 struct Args {
     /// Path to the program file, example: path/to/program.bin
-    #[clap(short, long)]
-    program: String,
+    #[clap(short, long, required_unless_present = "list_devices")]
+    program: Option<String>,
     /// Optional memory size in bytes, if not provided, defaults to 1 MiB
     memory: Option<usize>,
+    /// Physical address of the DRAM, example: 0x80000000
+    #[clap(long, default_value_t = DEFAULT_DRAM_BASE, value_parser = parse_address)]
+    dram_base: u64,
+    /// Optional XLEN (32 or 64), if not provided, it is selected by the ELF class of the program
+    #[clap(long)]
+    xlen: Option<Xlen>,
+    /// Device to attach, given as name[@address][,irq=number][,option=value]..., can be repeated,
+    /// example: uart@0x10000000,irq=10 or virtio-blk,file=disk.img,mode=cow
+    #[clap(long = "device")]
+    devices: Vec<DeviceSpec>,
+    /// Path to a machine configuration file, listing one device per line,
+    /// if not provided, a CLINT, a PLIC and a UART are attached like on the QEMU virt machine
+    #[clap(long)]
+    machine: Option<String>,
+    /// Run the program in supervisor mode, with its ecalls serviced by the built-in SBI firmware
+    #[clap(long)]
+    sbi: bool,
+    /// Device receiving the console input of the host, `sbi` for the SBI firmware,
+    /// if not provided, the SBI firmware with --sbi and the first UART otherwise,
+    /// example: virtio-console
+    #[clap(long)]
+    console: Option<String>,
+    /// List the devices that can be attached with --device and exit
+    #[clap(long)]
+    list_devices: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Returns the path to the program file, which is only missing when listing the devices.
+    fn program(&self) -> &str {
+        self.program.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Disassemble the executable sections of the program instead of running it
+    Disasm,
 }
 
-#[macroquad::main("RISC-V Virtual Machine")]
-async fn main() {
+fn main() {
     env_logger::builder().parse_env("LOG").init();
     let args = Args::parse();
 
-    let program_path = std::path::Path::new(&args.program);
+    if args.list_devices {
+        list_devices();
+        return;
+    }
+    match args.command {
+        Some(Command::Disasm) => {
+            let program = fs::read(args.program()).expect("Failed to read the program file");
+            let mut stdout = std::io::stdout().lock();
+            if let Err(error) = disasm::disassemble_elf(&program, &mut stdout) {
+                error!("Failed to disassemble the program: {}", error);
+                std::process::exit(1);
+            }
+        }
+        None => macroquad::Window::new("RISC-V Virtual Machine", run(args)),
+    }
+}
+
+/// Prints the kinds of devices that can be attached, with their default address and interrupt.
+fn list_devices() {
+    for kind in DeviceRegistry::new().kinds() {
+        let irq = kind.irq.map_or(String::new(), |irq| format!("irq {irq}"));
+        println!(
+            "{:<16}{:<#12x}{:<8}{}",
+            kind.name, kind.default_base, irq, kind.description
+        );
+    }
+}
+
+/// Attaches the devices of the machine configuration and the command line to `bus`,
+/// connecting the `console` device to the console input of `context`.
+fn attach_devices(
+    bus: &mut Bus,
+    args: &Args,
+    context: &DeviceContext,
+    console: &str,
+) -> anyhow::Result<()> {
+    let registry = DeviceRegistry::new();
+    let mut specs = match &args.machine {
+        Some(path) => devices::parse_machine_config(&fs::read_to_string(path)?)?,
+        None => devices::parse_machine_config(devices::DEFAULT_MACHINE)?,
+    };
+    specs.extend(args.devices.iter().cloned());
+
+    let has_console = match console {
+        SBI_CONSOLE => args.sbi,
+        _ => specs.iter().any(|spec| spec.name == console),
+    };
+    if !has_console {
+        warn!("No device `{}` receives the console input", console);
+    }
+    registry.attach_all(bus, &specs, context, console)?;
+    Ok(())
+}
+
+/// Forwards the standard input of the host to the console input of the devices.
+fn forward_stdin(console: Sender<u8>) {
+    let spawned = std::thread::Builder::new()
+        .name("console_input".to_string())
+        .spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                let Ok(byte) = byte else { break };
+                if console.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+    if let Err(error) = spawned {
+        error!("Failed to forward the standard input: {}", error);
+    }
+}
+
+/// Runs the program in the virtual machine and displays its output.
+async fn run(args: Args) {
+    let program_path = std::path::Path::new(args.program());
 
     info!("Loading program from: {}", program_path.display());
     let program: Vec<u8> = fs::read(program_path).expect("Failed to read the program file");
@@ -47,15 +186,71 @@ async fn main() {
         ByteSize(program.len() as u64)
     );
 
-    let (mut cpu, cpu_events) =
-        Cpu::new(memory_size).expect("Failed to create CPU with memory size");
+    let bus = Bus::new(args.dram_base, memory_size).expect("Failed to create the memory");
+    let (mut cpu, cpu_events) = Cpu::new(bus);
     info!(
-        "CPU initialized with memory size: {} bytes",
-        ByteSize(cpu.memory_size() as u64)
+        "CPU initialized with memory size: {} at {:#x}",
+        ByteSize(cpu.memory_size() as u64),
+        args.dram_base
     );
 
-    cpu.load_program(&program)
-        .expect("Failed to create CPU with program");
+    let (console, console_input) = crossbeam::channel::unbounded();
+    let context = DeviceContext {
+        events: cpu.event_sender(),
+        console_input,
+    };
+    // Each byte of the console input is received once, by the chosen console
+    let default_console = if args.sbi { SBI_CONSOLE } else { "uart" };
+    let console_device = args.console.as_deref().unwrap_or(default_console);
+    if let Err(error) = attach_devices(&mut cpu.bus, &args, &context, console_device) {
+        error!("Failed to attach the devices: {}", error);
+        return;
+    }
+    for (range, irq, device) in cpu.bus.devices() {
+        match irq {
+            Some(irq) => info!(
+                "Attached device {} at {:#x}..{:#x} with interrupt {}",
+                device.name(),
+                range.start,
+                range.end,
+                irq
+            ),
+            None => info!(
+                "Attached device {} at {:#x}..{:#x}",
+                device.name(),
+                range.start,
+                range.end
+            ),
+        }
+    }
+    forward_stdin(console.clone());
+
+    if let Err(error) = cpu.load_program(&program) {
+        error!("Failed to load the program: {}", error);
+        return;
+    }
+    if let Some(xlen) = args.xlen {
+        cpu.set_xlen(xlen);
+    }
+    if args.sbi {
+        info!("Servicing the supervisor ecalls with the built-in SBI firmware");
+        let sbi_context = if console_device == SBI_CONSOLE {
+            context.clone()
+        } else {
+            context.without_console_input()
+        };
+        cpu.enable_sbi(Sbi::new(&sbi_context));
+    }
+    // Programs that do not read the device tree run fine without it
+    match cpu.load_device_tree() {
+        Some(range) => info!(
+            "Device tree placed at {:#x}, size: {}",
+            range.start,
+            ByteSize(range.end - range.start)
+        ),
+        None => warn!("The device tree does not fit into memory above the program"),
+    }
+    info!("Executing the program as {}", cpu.xlen());
 
     info!("Starting CPU execution...");
 
@@ -63,12 +258,14 @@ async fn main() {
         .name("virtual_machine".to_string())
         .spawn(move || {
             while !cpu.is_halted() {
-                cpu.tick();
+                if let Err(error) = cpu.tick() {
+                    error!("Virtual machine stopped: {}", error);
+                }
             }
         })
         .expect("Failed to spawn VM thread");
 
-    let mut app = App::new(cpu_events);
+    let mut app = App::new(cpu_events, console);
     app.run().await;
     info!("Virtual machine execution completed.");
 
@@ -0,0 +1,457 @@
+//! # Memory Management Unit
+//!
+//! This module translates the virtual addresses of supervisor and user mode accesses into
+//! physical addresses, using the page tables selected by `satp`. The Sv39 and Sv48 schemes
+//! are supported on RV64 harts, with 3 and 4 levels of page tables respectively. Each page
+//! table entry (PTE) maps a 4 KiB page, or a 2 MiB, 1 GiB or 512 GiB superpage if it is a leaf
+//! above the last level.
+//!
+//! Translations are cached in a small [`Tlb`], which is only invalidated by writing `satp`
+//! and by `sfence.vma`, so like on real hardware, programs must fence after modifying the
+//! page tables. Machine mode accesses are never translated, unless `mstatus.MPRV` selects a
+//! lower privilege for loads and stores. The page tables are accessed with the privilege of
+//! supervisor mode, so they must be accessible according to the PMP entries.
+//! See also: [https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/supervisor.html#sec:sv39]
+
+use crate::{
+    bus::Bus,
+    csr::{CsrFile, MSTATUS_MXR, MSTATUS_SUM, PrivilegeMode, Xlen},
+    trap::{AccessType, Exception},
+};
+
+/// The size of a page in bytes.
+pub const PAGE_SIZE: u64 = 4096;
+/// The number of bits of the offset into a page.
+const PAGE_SHIFT: u32 = 12;
+/// The number of bits of the virtual page number resolved by each level of page tables.
+const LEVEL_BITS: u32 = 9;
+/// The size of a page table entry in bytes.
+const PTE_SIZE: u64 = 8;
+
+/// `pte.V`: The entry is valid.
+pub const PTE_V: u64 = 1 << 0;
+/// `pte.R`: The page is readable.
+pub const PTE_R: u64 = 1 << 1;
+/// `pte.W`: The page is writable.
+pub const PTE_W: u64 = 1 << 2;
+/// `pte.X`: The page is executable.
+pub const PTE_X: u64 = 1 << 3;
+/// `pte.U`: The page is accessible to user mode.
+pub const PTE_U: u64 = 1 << 4;
+/// `pte.G`: The mapping exists in all address spaces.
+pub const PTE_G: u64 = 1 << 5;
+/// `pte.A`: The page was accessed since the bit was last cleared.
+pub const PTE_A: u64 = 1 << 6;
+/// `pte.D`: The page was written since the bit was last cleared.
+pub const PTE_D: u64 = 1 << 7;
+/// The bits of the physical page number of a PTE, which start at bit 10.
+const PTE_PPN: u64 = ((1 << 44) - 1) << 10;
+/// The bits reserved for extensions (Svpbmt, Svnapot), which must be zero.
+const PTE_RESERVED: u64 = !((1 << 54) - 1);
+
+/// The bits of the physical page number of the root page table in `satp`.
+const SATP_PPN: u64 = (1 << 44) - 1;
+
+/// The address translation scheme selected by `satp.MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslationMode {
+    /// Virtual addresses are physical addresses.
+    Bare,
+    /// 39-bit virtual addresses with three levels of page tables.
+    Sv39,
+    /// 48-bit virtual addresses with four levels of page tables.
+    Sv48,
+}
+
+impl TranslationMode {
+    /// Returns the translation mode of the `satp` value, or [`None`] if the mode is not
+    /// supported. RV32 harts only support the Bare mode.
+    pub fn from_satp(satp: u64, xlen: Xlen) -> Option<Self> {
+        match xlen {
+            Xlen::Rv32 => (satp & 1 << 31 == 0).then_some(TranslationMode::Bare),
+            Xlen::Rv64 => match satp >> 60 {
+                0 => Some(TranslationMode::Bare),
+                8 => Some(TranslationMode::Sv39),
+                9 => Some(TranslationMode::Sv48),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the number of levels of page tables.
+    fn levels(self) -> u32 {
+        match self {
+            TranslationMode::Bare => 0,
+            TranslationMode::Sv39 => 3,
+            TranslationMode::Sv48 => 4,
+        }
+    }
+}
+
+/// Returns the mask of the address bits that are not translated by a leaf at `level`.
+fn page_offset_mask(level: u32) -> u64 {
+    (1 << (PAGE_SHIFT + LEVEL_BITS * level)) - 1
+}
+
+/// A cached translation of a virtual page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TlbEntry {
+    /// The virtual page number of the 4 KiB page the translation was made for.
+    vpn: u64,
+    /// The level of the leaf PTE, 0 for a 4 KiB page.
+    level: u32,
+    /// The leaf PTE, whose permissions are checked on every access.
+    pte: u64,
+}
+
+impl TlbEntry {
+    /// Returns `true` if the page or superpage of the entry contains `address`.
+    fn contains(&self, address: u64) -> bool {
+        (self.vpn << PAGE_SHIFT ^ address) & !page_offset_mask(self.level) == 0
+    }
+
+    /// Returns the physical address `address` is mapped to.
+    fn translate(&self, address: u64) -> u64 {
+        let mask = page_offset_mask(self.level);
+        (self.pte & PTE_PPN) << 2 & !mask | address & mask
+    }
+}
+
+/// A direct-mapped translation lookaside buffer, indexed by the virtual page number.
+#[derive(Debug, Clone)]
+pub struct Tlb {
+    entries: [Option<TlbEntry>; Tlb::ENTRIES],
+}
+
+impl Tlb {
+    /// The number of translations the TLB holds.
+    const ENTRIES: usize = 64;
+
+    /// Creates an empty TLB.
+    pub fn new() -> Self {
+        Tlb {
+            entries: [None; Tlb::ENTRIES],
+        }
+    }
+
+    /// Returns the cached translation of the page containing `address`.
+    fn lookup(&self, address: u64) -> Option<TlbEntry> {
+        let vpn = address >> PAGE_SHIFT;
+        self.entries[vpn as usize % Tlb::ENTRIES].filter(|entry| entry.vpn == vpn)
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        self.entries[entry.vpn as usize % Tlb::ENTRIES] = Some(entry);
+    }
+
+    /// Invalidates all cached translations.
+    pub fn flush(&mut self) {
+        self.entries = [None; Tlb::ENTRIES];
+    }
+
+    /// Invalidates the cached translations of the page or superpage containing `address`.
+    pub fn flush_address(&mut self, address: u64) {
+        for entry in &mut self.entries {
+            if entry.is_some_and(|entry| entry.contains(address)) {
+                *entry = None;
+            }
+        }
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Tlb::new()
+    }
+}
+
+/// The memory management unit of a hart.
+#[derive(Debug, Clone, Default)]
+pub struct Mmu {
+    pub tlb: Tlb,
+}
+
+impl Mmu {
+    /// Creates an MMU with an empty TLB.
+    pub fn new() -> Self {
+        Mmu { tlb: Tlb::new() }
+    }
+
+    /// Translates the virtual `address` of an access of `size` bytes made with the effective
+    /// `privilege`, walking the page tables in `bus` if the translation is not cached.
+    ///
+    /// Raises a page fault if the address is not mapped or the page does not permit the
+    /// access, and an access fault if a page table cannot be accessed.
+    pub fn translate(
+        &mut self,
+        bus: &mut Bus,
+        csrs: &CsrFile,
+        privilege: PrivilegeMode,
+        address: u64,
+        size: u64,
+        access_type: AccessType,
+    ) -> Result<u64, Exception> {
+        let mode = TranslationMode::from_satp(csrs.satp, csrs.xlen);
+        let levels = match mode {
+            Some(mode) if privilege != PrivilegeMode::Machine => mode.levels(),
+            _ => return Ok(address),
+        };
+        if levels == 0 {
+            return Ok(address);
+        }
+
+        // The unused upper bits of the virtual address must be copies of its highest bit
+        let unused_bits = 64 - PAGE_SHIFT - LEVEL_BITS * levels;
+        if ((address << unused_bits) as i64 >> unused_bits) as u64 != address {
+            return Err(access_type.page_fault(address));
+        }
+
+        let entry = match self.tlb.lookup(address) {
+            // Stores to clean pages must walk the page tables again to set the D bit
+            Some(entry) if access_type != AccessType::Store || entry.pte & PTE_D != 0 => entry,
+            _ => {
+                let entry = walk(bus, csrs, levels, address, size, access_type, privilege)?;
+                self.tlb.insert(entry);
+                entry
+            }
+        };
+
+        if !is_permitted(entry.pte, csrs, privilege, access_type) {
+            return Err(access_type.page_fault(address));
+        }
+        Ok(entry.translate(address))
+    }
+}
+
+/// Returns `true` if the leaf `pte` permits the access in the effective `privilege`.
+fn is_permitted(
+    pte: u64,
+    csrs: &CsrFile,
+    privilege: PrivilegeMode,
+    access_type: AccessType,
+) -> bool {
+    let is_user_page = pte & PTE_U != 0;
+    let privilege_allowed = match privilege {
+        PrivilegeMode::User => is_user_page,
+        // Supervisor mode may access user pages with `mstatus.SUM`, but never execute them
+        PrivilegeMode::Supervisor => {
+            !is_user_page || access_type != AccessType::Fetch && csrs.mstatus & MSTATUS_SUM != 0
+        }
+        PrivilegeMode::Machine => true,
+    };
+    let access_allowed = match access_type {
+        AccessType::Fetch => pte & PTE_X != 0,
+        // `mstatus.MXR` makes executable pages readable
+        AccessType::Load => pte & PTE_R != 0 || csrs.mstatus & MSTATUS_MXR != 0 && pte & PTE_X != 0,
+        AccessType::Store => pte & PTE_W != 0,
+    };
+    privilege_allowed && access_allowed
+}
+
+/// Walks the page tables with `levels` levels for the virtual `address` and returns the
+/// translation of its page. The A and D bits of the leaf PTE are updated for the access.
+fn walk(
+    bus: &mut Bus,
+    csrs: &CsrFile,
+    levels: u32,
+    address: u64,
+    size: u64,
+    access_type: AccessType,
+    privilege: PrivilegeMode,
+) -> Result<TlbEntry, Exception> {
+    let page_fault = access_type.page_fault(address);
+    let access_fault = access_type.access_fault(address, size);
+
+    let mut table = (csrs.satp & SATP_PPN) << PAGE_SHIFT;
+    for level in (0..levels).rev() {
+        let index = address >> (PAGE_SHIFT + LEVEL_BITS * level) & ((1 << LEVEL_BITS) - 1);
+        let pte_address = table + index * PTE_SIZE;
+        if !csrs.pmp.allows(
+            pte_address,
+            PTE_SIZE,
+            AccessType::Load,
+            PrivilegeMode::Supervisor,
+        ) {
+            return Err(access_fault);
+        }
+        let pte = bus
+            .read(pte_address, PTE_SIZE, AccessType::Load)
+            .map_err(|_| access_fault)?;
+
+        if pte & PTE_V == 0 || pte & (PTE_R | PTE_W) == PTE_W || pte & PTE_RESERVED != 0 {
+            return Err(page_fault);
+        }
+
+        // Entries without R and X point to the page table of the next level
+        if pte & (PTE_R | PTE_X) == 0 {
+            if level == 0 || pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                return Err(page_fault);
+            }
+            table = (pte & PTE_PPN) << 2;
+            continue;
+        }
+
+        // Superpages must be aligned to their size
+        if (pte & PTE_PPN) << 2 & page_offset_mask(level) != 0 {
+            return Err(page_fault);
+        }
+        // Faulting accesses must not set the A and D bits
+        if !is_permitted(pte, csrs, privilege, access_type) {
+            return Err(page_fault);
+        }
+
+        let mut updated = pte | PTE_A;
+        if access_type == AccessType::Store {
+            updated |= PTE_D;
+        }
+        if updated != pte {
+            if !csrs.pmp.allows(
+                pte_address,
+                PTE_SIZE,
+                AccessType::Store,
+                PrivilegeMode::Supervisor,
+            ) {
+                return Err(access_fault);
+            }
+            bus.write(pte_address, PTE_SIZE, updated)
+                .map_err(|_| access_fault)?;
+        }
+        return Ok(TlbEntry {
+            vpn: address >> PAGE_SHIFT,
+            level,
+            pte: updated,
+        });
+    }
+    Err(page_fault)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::DEFAULT_DRAM_BASE,
+        pmp::{PMP_A_NAPOT, PMP_R, PMP_W, PMP_X},
+    };
+
+    /// The physical address of the root page table in the tests.
+    const ROOT: u64 = DEFAULT_DRAM_BASE;
+
+    /// Returns a PTE mapping the physical address `address` with the given flags.
+    fn pte(address: u64, flags: u64) -> u64 {
+        address >> 2 | flags | PTE_V
+    }
+
+    /// Creates a bus and CSRs with Sv39 enabled, and a root page table at [`ROOT`].
+    fn sv39() -> (Bus, CsrFile) {
+        let bus = Bus::new(DEFAULT_DRAM_BASE, 0x10_0000).unwrap();
+        let mut csrs = CsrFile::new();
+        csrs.satp = 8 << 60 | ROOT >> PAGE_SHIFT;
+        // Grant supervisor and user mode access to all of memory
+        csrs.pmp.write_addr(0, u64::MAX);
+        csrs.pmp.write_cfg(0, PMP_A_NAPOT | PMP_R | PMP_W | PMP_X);
+        (bus, csrs)
+    }
+
+    #[test]
+    /// Pages and superpages are translated, and the A and D bits are set by the accesses.
+    fn test_sv39_translation() {
+        let (mut bus, mut csrs) = sv39();
+        let mut mmu = Mmu::new();
+        let supervisor = PrivilegeMode::Supervisor;
+
+        // A 1 GiB superpage at virtual address 0, mapping the DRAM
+        bus.write(ROOT, 8, pte(DEFAULT_DRAM_BASE, PTE_R | PTE_W | PTE_X))
+            .unwrap();
+        // A 4 KiB user page at 0x4000_1000 through the tables at ROOT + 0x1000 and 0x2000
+        bus.write(ROOT + 8, 8, pte(ROOT + 0x1000, 0)).unwrap();
+        bus.write(ROOT + 0x1000, 8, pte(ROOT + 0x2000, 0)).unwrap();
+        bus.write(ROOT + 0x2008, 8, pte(ROOT + 0x5000, PTE_R | PTE_W | PTE_U))
+            .unwrap();
+
+        let mut translate = |csrs: &CsrFile, privilege, address, access_type| {
+            mmu.translate(&mut bus, csrs, privilege, address, 8, access_type)
+        };
+        assert_eq!(
+            translate(&csrs, supervisor, 0x1234, AccessType::Fetch),
+            Ok(DEFAULT_DRAM_BASE + 0x1234)
+        );
+        assert_eq!(
+            translate(&csrs, PrivilegeMode::User, 0x4000_1008, AccessType::Store),
+            Ok(ROOT + 0x5008)
+        );
+        assert_eq!(
+            translate(&csrs, PrivilegeMode::User, 0x1234, AccessType::Load),
+            Err(Exception::LoadPageFault(0x1234))
+        );
+        // Supervisor mode accesses user pages only with SUM
+        assert_eq!(
+            translate(&csrs, supervisor, 0x4000_1000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x4000_1000))
+        );
+        csrs.mstatus |= MSTATUS_SUM;
+        assert_eq!(
+            translate(&csrs, supervisor, 0x4000_1000, AccessType::Load),
+            Ok(ROOT + 0x5000)
+        );
+        assert_eq!(
+            translate(&csrs, supervisor, 0x4000_1000, AccessType::Fetch),
+            Err(Exception::InstructionPageFault(0x4000_1000))
+        );
+        // Unmapped and non-canonical addresses fault
+        assert_eq!(
+            translate(&csrs, supervisor, 0x40_0000_0000, AccessType::Store),
+            Err(Exception::StorePageFault(0x40_0000_0000))
+        );
+        assert_eq!(
+            translate(&csrs, supervisor, 0x4020_0000, AccessType::Load),
+            Err(Exception::LoadPageFault(0x4020_0000))
+        );
+        assert_eq!(
+            translate(&csrs, PrivilegeMode::Machine, 0x4020_0000, AccessType::Load),
+            Ok(0x4020_0000)
+        );
+
+        let flags = |bus: &mut Bus, address| bus.read(address, 8, AccessType::Load).unwrap() & 0xff;
+        assert_eq!(flags(&mut bus, ROOT), PTE_V | PTE_R | PTE_W | PTE_X | PTE_A);
+        assert_eq!(
+            flags(&mut bus, ROOT + 0x2008),
+            PTE_V | PTE_R | PTE_W | PTE_U | PTE_A | PTE_D
+        );
+    }
+
+    #[test]
+    /// Cached translations are used until they are flushed from the TLB.
+    fn test_tlb_flush() {
+        let (mut bus, csrs) = sv39();
+        let mut mmu = Mmu::new();
+        let supervisor = PrivilegeMode::Supervisor;
+
+        bus.write(ROOT, 8, pte(DEFAULT_DRAM_BASE, PTE_R | PTE_A))
+            .unwrap();
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, supervisor, 0x3000, 4, AccessType::Load),
+            Ok(DEFAULT_DRAM_BASE + 0x3000)
+        );
+
+        bus.write(ROOT, 8, pte(DEFAULT_DRAM_BASE + 0x4000_0000, PTE_R | PTE_A))
+            .unwrap();
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, supervisor, 0x3000, 4, AccessType::Load),
+            Ok(DEFAULT_DRAM_BASE + 0x3000)
+        );
+        // Flushing any address in the superpage invalidates the translation
+        mmu.tlb.flush_address(0x1000_0000);
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, supervisor, 0x3000, 4, AccessType::Load),
+            Ok(DEFAULT_DRAM_BASE + 0x4000_3000)
+        );
+        // A misaligned superpage faults
+        bus.write(ROOT, 8, pte(DEFAULT_DRAM_BASE + 0x1000, PTE_R | PTE_A))
+            .unwrap();
+        mmu.tlb.flush();
+        assert_eq!(
+            mmu.translate(&mut bus, &csrs, supervisor, 0x3000, 4, AccessType::Load),
+            Err(Exception::LoadPageFault(0x3000))
+        );
+    }
+}
@@ -1,22 +1,186 @@
-use std::{io, ops::{Index, IndexMut, Range, RangeInclusive}};
+use std::{
+    io,
+    ops::{Index, IndexMut, Range, RangeInclusive},
+};
 
+use derive_more::Display;
 use memmap2::{MmapMut, MmapOptions};
 
+use crate::trap::AccessType;
+
+/// The access permissions of a memory region, displayed like `r-x`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[display(
+    "{}{}{}",
+    if *read { "r" } else { "-" },
+    if *write { "w" } else { "-" },
+    if *execute { "x" } else { "-" }
+)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    /// Readable, writable and executable.
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+
+    /// Readable and writable, but not executable.
+    pub const READ_WRITE: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: false,
+    };
+
+    /// Returns the permissions of the `p_flags` of an ELF program header.
+    pub fn from_elf_flags(flags: u32) -> Self {
+        use goblin::elf::program_header::{PF_R, PF_W, PF_X};
+        Permissions {
+            read: flags & PF_R != 0,
+            write: flags & PF_W != 0,
+            execute: flags & PF_X != 0,
+        }
+    }
+
+    /// Returns `true` if the permissions allow the kind of access.
+    pub fn allows(self, access_type: AccessType) -> bool {
+        match access_type {
+            AccessType::Fetch => self.execute,
+            AccessType::Load => self.read,
+            AccessType::Store => self.write,
+        }
+    }
+}
+
 pub struct MonitoredMemory {
     inner: MmapMut,
+
+    /// The regions with their own access permissions, which never overlap.
+    regions: Vec<(Range<usize>, Permissions)>,
+
+    /// The permissions of all memory outside of the [`regions`](Self::regions).
+    default_permissions: Permissions,
+
+    /// The reservation set registered by the last load-reserved (`lr.w` / `lr.d`).
+    ///
+    /// Any store overlapping this range invalidates the reservation, which makes the
+    /// matching store-conditional (`sc.w` / `sc.d`) fail.
+    reservation: Option<Range<usize>>,
+}
+
+macro_rules! impl_read_write {
+    ($($ty:ty),*) => {
+        ::paste::paste! {
+            $(
+                #[doc = "Reads a little-endian [`" $ty "`] starting at `address`."]
+                pub fn [<read_ $ty>](&self, address: usize) -> $ty {
+                    const SIZE: usize = ::std::mem::size_of::<$ty>();
+                    let bytes = &self.inner[address..address + SIZE];
+                    $ty::from_le_bytes(bytes.try_into().expect("Slice has the size of the type"))
+                }
+
+                #[doc = "Writes `value` as a little-endian [`" $ty "`] starting at `address`."]
+                pub fn [<write_ $ty>](&mut self, address: usize, value: $ty) {
+                    const SIZE: usize = ::std::mem::size_of::<$ty>();
+                    self.invalidate_reservation(address..address + SIZE);
+                    self.inner[address..address + SIZE].copy_from_slice(&value.to_le_bytes());
+                }
+            )*
+        }
+    };
 }
 
 impl MonitoredMemory {
     /// Creates a new instance of [`MonitoredMemory`] with the specified `size`.
     pub fn new(size: usize) -> io::Result<Self> {
         let inner = MmapOptions::new().len(size).map_anon()?;
-        Ok(MonitoredMemory { inner })
+        Ok(MonitoredMemory {
+            inner,
+            regions: Vec::new(),
+            default_permissions: Permissions::ALL,
+            reservation: None,
+        })
     }
 
     /// Returns the size of the [`MonitoredMemory`].
     pub fn size(&self) -> usize {
         self.inner.len()
     }
+
+    impl_read_write!(u8, u16, u32, u64);
+
+    /// Sets the permissions of the memory outside of the protected regions.
+    pub fn set_default_permissions(&mut self, permissions: Permissions) {
+        self.default_permissions = permissions;
+    }
+
+    /// Restricts the accesses to `range` to the given `permissions`,
+    /// replacing the permissions of any overlapping region within `range`.
+    pub fn protect(&mut self, range: Range<usize>, permissions: Permissions) {
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for (region, region_permissions) in self.regions.drain(..) {
+            // Keep the parts of the region before and after the new range
+            let before = region.start..region.end.min(range.start);
+            let after = region.start.max(range.end)..region.end;
+            for part in [before, after] {
+                if !part.is_empty() {
+                    regions.push((part, region_permissions));
+                }
+            }
+        }
+        regions.push((range, permissions));
+        self.regions = regions;
+    }
+
+    /// Removes all protected regions and allows any access.
+    pub fn clear_protection(&mut self) {
+        self.regions.clear();
+        self.default_permissions = Permissions::ALL;
+    }
+
+    /// Returns `true` if every byte of `range` may be accessed by the kind of access.
+    pub fn is_allowed(&self, range: Range<usize>, access_type: AccessType) -> bool {
+        let mut covered = 0;
+        for (region, permissions) in &self.regions {
+            let overlap = range.start.max(region.start)..range.end.min(region.end);
+            if overlap.is_empty() {
+                continue;
+            }
+            if !permissions.allows(access_type) {
+                return false;
+            }
+            covered += overlap.len();
+        }
+        covered == range.len() || self.default_permissions.allows(access_type)
+    }
+
+    /// Registers a reservation on `range`, replacing any previous reservation.
+    pub fn reserve(&mut self, range: Range<usize>) {
+        self.reservation = Some(range);
+    }
+
+    /// Consumes the current reservation and returns whether it still covers `range`.
+    ///
+    /// A store-conditional always clears the reservation, whether it succeeds or not.
+    pub fn take_reservation(&mut self, range: Range<usize>) -> bool {
+        self.reservation
+            .take()
+            .is_some_and(|reservation| reservation == range)
+    }
+
+    /// Invalidates the reservation if it overlaps with the written `range`.
+    fn invalidate_reservation(&mut self, range: Range<usize>) {
+        if self.reservation.as_ref().is_some_and(|reservation| {
+            reservation.start < range.end && range.start < reservation.end
+        }) {
+            self.reservation = None;
+        }
+    }
 }
 
 impl Index<usize> for MonitoredMemory {
@@ -29,6 +193,7 @@ impl Index<usize> for MonitoredMemory {
 
 impl IndexMut<usize> for MonitoredMemory {
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        self.invalidate_reservation(index..index + 1);
         &mut self.inner[index]
     }
 }
@@ -43,6 +208,7 @@ impl Index<Range<usize>> for MonitoredMemory {
 
 impl IndexMut<Range<usize>> for MonitoredMemory {
     fn index_mut(&mut self, index: Range<usize>) -> &mut Self::Output {
+        self.invalidate_reservation(index.clone());
         &mut self.inner[index]
     }
 }
@@ -57,9 +223,27 @@ impl Index<RangeInclusive<usize>> for MonitoredMemory {
 
 impl IndexMut<RangeInclusive<usize>> for MonitoredMemory {
     fn index_mut(&mut self, index: RangeInclusive<usize>) -> &mut Self::Output {
+        self.invalidate_reservation(*index.start()..index.end() + 1);
         &mut self.inner[index]
     }
 }
 
-
-
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Protecting part of a region keeps the permissions of the rest of it.
+    fn test_protect_sub_range() {
+        let mut memory = MonitoredMemory::new(0x1000).unwrap();
+        memory.set_default_permissions(Permissions::READ_WRITE);
+        memory.protect(0x100..0x200, Permissions::ALL);
+        memory.protect(0x140..0x180, Permissions::READ_WRITE);
+
+        assert!(memory.is_allowed(0x100..0x140, AccessType::Fetch));
+        assert!(!memory.is_allowed(0x140..0x180, AccessType::Fetch));
+        assert!(memory.is_allowed(0x180..0x200, AccessType::Fetch));
+        assert!(!memory.is_allowed(0x200..0x204, AccessType::Fetch));
+        assert!(memory.is_allowed(0x13c..0x144, AccessType::Store));
+    }
+}
@@ -0,0 +1,219 @@
+//! # Physical Memory Protection
+//!
+//! This module implements the PMP unit, which restricts the physical addresses supervisor and
+//! user mode may access. Each of the 16 entries is configured by a byte of the `pmpcfg`
+//! registers and an address register `pmpaddr`, which holds bits 2 and up of an address:
+//!
+//! - TOR: the entry matches `pmpaddr[i - 1] <= address < pmpaddr[i]`
+//! - NA4: the entry matches the 4 bytes at `pmpaddr[i]`
+//! - NAPOT: the entry matches a naturally aligned power-of-two region of at least 8 bytes,
+//!   whose size is encoded by the number of trailing ones of `pmpaddr[i]`
+//!
+//! The lowest-numbered entry that matches any byte of an access decides whether it is
+//! permitted. Supervisor and user mode accesses fail if no entry matches. Machine mode accesses
+//! are only checked against locked entries, which cannot be changed until reset.
+//! See also: [https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/machine.html#pmp]
+
+use std::ops::Range;
+
+use crate::{csr::PrivilegeMode, trap::AccessType};
+
+/// The number of implemented PMP entries.
+pub const PMP_ENTRIES: usize = 16;
+
+/// `pmpcfg.R`: The region is readable.
+pub const PMP_R: u8 = 1 << 0;
+/// `pmpcfg.W`: The region is writable.
+pub const PMP_W: u8 = 1 << 1;
+/// `pmpcfg.X`: The region is executable.
+pub const PMP_X: u8 = 1 << 2;
+/// `pmpcfg.A`: The address matching mode of the entry.
+pub const PMP_A: u8 = 0b11 << 3;
+/// `pmpcfg.A`: The entry is disabled.
+pub const PMP_A_OFF: u8 = 0 << 3;
+/// `pmpcfg.A`: Top of range.
+pub const PMP_A_TOR: u8 = 1 << 3;
+/// `pmpcfg.A`: Naturally aligned four-byte region.
+pub const PMP_A_NA4: u8 = 2 << 3;
+/// `pmpcfg.A`: Naturally aligned power-of-two region.
+pub const PMP_A_NAPOT: u8 = 3 << 3;
+/// `pmpcfg.L`: The entry is locked and also enforced in machine mode.
+pub const PMP_L: u8 = 1 << 7;
+/// The bits of a configuration that can be written, bits 5 and 6 are reserved.
+const PMP_CFG_WRITABLE: u8 = PMP_R | PMP_W | PMP_X | PMP_A | PMP_L;
+
+/// The bits of `pmpaddr`, which holds bits 2 to 55 of a 56-bit physical address.
+const PMP_ADDR_MASK: u64 = (1 << 54) - 1;
+
+/// The configuration and address registers of the PMP entries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    /// Creates a PMP unit with all entries disabled and unlocked.
+    pub fn new() -> Self {
+        Pmp {
+            cfg: [0; PMP_ENTRIES],
+            addr: [0; PMP_ENTRIES],
+        }
+    }
+
+    /// Returns the configuration of entry `index`.
+    pub fn cfg(&self, index: usize) -> u8 {
+        self.cfg[index]
+    }
+
+    /// Returns the address register of entry `index`.
+    pub fn addr(&self, index: usize) -> u64 {
+        self.addr[index]
+    }
+
+    fn is_locked(&self, index: usize) -> bool {
+        self.cfg[index] & PMP_L != 0
+    }
+
+    /// Writes the configuration of entry `index`, unless the entry is locked.
+    pub fn write_cfg(&mut self, index: usize, value: u8) {
+        if self.is_locked(index) {
+            return;
+        }
+        let mut cfg = value & PMP_CFG_WRITABLE;
+        // Writable regions must also be readable
+        if cfg & PMP_R == 0 {
+            cfg &= !PMP_W;
+        }
+        self.cfg[index] = cfg;
+    }
+
+    /// Writes the address register of entry `index`, unless it is locked by its own entry or
+    /// used as the bottom of a locked TOR range.
+    pub fn write_addr(&mut self, index: usize, value: u64) {
+        let next_locks_bottom = index + 1 < PMP_ENTRIES
+            && self.is_locked(index + 1)
+            && self.cfg[index + 1] & PMP_A == PMP_A_TOR;
+        if self.is_locked(index) || next_locks_bottom {
+            return;
+        }
+        self.addr[index] = value & PMP_ADDR_MASK;
+    }
+
+    /// Returns the addresses matched by entry `index`, or [`None`] if it is disabled.
+    fn range(&self, index: usize) -> Option<Range<u64>> {
+        let addr = self.addr[index];
+        match self.cfg[index] & PMP_A {
+            PMP_A_TOR => {
+                let bottom = if index == 0 { 0 } else { self.addr[index - 1] };
+                Some(bottom << 2..addr << 2)
+            }
+            PMP_A_NA4 => Some(addr << 2..(addr << 2) + 4),
+            PMP_A_NAPOT => {
+                let ones = addr.trailing_ones();
+                let base = (addr & !((1 << ones) - 1)) << 2;
+                Some(base..base + (1 << (ones + 3)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the access of `size` bytes at the physical `address` made in
+    /// `privilege` is permitted.
+    pub fn allows(
+        &self,
+        address: u64,
+        size: u64,
+        access_type: AccessType,
+        privilege: PrivilegeMode,
+    ) -> bool {
+        let end = address.saturating_add(size);
+        for index in 0..PMP_ENTRIES {
+            let Some(range) = self.range(index) else {
+                continue;
+            };
+            if end <= range.start || range.end <= address {
+                continue;
+            }
+            // Accesses that are only partially matched fail
+            if address < range.start || range.end < end {
+                return false;
+            }
+            let cfg = self.cfg[index];
+            if privilege == PrivilegeMode::Machine && cfg & PMP_L == 0 {
+                return true;
+            }
+            let permission = match access_type {
+                AccessType::Fetch => PMP_X,
+                AccessType::Load => PMP_R,
+                AccessType::Store => PMP_W,
+            };
+            return cfg & permission != 0;
+        }
+        privilege == PrivilegeMode::Machine
+    }
+}
+
+impl Default for Pmp {
+    fn default() -> Self {
+        Pmp::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Accesses are checked against the first matching entry of each matching mode.
+    fn test_address_matching() {
+        let mut pmp = Pmp::new();
+        let user = PrivilegeMode::User;
+        assert!(!pmp.allows(0x1000, 4, AccessType::Load, user));
+        assert!(pmp.allows(0x1000, 4, AccessType::Store, PrivilegeMode::Machine));
+
+        // 0x1000..0x2000 is read-only, the 4 bytes at 0x2000 are writable
+        // and 0x0..0x1_0000 is executable
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(1, PMP_A_TOR | PMP_R);
+        pmp.write_addr(2, 0x2000 >> 2);
+        pmp.write_cfg(2, PMP_A_NA4 | PMP_R | PMP_W);
+        pmp.write_addr(3, 0x7fff >> 2);
+        pmp.write_cfg(3, PMP_A_NAPOT | PMP_X);
+
+        assert!(pmp.allows(0x1ff8, 8, AccessType::Load, user));
+        assert!(!pmp.allows(0x1ff8, 8, AccessType::Store, user));
+        assert!(pmp.allows(0x2000, 4, AccessType::Store, user));
+        // The access straddles the end of the NA4 region
+        assert!(!pmp.allows(0x2000, 8, AccessType::Store, user));
+        assert!(pmp.allows(0xfffc, 4, AccessType::Fetch, user));
+        assert!(!pmp.allows(0x1_0000, 4, AccessType::Fetch, user));
+        assert!(!pmp.allows(0x0, 4, AccessType::Load, user));
+
+        // The reserved combination W without R is not stored
+        pmp.write_cfg(4, PMP_A_NA4 | PMP_W);
+        assert_eq!(pmp.cfg(4), PMP_A_NA4);
+    }
+
+    #[test]
+    /// Locked entries cannot be changed and also restrict machine mode.
+    fn test_locked_entries() {
+        let mut pmp = Pmp::new();
+        let machine = PrivilegeMode::Machine;
+        pmp.write_addr(0, 0x1000 >> 2);
+        pmp.write_addr(1, 0x2000 >> 2);
+        pmp.write_cfg(1, PMP_A_TOR | PMP_R | PMP_X | PMP_L);
+
+        assert!(pmp.allows(0x1000, 4, AccessType::Fetch, machine));
+        assert!(!pmp.allows(0x1000, 4, AccessType::Store, machine));
+        assert!(pmp.allows(0x2000, 4, AccessType::Store, machine));
+
+        // Both bounds of the locked TOR range are locked
+        pmp.write_cfg(1, PMP_A_OFF);
+        pmp.write_addr(1, 0);
+        pmp.write_addr(0, 0);
+        assert_eq!(pmp.cfg(1), PMP_A_TOR | PMP_R | PMP_X | PMP_L);
+        assert_eq!((pmp.addr(0), pmp.addr(1)), (0x1000 >> 2, 0x2000 >> 2));
+    }
+}
@@ -0,0 +1,374 @@
+//! # Supervisor Binary Interface
+//!
+//! This module implements the SBI firmware of the VM, which services the `ecall`s of supervisor
+//! mode kernels in place of machine mode firmware like OpenSBI. Arguments are passed in `a0`
+//! to `a5`, the extension in `a7` and the function in `a6`. Calls return an error code in `a0`
+//! and a value in `a1`, except for the legacy extensions, which only return `a0`. Like the
+//! registers, the arguments are sign-extended on RV32.
+//!
+//! The following extensions are supported for the single hart of the VM:
+//!
+//! - Base: the version of the implementation and the supported extensions
+//! - TIME: the supervisor timer interrupt
+//! - IPI: supervisor software interrupts
+//! - RFENCE: remote fences, which only fence the local hart
+//! - HSM: the state of the hart and retentive suspend
+//! - SRST: system shutdown
+//! - Legacy console putchar and getchar
+//!
+//! See also: [https://github.com/riscv-non-isa/riscv-sbi-doc/blob/master/src/binary-encoding.adoc]
+
+use crossbeam::channel::{Receiver, Sender};
+use derive_more::Display;
+
+use crate::{
+    cpu::CpuEvent,
+    csr::{self, CsrFile, MIP_SSIP, MIP_STIP, PrivilegeMode},
+    devices::DeviceContext,
+    mmu::Tlb,
+    utils::push_utf8,
+};
+
+/// The legacy extension writing a character to the console.
+pub const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+/// The legacy extension reading a character from the console.
+pub const EXT_LEGACY_GETCHAR: u64 = 0x02;
+/// The base extension.
+pub const EXT_BASE: u64 = 0x10;
+/// The timer extension (`TIME`).
+pub const EXT_TIME: u64 = 0x5449_4d45;
+/// The inter-processor interrupt extension (`sPI`).
+pub const EXT_IPI: u64 = 0x0073_5049;
+/// The remote fence extension (`RFNC`).
+pub const EXT_RFENCE: u64 = 0x5246_4e43;
+/// The hart state management extension (`HSM`).
+pub const EXT_HSM: u64 = 0x0048_534d;
+/// The system reset extension (`SRST`).
+pub const EXT_SRST: u64 = 0x5352_5354;
+
+/// The extensions reported as available by the probe function.
+const EXTENSIONS: [u64; 8] = [
+    EXT_LEGACY_PUTCHAR,
+    EXT_LEGACY_GETCHAR,
+    EXT_BASE,
+    EXT_TIME,
+    EXT_IPI,
+    EXT_RFENCE,
+    EXT_HSM,
+    EXT_SRST,
+];
+
+/// The implemented version of the SBI specification, 2.0.
+const SPEC_VERSION: u64 = 2 << 24;
+/// The implementation ID of the VM, which is not assigned by the SBI specification.
+const IMPL_ID: u64 = 0x52_564d;
+
+/// The call completed successfully.
+pub const SBI_SUCCESS: i64 = 0;
+/// The call failed for an unspecified reason.
+pub const SBI_ERR_FAILED: i64 = -1;
+/// The extension or function is not supported.
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+/// An argument is invalid, e.g. a hart that does not exist.
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+/// The hart is already started.
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+/// `hart_get_status`: The hart is started.
+const HSM_STARTED: u64 = 0;
+/// `hart_suspend`: The default retentive suspend, which returns like `wfi`.
+/// The suspend types, like the reset types and reasons, are 32-bit values.
+const HSM_SUSPEND_RETENTIVE: u64 = 0x0000_0000;
+/// `hart_suspend`: The default non-retentive suspend.
+const HSM_SUSPEND_NON_RETENTIVE: u64 = 0x8000_0000;
+
+/// `system_reset`: Shut the system down.
+const SRST_SHUTDOWN: u64 = 0;
+/// `system_reset`: The highest reset type, warm reboot.
+const SRST_WARM_REBOOT: u64 = 2;
+/// `system_reset`: The system is reset because of a failure.
+const SRST_REASON_FAILURE: u64 = 1;
+
+/// The result of an SBI call, which the hart applies to its state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum SbiResult {
+    /// The call returns `error` in `a0` and `value` in `a1`.
+    #[display("Return({error}, {value:#x})")]
+    Return { error: i64, value: u64 },
+    /// The legacy call returns `_0` in `a0`.
+    #[display("Legacy({_0:#x})")]
+    Legacy(u64),
+    /// The hart waits for an interrupt, like `wfi`, before the call returns successfully.
+    Suspend,
+    /// The system shuts down with `exit_code`.
+    #[display("Shutdown({exit_code})")]
+    Shutdown { exit_code: i32 },
+}
+
+impl SbiResult {
+    fn error(error: i64) -> Self {
+        SbiResult::Return { error, value: 0 }
+    }
+
+    fn value(value: u64) -> Self {
+        SbiResult::Return {
+            error: SBI_SUCCESS,
+            value,
+        }
+    }
+}
+
+/// The state of the SBI firmware.
+#[derive(Debug)]
+pub struct Sbi {
+    /// The time at which the supervisor timer interrupt is raised.
+    timer_deadline: u64,
+    /// The bytes of a UTF-8 sequence that is not yet completely written to the console.
+    console_pending: Vec<u8>,
+    events: Sender<CpuEvent>,
+    console_input: Receiver<u8>,
+}
+
+impl Sbi {
+    /// Creates the firmware, which uses the console of `context`.
+    pub fn new(context: &DeviceContext) -> Self {
+        Sbi {
+            timer_deadline: u64::MAX,
+            console_pending: Vec::new(),
+            events: context.events.clone(),
+            console_input: context.console_input.clone(),
+        }
+    }
+
+    /// Returns `true` if the supervisor timer interrupt is due at `time`.
+    pub fn is_timer_due(&self, time: u64) -> bool {
+        time >= self.timer_deadline
+    }
+
+    /// Services the call of function `function` of extension `extension` with the arguments
+    /// `args` (`a0` to `a5`), and the hart state it may modify.
+    pub fn call(
+        &mut self,
+        extension: u64,
+        function: u64,
+        args: [u64; 6],
+        csrs: &mut CsrFile,
+        tlb: &mut Tlb,
+    ) -> SbiResult {
+        match extension {
+            EXT_LEGACY_PUTCHAR => {
+                if let Some(text) = push_utf8(&mut self.console_pending, args[0] as u8) {
+                    // The output is dropped if the application is not listening
+                    let _ = self.events.send(CpuEvent::Write { text });
+                }
+                SbiResult::Legacy(0)
+            }
+            EXT_LEGACY_GETCHAR => match self.console_input.try_recv() {
+                Ok(byte) => SbiResult::Legacy(byte as u64),
+                Err(_) => SbiResult::Legacy(u64::MAX),
+            },
+            EXT_BASE => Self::call_base(function, args, csrs),
+            EXT_TIME if function == 0 => {
+                // On RV32, the time is passed in two registers
+                self.timer_deadline = match csrs.xlen {
+                    csr::Xlen::Rv32 => args[0] & 0xffff_ffff | args[1] << 32,
+                    csr::Xlen::Rv64 => args[0],
+                };
+                csrs.mip &= !MIP_STIP;
+                SbiResult::value(0)
+            }
+            EXT_IPI if function == 0 => match targets_hart(args[0], args[1]) {
+                Some(true) => {
+                    csrs.mip |= MIP_SSIP;
+                    SbiResult::value(0)
+                }
+                Some(false) => SbiResult::value(0),
+                None => SbiResult::error(SBI_ERR_INVALID_PARAM),
+            },
+            EXT_RFENCE => match (function, targets_hart(args[0], args[1])) {
+                // The functions for hypervisors are not supported
+                (3.., _) => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+                (_, None) => SbiResult::error(SBI_ERR_INVALID_PARAM),
+                // Instructions are never cached, so `fence.i` has nothing to do.
+                // Flushing all translations is a valid implementation of any `sfence.vma`.
+                (1 | 2, Some(true)) => {
+                    tlb.flush();
+                    SbiResult::value(0)
+                }
+                _ => SbiResult::value(0),
+            },
+            EXT_HSM => Self::call_hsm(function, args),
+            EXT_SRST if function == 0 => match (args[0] as u32 as u64, args[1] as u32 as u64) {
+                (SRST_SHUTDOWN, reason) => SbiResult::Shutdown {
+                    exit_code: (reason == SRST_REASON_FAILURE) as i32,
+                },
+                // The VM cannot reboot, as it does not keep the program it started with
+                (..=SRST_WARM_REBOOT, _) => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+                _ => SbiResult::error(SBI_ERR_INVALID_PARAM),
+            },
+            _ => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /// Services the base extension, which reports the versions of the specification and the
+    /// implementation, the available extensions and the ids of the hart.
+    fn call_base(function: u64, args: [u64; 6], csrs: &CsrFile) -> SbiResult {
+        let machine_csr = |address| csrs.read(address, PrivilegeMode::Machine).unwrap_or(0);
+        match function {
+            0 => SbiResult::value(SPEC_VERSION),
+            1 => SbiResult::value(IMPL_ID),
+            2 => SbiResult::value(impl_version()),
+            3 => SbiResult::value(EXTENSIONS.contains(&args[0]) as u64),
+            4 => SbiResult::value(machine_csr(csr::MVENDORID)),
+            5 => SbiResult::value(machine_csr(csr::MARCHID)),
+            6 => SbiResult::value(machine_csr(csr::MIMPID)),
+            _ => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+
+    /// Services the hart state management extension for the single hart, which is always
+    /// started and only supports the retentive suspend.
+    fn call_hsm(function: u64, args: [u64; 6]) -> SbiResult {
+        let is_valid_hart = args[0] == 0;
+        match function {
+            // hart_start: the only hart is always running
+            0 if is_valid_hart => SbiResult::error(SBI_ERR_ALREADY_AVAILABLE),
+            // hart_stop: the only hart cannot be stopped
+            1 => SbiResult::error(SBI_ERR_FAILED),
+            2 if is_valid_hart => SbiResult::value(HSM_STARTED),
+            0 | 2 => SbiResult::error(SBI_ERR_INVALID_PARAM),
+            3 => match args[0] as u32 as u64 {
+                HSM_SUSPEND_RETENTIVE => SbiResult::Suspend,
+                HSM_SUSPEND_NON_RETENTIVE => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+                _ => SbiResult::error(SBI_ERR_INVALID_PARAM),
+            },
+            _ => SbiResult::error(SBI_ERR_NOT_SUPPORTED),
+        }
+    }
+}
+
+/// Returns whether the harts selected by `hart_mask` and `hart_mask_base` include hart 0,
+/// or [`None`] if they include harts that do not exist. A base of -1 selects all harts.
+fn targets_hart(hart_mask: u64, hart_mask_base: u64) -> Option<bool> {
+    match (hart_mask, hart_mask_base) {
+        (_, u64::MAX) => Some(true),
+        (0, _) => Some(false),
+        (1, 0) => Some(true),
+        _ => None,
+    }
+}
+
+/// Returns the version of the VM as `major << 16 | minor`.
+fn impl_version() -> u64 {
+    let major: u64 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0);
+    let minor: u64 = env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0);
+    major << 16 | minor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::{Bus, DEFAULT_DRAM_BASE},
+        devices::DeviceRegistry,
+        trap::AccessType,
+    };
+
+    #[test]
+    /// The extensions are probed and the timer, IPI and reset calls update the hart state.
+    fn test_sbi_calls() {
+        let (events, cpu_events) = crossbeam::channel::unbounded();
+        let (console, console_input) = crossbeam::channel::unbounded();
+        let mut sbi = Sbi::new(&DeviceContext {
+            events,
+            console_input,
+        });
+        let mut csrs = CsrFile::new();
+        let mut tlb = Tlb::new();
+        let mut call = |extension, function, args: &[u64]| {
+            let mut registers = [0; 6];
+            registers[..args.len()].copy_from_slice(args);
+            sbi.call(extension, function, registers, &mut csrs, &mut tlb)
+        };
+
+        assert_eq!(call(EXT_BASE, 0, &[]), SbiResult::value(2 << 24));
+        assert_eq!(call(EXT_BASE, 3, &[EXT_HSM]), SbiResult::value(1));
+        assert_eq!(call(EXT_BASE, 3, &[0x4442_4e43]), SbiResult::value(0));
+        assert_eq!(
+            call(0x4442_4e43, 0, &[]),
+            SbiResult::error(SBI_ERR_NOT_SUPPORTED)
+        );
+
+        assert_eq!(call(EXT_TIME, 0, &[100]), SbiResult::value(0));
+        assert_eq!(call(EXT_IPI, 0, &[1, 0]), SbiResult::value(0));
+        assert_eq!(
+            call(EXT_IPI, 0, &[1, 1]),
+            SbiResult::error(SBI_ERR_INVALID_PARAM)
+        );
+        assert_eq!(call(EXT_HSM, 2, &[0]), SbiResult::value(HSM_STARTED));
+        assert_eq!(call(EXT_HSM, 3, &[0]), SbiResult::Suspend);
+        assert_eq!(
+            call(EXT_SRST, 0, &[SRST_SHUTDOWN, SRST_REASON_FAILURE]),
+            SbiResult::Shutdown { exit_code: 1 }
+        );
+
+        for byte in "é".bytes() {
+            assert_eq!(
+                call(EXT_LEGACY_PUTCHAR, 0, &[byte as u64]),
+                SbiResult::Legacy(0)
+            );
+        }
+        console.send(b'y').unwrap();
+        assert_eq!(
+            call(EXT_LEGACY_GETCHAR, 0, &[]),
+            SbiResult::Legacy(b'y' as u64)
+        );
+        assert_eq!(
+            call(EXT_LEGACY_GETCHAR, 0, &[]),
+            SbiResult::Legacy(u64::MAX)
+        );
+
+        assert_eq!(csrs.mip, MIP_SSIP);
+        assert!(!sbi.is_timer_due(99));
+        assert!(sbi.is_timer_due(100));
+        assert_eq!(
+            cpu_events.try_recv(),
+            Ok(CpuEvent::Write {
+                text: "é".to_string()
+            })
+        );
+    }
+
+    #[test]
+    /// The console input goes to the SBI firmware, not to a UART attached next to it.
+    fn test_getchar_with_uart() {
+        let (console, console_input) = crossbeam::channel::unbounded();
+        let context = DeviceContext {
+            console_input,
+            ..DeviceContext::default()
+        };
+        let mut bus = Bus::new(DEFAULT_DRAM_BASE, 4096).unwrap();
+        DeviceRegistry::new()
+            .attach(
+                &mut bus,
+                &"uart".parse().unwrap(),
+                &context.without_console_input(),
+            )
+            .unwrap();
+        let mut sbi = Sbi::new(&context);
+        let mut csrs = CsrFile::new();
+        let mut tlb = Tlb::new();
+
+        console.send(b'y').unwrap();
+        for _ in 0..2048 {
+            bus.tick();
+        }
+        // Reading the line status register of the UART polls the input
+        let lsr = bus.read(0x1000_0005, 1, AccessType::Load).unwrap();
+        assert_eq!(lsr & 1, 0);
+        assert_eq!(
+            sbi.call(EXT_LEGACY_GETCHAR, 0, [0; 6], &mut csrs, &mut tlb),
+            SbiResult::Legacy(b'y' as u64)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! # Traps
+//!
+//! This module defines the synchronous exceptions a hart can raise and the asynchronous
+//! interrupts it can receive. Taking a trap saves the program counter in `mepc`, the cause in
+//! `mcause` and additional information in `mtval`, before execution continues at the trap
+//! handler configured in `mtvec`. Traps delegated to supervisor mode use the supervisor
+//! registers `sepc`, `scause`, `stval` and `stvec` instead.
+//! See also: [https://five-embeddev.com/riscv-priv-isa-manual/Priv-v1.12/machine.html#sec:mcause]
+
+use derive_more::Display;
+
+/// A synchronous exception, caused by the execution of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Exception {
+    /// The misaligned target address of a control transfer.
+    #[display("instruction address misaligned: {_0:#x}")]
+    InstructionAddressMisaligned(u64),
+    /// The address of the instruction parcel that could not be fetched.
+    #[display("instruction access fault at {_0:#x}")]
+    InstructionAccessFault(u64),
+    /// The raw bits of the illegal instruction.
+    #[display("illegal instruction: {_0:#x}")]
+    IllegalInstruction(u32),
+    /// The address of the `ebreak` instruction.
+    #[display("breakpoint at {_0:#x}")]
+    Breakpoint(u64),
+    /// The misaligned load address.
+    #[display("load address misaligned: {_0:#x}")]
+    LoadAddressMisaligned(u64),
+    /// The load address and access size outside of memory.
+    #[display("load access fault at {address:#x} ({size} bytes)")]
+    LoadAccessFault { address: u64, size: u64 },
+    /// The misaligned store or AMO address.
+    #[display("store address misaligned: {_0:#x}")]
+    StoreAddressMisaligned(u64),
+    /// The store or AMO address and access size outside of memory.
+    #[display("store access fault at {address:#x} ({size} bytes)")]
+    StoreAccessFault { address: u64, size: u64 },
+    #[display("environment call from U-mode")]
+    EnvironmentCallFromUMode,
+    #[display("environment call from S-mode")]
+    EnvironmentCallFromSMode,
+    #[display("environment call from M-mode")]
+    EnvironmentCallFromMMode,
+    /// The virtual address of the instruction parcel that could not be translated.
+    #[display("instruction page fault at {_0:#x}")]
+    InstructionPageFault(u64),
+    /// The virtual load address that could not be translated.
+    #[display("load page fault at {_0:#x}")]
+    LoadPageFault(u64),
+    /// The virtual store or AMO address that could not be translated.
+    #[display("store page fault at {_0:#x}")]
+    StorePageFault(u64),
+}
+
+impl Exception {
+    /// Returns the exception code written to `mcause`.
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault { .. } => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault { .. } => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    /// Returns the exception specific value written to `mtval`.
+    pub fn value(&self) -> u64 {
+        match self {
+            Exception::IllegalInstruction(instruction) => *instruction as u64,
+            Exception::InstructionAddressMisaligned(address)
+            | Exception::InstructionAccessFault(address)
+            | Exception::Breakpoint(address)
+            | Exception::LoadAddressMisaligned(address)
+            | Exception::StoreAddressMisaligned(address)
+            | Exception::InstructionPageFault(address)
+            | Exception::LoadPageFault(address)
+            | Exception::StorePageFault(address)
+            | Exception::LoadAccessFault { address, .. }
+            | Exception::StoreAccessFault { address, .. } => *address,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+}
+
+/// An asynchronous interrupt, raised by a device and enabled in `mie`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Interrupt {
+    #[display("supervisor software interrupt")]
+    SupervisorSoftware,
+    #[display("machine software interrupt")]
+    MachineSoftware,
+    #[display("supervisor timer interrupt")]
+    SupervisorTimer,
+    #[display("machine timer interrupt")]
+    MachineTimer,
+    #[display("supervisor external interrupt")]
+    SupervisorExternal,
+    #[display("machine external interrupt")]
+    MachineExternal,
+}
+
+impl Interrupt {
+    /// The interrupts in order of decreasing priority.
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    /// Returns the exception code written to `mcause` or `scause`, without the interrupt bit.
+    pub fn code(self) -> u64 {
+        match self {
+            Interrupt::SupervisorSoftware => 1,
+            Interrupt::MachineSoftware => 3,
+            Interrupt::SupervisorTimer => 5,
+            Interrupt::MachineTimer => 7,
+            Interrupt::SupervisorExternal => 9,
+            Interrupt::MachineExternal => 11,
+        }
+    }
+
+    /// Returns the bit of the interrupt in `mip` and `mie`.
+    pub fn mask(self) -> u64 {
+        1 << self.code()
+    }
+}
+
+/// The cause of a trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum Trap {
+    #[display("{_0}")]
+    Exception(Exception),
+    #[display("{_0}")]
+    Interrupt(Interrupt),
+}
+
+/// The kind of a memory access, which selects the exceptions it raises.
+/// AMOs raise store exceptions, even though they also read memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    /// Returns the address misaligned exception for this kind of access.
+    pub fn misaligned(self, address: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAddressMisaligned(address),
+            AccessType::Load => Exception::LoadAddressMisaligned(address),
+            AccessType::Store => Exception::StoreAddressMisaligned(address),
+        }
+    }
+
+    /// Returns the access fault exception for this kind of access.
+    pub fn access_fault(self, address: u64, size: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionAccessFault(address),
+            AccessType::Load => Exception::LoadAccessFault { address, size },
+            AccessType::Store => Exception::StoreAccessFault { address, size },
+        }
+    }
+
+    /// Returns the page fault exception for this kind of access.
+    pub fn page_fault(self, address: u64) -> Exception {
+        match self {
+            AccessType::Fetch => Exception::InstructionPageFault(address),
+            AccessType::Load => Exception::LoadPageFault(address),
+            AccessType::Store => Exception::StorePageFault(address),
+        }
+    }
+}
@@ -29,6 +29,30 @@ impl_sign_extend!(u16, i16);
 impl_sign_extend!(u32, i32);
 impl_sign_extend!(u64, i64);
 
+/// Parses an address given in hexadecimal (with a `0x` prefix) or decimal.
+/// Underscores can be used to separate digits, e.g. `0x8000_0000`.
+pub fn parse_address(address: &str) -> Result<u64, String> {
+    let address = address.replace('_', "");
+    match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|error| error.to_string())
+}
+
+/// Appends `byte` to the incomplete UTF-8 sequence in `pending` and returns the text once
+/// the sequence is complete. Invalid sequences are replaced by `U+FFFD`.
+pub fn push_utf8(pending: &mut Vec<u8>, byte: u8) -> Option<String> {
+    pending.push(byte);
+    let text = match std::str::from_utf8(pending) {
+        Ok(text) => text.to_string(),
+        Err(error) if error.error_len().is_none() => return None,
+        Err(_) => String::from_utf8_lossy(pending).into_owned(),
+    };
+    pending.clear();
+    Some(text)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,4 +99,3 @@ mod tests {
         );
     }
 }
-